@@ -0,0 +1,16 @@
+// The gRPC service is generated from proto/kernel.proto with the grpc
+// feature, using a protoc that comes with the build so none has to be
+// installed.
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/kernel.proto");
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+        // jupyterm only serves it, and the generated client needs edition 2021
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/kernel.proto"], &["proto"])
+            .expect("couldn't compile the proto");
+    }
+}
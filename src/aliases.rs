@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+
+// Code templates from the [aliases] table in config.toml, run by typing a
+// cell like `;name arg1 arg2`. {1}, {2}, ... in the template are replaced with
+// the arguments. A backslash in front of the sigil sends the line as it is.
+#[derive(Debug)]
+pub struct Aliases {
+    pub sigil: String,
+    pub templates: BTreeMap<String, String>,
+}
+
+impl Aliases {
+    // Ok(None) if `cell` isn't an alias, otherwise the code to run instead
+    pub fn expand(&self, cell: &str) -> Result<Option<String>, String> {
+        let line = cell.trim();
+        if self.sigil.is_empty() || line.contains('\n') {
+            return Ok(None);
+        }
+
+        let escaped = format!("\\{}", self.sigil);
+        if line.starts_with(&escaped) {
+            return Ok(Some(line[1..].to_string()));
+        }
+        if !line.starts_with(&self.sigil) {
+            return Ok(None);
+        }
+
+        let mut words = line[self.sigil.len()..].split_whitespace();
+        let name = words.next().unwrap_or("");
+        let template = match self.templates.get(name) {
+            Some(template) => template,
+            // maybe it means something to the kernel, like IPython's ;autocall
+            None => return Ok(None),
+        };
+
+        let args: Vec<&str> = words.collect();
+        let mut code = template.clone();
+        for (i, arg) in args.iter().enumerate() {
+            code = code.replace(&format!("{{{}}}", i + 1), arg);
+        }
+
+        let missing = args.len() + 1;
+        if code.contains(&format!("{{{}}}", missing)) {
+            return Err(format!(
+                "alias {} needs more than {} argument{}",
+                name,
+                args.len(),
+                if args.len() == 1 { "" } else { "s" }
+            ));
+        }
+        Ok(Some(code.trim_end().to_string()))
+    }
+}
@@ -0,0 +1,68 @@
+use crate::display::strip_ansi;
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Where :capture sends the next cell's output, its text_of each message
+// written as it comes in, so a cell that dies halfway still leaves what it
+// printed.
+#[derive(Debug)]
+pub struct Capture {
+    file: File,
+    path: PathBuf,
+    // whether the output is still printed too
+    pub display: bool,
+    written: u64,
+}
+
+impl Capture {
+    // Opened when :capture is given, so a path that can't be written to is
+    // reported before there's a cell to lose.
+    pub fn open(path: &Path, append: bool, display: bool) -> io::Result<Capture> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)?;
+        Ok(Capture {
+            file,
+            path: path.to_path_buf(),
+            display,
+            written: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // how much has gone to the file
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn write(&mut self, text: &str) -> io::Result<()> {
+        self.file.write_all(text.as_bytes())?;
+        self.written += text.len() as u64;
+        Ok(())
+    }
+}
+
+// The text of a stream or of a result's text/plain, without the colours,
+// what :capture writes and :copy copies. None for the other messages and
+// results with nothing but pictures.
+pub fn text_of(msg_type: &str, content: &Value) -> Option<String> {
+    let text = match msg_type {
+        "stream" => content["text"].as_str()?,
+        "execute_result" | "display_data" => content["data"]["text/plain"].as_str()?,
+        _ => return None,
+    };
+    let mut text = strip_ansi(text);
+    // results end without one, streams have their own
+    if msg_type != "stream" && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    Some(text)
+}
@@ -0,0 +1,2169 @@
+use crate::editor::Helper;
+use crate::error::Error;
+use crate::events::{OutputEvent, OutputKind};
+use crate::protocol::{self, CountOrder};
+use crate::rate_limit::RateLimiter;
+use crate::signing::{SignatureScheme, Signer};
+use crate::socket_options::SocketOption;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, FixedOffset, SecondsFormat, Utc};
+use lru::LruCache;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::num::NonZeroUsize;
+use std::ops::Deref;
+use std::str;
+use std::time::{Duration, Instant};
+#[cfg(feature = "async")]
+use tokio::sync::broadcast;
+use tracing::{field, info_span, trace, warn};
+use uuid::Uuid;
+
+// the version of the Jupyter messaging protocol jupyterm speaks
+pub const PROTOCOL_VERSION: &str = "5.3";
+
+// What execute_raw_bytes puts in front of the base64 of its code. No kernel
+// knows it on its own, it's for kernels written to look for it.
+pub const RAW_CODE_PREFIX: &str = "base64:";
+
+// how long to wait for a reply on the shell channel, in milliseconds
+const REPLY_TIMEOUT: i64 = 1000;
+
+// the debugger can take a while to start up on the first debug_request
+const DEBUG_REPLY_TIMEOUT: i64 = 10000;
+
+// how long execute_with_timeout waits for the rest of an interrupted cell
+const INTERRUPT_GRACE: Duration = Duration::from_millis(500);
+
+// how long cancel_pending_execute waits for a kernel to stop after interrupting it
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how many iopub msg_ids to remember for dropping retransmitted messages
+const SEEN_IDS_CAPACITY: usize = 1024;
+
+// how often wait_for_kernel_ready asks again
+const KERNEL_INFO_INTERVAL: Duration = Duration::from_millis(200);
+
+// how long clone_session gives an already running kernel to answer
+const CLONE_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how many output events a slow subscriber can fall behind by
+#[cfg(feature = "async")]
+const EVENTS_CAPACITY: usize = 256;
+
+fn make_channel(
+    context: &zmq::Context,
+    ports: &Value,
+    channel_type: &str,
+    identity: &str,
+    options: &[(String, SocketOption)],
+) -> Channel {
+    let url = format!("tcp://127.0.0.1:{}", ports[channel_type]);
+    let kind = match channel_type {
+        "shell" | "stdin" | "control" => zmq::DEALER,
+        "iopub" => zmq::SUB,
+        "hb" => zmq::REQ,
+        _ => {
+            panic!("Unknown channel type!");
+        }
+    };
+    let channel = context.socket(kind).unwrap();
+    channel.set_linger(1000).unwrap();
+    // shell and stdin share an identity so the kernel can route
+    // input_requests back to us
+    if kind == zmq::DEALER {
+        channel.set_identity(identity.as_bytes()).unwrap();
+    }
+    // before connecting, the high water marks only count for connections
+    // made after them
+    for (_, option) in options.iter().filter(|(name, _)| name == channel_type) {
+        if let Err(err) = option.apply(&channel) {
+            warn!(channel = channel_type, "couldn't set {:?}: {}", option, err);
+        }
+    }
+    channel.connect(&url).unwrap();
+    if kind == zmq::SUB {
+        channel.set_subscribe(b"").unwrap();
+    }
+    Channel {
+        socket: channel,
+        kind,
+        url,
+    }
+}
+
+// A client's socket for one of the kernel's channels, with what it is and
+// where it's connected, which zmq::Socket itself has no Debug for.
+pub struct Channel {
+    socket: zmq::Socket,
+    kind: zmq::SocketType,
+    url: String,
+}
+
+impl Deref for Channel {
+    type Target = zmq::Socket;
+
+    fn deref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Channel")
+            .field("kind", &self.kind)
+            .field("url", &self.url)
+            .finish()
+    }
+}
+
+// like "DEALER tcp://127.0.0.1:5555"
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} {}", self.kind, self.url)
+    }
+}
+
+// How to interrupt a kernel, from interrupt_mode in its kernelspec.
+// Signal means SIGINT to the kernel's process group, which only works for
+// kernels jupyterm started, message an interrupt_request on control.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterruptMode {
+    Signal,
+    Message,
+}
+
+impl InterruptMode {
+    pub fn parse(mode: &str) -> Option<InterruptMode> {
+        match mode {
+            "signal" => Some(InterruptMode::Signal),
+            "message" => Some(InterruptMode::Message),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadySocket {
+    Iopub,
+    Shell,
+    Stdin,
+    Heartbeat,
+}
+
+// The messages about one request as for_each_message hands them over, one
+// at a time for callers that can't take them in a closure, like ones that
+// give the client back between messages.
+#[derive(Debug)]
+pub struct RequestMessages {
+    msg_id: String,
+    idle: bool,
+    replied: bool,
+    // both channels can have one ready at once
+    pending: VecDeque<Map<String, Value>>,
+}
+
+impl RequestMessages {
+    pub fn new(msg_id: &str) -> RequestMessages {
+        RequestMessages {
+            msg_id: msg_id.to_string(),
+            idle: false,
+            replied: false,
+            pending: VecDeque::new(),
+        }
+    }
+
+    // whether the kernel is idle and has replied, and they've all been read
+    pub fn done(&self) -> bool {
+        self.idle && self.replied && self.pending.is_empty()
+    }
+
+    // The next message, waiting up to timeout_ms for one on `client`'s
+    // channels. None once done, or when nothing came for the request in time.
+    pub fn next(
+        &mut self,
+        client: &mut Cutypr,
+        timeout_ms: i64,
+    ) -> Result<Option<Map<String, Value>>, Error> {
+        if self.pending.is_empty() && !self.done() {
+            for socket in client.poll_all(timeout_ms)? {
+                let msg = match socket {
+                    ReadySocket::Iopub => match client.get_msg() {
+                        Some(msg) => msg,
+                        None => continue,
+                    },
+                    ReadySocket::Shell => match client.get_shell_msg() {
+                        Some(msg) => msg,
+                        None => continue,
+                    },
+                    // stdin is for whoever asked with allow_stdin
+                    ReadySocket::Stdin | ReadySocket::Heartbeat => continue,
+                };
+                if msg["parent_header"]["msg_id"] != self.msg_id.as_str() {
+                    continue;
+                }
+
+                match msg["header"]["msg_type"].as_str() {
+                    Some("status") => self.idle = msg["content"]["execution_state"] == "idle",
+                    Some("execute_reply") => self.replied = true,
+                    _ => {}
+                }
+                self.pending.push_back(msg);
+            }
+        }
+        Ok(self.pending.pop_front())
+    }
+}
+
+// What running one cell produced. status is the execute_reply's "ok",
+// "error" or "aborted", and stays None for cells that were never run.
+#[derive(Clone, Debug, Default)]
+pub struct ExecutionResult {
+    pub status: Option<String>,
+    pub execution_count: Option<i64>,
+    pub outputs: Vec<OutputEvent>,
+    // None from kernels that don't say when they started on a cell
+    pub timing: Option<KernelTiming>,
+}
+
+// A cell execute_with_timeout gave up on, with what it sent before that
+// and in the grace period after interrupting it. source is None when the
+// cell ran out of time, and why when the kernel was lost first, which
+// leaves the cell unfinished too.
+#[derive(Debug)]
+pub struct TimeoutError {
+    pub partial_output: ExecutionResult,
+    pub source: Option<Box<Error>>,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.source {
+            Some(err) => write!(f, "lost the kernel before the cell finished: {}", err),
+            None => write!(f, "the cell timed out and was interrupted"),
+        }
+    }
+}
+
+impl std::error::Error for TimeoutError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|err| err as &(dyn std::error::Error + 'static))
+    }
+}
+
+// How long a cell spent in the kernel by the kernel's own clock, from when
+// ipykernel says it started on it in the execute_reply's metadata to the
+// reply's date. queued is from the request's date to the start, and is
+// only there when both clocks agree that far.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KernelTiming {
+    pub queued: Option<Duration>,
+    pub ran: Duration,
+}
+
+pub fn kernel_timing(reply: &Map<String, Value>) -> Option<KernelTiming> {
+    let date = |value: &Value| -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(value.as_str()?).ok()
+    };
+    let started = date(&reply["metadata"]["started"])?;
+    let finished = date(&reply["header"]["date"])?;
+    let sent = date(&reply["parent_header"]["date"]);
+    Some(KernelTiming {
+        queued: sent.and_then(|sent| (started - sent).to_std().ok()),
+        ran: (finished - started).to_std().ok()?,
+    })
+}
+
+// How an execute_request asks for its code to be run. The default is what
+// a cell typed at the prompt gets.
+#[derive(Clone, Debug)]
+pub struct ExecuteOptions {
+    // no execute_result or execute_input, and no execution count
+    pub silent: bool,
+    // a place in the kernel's %history, and a new execution count
+    pub store_history: bool,
+    pub allow_stdin: bool,
+    // name to expression, evaluated after the code, none sends null
+    pub user_expressions: Map<String, Value>,
+    // if it fails, the kernel aborts what's queued behind it, and
+    // execute_many_with skips the cells after it
+    pub stop_on_error: bool,
+}
+
+impl Default for ExecuteOptions {
+    fn default() -> ExecuteOptions {
+        ExecuteOptions {
+            silent: false,
+            store_history: true,
+            allow_stdin: true,
+            user_expressions: Map::new(),
+            stop_on_error: true,
+        }
+    }
+}
+
+impl ExecuteOptions {
+    // for jupyterm's own code, which shouldn't show up anywhere
+    pub fn internal() -> ExecuteOptions {
+        ExecuteOptions {
+            silent: true,
+            store_history: false,
+            allow_stdin: false,
+            user_expressions: Map::new(),
+            stop_on_error: true,
+        }
+    }
+}
+
+// What a user_expression came to in an execute_reply, its text/plain or
+// the exception it raised.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExpressionResult {
+    Ok(String),
+    Error { ename: String, evalue: String },
+}
+
+// The text of a set_next_input payload in an execute_reply's content,
+// which IPython's %load and %recall send for the next cell.
+pub fn next_input(content: &Value) -> Option<String> {
+    content["payload"]
+        .as_array()?
+        .iter()
+        .find(|payload| payload["source"] == "set_next_input")?["text"]
+        .as_str()
+        .map(String::from)
+}
+
+// the user_expressions of an execute_reply's content, by the names they
+// were sent with
+pub fn user_expressions(content: &Value) -> BTreeMap<String, ExpressionResult> {
+    let expressions = match content["user_expressions"].as_object() {
+        Some(expressions) => expressions,
+        None => return BTreeMap::new(),
+    };
+    expressions
+        .iter()
+        .map(|(name, result)| {
+            let result = match result["status"].as_str() {
+                Some("ok") => ExpressionResult::Ok(
+                    result["data"]["text/plain"]
+                        .as_str()
+                        .unwrap_or("")
+                        .to_string(),
+                ),
+                _ => ExpressionResult::Error {
+                    ename: result["ename"].as_str().unwrap_or("error").to_string(),
+                    evalue: result["evalue"].as_str().unwrap_or("").to_string(),
+                },
+            };
+            (name.clone(), result)
+        })
+        .collect()
+}
+
+// What to ask for in a history_request, with at most this many cells.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HistoryQuery {
+    // the latest cells
+    Tail(usize),
+    // cells matching a glob pattern like "*plot*"
+    Search(String, usize),
+}
+
+// One cell of the kernel's history, by the kernel session it ran in and
+// its line, that is its In number, there.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub session: i64,
+    pub line: i64,
+    pub source: String,
+    // the text of its Out[N], when asked for and it had one
+    pub output: Option<String>,
+}
+
+// The entries of a history_reply, [session, line, input] each, or
+// [session, line, [input, output]] when the request asked for output.
+fn history_entries(reply: &Map<String, Value>) -> Vec<HistoryEntry> {
+    let entries = reply["content"]["history"]
+        .as_array()
+        .map_or(&[][..], Vec::as_slice);
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let (source, output) = match &entry[2] {
+                Value::Array(pair) => (pair.first()?.as_str()?, pair.get(1)),
+                source => (source.as_str()?, None),
+            };
+            Some(HistoryEntry {
+                session: entry[0].as_i64()?,
+                line: entry[1].as_i64()?,
+                source: source.to_string(),
+                output: output.and_then(Value::as_str).map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+// content of a kernel_info_reply
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct KernelInfoReply {
+    // the protocol has had it since 5.1
+    pub status: String,
+    #[serde(deserialize_with = "protocol_version")]
+    pub protocol_version: String,
+    pub implementation: String,
+    pub implementation_version: String,
+    pub language_info: LanguageInfo,
+    pub banner: String,
+    pub help_links: Vec<HelpLink>,
+    // whether it answers debug_request, ipykernel 6 and later do
+    pub debugger: bool,
+}
+
+// content of a comm_info_reply, the comms open in the kernel by comm id
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CommInfoReply {
+    pub status: String,
+    pub comms: HashMap<String, CommTarget>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CommTarget {
+    pub target_name: String,
+}
+
+impl KernelInfoReply {
+    // like "python 3.11.4, ipython 8.12.0", for saying which kernel this is
+    pub fn summary(&self) -> String {
+        let language = &self.language_info;
+        format!(
+            "{} {}, {} {}",
+            language.name, language.version, self.implementation, self.implementation_version
+        )
+    }
+
+    // Why jupyterm might not get along with the kernel, when it speaks
+    // another major version of the protocol or one from before 5.0.
+    pub fn protocol_warning(&self) -> Option<String> {
+        let major = |version: &str| version.split('.').next()?.parse::<u32>().ok();
+        let theirs = major(&self.protocol_version)?;
+        if Some(theirs) == major(PROTOCOL_VERSION) {
+            return None;
+        }
+        Some(format!(
+            "the kernel speaks version {} of the Jupyter protocol and jupyterm speaks {}, some things may not work",
+            self.protocol_version, PROTOCOL_VERSION
+        ))
+    }
+}
+
+// "5.3", or [4, 1] from kernels older than 5.0
+fn protocol_version<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(version) => version,
+        Value::Array(parts) => parts
+            .iter()
+            .map(|part| part.to_string())
+            .collect::<Vec<_>>()
+            .join("."),
+        _ => String::new(),
+    })
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LanguageInfo {
+    pub name: String,
+    pub version: String,
+    pub mimetype: String,
+    pub file_extension: String,
+}
+
+// documentation the kernel points to, shown by :links
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct HelpLink {
+    pub text: String,
+    pub url: String,
+}
+
+pub struct Session {
+    // the connection file's key, messages go unsigned without one
+    pub key: String,
+    pub session_id: String,
+    // the connection file's signature_scheme
+    pub signature_scheme: SignatureScheme,
+}
+
+// without the key, which is what lets anyone run code on the kernel
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let key = if self.key.is_empty() { "" } else { "..." };
+        f.debug_struct("Session")
+            .field("key", &key)
+            .field("session_id", &self.session_id)
+            .field("signature_scheme", &self.signature_scheme)
+            .finish()
+    }
+}
+
+pub struct Cutypr {
+    context: zmq::Context,
+    session: Session,
+    signer: Box<dyn Signer>,
+    ports: Value,
+    message_count: Cell<i32>,
+    shell_channel: Option<Channel>,
+    iopub_channel: Option<Channel>,
+    stdin_channel: Option<Channel>,
+    hb_channel: Option<Channel>,
+    control_channel: Option<Channel>,
+    seen_ids: LruCache<String, ()>,
+    // the execution counts on iopub, which should only go up
+    count_order: CountOrder,
+    // set_socket_option's, by channel, for every socket made for it
+    socket_options: Vec<(String, SocketOption)>,
+    // only known for kernels jupyterm started
+    kernel_pid: Option<u32>,
+    interrupt_mode: InterruptMode,
+    #[cfg(windows)]
+    interrupt_event: Option<isize>,
+    #[cfg(feature = "async")]
+    events: Option<broadcast::Sender<OutputEvent>>,
+    // the kernelspec's name, or the kernel's implementation when that's unknown,
+    // for traces
+    kernel_name: String,
+    // --max-executions-per-second, execute_requests wait for it
+    rate_limiter: RefCell<Option<RateLimiter>>,
+    // the msg_ids of the execute_requests not replied to yet, in the order
+    // the kernel runs them, for cancel_pending_execute
+    pending_executes: RefCell<VecDeque<String>>,
+}
+
+// what it's connected to and how, leaving out the zmq context and the
+// signer, which has the key
+impl fmt::Debug for Cutypr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cutypr")
+            .field("session", &self.session)
+            .field("ports", &self.ports)
+            .field("message_count", &self.message_count)
+            .field("shell_channel", &self.shell_channel)
+            .field("iopub_channel", &self.iopub_channel)
+            .field("stdin_channel", &self.stdin_channel)
+            .field("hb_channel", &self.hb_channel)
+            .field("control_channel", &self.control_channel)
+            .field("count_order", &self.count_order)
+            .field("socket_options", &self.socket_options)
+            .field("kernel_pid", &self.kernel_pid)
+            .field("interrupt_mode", &self.interrupt_mode)
+            .field("kernel_name", &self.kernel_name)
+            .field("rate_limiter", &self.rate_limiter)
+            .field("pending_executes", &self.pending_executes)
+            .finish_non_exhaustive()
+    }
+}
+
+// what signs and verifies messages for the session's key, made again
+// whenever the key changes
+fn signer(session: &Session) -> Box<dyn Signer> {
+    session.signature_scheme.signer(session.key.as_bytes())
+}
+
+impl Cutypr {
+    pub fn new(session: Session, ports: Value) -> Cutypr {
+        Cutypr {
+            context: zmq::Context::new(),
+            signer: signer(&session),
+            session,
+            ports,
+            message_count: Cell::new(1),
+            shell_channel: None,
+            iopub_channel: None,
+            stdin_channel: None,
+            hb_channel: None,
+            control_channel: None,
+            seen_ids: LruCache::new(NonZeroUsize::new(SEEN_IDS_CAPACITY).unwrap()),
+            count_order: CountOrder::new(),
+            socket_options: Vec::new(),
+            kernel_pid: None,
+            interrupt_mode: InterruptMode::Message,
+            #[cfg(windows)]
+            interrupt_event: None,
+            #[cfg(feature = "async")]
+            events: None,
+            kernel_name: String::new(),
+            rate_limiter: RefCell::new(None),
+            pending_executes: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    // A second client on the same kernel, with a session of its own, for a
+    // worker sending code alongside this one. It has this one's socket
+    // options and what it knows of the kernel's process, and its own sockets,
+    // so replies and input_requests go to whichever sent the request, while
+    // both see everything on iopub. Its channels are up and the kernel has
+    // answered on them by the time it's returned. Its session id is a new
+    // random UUID, like the session ids jupyter_client makes.
+    pub fn clone_session(&self) -> Result<Cutypr, Error> {
+        let session = Session {
+            key: self.session.key.clone(),
+            session_id: Uuid::new_v4().to_string(),
+            signature_scheme: self.session.signature_scheme,
+        };
+        let mut client = Cutypr::new(session, self.ports.clone());
+        client.socket_options = self.socket_options.clone();
+        client.kernel_pid = self.kernel_pid;
+        client.interrupt_mode = self.interrupt_mode;
+        #[cfg(windows)]
+        {
+            client.interrupt_event = self.interrupt_event;
+        }
+        client.kernel_name = self.kernel_name.clone();
+        client.initialize_channels();
+        client.wait_for_kernel_ready(CLONE_READY_TIMEOUT)?;
+        Ok(client)
+    }
+
+    // every iopub message read from here on is also sent to the receiver
+    #[cfg(feature = "async")]
+    pub fn subscribe(&mut self) -> broadcast::Receiver<OutputEvent> {
+        self.events
+            .get_or_insert_with(|| broadcast::channel(EVENTS_CAPACITY).0)
+            .subscribe()
+    }
+
+    pub fn initialize_channels(&mut self) {
+        self.shell_channel = Some(self.make_channel("shell"));
+        self.iopub_channel = Some(self.make_channel("iopub"));
+        self.stdin_channel = Some(self.make_channel("stdin"));
+        self.hb_channel = Some(self.make_channel("hb"));
+        self.control_channel = Some(self.make_channel("control"));
+    }
+
+    // Talk to the kernel on new ports and key from now on, after it's been
+    // restarted on them. The old sockets, and whatever was left unread on
+    // them, are dropped.
+    pub fn reconnect(&mut self, key: &str, ports: Value) {
+        self.session.key = key.to_string();
+        self.signer = signer(&self.session);
+        self.ports = ports;
+        self.seen_ids.clear();
+        self.count_order.reset();
+        self.pending_executes.get_mut().clear();
+        self.initialize_channels();
+    }
+
+    // just the control channel, for sending one-off requests like shutdown
+    // to a kernel without subscribing to its output
+    pub fn initialize_control_channel(&mut self) {
+        self.control_channel = Some(self.make_channel("control"));
+    }
+
+    fn make_channel(&self, channel_type: &str) -> Channel {
+        make_channel(
+            &self.context,
+            &self.ports,
+            channel_type,
+            &self.session.session_id,
+            &self.socket_options,
+        )
+    }
+
+    // Set a ZMQ option on the socket for `channel`, one of
+    // socket_options::CHANNELS, and on the ones it's connected with again
+    // after a restart. Options set before the channels are initialized are
+    // set as their sockets are made, where failing is only logged.
+    pub fn set_socket_option(&mut self, channel: &str, option: SocketOption) -> Result<(), Error> {
+        let socket = match channel {
+            "shell" => &self.shell_channel,
+            "iopub" => &self.iopub_channel,
+            "stdin" => &self.stdin_channel,
+            "control" => &self.control_channel,
+            "hb" => &self.hb_channel,
+            _ => return Err(Error::Channel(channel.to_string())),
+        };
+        if let Some(socket) = socket {
+            option.apply(socket)?;
+        }
+        let same = |(name, set): &(String, SocketOption)| {
+            name == channel && mem::discriminant(set) == mem::discriminant(&option)
+        };
+        self.socket_options.retain(|set| !same(set));
+        self.socket_options.push((channel.to_string(), option));
+        Ok(())
+    }
+
+    fn make_message(&self, message_type: &str, content: Map<String, Value>) -> Map<String, Value> {
+        let mut msg = Map::new();
+
+        let msg_id = format!("{}_{}", self.session.session_id, self.message_count.get());
+        self.message_count.set(self.message_count.get() + 1);
+
+        let mut header = Map::new();
+        header.insert("msg_id".to_string(), Value::String(msg_id.clone()));
+        header.insert(
+            "msg_type".to_string(),
+            Value::String(message_type.to_string()),
+        );
+        header.insert("username".to_string(), Value::String("vinayak".to_string()));
+        header.insert(
+            "version".to_string(),
+            Value::String(PROTOCOL_VERSION.to_string()),
+        );
+        // when the kernel got the request is measured from here
+        header.insert(
+            "date".to_string(),
+            Value::String(Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true)),
+        );
+        header.insert(
+            "session".to_string(),
+            Value::String(self.session.session_id.to_string()),
+        );
+
+        msg.insert("header".to_string(), Value::Object(header));
+        msg.insert("msg_id".to_string(), Value::String(msg_id.clone()));
+        msg.insert(
+            "msg_type".to_string(),
+            Value::String(message_type.to_string()),
+        );
+        msg.insert("content".to_string(), Value::Object(content));
+        msg.insert("metadata".to_string(), Value::Object(Map::new()));
+        msg.insert("parent_header".to_string(), Value::Object(Map::new()));
+
+        msg
+    }
+
+    fn sign(&self, msg_list: &[String]) -> String {
+        let frames: Vec<&[u8]> = msg_list.iter().map(|frame| frame.as_bytes()).collect();
+        self.signer.sign(&frames)
+    }
+
+    fn serialize(&self, msg: Map<String, Value>) -> Vec<String> {
+        let mut msg_list: Vec<String> = vec![
+            msg["header"].to_string(),
+            msg["parent_header"].to_string(),
+            msg["metadata"].to_string(),
+            msg["content"].to_string(),
+        ];
+
+        // sign
+        let signature = self.sign(&msg_list);
+
+        msg_list.insert(0, signature);
+        msg_list.insert(0, String::from("<IDS|MSG>"));
+        msg_list
+    }
+
+    pub fn execute(&self, code: &str) -> String {
+        self.execute_with(code, &ExecuteOptions::default())
+    }
+
+    // Like execute, for code that isn't UTF-8 or isn't text at all, sent as
+    // RAW_CODE_PREFIX and its base64 since the code field is a JSON string.
+    // Kernels that don't look for the prefix get it as it is, which makes
+    // this a way to send them code they can't make sense of, too.
+    pub fn execute_raw_bytes(&self, code: &[u8]) -> String {
+        self.execute(&format!("{}{}", RAW_CODE_PREFIX, STANDARD.encode(code)))
+    }
+
+    // Run `code` without a new execution count or a place in the kernel's
+    // history, and without display hooks, for jupyterm's own requests. What
+    // comes back is left to the caller, and nothing can answer input().
+    pub fn execute_silent(&self, code: &str) -> String {
+        self.execute_with(code, &ExecuteOptions::internal())
+    }
+
+    // Like execute, but the kernel also evaluates `expressions` once the
+    // cell has run, name to Python expression, and puts what they came to in
+    // the execute_reply for user_expressions() to read.
+    pub fn execute_with_expressions(&self, code: &str, expressions: &Map<String, Value>) -> String {
+        let options = ExecuteOptions {
+            user_expressions: expressions.clone(),
+            ..ExecuteOptions::default()
+        };
+        self.execute_with(code, &options)
+    }
+
+    // Every execute goes through here, so with a rate limit set this is
+    // where a request sleeps until it's allowed out.
+    pub fn execute_with(&self, code: &str, options: &ExecuteOptions) -> String {
+        if let Some(limiter) = self.rate_limiter.borrow_mut().as_mut() {
+            limiter.acquire();
+        }
+
+        // make content
+        let mut content = Map::new();
+        content.insert("code".to_string(), Value::String(code.to_string()));
+        content.insert("silent".to_string(), Value::Bool(options.silent));
+        content.insert(
+            "store_history".to_string(),
+            Value::Bool(options.store_history),
+        );
+        let user_expressions = if options.user_expressions.is_empty() {
+            Value::Null
+        } else {
+            Value::Object(options.user_expressions.clone())
+        };
+        content.insert("user_expressions".to_string(), user_expressions);
+        content.insert("allow_stdin".to_string(), Value::Bool(options.allow_stdin));
+        content.insert(
+            "stop_on_error".to_string(),
+            Value::Bool(options.stop_on_error),
+        );
+
+        // make_message(execute_request, content)
+        let msg = self.make_message("execute_request", content);
+        let msg_id = msg["msg_id"].as_str().unwrap().to_string();
+
+        // serialize
+        let msg_list = self.serialize(msg);
+
+        // send_multipart
+        self.shell_channel
+            .as_ref()
+            .unwrap()
+            .send_multipart(&msg_list, 0)
+            .unwrap();
+
+        self.pending_executes.borrow_mut().push_back(msg_id.clone());
+        msg_id
+    }
+
+    // Run `code` and gather its outputs and execute_reply instead of
+    // displaying them. There is nobody to answer input(), so stdin is off.
+    pub fn execute_and_collect(&mut self, code: &str) -> Result<ExecutionResult, Error> {
+        // exported to OpenTelemetry with the opentelemetry feature
+        let span = info_span!(
+            "execute_and_collect",
+            kernel.name = self.kernel_name.as_str(),
+            execution_count = field::Empty,
+            code.length = code.len(),
+        );
+        let _entered = span.enter();
+
+        let options = ExecuteOptions {
+            allow_stdin: false,
+            ..ExecuteOptions::default()
+        };
+        let msg_id = self.execute_with(code, &options);
+        let result = self.collect(&msg_id, None)?;
+        if let Some(count) = result.execution_count {
+            span.record("execution_count", count);
+        }
+        Ok(result)
+    }
+
+    // Like execute_and_collect, but the cell doesn't get an execution count
+    // or show up in the kernel's history. Kernels still send its streams.
+    pub fn execute_silent_and_collect(&mut self, code: &str) -> Result<ExecutionResult, Error> {
+        let msg_id = self.execute_silent(code);
+        self.collect(&msg_id, None)
+    }
+
+    // Clear the kernel's namespace, imports and history with IPython's
+    // `%reset -f`, without restarting it. Much faster than a restart, but
+    // only for kernels with IPython's magics, others raise Error::Kernel.
+    pub fn reset_kernel_state(&mut self) -> Result<(), Error> {
+        let result = self.execute_silent_and_collect("%reset -f")?;
+        if result.status.as_deref() == Some("ok") {
+            // and its count with them
+            self.count_order.reset();
+            return Ok(());
+        }
+        let error = result.outputs.iter().find_map(|event| match &event.kind {
+            OutputKind::Error { ename, evalue, .. } => Some(format!("{}: {}", ename, evalue)),
+            _ => None,
+        });
+        Err(Error::Kernel(error.unwrap_or_else(|| {
+            "an error running %reset -f".to_string()
+        })))
+    }
+
+    // Like execute_and_collect, but a cell still running after `timeout` is
+    // interrupted and comes back as a TimeoutError, with its output so far.
+    // A socket that fails while waiting leaves the cell unfinished too, it
+    // comes back the same way, with the output from before and the error.
+    pub fn execute_with_timeout(
+        &mut self,
+        code: &str,
+        timeout: Duration,
+    ) -> Result<ExecutionResult, TimeoutError> {
+        let options = ExecuteOptions {
+            allow_stdin: false,
+            ..ExecuteOptions::default()
+        };
+        let msg_id = self.execute_with(code, &options);
+        let mut result = ExecutionResult::default();
+        match self.collect_into(&msg_id, Some(timeout), &mut result) {
+            Ok(false) => Ok(result),
+            Ok(true) => Err(TimeoutError {
+                partial_output: result,
+                source: None,
+            }),
+            Err(err) => Err(TimeoutError {
+                partial_output: result,
+                source: Some(Box::new(err)),
+            }),
+        }
+    }
+
+    // Stop the cell that's running and leave the kernel ready for the next
+    // one: interrupt it, wait for it to go idle, then run an empty silent
+    // cell so the replies to anything queued behind it, which the kernel
+    // aborts, are read and dropped on the way. What the running cell sent
+    // comes back with status "aborted", or with no status when no cell was
+    // waiting for its reply, and Error::Timeout if the kernel kept going
+    // regardless. It takes &mut self and gives back the ExecutionResult,
+    // not (), because reading the cell's messages does and the result is
+    // what the caller has of the cell.
+    pub fn cancel_pending_execute(&mut self) -> Result<ExecutionResult, Error> {
+        let msg_id = match self.pending_executes.get_mut().front() {
+            Some(msg_id) => msg_id.clone(),
+            None => return Ok(ExecutionResult::default()),
+        };
+        self.interrupt_kernel()?;
+        let mut result = match self.collect(&msg_id, Some(CANCEL_TIMEOUT)) {
+            Ok(result) => result,
+            Err(Error::CellTimeout(_)) => return Err(Error::Timeout("idle after an interrupt")),
+            Err(err) => return Err(err),
+        };
+        result.status = Some("aborted".to_string());
+
+        let flush = self.execute_silent("");
+        match self.collect(&flush, Some(CANCEL_TIMEOUT)) {
+            Ok(_) => Ok(result),
+            Err(Error::CellTimeout(_)) => Err(Error::Timeout("execute_reply")),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn collect(
+        &mut self,
+        msg_id: &str,
+        timeout: Option<Duration>,
+    ) -> Result<ExecutionResult, Error> {
+        let mut result = ExecutionResult::default();
+        if self.collect_into(msg_id, timeout, &mut result)? {
+            return Err(Error::CellTimeout(TimeoutError {
+                partial_output: result,
+                source: None,
+            }));
+        }
+        Ok(result)
+    }
+
+    // collect's loop, into `result` so what came is kept if it fails.
+    // Whether the cell ran out of time and was interrupted.
+    fn collect_into(
+        &mut self,
+        msg_id: &str,
+        timeout: Option<Duration>,
+        result: &mut ExecutionResult,
+    ) -> Result<bool, Error> {
+        let mut idle = false;
+        let mut replied = false;
+        let mut deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut interrupted = false;
+
+        while !idle || !replied {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                if interrupted {
+                    break;
+                }
+                // the KeyboardInterrupt and the rest of the cell's output
+                // are worth a short wait
+                if let Err(err) = self.interrupt_kernel() {
+                    warn!(%err, "couldn't interrupt a cell that ran out of time");
+                }
+                interrupted = true;
+                deadline = Some(Instant::now() + INTERRUPT_GRACE);
+            }
+            for socket in self.poll_all(10)? {
+                match socket {
+                    ReadySocket::Iopub => {
+                        let msg = match self.get_msg() {
+                            Some(msg) => msg,
+                            None => continue,
+                        };
+                        if msg["parent_header"]["msg_id"] != msg_id {
+                            continue;
+                        }
+
+                        let event = OutputEvent::from_msg(&msg);
+                        match &event.kind {
+                            OutputKind::Status { execution_state } => {
+                                idle = execution_state == "idle";
+                            }
+                            OutputKind::ExecuteInput { .. } => {}
+                            _ => result.outputs.push(event),
+                        }
+                    }
+                    ReadySocket::Shell => {
+                        let reply = match self.get_shell_msg() {
+                            Some(reply) => reply,
+                            None => continue,
+                        };
+                        if reply["parent_header"]["msg_id"] == msg_id {
+                            result.status = reply["content"]["status"].as_str().map(String::from);
+                            result.execution_count = reply["content"]["execution_count"].as_i64();
+                            result.timing = kernel_timing(&reply);
+                            replied = true;
+                        }
+                    }
+                    ReadySocket::Stdin | ReadySocket::Heartbeat => {}
+                }
+            }
+        }
+
+        Ok(interrupted)
+    }
+
+    // Hand every message about the request `msg_id` to `on_msg` as it comes,
+    // the iopub ones and then the shell reply, until the kernel is idle and
+    // has replied. Errors from on_msg stop it there.
+    pub fn for_each_message<F>(&mut self, msg_id: &str, mut on_msg: F) -> Result<(), Error>
+    where
+        F: FnMut(&Map<String, Value>) -> Result<(), Error>,
+    {
+        let mut messages = RequestMessages::new(msg_id);
+        while !messages.done() {
+            if let Some(msg) = messages.next(self, 10)? {
+                on_msg(&msg)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Run cells one after another, like execute_and_collect. An error
+    // skips the rest, which come back with no status.
+    pub fn execute_many(&mut self, cells: &[&str]) -> Result<Vec<ExecutionResult>, Error> {
+        let options = ExecuteOptions {
+            allow_stdin: false,
+            ..ExecuteOptions::default()
+        };
+        self.execute_many_with(cells, &options)
+    }
+
+    // Like execute_many, with each cell sent with `options`. An error only
+    // skips the rest with stop_on_error, without it every cell runs.
+    pub fn execute_many_with(
+        &mut self,
+        cells: &[&str],
+        options: &ExecuteOptions,
+    ) -> Result<Vec<ExecutionResult>, Error> {
+        let mut results = Vec::with_capacity(cells.len());
+        let mut failed = false;
+
+        for cell in cells {
+            if failed {
+                results.push(ExecutionResult::default());
+                continue;
+            }
+
+            let msg_id = self.execute_with(cell, options);
+            let result = self.collect(&msg_id, None)?;
+            failed = options.stop_on_error && result.status.as_deref() == Some("error");
+            results.push(result);
+        }
+
+        Ok(results)
+    }
+
+    // send a request on the shell channel and wait for the reply to it
+    pub fn request(
+        &self,
+        message_type: &str,
+        content: Map<String, Value>,
+    ) -> Option<Map<String, Value>> {
+        self.send_request(self.shell_channel.as_ref().unwrap(), message_type, content)
+    }
+
+    // like request(), but on the control channel, which the kernel reads even
+    // while it's busy running a cell
+    pub fn control_request(
+        &self,
+        message_type: &str,
+        content: Map<String, Value>,
+    ) -> Option<Map<String, Value>> {
+        self.send_request(
+            self.control_channel.as_ref().unwrap(),
+            message_type,
+            content,
+        )
+    }
+
+    // Send a Debug Adapter Protocol request as the content of a
+    // debug_request on the control channel, and return the DAP response the
+    // debug_reply carries. Only the command and arguments are needed, type
+    // defaults to "request" and seq to a number of the client's own.
+    pub fn send_debug_request(&self, dap_request: &Value) -> Result<Value, Error> {
+        let mut request = match dap_request.as_object() {
+            Some(request) if request["command"].is_string() => request.clone(),
+            _ => {
+                return Err(Error::Debugger(
+                    "a DAP request is an object with a command".to_string(),
+                ))
+            }
+        };
+        request
+            .entry("type")
+            .or_insert_with(|| Value::from("request"));
+        let seq = request
+            .entry("seq")
+            .or_insert_with(|| Value::from(self.message_count.get()))
+            .clone();
+
+        let reply = self
+            .send_request_within(
+                self.control_channel.as_ref().unwrap(),
+                "debug_request",
+                request,
+                DEBUG_REPLY_TIMEOUT,
+            )
+            .ok_or(Error::Timeout("debug_reply"))?;
+        let response = &reply["content"];
+        // kernels without a debugger answer with an empty reply, if at all
+        if !response.is_object() || response.as_object().unwrap().is_empty() {
+            return Err(Error::Debugger("the kernel has no debugger".to_string()));
+        }
+        if response["request_seq"] != seq {
+            warn!(
+                expected = %seq,
+                got = %response["request_seq"],
+                "debug_reply for another DAP request"
+            );
+        }
+        Ok(response.clone())
+    }
+
+    fn send_request(
+        &self,
+        channel: &zmq::Socket,
+        message_type: &str,
+        content: Map<String, Value>,
+    ) -> Option<Map<String, Value>> {
+        self.send_request_within(channel, message_type, content, REPLY_TIMEOUT)
+    }
+
+    fn send_request_within(
+        &self,
+        channel: &zmq::Socket,
+        message_type: &str,
+        content: Map<String, Value>,
+        timeout: i64,
+    ) -> Option<Map<String, Value>> {
+        let msg = self.make_message(message_type, content);
+        let msg_id = msg["msg_id"].clone();
+
+        channel.send_multipart(&self.serialize(msg), 0).unwrap();
+
+        // replies to earlier requests that nobody waited for are dropped here
+        loop {
+            match channel.poll(zmq::POLLIN, timeout) {
+                // a Ctrl-C while waiting, the request is still out there
+                Err(zmq::Error::EINTR) => continue,
+                Ok(0) => return None,
+                result => {
+                    result.expect("client failed polling");
+                }
+            }
+            let reply = match self.recv_msg(channel) {
+                Some(reply) => reply,
+                None => continue,
+            };
+            if reply["parent_header"]["msg_id"] == msg_id {
+                return Some(reply);
+            }
+        }
+    }
+
+    // Cells from the kernel's own history, which IPython keeps across
+    // sessions. None if the kernel didn't answer, empty if it keeps none.
+    pub fn history(&self, query: &HistoryQuery) -> Option<Vec<HistoryEntry>> {
+        let mut content = Map::new();
+        content.insert("output".to_string(), Value::Bool(false));
+        content.insert("raw".to_string(), Value::Bool(true));
+        match query {
+            HistoryQuery::Tail(n) => {
+                content.insert("hist_access_type".to_string(), Value::from("tail"));
+                content.insert("n".to_string(), Value::from(*n));
+            }
+            HistoryQuery::Search(pattern, n) => {
+                content.insert("hist_access_type".to_string(), Value::from("search"));
+                content.insert("pattern".to_string(), Value::from(pattern.as_str()));
+                content.insert("unique".to_string(), Value::Bool(true));
+                content.insert("n".to_string(), Value::from(*n));
+            }
+        }
+
+        let reply = self.request("history_request", content)?;
+        Some(history_entries(&reply))
+    }
+
+    // The last `n` cells of the kernel's history with what they returned,
+    // only the ones from `session` if it's given. That's IPython's session
+    // number, like "3", or one relative to the newest session in the
+    // entries like %history takes them, "~1" for the one before it. Outputs
+    // are kept as text, so a cell with no Out[N] has None.
+    pub fn get_output_history(
+        &self,
+        session: Option<&str>,
+        n: usize,
+    ) -> Result<Vec<HistoryEntry>, Error> {
+        let mut content = Map::new();
+        content.insert("output".to_string(), Value::Bool(true));
+        content.insert("raw".to_string(), Value::Bool(true));
+        content.insert("hist_access_type".to_string(), Value::from("tail"));
+        content.insert("n".to_string(), Value::from(n));
+        let mut entries = self.history_reply(content)?;
+        if let Some(session) = session {
+            let invalid = || {
+                Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("not a history session: {:?}", session),
+                ))
+            };
+            // the tail request has no session field, so the entries from
+            // other sessions are left out once they're here
+            let session = match session.strip_prefix('~') {
+                Some(back) => {
+                    let back: i64 = back.parse().map_err(|_| invalid())?;
+                    let newest = entries.iter().map(|entry| entry.session).max();
+                    newest.map_or(0, |newest| newest - back)
+                }
+                None => session.parse().map_err(|_| invalid())?,
+            };
+            entries.retain(|entry| entry.session == session);
+        }
+        Ok(entries)
+    }
+
+    pub fn search_history(&self, pattern: &str) -> Result<Vec<HistoryEntry>, Error> {
+        let mut content = Map::new();
+        content.insert("output".to_string(), Value::Bool(false));
+        content.insert("raw".to_string(), Value::Bool(true));
+        content.insert("hist_access_type".to_string(), Value::from("search"));
+        content.insert("pattern".to_string(), Value::from(pattern));
+        self.history_reply(content)
+    }
+
+    // a history_request's entries, or what the kernel said went wrong
+    fn history_reply(&self, content: Map<String, Value>) -> Result<Vec<HistoryEntry>, Error> {
+        let reply = self
+            .request("history_request", content)
+            .ok_or(Error::Timeout("history_reply"))?;
+        if reply["content"]["status"] == "error" {
+            return Err(Error::Kernel(format!(
+                "{}: {}",
+                reply["content"]["ename"].as_str().unwrap_or("an error"),
+                reply["content"]["evalue"].as_str().unwrap_or("")
+            )));
+        }
+        Ok(history_entries(&reply))
+    }
+
+    // The comms open in the kernel, only the ones for `target_name` if
+    // it's given, like "jupyter.widget" for ipywidgets' models.
+    pub fn get_comm_info(&self, target_name: Option<&str>) -> Result<CommInfoReply, Error> {
+        let mut content = Map::new();
+        if let Some(target_name) = target_name {
+            content.insert("target_name".to_string(), Value::from(target_name));
+        }
+        let reply = self
+            .request("comm_info_request", content)
+            .ok_or(Error::Timeout("comm_info_reply"))?;
+        Ok(serde_json::from_value(reply["content"].clone())?)
+    }
+
+    // ask the kernel to exit (or restart), returns its shutdown_reply
+    pub fn shutdown(&self, restart: bool) -> Option<Map<String, Value>> {
+        let mut content = Map::new();
+        content.insert("restart".to_string(), Value::Bool(restart));
+        self.control_request("shutdown_request", content)
+    }
+
+    // stop the cell that's running, returns the interrupt_reply
+    pub fn interrupt(&self) -> Option<Map<String, Value>> {
+        self.control_request("interrupt_request", Map::new())
+    }
+
+    // At most `executions_per_second` execute_requests a second from now
+    // on, with up to a second's worth in a burst, None for no limit.
+    pub fn set_rate_limit(&mut self, executions_per_second: Option<f64>) {
+        *self.rate_limiter.get_mut() = executions_per_second.map(RateLimiter::new);
+    }
+
+    pub fn set_kernel_name(&mut self, name: &str) {
+        self.kernel_name = name.to_string();
+    }
+
+    pub fn kernel_name(&self) -> &str {
+        &self.kernel_name
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session.session_id
+    }
+
+    pub fn set_kernel_pid(&mut self, pid: u32) {
+        self.kernel_pid = Some(pid);
+    }
+
+    pub fn get_kernel_pid(&self) -> Option<u32> {
+        self.kernel_pid
+    }
+
+    // The last resort for a kernel that ignores shutdown_request: SIGKILL,
+    // or TerminateProcess on Windows.
+    pub fn kill_kernel(&mut self) -> Result<(), Error> {
+        let pid = self.kernel_pid.ok_or(Error::NoProcess)?;
+
+        #[cfg(unix)]
+        {
+            if unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) } != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        #[cfg(windows)]
+        unsafe {
+            use windows_sys::Win32::Foundation::CloseHandle;
+            use windows_sys::Win32::System::Threading::{
+                OpenProcess, TerminateProcess, PROCESS_TERMINATE,
+            };
+
+            let process = OpenProcess(PROCESS_TERMINATE, 0, pid);
+            if process.is_null() {
+                return Err(io::Error::last_os_error().into());
+            }
+            let terminated = TerminateProcess(process, 1);
+            let err = io::Error::last_os_error();
+            CloseHandle(process);
+            if terminated == 0 {
+                return Err(err.into());
+            }
+        }
+
+        self.kernel_pid = None;
+        Ok(())
+    }
+
+    pub fn set_interrupt_mode(&mut self, mode: InterruptMode) {
+        self.interrupt_mode = mode;
+    }
+
+    // The event jupyter_client created and handed the kernel in
+    // JPY_INTERRUPT_EVENT, which Windows kernels wait on instead of SIGINT.
+    #[cfg(windows)]
+    pub fn set_interrupt_event(&mut self, event: isize) {
+        self.interrupt_event = Some(event);
+    }
+
+    // what interrupt_kernel will do, which is always a message without a
+    // process to signal
+    pub fn interrupt_mode(&self) -> InterruptMode {
+        #[cfg(unix)]
+        let can_signal = self.kernel_pid.is_some();
+        #[cfg(windows)]
+        let can_signal = self.interrupt_event.is_some();
+
+        if can_signal {
+            self.interrupt_mode
+        } else {
+            InterruptMode::Message
+        }
+    }
+
+    // interrupt the way the kernelspec asks for, an error if no
+    // interrupt_reply comes back to a message
+    pub fn interrupt_kernel(&self) -> Result<(), Error> {
+        if self.interrupt_mode() == InterruptMode::Message {
+            return match self.interrupt() {
+                Some(_) => Ok(()),
+                None => Err(Error::Timeout("interrupt_reply")),
+            };
+        }
+
+        #[cfg(unix)]
+        {
+            let pid = self.kernel_pid.ok_or(Error::NoProcess)?;
+            // launched independent, so the kernel leads its own process group
+            if unsafe { libc::killpg(pid as libc::pid_t, libc::SIGINT) } != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        #[cfg(windows)]
+        {
+            let event = self.interrupt_event.ok_or(Error::NoProcess)?;
+            let event = event as windows_sys::Win32::Foundation::HANDLE;
+            if unsafe { windows_sys::Win32::System::Threading::SetEvent(event) } == 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        Ok(())
+    }
+
+    // Median time from sending an empty, silent execute_request to the
+    // kernel going idle again, over `n` requests. Iopub is read through
+    // get_msg as it is for cells, so the samples include what that costs.
+    pub fn measure_roundtrip_latency(&mut self, n: u32) -> Result<Duration, Error> {
+        let mut latencies = self.roundtrip_latencies(n)?;
+        latencies.sort();
+        Ok(latencies
+            .get(latencies.len() / 2)
+            .copied()
+            .unwrap_or_default())
+    }
+
+    // every sample behind measure_roundtrip_latency, in the order they ran
+    pub fn roundtrip_latencies(&mut self, n: u32) -> Result<Vec<Duration>, Error> {
+        let mut latencies = Vec::with_capacity(n as usize);
+
+        for _ in 0..n {
+            let start = Instant::now();
+            let msg_id = self.execute_silent("");
+            let mut idle = false;
+            let mut replied = false;
+
+            // read the execute_reply too so it isn't left for someone else
+            while !idle || !replied {
+                let ready = self.poll_all(REPLY_TIMEOUT)?;
+                if ready.is_empty() {
+                    return Err(Error::Timeout("execute_reply"));
+                }
+
+                for socket in ready {
+                    match socket {
+                        ReadySocket::Iopub => {
+                            let msg = match self.get_msg() {
+                                Some(msg) => msg,
+                                None => continue,
+                            };
+                            if msg["parent_header"]["msg_id"] == msg_id.as_str()
+                                && msg["content"]["execution_state"] == "idle"
+                            {
+                                latencies.push(start.elapsed());
+                                idle = true;
+                            }
+                        }
+                        ReadySocket::Shell => {
+                            replied |= self.get_shell_msg().is_some_and(|reply| {
+                                reply["parent_header"]["msg_id"] == msg_id.as_str()
+                            });
+                        }
+                        // a silent request with nothing to run asks for no input
+                        ReadySocket::Stdin | ReadySocket::Heartbeat => {}
+                    }
+                }
+            }
+        }
+
+        Ok(latencies)
+    }
+
+    // A freshly started kernel may not be listening yet, so keep asking for
+    // its kernel_info until one of the requests gets an answer. The status
+    // messages they cause also tell us iopub is subscribed, before that the
+    // kernel's first outputs would go nowhere.
+    pub fn wait_for_kernel_ready(&mut self, timeout: Duration) -> Result<KernelInfoReply, Error> {
+        let deadline = Instant::now() + timeout;
+        let mut sent = Vec::new();
+        let mut info = None;
+        let mut subscribed = false;
+
+        while Instant::now() < deadline {
+            let msg = self.make_message("kernel_info_request", Map::new());
+            sent.push(msg["msg_id"].clone());
+            self.shell_channel
+                .as_ref()
+                .unwrap()
+                .send_multipart(&self.serialize(msg), 0)?;
+
+            let retry = (Instant::now() + KERNEL_INFO_INTERVAL).min(deadline);
+            while let Some(left) = retry.checked_duration_since(Instant::now()) {
+                let ready = self.poll_all(left.as_millis() as i64)?;
+                if ready.is_empty() {
+                    break;
+                }
+
+                for socket in ready {
+                    match socket {
+                        ReadySocket::Iopub => {
+                            if let Some(msg) = self.get_msg() {
+                                subscribed |= sent.contains(&msg["parent_header"]["msg_id"]);
+                            }
+                        }
+                        // the kernel answers every request that reached it, any one will do
+                        ReadySocket::Shell => {
+                            let reply = match self.get_shell_msg() {
+                                Some(reply) => Value::Object(reply),
+                                None => continue,
+                            };
+                            if reply["header"]["msg_type"] == "kernel_info_reply"
+                                && sent.contains(&reply["parent_header"]["msg_id"])
+                            {
+                                // kernels older than 5.1 don't send a status
+                                info = serde_json::from_value::<KernelInfoReply>(
+                                    reply["content"].clone(),
+                                )
+                                .ok()
+                                .filter(|info| info.status == "ok" || info.status.is_empty())
+                                .or(info);
+                            }
+                        }
+                        ReadySocket::Stdin | ReadySocket::Heartbeat => {}
+                    }
+                }
+
+                if subscribed {
+                    if let Some(info) = info {
+                        return Ok(info);
+                    }
+                }
+            }
+        }
+
+        Err(Error::Timeout("kernel_info_reply"))
+    }
+
+    fn deserialize(&self, msg_frames: &[Vec<u8>]) -> Map<String, Value> {
+        let header = serde_json::from_str(str::from_utf8(&msg_frames[0]).unwrap()).unwrap();
+        let parent_header = serde_json::from_str(str::from_utf8(&msg_frames[1]).unwrap()).unwrap();
+        let metadata = serde_json::from_str(str::from_utf8(&msg_frames[2]).unwrap()).unwrap();
+        let content = serde_json::from_str(str::from_utf8(&msg_frames[3]).unwrap()).unwrap();
+
+        let mut msg = Map::new();
+        msg.insert("header".to_string(), Value::Object(header));
+        msg.insert("parent_header".to_string(), Value::Object(parent_header));
+        msg.insert("metadata".to_string(), Value::Object(metadata));
+        msg.insert("content".to_string(), Value::Object(content));
+
+        msg
+    }
+
+    // wait up to timeout_ms for any of the channels to have something to read
+    pub fn poll_all(&self, timeout_ms: i64) -> Result<Vec<ReadySocket>, Error> {
+        let channels = [
+            (ReadySocket::Iopub, &self.iopub_channel),
+            (ReadySocket::Shell, &self.shell_channel),
+            (ReadySocket::Stdin, &self.stdin_channel),
+            (ReadySocket::Heartbeat, &self.hb_channel),
+        ];
+
+        let mut sockets = Vec::new();
+        let mut items = Vec::new();
+        for (socket, channel) in channels.iter() {
+            if let Some(channel) = channel {
+                sockets.push(*socket);
+                items.push(channel.as_poll_item(zmq::POLLIN));
+            }
+        }
+
+        match zmq::poll(&mut items, timeout_ms) {
+            // a signal like Ctrl-C, the caller gets to look at it before polling again
+            Err(zmq::Error::EINTR) => return Ok(Vec::new()),
+            result => result?,
+        };
+
+        Ok(sockets
+            .into_iter()
+            .zip(items.iter())
+            .filter(|(_, item)| item.is_readable())
+            .map(|(socket, _)| socket)
+            .collect())
+    }
+
+    // None if the message is a retransmission of one we've already seen,
+    // or its signature doesn't match
+    pub fn get_msg(&mut self) -> Option<Map<String, Value>> {
+        let msg = self.recv_msg(self.iopub_channel.as_ref().unwrap())?;
+
+        if let Some(msg_id) = msg["header"]["msg_id"].as_str() {
+            if self.seen_ids.put(msg_id.to_string(), ()).is_some() {
+                trace!(msg_id, "dropping duplicate iopub message");
+                return None;
+            }
+        }
+        self.count_order.check(&msg);
+
+        // sending only fails when nobody is subscribed any more
+        #[cfg(feature = "async")]
+        if let Some(events) = &self.events {
+            let _ = events.send(OutputEvent::from_msg(&msg));
+        }
+
+        Some(msg)
+    }
+
+    // Replies from the shell channel that arrive outside of request(), None
+    // if the signature doesn't match.
+    pub fn get_shell_msg(&self) -> Option<Map<String, Value>> {
+        let msg = self.recv_msg(self.shell_channel.as_ref().unwrap())?;
+        if msg["header"]["msg_type"] == "execute_reply" {
+            let parent = &msg["parent_header"]["msg_id"];
+            self.pending_executes
+                .borrow_mut()
+                .retain(|msg_id| parent != msg_id.as_str());
+        }
+        Some(msg)
+    }
+
+    pub fn get_input_request(&self) -> Option<Map<String, Value>> {
+        self.recv_msg(self.stdin_channel.as_ref().unwrap())
+    }
+
+    // Every input_request already waiting on the stdin channel, oldest
+    // first, for kernels that ask again before the last one is answered.
+    pub fn get_input_requests(&self) -> Vec<Map<String, Value>> {
+        let channel = self.stdin_channel.as_ref().unwrap();
+        let mut requests = Vec::new();
+        while channel.poll(zmq::POLLIN, 0).is_ok_and(|ready| ready > 0) {
+            requests.extend(self.recv_msg(channel));
+        }
+        requests
+    }
+
+    pub fn input_reply(&self, input_request: &Map<String, Value>, value: &str) {
+        let mut content = Map::new();
+        content.insert("value".to_string(), Value::String(value.to_string()));
+
+        let mut msg = self.make_message("input_reply", content);
+        msg.insert("parent_header".to_string(), input_request["header"].clone());
+
+        self.stdin_channel
+            .as_ref()
+            .unwrap()
+            .send_multipart(&self.serialize(msg), 0)
+            .unwrap();
+    }
+
+    // None for a message that isn't signed with the session's key, which
+    // is dropped: anyone who can reach the ports could have sent it.
+    // Connections without a key check nothing.
+    fn recv_msg(&self, channel: &zmq::Socket) -> Option<Map<String, Value>> {
+        let msg_list = channel.recv_multipart(0).unwrap();
+
+        // https://gitlab.com/srwalker101/rust-jupyter-client/-/blob/dev/src/wire.rs#L28
+        let delim_idx = msg_list
+            .iter()
+            .position(|r| String::from_utf8(r.to_vec()).unwrap() == "<IDS|MSG>")
+            .unwrap();
+        let msg_frames = &msg_list[delim_idx + 2..];
+        let signature = String::from_utf8_lossy(&msg_list[delim_idx + 1]);
+        let signed: Vec<&[u8]> = msg_frames.iter().take(4).map(Vec::as_slice).collect();
+        if !self.signer.verify(&signature, &signed) {
+            warn!(
+                scheme = self.session.signature_scheme.name(),
+                "dropping a message whose signature doesn't match"
+            );
+            return None;
+        }
+
+        // deserialize
+        let msg = self.deserialize(msg_frames);
+        if protocol::is_strict() {
+            protocol::check(&msg);
+        }
+        Some(msg)
+    }
+}
+
+impl Helper for Cutypr {
+    fn is_complete(&self, code: &str) -> Option<Map<String, Value>> {
+        let mut content = Map::new();
+        content.insert("code".to_string(), Value::String(code.to_string()));
+
+        let reply = self.request("is_complete_request", content)?;
+        reply["content"].as_object().cloned()
+    }
+
+    fn complete(&self, code: &str, cursor_pos: usize) -> Option<Map<String, Value>> {
+        let mut content = Map::new();
+        content.insert("code".to_string(), Value::String(code.to_string()));
+        content.insert("cursor_pos".to_string(), Value::from(cursor_pos));
+
+        let reply = self.request("complete_request", content)?;
+        reply["content"].as_object().cloned()
+    }
+
+    fn inspect(&self, code: &str, cursor_pos: usize) -> Option<Map<String, Value>> {
+        let mut content = Map::new();
+        content.insert("code".to_string(), Value::String(code.to_string()));
+        content.insert("cursor_pos".to_string(), Value::from(cursor_pos));
+        content.insert("detail_level".to_string(), Value::from(0));
+
+        let reply = self.request("inspect_request", content)?;
+        reply["content"].as_object().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notebook;
+    use crate::test_kernel::TestKernel;
+    use crate::transcript::Transcript;
+    use serde_json::json;
+    use std::thread;
+
+    fn status(reply: Option<Map<String, Value>>) -> Value {
+        reply.map_or(Value::Null, |reply| reply["content"]["status"].clone())
+    }
+
+    #[test]
+    fn control_channel_alone_interrupts_and_shuts_down() {
+        let kernel = TestKernel::start("secret");
+        let mut client = Cutypr::new(kernel.session(), kernel.info.ports());
+        client.initialize_control_channel();
+
+        assert_eq!(status(client.interrupt()), "ok");
+        assert_eq!(status(client.shutdown(false)), "ok");
+
+        let requests = kernel.requests();
+        let types: Vec<&str> = requests.iter().map(|request| request.msg_type()).collect();
+        assert_eq!(types, ["interrupt_request", "shutdown_request"]);
+        for request in &requests {
+            assert_eq!(request.channel, "control");
+            assert!(request.signed_with("secret"));
+            assert!(!request.signed_with("not the key"));
+        }
+        assert_eq!(requests[1].content["restart"], false);
+    }
+
+    #[test]
+    fn interrupts_a_running_cell_from_another_client() {
+        let kernel = TestKernel::start("secret");
+        let mut client = kernel.client();
+        let mut control = Cutypr::new(kernel.session(), kernel.info.ports());
+        control.initialize_control_channel();
+
+        let started = Instant::now();
+        let msg_id = client.execute("sleep 10");
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(status(control.interrupt()), "ok");
+        let result = client.collect(&msg_id, None).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(result.status.as_deref(), Some("error"));
+        assert_eq!(kernel.requests_of("interrupt_request").len(), 1);
+    }
+
+    #[test]
+    fn a_kernel_that_is_gone_does_not_answer() {
+        let kernel = TestKernel::start("secret");
+        let mut client = Cutypr::new(kernel.session(), kernel.info.ports());
+        client.initialize_control_channel();
+        assert_eq!(status(client.shutdown(false)), "ok");
+        // it stopped for good, nothing is left to answer
+        drop(kernel);
+        assert!(client.interrupt().is_none());
+    }
+
+    #[test]
+    fn reconnecting_starts_over_with_the_new_key() {
+        let old = TestKernel::start("old key");
+        let new = TestKernel::start("new key");
+        let mut client = old.client();
+        client.execute_and_collect("1 + 1").unwrap();
+        let result = client.execute_and_collect("2 + 2").unwrap();
+        assert_eq!(result.execution_count, Some(2));
+        assert!(!client.seen_ids.is_empty());
+
+        client.reconnect(&new.info.key, new.info.ports());
+        assert!(client.seen_ids.is_empty());
+        // the new kernel counting from 1 again isn't out of order
+        let first = json!({
+            "header": {"msg_type": "execute_input", "msg_id": "first"},
+            "content": {"execution_count": 1},
+        });
+        assert!(client
+            .count_order
+            .observe(first.as_object().unwrap())
+            .is_none());
+
+        // the new kernel's replies only verify with the new key, so
+        // collecting would time out on the old one
+        client
+            .wait_for_kernel_ready(Duration::from_secs(5))
+            .unwrap();
+        let result = client.execute_and_collect("2 + 2").unwrap();
+        assert_eq!(result.status.as_deref(), Some("ok"));
+        assert_eq!(result.execution_count, Some(1));
+        let requests = new.requests_of("execute_request");
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].signed_with("new key"));
+        assert!(!requests[0].signed_with("old key"));
+    }
+
+    #[test]
+    fn a_cloned_session_has_a_uuid_of_its_own() {
+        let kernel = TestKernel::start("secret");
+        let client = kernel.client();
+        let mut clone = client.clone_session().unwrap();
+        let id = &clone.session.session_id;
+        assert_ne!(id, &client.session.session_id);
+        let uuid = Uuid::parse_str(id).unwrap();
+        assert_eq!(uuid.get_version(), Some(uuid::Version::Random));
+        assert_eq!(uuid.get_variant(), uuid::Variant::RFC4122);
+        assert_ne!(id, &client.clone_session().unwrap().session.session_id);
+
+        clone.execute_and_collect("1 + 1").unwrap();
+        let requests = kernel.requests_of("execute_request");
+        assert_eq!(
+            requests[0].header["session"],
+            clone.session.session_id.as_str()
+        );
+    }
+
+    #[test]
+    fn each_execute_asks_for_what_its_options_say() {
+        let kernel = TestKernel::start("secret");
+        let mut client = kernel.client();
+        let mut expressions = Map::new();
+        expressions.insert("x".to_string(), json!("1 + 1"));
+
+        let msg_id = client.execute("typed");
+        client.collect(&msg_id, None).unwrap();
+        let msg_id = client.execute_silent("internal");
+        client.collect(&msg_id, None).unwrap();
+        let msg_id = client.execute_with_expressions("watched", &expressions);
+        client.collect(&msg_id, None).unwrap();
+        client.execute_and_collect("collected").unwrap();
+        client
+            .execute_silent_and_collect("collected silently")
+            .unwrap();
+        let options = ExecuteOptions {
+            store_history: false,
+            ..ExecuteOptions::default()
+        };
+        let msg_id = client.execute_with("unstored", &options);
+        client.collect(&msg_id, None).unwrap();
+        let options = ExecuteOptions {
+            stop_on_error: false,
+            ..ExecuteOptions::default()
+        };
+        let msg_id = client.execute_with("carry on", &options);
+        client.collect(&msg_id, None).unwrap();
+
+        let contents: Vec<Value> = kernel
+            .requests_of("execute_request")
+            .into_iter()
+            .map(|request| request.content)
+            .collect();
+        let content = |code, silent, store_history, allow_stdin, user_expressions| {
+            json!({
+                "code": code,
+                "silent": silent,
+                "store_history": store_history,
+                "allow_stdin": allow_stdin,
+                "user_expressions": user_expressions,
+                "stop_on_error": true,
+            })
+        };
+        let mut carry_on = content("carry on", false, true, true, Value::Null);
+        carry_on["stop_on_error"] = json!(false);
+        assert_eq!(
+            contents,
+            [
+                content("typed", false, true, true, Value::Null),
+                content("internal", true, false, false, Value::Null),
+                content("watched", false, true, true, json!({"x": "1 + 1"})),
+                content("collected", false, true, false, Value::Null),
+                content("collected silently", true, false, false, Value::Null),
+                content("unstored", false, false, true, Value::Null),
+                carry_on,
+            ]
+        );
+
+        // only the cells that stored history took a count
+        let result = client.execute_and_collect("counted").unwrap();
+        assert_eq!(result.execution_count, Some(5));
+    }
+
+    #[test]
+    fn request_messages_are_only_about_their_request() {
+        let kernel = TestKernel::start("secret");
+        let mut client = kernel.client();
+        let other = kernel.client();
+        // iopub goes to every client, this one sees the other's cell too
+        let others = other.execute("theirs");
+        thread::sleep(Duration::from_millis(100));
+        let msg_id = client.execute("ours");
+
+        let mut messages = RequestMessages::new(&msg_id);
+        let mut types = Vec::new();
+        while !messages.done() {
+            if let Some(msg) = messages.next(&mut client, 1000).unwrap() {
+                assert_eq!(msg["parent_header"]["msg_id"], msg_id.as_str());
+                types.push(msg["header"]["msg_type"].as_str().unwrap().to_string());
+            }
+        }
+        // the reply is on shell, which can come before or after iopub
+        let replies = types.iter().filter(|msg_type| *msg_type == "execute_reply");
+        assert_eq!(replies.count(), 1);
+        types.retain(|msg_type| msg_type != "execute_reply");
+        assert_eq!(
+            types,
+            [
+                "status",
+                "execute_input",
+                "stream",
+                "execute_result",
+                "status"
+            ]
+        );
+        assert!(messages.next(&mut client, 10).unwrap().is_none());
+        assert_ne!(others, msg_id);
+    }
+
+    #[test]
+    fn for_each_message_stops_at_the_first_error() {
+        let kernel = TestKernel::start("secret");
+        let mut client = kernel.client();
+        let msg_id = client.execute("raise");
+        let mut seen = Vec::new();
+        let result = client.for_each_message(&msg_id, |msg| {
+            seen.push(msg["header"]["msg_type"].as_str().unwrap().to_string());
+            match msg["header"]["msg_type"].as_str() {
+                Some("error") => Err(Error::Kernel(msg["content"]["evalue"].to_string())),
+                _ => Ok(()),
+            }
+        });
+        assert!(matches!(result, Err(Error::Kernel(evalue)) if evalue == "\"boom\""));
+        // nothing after it, like the idle status
+        assert_eq!(seen.last().map(String::as_str), Some("error"));
+        assert_eq!(
+            seen.iter().filter(|msg_type| *msg_type == "status").count(),
+            1
+        );
+    }
+
+    #[test]
+    fn a_cell_that_runs_too_long_is_interrupted() {
+        let kernel = TestKernel::start("secret");
+        let mut client = kernel.client();
+        let result = client
+            .execute_with_timeout("quick", Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(result.status.as_deref(), Some("ok"));
+
+        let started = Instant::now();
+        let err = client
+            .execute_with_timeout("sleep 10", Duration::from_millis(200))
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(kernel.requests_of("interrupt_request").len(), 1);
+        assert!(err.source.is_none());
+        assert_eq!(err.to_string(), "the cell timed out and was interrupted");
+        // the KeyboardInterrupt came in the grace period after it
+        let partial = err.partial_output;
+        assert_eq!(partial.status.as_deref(), Some("error"));
+        assert!(partial.outputs.iter().any(|event| matches!(
+            &event.kind,
+            OutputKind::Error { ename, .. } if ename == "KeyboardInterrupt"
+        )));
+    }
+
+    #[test]
+    fn a_lost_kernel_is_the_source_of_a_timeout() {
+        let lost = TimeoutError {
+            partial_output: ExecutionResult {
+                execution_count: Some(3),
+                ..ExecutionResult::default()
+            },
+            source: Some(Box::new(Error::Zmq(zmq::Error::ETERM))),
+        };
+        assert!(lost
+            .to_string()
+            .starts_with("lost the kernel before the cell finished: "));
+        let source = std::error::Error::source(&lost).unwrap();
+        assert_eq!(
+            source.to_string(),
+            Error::Zmq(zmq::Error::ETERM).to_string()
+        );
+        assert_eq!(lost.partial_output.execution_count, Some(3));
+    }
+
+    #[test]
+    fn output_history_by_session() {
+        let kernel = TestKernel::start("secret");
+        let mut client = kernel.client();
+        for code in ["a = 1", "a + 1", "print(a);"] {
+            client.execute_and_collect(code).unwrap();
+        }
+
+        let entries = client.get_output_history(None, 2).unwrap();
+        let lines: Vec<(i64, &str, Option<&str>)> = entries
+            .iter()
+            .map(|entry| (entry.line, entry.source.as_str(), entry.output.as_deref()))
+            .collect();
+        assert_eq!(
+            lines,
+            [(2, "a + 1", Some("5")), (3, "print(a);", Some("9"))]
+        );
+        let request = &kernel.requests_of("history_request")[0];
+        assert_eq!(request.content["hist_access_type"], "tail");
+        assert_eq!(request.content["n"], 2);
+        assert_eq!(request.content["output"], true);
+
+        // the test kernel's cells are all in session 1
+        assert_eq!(client.get_output_history(Some("1"), 10).unwrap().len(), 3);
+        assert_eq!(client.get_output_history(Some("~0"), 10).unwrap().len(), 3);
+        assert!(client
+            .get_output_history(Some("~1"), 10)
+            .unwrap()
+            .is_empty());
+        assert!(client.get_output_history(Some("2"), 10).unwrap().is_empty());
+        assert!(matches!(
+            client.get_output_history(Some("last"), 10),
+            Err(Error::Io(err)) if err.kind() == io::ErrorKind::InvalidInput
+        ));
+    }
+
+    #[test]
+    fn cancelling_stops_the_running_cell_and_what_is_queued() {
+        let kernel = TestKernel::start("secret");
+        let mut client = kernel.client();
+        assert_eq!(client.cancel_pending_execute().unwrap().status, None);
+
+        client.execute("sleep 10");
+        client.execute("queued");
+        thread::sleep(Duration::from_millis(100));
+        let started = Instant::now();
+        let result = client.cancel_pending_execute().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(result.status.as_deref(), Some("aborted"));
+        assert!(result.outputs.iter().any(|event| matches!(
+            &event.kind,
+            OutputKind::Error { ename, .. } if ename == "KeyboardInterrupt"
+        )));
+        assert_eq!(kernel.requests_of("interrupt_request").len(), 1);
+        assert!(client.pending_executes.borrow().is_empty());
+
+        // the queued cell's reply was read and dropped, the next cell gets its own
+        let result = client.execute_and_collect("next").unwrap();
+        assert_eq!(result.status.as_deref(), Some("ok"));
+        assert_eq!(result.execution_count, Some(3));
+        let codes: Vec<Value> = kernel
+            .requests_of("execute_request")
+            .iter()
+            .map(|request| request.content["code"].clone())
+            .collect();
+        assert_eq!(codes, ["sleep 10", "queued", "", "next"]);
+    }
+
+    #[test]
+    fn counts_follow_the_replies_across_errors() {
+        let kernel = TestKernel::start("secret");
+        let mut client = kernel.client();
+        let mut transcript = Transcript::new();
+
+        // recorded the way the REPL does, from each execute_reply
+        for code in ["1", "raise", "22", "raise", "333"] {
+            let result = client.execute_and_collect(code).unwrap();
+            let count = result.execution_count.unwrap();
+            transcript.record(count, code);
+            if result.status.as_deref() == Some("error") {
+                transcript.mark_failed(count);
+            }
+            // what Out[n] and a saved notebook's output are numbered with
+            let results: Vec<Option<i64>> = notebook::outputs(&result)
+                .into_iter()
+                .filter_map(|output| match output {
+                    notebook::Output::ExecuteResult {
+                        execution_count, ..
+                    } => Some(execution_count),
+                    _ => None,
+                })
+                .collect();
+            if code == "raise" {
+                assert!(results.is_empty());
+            } else {
+                assert_eq!(results, [Some(count)]);
+            }
+        }
+        let cells: Vec<(i64, &str)> = transcript.cells().collect();
+        assert_eq!(
+            cells,
+            [(1, "1"), (2, "raise"), (3, "22"), (4, "raise"), (5, "333")]
+        );
+        // and the errors are the ones marked failed
+        assert_eq!(transcript.to_script("#", false).matches("raise").count(), 0);
+
+        // the next prompt's In number is the kernel's next one
+        let result = client.execute_and_collect("4444").unwrap();
+        assert_eq!(result.execution_count, Some(6));
+    }
+
+    #[test]
+    fn messages_signed_with_another_key_are_dropped() {
+        let kernel = TestKernel::start("secret");
+        let connect = |key: &str| {
+            let session = Session {
+                key: key.to_string(),
+                session_id: Uuid::new_v4().to_string(),
+                signature_scheme: SignatureScheme::for_connection("hmac-sha256", key),
+            };
+            let mut client = Cutypr::new(session, kernel.info.ports());
+            client.initialize_channels();
+            client.wait_for_kernel_ready(Duration::from_millis(500))
+        };
+
+        // the kernel answers, but nothing it sends verifies
+        assert!(connect("not the key").is_err());
+        assert!(!kernel.requests_of("kernel_info_request").is_empty());
+        // without a key nothing is checked
+        assert!(connect("").is_ok());
+        assert!(connect("secret").is_ok());
+    }
+
+    #[test]
+    fn execute_many_stops_at_an_error_only_with_stop_on_error() {
+        let kernel = TestKernel::start("secret");
+        let mut client = kernel.client();
+        let cells = ["1", "raise", "22"];
+        let status = |results: &[ExecutionResult]| -> Vec<Option<String>> {
+            results.iter().map(|result| result.status.clone()).collect()
+        };
+
+        let results = client.execute_many(&cells).unwrap();
+        assert_eq!(
+            status(&results),
+            [Some("ok".to_string()), Some("error".to_string()), None]
+        );
+        assert_eq!(results[2].execution_count, None);
+
+        let options = ExecuteOptions {
+            stop_on_error: false,
+            ..ExecuteOptions::default()
+        };
+        let results = client.execute_many_with(&cells, &options).unwrap();
+        assert_eq!(
+            status(&results),
+            [
+                Some("ok".to_string()),
+                Some("error".to_string()),
+                Some("ok".to_string())
+            ]
+        );
+        let sent: Vec<Value> = kernel
+            .requests_of("execute_request")
+            .into_iter()
+            .map(|request| request.content["stop_on_error"].clone())
+            .collect();
+        assert_eq!(sent, [true, true, false, false, false]);
+    }
+}
@@ -0,0 +1,86 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::process::{Command, Stdio};
+
+// Whether to hand the terminal the text in an OSC 52 escape instead of
+// running a program here. Over ssh that's the only way to reach the
+// clipboard of the machine in front of the user, and the terminals below
+// take it locally too. FORCE_OSC52=1 or 0 settles it either way.
+pub fn supports_osc52() -> bool {
+    if let Ok(force) = env::var("FORCE_OSC52") {
+        return force != "0";
+    }
+    if !io::stdout().is_terminal() {
+        return false;
+    }
+    let term = env::var("TERM").unwrap_or_default();
+    env::var_os("SSH_TTY").is_some()
+        || env::var_os("KITTY_WINDOW_ID").is_some()
+        || env::var_os("WT_SESSION").is_some()
+        || ["kitty", "ghostty", "foot", "alacritty"]
+            .iter()
+            .any(|name| term.contains(name))
+        || matches!(
+            env::var("TERM_PROGRAM").as_deref(),
+            Ok("iTerm.app") | Ok("WezTerm") | Ok("ghostty")
+        )
+}
+
+// the programs that take stdin onto the clipboard here, first found wins
+fn programs() -> Vec<&'static [&'static str]> {
+    if cfg!(target_os = "macos") {
+        vec![&["pbcopy"]]
+    } else if cfg!(windows) {
+        vec![&["clip"]]
+    } else {
+        let mut programs: Vec<&'static [&'static str]> = Vec::new();
+        if env::var_os("WAYLAND_DISPLAY").is_some() {
+            programs.push(&["wl-copy"]);
+        }
+        programs.push(&["xclip", "-selection", "clipboard"]);
+        programs.push(&["xsel", "--clipboard", "--input"]);
+        programs
+    }
+}
+
+// Put `text` on the clipboard, and say how.
+pub fn copy(text: &str) -> io::Result<&'static str> {
+    if supports_osc52() {
+        let mut stdout = io::stdout();
+        write!(stdout, "\x1b]52;c;{}\x07", STANDARD.encode(text))?;
+        stdout.flush()?;
+        return Ok("OSC 52");
+    }
+    for program in programs() {
+        let spawned = Command::new(program[0])
+            .args(&program[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        // closed when it's dropped, which is what the program waits for
+        child.stdin.take().unwrap().write_all(text.as_bytes())?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "{} exited with {}",
+                program[0], status
+            )));
+        }
+        return Ok(program[0]);
+    }
+    let names: Vec<&str> = programs().iter().map(|program| program[0]).collect();
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "the terminal doesn't take OSC 52 and there's no {}",
+            names.join(" or ")
+        ),
+    ))
+}
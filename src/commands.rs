@@ -0,0 +1,499 @@
+// Commands handled by jupyterm itself rather than the kernel, typed as a
+// single line starting with ':'. A few magics that only make sense for the
+// terminal jupyterm runs in are taken over too.
+// cells :history lists without a number
+pub const DEFAULT_HISTORY: usize = 20;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    // :aliases, list the [aliases] from the config
+    Aliases,
+    // :break FILE:LINE, a breakpoint in the kernel's debugger
+    Break(String, u64),
+    // :bt, the stack of the stopped kernel
+    Backtrace,
+    // :locals, the variables in the innermost frame of the stopped kernel
+    Locals,
+    // :continue, let the stopped kernel run on
+    Continue,
+    // :step, run the stopped kernel to the next line, into calls
+    Step,
+    // :clear or %clear / %cls, wipe the screen
+    Clear,
+    // :detach, quit and leave the kernel running
+    Detach,
+    // :history [N | search PATTERN | load N], the kernel's own history
+    History(HistoryCommand),
+    // :interrupt, interrupt the kernel and say how
+    Interrupt,
+    // :kernel, what the kernel said about itself
+    Kernel,
+    // :kernel-log, what the kernel jupyterm started wrote to its stdout and stderr
+    KernelLog,
+    // :links, the help_links from the kernel_info_reply
+    Links,
+    // :open-last [MIME], the last rich output in the desktop's viewer for it
+    OpenLast(Option<String>),
+    // :out [N], what cell N printed and returned shown again, the last
+    // cell's with output without one
+    Out(Option<i64>),
+    // :traceback, the whole of the last traceback, in the pager
+    Traceback,
+    // :record [start PATH | stop], the cells from now on into a notebook
+    Record(RecordCommand),
+    // :copy [in], the last cell's output, or the cell itself, on the clipboard
+    Copy {
+        input: bool,
+    },
+    // :capture PATH [--append] [--no-display], the next cell's output to PATH too
+    Capture {
+        path: String,
+        append: bool,
+        display: bool,
+    },
+    // :set <option> <value>
+    Set(String, String),
+    // :rerun [N | N-M ...], inclusive ranges of In numbers, none for the last cell
+    Rerun(Vec<(i64, i64)>),
+    // :replay [N | N-M ...] [--stop-on-diff], the cells run again and held
+    // against what they printed the first time, all of them without ranges
+    Replay {
+        ranges: Vec<(i64, i64)>,
+        stop_on_diff: bool,
+    },
+    // :load PATH [START-END], the file, or those lines of it, as the next cell to edit
+    Load {
+        path: String,
+        lines: Option<(usize, usize)>,
+    },
+    // :save-history PATH [--include-errors], the cells run so far as a script
+    SaveHistory {
+        path: String,
+        include_errors: bool,
+    },
+    // :save-state FILE, write what --restore FILE needs to pick the session up again
+    SaveState(String),
+    // :export md PATH, the session's cells and their outputs as Markdown
+    Export(ExportFormat, String),
+    // %comm_info [TARGET], the comms open in the kernel, for TARGET only if given
+    CommInfo(Option<String>),
+    // %env NAME or %env NAME=VALUE, read or set a variable in the kernel's environment
+    Env(String, Option<String>),
+    // :session [new | attach], list the sessions or add one
+    Session(SessionCommand),
+    // :switch NAME, send cells to another session's kernel from now on
+    Switch(String),
+    // :watch [EXPR], show EXPR after every cell, or list what's watched
+    Watch(Option<String>),
+    // :unwatch [EXPR], stop showing EXPR, or everything
+    Unwatch(Option<String>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SessionCommand {
+    // :session
+    List,
+    // :session new [NAME] [--kernel KERNEL], start a kernel for it
+    New {
+        name: Option<String>,
+        kernel: Option<String>,
+    },
+    // :session attach FILE [NAME], a running kernel by its connection file
+    Attach {
+        file: String,
+        name: Option<String>,
+    },
+    // :session info, where the --session one is kept and what's in it
+    Info,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HistoryCommand {
+    // :history [N], the last N cells
+    Tail(usize),
+    // :history search PATTERN, with * and ? globs
+    Search(String),
+    // :history load N, entry N of the last listing into the input
+    Load(usize),
+    // %history -o [N], the last N cells with their Out[N]
+    Outputs(usize),
+    // %history -g PATTERN [--unique], every cell containing PATTERN, each
+    // source once with --unique
+    Grep { pattern: String, unique: bool },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExportFormat {
+    Markdown,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RecordCommand {
+    // :record, where the session is being recorded to
+    Status,
+    // :record start PATH
+    Start(String),
+    // :record stop
+    Stop,
+}
+
+// None if `cell` is meant for the kernel, otherwise the command or what's
+// wrong with it
+pub fn parse(cell: &str) -> Option<Result<Command, String>> {
+    let line = cell.trim();
+    if line.contains('\n') {
+        return None;
+    }
+    // the kernel would clear its own stdout, not our screen
+    if line == "%clear" || line == "%cls" {
+        return Some(Ok(Command::Clear));
+    }
+    if line == "%comm_info" || line.starts_with("%comm_info ") {
+        let mut words = line.split_whitespace().skip(1);
+        return Some(match (words.next(), words.next()) {
+            (target, None) => Ok(Command::CommInfo(target.map(str::to_string))),
+            _ => Err("usage: %comm_info [TARGET]".to_string()),
+        });
+    }
+    // %history -o and -g are asked for with a history_request rather than
+    // printed by the kernel; other %history forms go to the kernel
+    if let Some(args) = line.strip_prefix("%history ") {
+        let words: Vec<&str> = args.split_whitespace().collect();
+        if matches!(words.first(), Some(&"-o") | Some(&"-g")) {
+            return Some(parse_history_magic(&words));
+        }
+    }
+    if let Some(args) = line.strip_prefix("%env ") {
+        // a bare %env, listing everything, is left to the kernel
+        return Some(parse_env(args.trim()));
+    }
+    if !line.starts_with(':') {
+        return None;
+    }
+
+    let mut words = line[1..].split_whitespace();
+    // everything after the command, as it was typed
+    let rest = line[1..]
+        .split_once(char::is_whitespace)
+        .map(|(_, rest)| rest.trim().to_string());
+    let command = match words.next() {
+        Some("aliases") => Ok(Command::Aliases),
+        Some("break") => match (words.next(), words.next()) {
+            (Some(location), None) => parse_breakpoint(location),
+            _ => Err("usage: :break FILE:LINE".to_string()),
+        },
+        Some("bt") => Ok(Command::Backtrace),
+        Some("locals") => Ok(Command::Locals),
+        Some("continue") => Ok(Command::Continue),
+        Some("step") => Ok(Command::Step),
+        Some("clear") => Ok(Command::Clear),
+        Some("detach") => Ok(Command::Detach),
+        Some("kernel") => Ok(Command::Kernel),
+        Some("kernel-log") => Ok(Command::KernelLog),
+        Some("interrupt") => Ok(Command::Interrupt),
+        Some("history") => parse_history(&words.collect::<Vec<_>>()),
+        Some("links") => Ok(Command::Links),
+        Some("open-last") => match (words.next(), words.next()) {
+            (mime, None) => Ok(Command::OpenLast(mime.map(str::to_string))),
+            _ => Err("usage: :open-last [MIME]".to_string()),
+        },
+        Some("out") => match (words.next(), words.next()) {
+            (None, _) => Ok(Command::Out(None)),
+            (Some(n), None) => match n.parse::<i64>() {
+                Ok(n) if n > 0 => Ok(Command::Out(Some(n))),
+                _ => Err(format!("expected a cell number, not {:?}", n)),
+            },
+            _ => Err("usage: :out [N]".to_string()),
+        },
+        Some("traceback") => Ok(Command::Traceback),
+        Some("copy") => match (words.next(), words.next()) {
+            (None, None) => Ok(Command::Copy { input: false }),
+            (Some("in"), None) => Ok(Command::Copy { input: true }),
+            _ => Err("usage: :copy [in]".to_string()),
+        },
+        Some("record") => match (words.next(), words.next(), words.next()) {
+            (None, _, _) => Ok(Command::Record(RecordCommand::Status)),
+            (Some("start"), Some(path), None) => {
+                Ok(Command::Record(RecordCommand::Start(path.to_string())))
+            }
+            (Some("stop"), None, _) => Ok(Command::Record(RecordCommand::Stop)),
+            _ => Err("usage: :record [start PATH | stop]".to_string()),
+        },
+        Some("capture") => parse_capture(&words.collect::<Vec<_>>()),
+        Some("set") => match (words.next(), words.next()) {
+            (Some(option), Some(value)) => Ok(Command::Set(option.to_string(), value.to_string())),
+            _ => Err("usage: :set <option> <value>".to_string()),
+        },
+        Some("load") => match (words.next(), words.next(), words.next()) {
+            (Some(path), lines, None) => {
+                lines
+                    .map(parse_lines)
+                    .transpose()
+                    .map(|lines| Command::Load {
+                        path: path.to_string(),
+                        lines,
+                    })
+            }
+            _ => Err("usage: :load PATH [START-END]".to_string()),
+        },
+        Some("save-history") => {
+            let usage = || "usage: :save-history PATH [--include-errors]".to_string();
+            let mut path = None;
+            let mut include_errors = false;
+            for word in words {
+                match word {
+                    "--include-errors" => include_errors = true,
+                    word if path.is_none() && !word.starts_with('-') => {
+                        path = Some(word.to_string())
+                    }
+                    _ => return Some(Err(usage())),
+                }
+            }
+            match path {
+                Some(path) => Ok(Command::SaveHistory {
+                    path,
+                    include_errors,
+                }),
+                None => Err(usage()),
+            }
+        }
+        Some("save-state") => match words.next() {
+            Some(path) => Ok(Command::SaveState(path.to_string())),
+            None => Err("usage: :save-state FILE".to_string()),
+        },
+        Some("export") => match (words.next(), words.next(), words.next()) {
+            (Some("md"), Some(path), None) => {
+                Ok(Command::Export(ExportFormat::Markdown, path.to_string()))
+            }
+            (Some(format), Some(_), None) => Err(format!("can't export {}, only md", format)),
+            _ => Err("usage: :export md PATH".to_string()),
+        },
+        Some("session") => parse_session(&words.collect::<Vec<_>>()),
+        Some("switch") => match (words.next(), words.next()) {
+            (Some(name), None) => Ok(Command::Switch(name.to_string())),
+            _ => Err("usage: :switch NAME".to_string()),
+        },
+        Some("watch") => Ok(Command::Watch(rest)),
+        Some("unwatch") => Ok(Command::Unwatch(rest)),
+        Some("rerun") => words
+            .map(parse_range)
+            .collect::<Result<_, _>>()
+            .map(Command::Rerun),
+        Some("replay") => {
+            let mut stop_on_diff = false;
+            let mut ranges = Vec::new();
+            for word in words {
+                match word {
+                    "--stop-on-diff" => stop_on_diff = true,
+                    word if word.starts_with('-') => {
+                        return Some(Err(
+                            "usage: :replay [N | N-M ...] [--stop-on-diff]".to_string()
+                        ))
+                    }
+                    word => match parse_range(word) {
+                        Ok(range) => ranges.push(range),
+                        Err(err) => return Some(Err(err)),
+                    },
+                }
+            }
+            Ok(Command::Replay {
+                ranges,
+                stop_on_diff,
+            })
+        }
+        Some(name) => Err(format!("unknown command :{}", name)),
+        None => Err("missing command after ':'".to_string()),
+    };
+    Some(command)
+}
+
+fn parse_capture(words: &[&str]) -> Result<Command, String> {
+    let usage = || "usage: :capture PATH [--append] [--no-display]".to_string();
+    let mut path = None;
+    let mut append = false;
+    let mut display = true;
+    for word in words {
+        match *word {
+            "--append" => append = true,
+            "--no-display" => display = false,
+            word if path.is_none() && !word.starts_with('-') => path = Some(word.to_string()),
+            _ => return Err(usage()),
+        }
+    }
+    Ok(Command::Capture {
+        path: path.ok_or_else(usage)?,
+        append,
+        display,
+    })
+}
+
+fn parse_session(words: &[&str]) -> Result<Command, String> {
+    let command = match words {
+        [] => SessionCommand::List,
+        ["info"] => SessionCommand::Info,
+        ["new", rest @ ..] => {
+            let usage = || "usage: :session new [NAME] [--kernel KERNEL]".to_string();
+            let mut name = None;
+            let mut kernel = None;
+            let mut rest = rest.iter();
+            while let Some(word) = rest.next() {
+                match *word {
+                    "--kernel" => kernel = Some(rest.next().ok_or_else(usage)?.to_string()),
+                    word if name.is_none() && !word.starts_with('-') => {
+                        name = Some(word.to_string())
+                    }
+                    _ => return Err(usage()),
+                }
+            }
+            SessionCommand::New { name, kernel }
+        }
+        ["attach", file] => SessionCommand::Attach {
+            file: file.to_string(),
+            name: None,
+        },
+        ["attach", file, name] => SessionCommand::Attach {
+            file: file.to_string(),
+            name: Some(name.to_string()),
+        },
+        ["attach", ..] => return Err("usage: :session attach FILE [NAME]".to_string()),
+        _ => {
+            return Err(
+                "usage: :session, :session new, :session attach or :session info".to_string(),
+            )
+        }
+    };
+    Ok(Command::Session(command))
+}
+
+fn parse_history(words: &[&str]) -> Result<Command, String> {
+    let number = |word: &str| word.parse::<usize>().ok().filter(|n| *n > 0);
+    let command = match words {
+        [] => HistoryCommand::Tail(DEFAULT_HISTORY),
+        ["search", pattern @ ..] if !pattern.is_empty() => {
+            HistoryCommand::Search(pattern.join(" "))
+        }
+        ["load", n] => HistoryCommand::Load(
+            number(n).ok_or_else(|| format!("expected an entry number, not {:?}", n))?,
+        ),
+        [n] if number(n).is_some() => HistoryCommand::Tail(number(n).unwrap()),
+        _ => {
+            return Err(
+                "usage: :history [N], :history search PATTERN or :history load N".to_string(),
+            )
+        }
+    };
+    Ok(Command::History(command))
+}
+
+fn parse_history_magic(words: &[&str]) -> Result<Command, String> {
+    let command = match words {
+        ["-o"] => HistoryCommand::Outputs(DEFAULT_HISTORY),
+        ["-o", n] => HistoryCommand::Outputs(
+            n.parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| format!("expected a number of cells, not {:?}", n))?,
+        ),
+        ["-g", rest @ ..] => {
+            let unique = rest.iter().any(|word| *word == "-u" || *word == "--unique");
+            let pattern: Vec<&str> = rest
+                .iter()
+                .copied()
+                .filter(|word| *word != "-u" && *word != "--unique")
+                .collect();
+            if pattern.is_empty() {
+                return Err("usage: %history -g PATTERN [--unique]".to_string());
+            }
+            HistoryCommand::Grep {
+                pattern: pattern.join(" "),
+                unique,
+            }
+        }
+        _ => return Err("usage: %history -o [N] or %history -g PATTERN [--unique]".to_string()),
+    };
+    Ok(Command::History(command))
+}
+
+// "file.py:12", split at the last ':' so Windows paths keep their drive
+fn parse_breakpoint(location: &str) -> Result<Command, String> {
+    match location.rsplit_once(':') {
+        Some((file, line)) if !file.is_empty() => match line.parse::<u64>() {
+            Ok(line) if line > 0 => Ok(Command::Break(file.to_string(), line)),
+            _ => Err(format!("expected a line number, not {:?}", line)),
+        },
+        _ => Err("usage: :break FILE:LINE".to_string()),
+    }
+}
+
+// "3" or "3-6"
+fn parse_range(word: &str) -> Result<(i64, i64), String> {
+    let bad = || {
+        format!(
+            "expected an In number like 3 or a range like 3-6, not {:?}",
+            word
+        )
+    };
+    let (from, to) = match word.split_once('-') {
+        Some((from, to)) => (from, to),
+        None => (word, word),
+    };
+    let from: i64 = from.parse().map_err(|_| bad())?;
+    let to: i64 = to.parse().map_err(|_| bad())?;
+    if from > to {
+        return Err(bad());
+    }
+    Ok((from, to))
+}
+
+// "10-20", lines of a file, counted from 1
+fn parse_lines(word: &str) -> Result<(usize, usize), String> {
+    let bad = || format!("expected lines like 10-20, not {:?}", word);
+    let (from, to) = word.split_once('-').ok_or_else(bad)?;
+    let from: usize = from.parse().map_err(|_| bad())?;
+    let to: usize = to.parse().map_err(|_| bad())?;
+    if from == 0 || from > to {
+        return Err(bad());
+    }
+    Ok((from, to))
+}
+
+// "NAME" or "NAME=VALUE"
+fn parse_env(args: &str) -> Result<Command, String> {
+    let (name, value) = match args.split_once('=') {
+        Some((name, value)) => (name.trim(), Some(value.trim().to_string())),
+        None => (args, None),
+    };
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err("usage: %env NAME or %env NAME=VALUE".to_string());
+    }
+    Ok(Command::Env(name.to_string(), value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_history_takes_a_path_and_include_errors() {
+        assert_eq!(
+            parse(":save-history out.py"),
+            Some(Ok(Command::SaveHistory {
+                path: "out.py".to_string(),
+                include_errors: false,
+            }))
+        );
+        assert_eq!(
+            parse(":save-history --include-errors session"),
+            Some(Ok(Command::SaveHistory {
+                path: "session".to_string(),
+                include_errors: true,
+            }))
+        );
+        let usage = Some(Err(
+            "usage: :save-history PATH [--include-errors]".to_string()
+        ));
+        assert_eq!(parse(":save-history"), usage);
+        assert_eq!(parse(":save-history a.py b.py"), usage);
+        assert_eq!(parse(":save-history a.py --all"), usage);
+    }
+}
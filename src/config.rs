@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// Settings read from config.toml in the jupyterm config directory,
+// ~/.config/jupyterm on Linux.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    // key that shows the docstring of the object under the cursor
+    pub inspect_key: String,
+    // "emacs" or "vi", unset falls back to editing-mode in ~/.inputrc
+    pub editing_mode: Option<String>,
+    // print how long each cell took, unless it was quicker than the threshold
+    pub timing: bool,
+    pub timing_threshold_ms: u64,
+    // ring the bell (and run notify_command) when a slow cell finishes
+    pub notify: bool,
+    pub notify_threshold_ms: u64,
+    pub notify_command: Option<String>,
+    // cells like `;name args` expand to the code in [aliases]
+    pub alias_sigil: String,
+    pub aliases: BTreeMap<String, String>,
+    // run silently in every kernel jupyterm starts, before the first prompt:
+    // $PYTHONSTARTUP if pythonstartup is set and it's a Python kernel, then
+    // the files, then the code
+    pub pythonstartup: bool,
+    pub startup_files: Vec<PathBuf>,
+    pub startup_code: Option<String>,
+    // keep a Python kernel's COLUMNS and LINES up to date as the terminal
+    // is resized, kernels jupyterm starts get them at launch either way
+    pub sync_terminal_size: bool,
+    // widest a cell of an HTML table, like a DataFrame's, gets before it's
+    // cut short
+    pub max_column_width: usize,
+    // where the files in tracebacks link to, with {path} and {line} filled
+    // in, file:// URLs when it's unset
+    pub traceback_link_template: Option<String>,
+    // tracebacks with more frames than this show the first and the last
+    // ones only, until :traceback, 0 shows them all
+    pub traceback_frames: usize,
+    // a cell printing more lines or bytes a second than these only gets a
+    // line a second saying how many it printed, 0 for no limit
+    pub max_output_lines_per_second: u64,
+    pub max_output_bytes_per_second: u64,
+    // cells whose outputs :out can show again, the latest ones, 0 for none
+    pub out_cache_size: usize,
+    // what :replay and jupyterm diff leave out of outputs before comparing
+    // them, regexes for what changes from one run to the next
+    pub replay_filters: Vec<String>,
+    // ZMQ options for the sockets of every kernel session, by channel, like
+    // iopub.rcvhwm = 1000, see socket_options::SocketOption
+    pub socket_options: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            inspect_key: "alt-i".to_string(),
+            editing_mode: None,
+            timing: false,
+            timing_threshold_ms: 100,
+            notify: false,
+            notify_threshold_ms: 10_000,
+            notify_command: None,
+            alias_sigil: ";".to_string(),
+            aliases: BTreeMap::new(),
+            pythonstartup: false,
+            startup_files: Vec::new(),
+            startup_code: None,
+            sync_terminal_size: false,
+            max_column_width: 30,
+            traceback_link_template: None,
+            traceback_frames: 10,
+            max_output_lines_per_second: 1000,
+            max_output_bytes_per_second: 1_000_000,
+            out_cache_size: 50,
+            replay_filters: vec![
+                // like <object at 0x7f3a2c1b4d60>
+                r"0x[0-9a-fA-F]+".to_string(),
+                r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(\.\d+)?".to_string(),
+            ],
+            socket_options: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("jupyterm").join("config.toml"))
+    }
+
+    // a missing file gives the defaults, a broken one is reported and ignored
+    pub fn load() -> Config {
+        let path = match Config::path() {
+            Some(path) if path.exists() => path,
+            _ => return Config::default(),
+        };
+
+        let parsed = fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| toml::from_str(&text).map_err(|err| err.to_string()));
+        match parsed {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("jupyterm: ignoring {}: {}", path.display(), err);
+                Config::default()
+            }
+        }
+    }
+
+    pub fn editing_mode(&self) -> String {
+        self.editing_mode
+            .clone()
+            .or_else(inputrc_editing_mode)
+            .unwrap_or_else(|| "emacs".to_string())
+    }
+}
+
+// `set editing-mode vi` from readline's init file, $INPUTRC or ~/.inputrc
+fn inputrc_editing_mode() -> Option<String> {
+    let path = env::var_os("INPUTRC")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".inputrc")))?;
+    let text = fs::read_to_string(path).ok()?;
+
+    text.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some("set"), Some("editing-mode"), Some(mode)) => Some(mode.to_string()),
+                _ => None,
+            }
+        })
+        .next_back()
+}
@@ -0,0 +1,385 @@
+use crate::error::Error;
+use crate::paths::runtime_dir;
+use crate::signing::SignatureScheme;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+// times find_free_ports picks again when a port it let go of was taken
+const FREE_PORT_ATTEMPTS: u32 = 5;
+
+// The contents of a kernel-*.json connection file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ConnectionInfo {
+    pub shell_port: u16,
+    pub iopub_port: u16,
+    pub stdin_port: u16,
+    pub control_port: u16,
+    pub hb_port: u16,
+    #[serde(default = "default_ip")]
+    pub ip: String,
+    #[serde(default)]
+    pub key: String,
+    #[serde(default = "default_transport")]
+    pub transport: String,
+    #[serde(default = "default_signature_scheme")]
+    pub signature_scheme: String,
+    #[serde(default)]
+    pub kernel_name: String,
+}
+
+const TRANSPORTS: &[&str] = &["tcp", "ipc"];
+const PORT_FIELDS: &[&str] = &[
+    "shell_port",
+    "iopub_port",
+    "stdin_port",
+    "control_port",
+    "hb_port",
+];
+
+// One thing wrong with a connection file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidationError {
+    // it couldn't be read, or isn't a JSON object
+    Unreadable(String),
+    Missing(&'static str),
+    NotAString(&'static str),
+    // a port that isn't a number from 1024 to 65535
+    BadPort(&'static str, serde_json::Value),
+    SignatureScheme(String),
+    Transport(String),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::Unreadable(err) => write!(f, "{}", err),
+            ValidationError::Missing(field) => write!(f, "{} is missing", field),
+            ValidationError::NotAString(field) => write!(f, "{} isn't a string", field),
+            ValidationError::BadPort(field, port) => {
+                write!(
+                    f,
+                    "{} is {}, expected a port from 1024 to 65535",
+                    field, port
+                )
+            }
+            ValidationError::SignatureScheme(scheme) => write!(
+                f,
+                "signature_scheme {:?} isn't supported, only {}",
+                scheme,
+                SignatureScheme::ALL
+                    .iter()
+                    .map(|scheme| scheme.name())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ValidationError::Transport(transport) => {
+                write!(f, "transport is {:?}, expected tcp or ipc", transport)
+            }
+        }
+    }
+}
+
+// Read the connection file at `path`, with everything wrong with it if
+// anything is, rather than stopping at the first problem.
+pub fn validate_connection_file(path: &Path) -> Result<ConnectionInfo, Vec<ValidationError>> {
+    let unreadable = |err: String| vec![ValidationError::Unreadable(err)];
+    let text = fs::read_to_string(path).map_err(|err| unreadable(err.to_string()))?;
+    let value: serde_json::Value =
+        serde_json::from_str(&text).map_err(|err| unreadable(format!("invalid JSON: {}", err)))?;
+    let fields = value
+        .as_object()
+        .ok_or_else(|| unreadable("not a JSON object".to_string()))?;
+
+    let mut errors = Vec::new();
+    let mut string = |field: &'static str| match fields.get(field) {
+        None => {
+            errors.push(ValidationError::Missing(field));
+            None
+        }
+        Some(value) => {
+            let string = value.as_str();
+            if string.is_none() {
+                errors.push(ValidationError::NotAString(field));
+            }
+            string
+        }
+    };
+    let transport = string("transport");
+    let scheme = string("signature_scheme");
+    // may be empty, for an unsigned connection
+    string("key");
+    string("ip");
+
+    if let Some(transport) = transport.filter(|transport| !TRANSPORTS.contains(transport)) {
+        errors.push(ValidationError::Transport(transport.to_string()));
+    }
+    if let Some(scheme) = scheme.filter(|scheme| SignatureScheme::parse(scheme).is_none()) {
+        errors.push(ValidationError::SignatureScheme(scheme.to_string()));
+    }
+    for field in PORT_FIELDS {
+        match fields.get(*field) {
+            None => errors.push(ValidationError::Missing(field)),
+            // ipc "ports" are only suffixes of the socket paths
+            Some(port) if transport == Some("ipc") && port.is_u64() => {}
+            Some(port) => {
+                let in_range = port
+                    .as_u64()
+                    .is_some_and(|port| (1024..=65535).contains(&port));
+                if !in_range {
+                    errors.push(ValidationError::BadPort(field, port.clone()));
+                }
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    serde_json::from_value(value).map_err(|err| unreadable(err.to_string()))
+}
+
+fn default_ip() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_transport() -> String {
+    "tcp".to_string()
+}
+
+fn default_signature_scheme() -> String {
+    SignatureScheme::default().name().to_string()
+}
+
+impl ConnectionInfo {
+    pub fn read(path: &Path) -> Result<ConnectionInfo, Error> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    // the channel name to port map Cutypr::new takes
+    pub fn ports(&self) -> serde_json::Value {
+        json!({
+            "shell": self.shell_port,
+            "iopub": self.iopub_port,
+            "stdin": self.stdin_port,
+            "control": self.control_port,
+            "hb": self.hb_port,
+        })
+    }
+
+    // whether anything is listening on the shell port, without talking to it
+    pub fn is_alive(&self) -> bool {
+        let addr: SocketAddr = match format!("{}:{}", self.ip, self.shell_port).parse() {
+            Ok(addr) => addr,
+            Err(_) => return false,
+        };
+        TcpStream::connect_timeout(&addr, Duration::from_millis(200)).is_ok()
+    }
+}
+
+// kernel-*.json files in the runtime dir, newest first
+pub fn connection_files() -> Vec<PathBuf> {
+    let entries = match runtime_dir().and_then(|dir| fs::read_dir(dir).ok()) {
+        Some(entries) => entries,
+        None => return Vec::new(),
+    };
+
+    let mut files: Vec<(PathBuf, SystemTime)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            name.starts_with("kernel-") && name.ends_with(".json")
+        })
+        .filter_map(|entry| {
+            let modified = entry.metadata().and_then(|m| m.modified()).ok()?;
+            Some((entry.path(), modified))
+        })
+        .collect();
+    files.sort_by_key(|(_, modified)| Reverse(*modified));
+    files.into_iter().map(|(path, _)| path).collect()
+}
+
+// Ports nothing is listening on, and that no connection file in the
+// runtime dir claims for a kernel, even one that isn't running right now,
+// for a kernel to bind. The OS picks them, and they're let go of for the
+// kernel, so one can be taken in between; they're checked again once
+// they're all free and picked again if one was.
+pub fn find_free_ports(n: u32) -> Result<Vec<u16>, Error> {
+    let claimed: HashSet<u16> = connection_files()
+        .iter()
+        .filter_map(|path| ConnectionInfo::read(path).ok())
+        .flat_map(|info| {
+            [
+                info.shell_port,
+                info.iopub_port,
+                info.stdin_port,
+                info.control_port,
+                info.hb_port,
+            ]
+        })
+        .collect();
+
+    let mut attempt = 1;
+    loop {
+        // all held open until the end so the OS hands out a new port every time
+        let mut listeners = Vec::new();
+        let mut ports = Vec::new();
+        while ports.len() < n as usize {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let port = listener.local_addr()?.port();
+            if !claimed.contains(&port) {
+                ports.push(port);
+            }
+            listeners.push(listener);
+        }
+        drop(listeners);
+
+        let still_free = ports
+            .iter()
+            .all(|port| TcpListener::bind(("127.0.0.1", *port)).is_ok());
+        if still_free {
+            return Ok(ports);
+        }
+        if attempt == FREE_PORT_ATTEMPTS {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "the free ports kept being taken",
+            )));
+        }
+        attempt += 1;
+    }
+}
+
+// Only something that read the key from us can use the local sockets to
+// run code in the kernel, so it's 32 bytes from the OS's CSPRNG, which
+// nothing outside can predict.
+pub fn random_key() -> String {
+    let mut key = [0u8; 32];
+    getrandom::fill(&mut key).expect("the OS has no randomness for a kernel key");
+    hex::encode(key)
+}
+
+// A new kernel id, a random UUID like the ones jupyter_client names
+// kernel-<id>.json files with.
+pub fn new_kernel_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+// Write `info` to a connection file of its own in `dir`, named after a
+// new kernel id like jupyter_client's, so `jupyter console --existing`
+// and the rest find it, and return its path. Only its owner can read it
+// on Unix, the key in it is all it takes to run code in the kernel.
+pub fn write_connection_file(info: &ConnectionInfo, dir: &Path) -> Result<PathBuf, Error> {
+    let path = dir.join(format!("kernel-{}.json", new_kernel_id()));
+    fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+    }
+    info.write(&path)?;
+    Ok(path)
+}
+
+// A path, or a file name in the runtime dir like jupyter's --existing takes.
+// An empty name means the newest connection file.
+pub fn find_connection_file(name: &str) -> Option<PathBuf> {
+    if name.is_empty() {
+        return connection_files().into_iter().next();
+    }
+
+    let path = PathBuf::from(name);
+    if path.exists() {
+        return Some(path);
+    }
+    let path = runtime_dir()?.join(name);
+    if path.exists() {
+        return Some(path);
+    }
+    None
+}
+
+// how to point --existing at `path`: the bare file name if it's in the runtime dir
+pub fn short_name(path: &Path) -> String {
+    match (path.parent(), runtime_dir(), path.file_name()) {
+        (Some(parent), Some(dir), Some(name)) if parent == dir => {
+            name.to_string_lossy().into_owned()
+        }
+        _ => path.display().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::thread;
+
+    #[test]
+    fn bare_names_are_looked_for_in_the_runtime_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        // no other test here reads the runtime dir
+        env::set_var("JUPYTER_RUNTIME_DIR", dir.path());
+        let info = ConnectionInfo {
+            shell_port: 5000,
+            iopub_port: 5001,
+            stdin_port: 5002,
+            control_port: 5003,
+            hb_port: 5004,
+            ip: default_ip(),
+            key: "secret".to_string(),
+            transport: default_transport(),
+            signature_scheme: default_signature_scheme(),
+            kernel_name: String::new(),
+        };
+        let older = dir.path().join("kernel-older.json");
+        info.write(&older).unwrap();
+        // file times are only so fine
+        thread::sleep(Duration::from_millis(20));
+        let newer = dir.path().join("kernel-newer.json");
+        info.write(&newer).unwrap();
+
+        assert_eq!(
+            find_connection_file("kernel-older.json"),
+            Some(older.clone())
+        );
+        assert_eq!(find_connection_file(older.to_str().unwrap()), Some(older));
+        assert_eq!(find_connection_file(""), Some(newer.clone()));
+        assert_eq!(find_connection_file("kernel-missing.json"), None);
+        assert_eq!(short_name(&newer), "kernel-newer.json");
+    }
+
+    #[test]
+    fn keys_and_kernel_ids_are_random() {
+        let key = random_key();
+        assert_eq!(key.len(), 64);
+        assert!(key.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(key, random_key());
+
+        let id = new_kernel_id();
+        let uuid = Uuid::parse_str(&id).unwrap();
+        assert_eq!(uuid.get_version(), Some(uuid::Version::Random));
+        assert_eq!(uuid.get_variant(), uuid::Variant::RFC4122);
+        assert_ne!(id, new_kernel_id());
+    }
+}
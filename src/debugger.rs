@@ -0,0 +1,181 @@
+use crate::client::Cutypr;
+use crate::error::Error;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+// The Jupyter debug protocol: Debug Adapter Protocol requests and responses
+// as the content of debug_request and debug_reply on the control channel,
+// and its events as debug_event messages on iopub.
+pub struct Debugger {
+    seq: u64,
+    // the lines :break set in each file, setBreakpoints replaces a file's
+    // whole list every time
+    breakpoints: BTreeMap<String, Vec<u64>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StackFrame {
+    pub id: i64,
+    pub name: String,
+    #[serde(default)]
+    pub source: Source,
+    pub line: i64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Source {
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Scope {
+    name: String,
+    variables_reference: i64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Variable {
+    pub name: String,
+    pub value: String,
+    #[serde(default, rename = "type")]
+    pub kind: String,
+}
+
+// where a breakpoint ended up, the debugger may move it to the next line
+// with code on it
+#[derive(Debug)]
+pub struct Breakpoint {
+    pub line: u64,
+    pub verified: bool,
+}
+
+// The thread that stopped, if a debug_event's content says one did.
+pub fn stopped_thread(event: &Value) -> Option<i64> {
+    if event["event"] != "stopped" {
+        return None;
+    }
+    event["body"]["threadId"].as_i64()
+}
+
+impl Debugger {
+    // Set up the kernel's debugger and attach to it, for a kernel whose
+    // kernel_info_reply says it has one.
+    pub fn start(client: &Cutypr, language: &str) -> Result<Debugger, Error> {
+        let mut debugger = Debugger {
+            seq: 0,
+            breakpoints: BTreeMap::new(),
+        };
+        debugger.request(
+            client,
+            "initialize",
+            json!({
+                "clientID": "jupyterm",
+                "clientName": "jupyterm",
+                "adapterID": language,
+                "pathFormat": "path",
+                "linesStartAt1": true,
+                "columnsStartAt1": true,
+                "supportsVariableType": true,
+            }),
+        )?;
+        debugger.request(client, "attach", json!({}))?;
+        debugger.request(client, "configurationDone", json!({}))?;
+        Ok(debugger)
+    }
+
+    pub fn set_breakpoint(
+        &mut self,
+        client: &Cutypr,
+        path: &str,
+        line: u64,
+    ) -> Result<Breakpoint, Error> {
+        let mut lines = self.breakpoints.get(path).cloned().unwrap_or_default();
+        if !lines.contains(&line) {
+            lines.push(line);
+        }
+        let breakpoints: Vec<Value> = lines.iter().map(|line| json!({ "line": line })).collect();
+        let body = self.request(
+            client,
+            "setBreakpoints",
+            json!({
+                "source": { "path": path },
+                "breakpoints": breakpoints,
+                "sourceModified": false,
+            }),
+        )?;
+        // they come back in the order they were sent
+        let set = &body["breakpoints"][lines.iter().position(|l| *l == line).unwrap()];
+        self.breakpoints.insert(path.to_string(), lines);
+        Ok(Breakpoint {
+            line: set["line"].as_u64().unwrap_or(line),
+            verified: set["verified"].as_bool().unwrap_or(false),
+        })
+    }
+
+    // innermost frame first
+    pub fn stack(&mut self, client: &Cutypr, thread: i64) -> Result<Vec<StackFrame>, Error> {
+        let body = self.request(client, "stackTrace", json!({ "threadId": thread }))?;
+        Ok(serde_json::from_value(body["stackFrames"].clone())?)
+    }
+
+    // the variables in the frame's local scope, or its first one if none is
+    // called that
+    pub fn locals(&mut self, client: &Cutypr, frame: i64) -> Result<Vec<Variable>, Error> {
+        let body = self.request(client, "scopes", json!({ "frameId": frame }))?;
+        let scopes: Vec<Scope> = serde_json::from_value(body["scopes"].clone())?;
+        let scope = match scopes
+            .iter()
+            .find(|scope| scope.name == "Locals")
+            .or_else(|| scopes.first())
+        {
+            Some(scope) => scope,
+            None => return Ok(Vec::new()),
+        };
+        let body = self.request(
+            client,
+            "variables",
+            json!({ "variablesReference": scope.variables_reference }),
+        )?;
+        Ok(serde_json::from_value(body["variables"].clone())?)
+    }
+
+    pub fn resume(&mut self, client: &Cutypr, thread: i64) -> Result<(), Error> {
+        self.request(client, "continue", json!({ "threadId": thread }))?;
+        Ok(())
+    }
+
+    // into a call on the current line, like pdb's step
+    pub fn step(&mut self, client: &Cutypr, thread: i64) -> Result<(), Error> {
+        self.request(client, "stepIn", json!({ "threadId": thread }))?;
+        Ok(())
+    }
+
+    // the body of the response, or the debugger's reason for failing
+    fn request(
+        &mut self,
+        client: &Cutypr,
+        command: &str,
+        arguments: Value,
+    ) -> Result<Value, Error> {
+        self.seq += 1;
+        let response = client.send_debug_request(&json!({
+            "seq": self.seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        }))?;
+        if response["success"].as_bool() != Some(true) {
+            let message = response["message"].as_str().unwrap_or("");
+            return Err(Error::Debugger(if message.is_empty() {
+                format!("{} failed", command)
+            } else {
+                format!("{} failed: {}", command, message)
+            }));
+        }
+        Ok(response.get("body").cloned().unwrap_or(Value::Null))
+    }
+}
@@ -0,0 +1,265 @@
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+// Remove terminal escape sequences (colors, OSC titles and links) from text.
+pub fn strip_ansi(text: &str) -> String {
+    visible_chars(text).into_iter().map(|(_, c)| c).collect()
+}
+
+// The characters of `text` outside its escape sequences, with their byte
+// offsets in it.
+fn visible_chars(text: &str) -> Vec<(usize, char)> {
+    let mut visible = Vec::with_capacity(text.len());
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if c != '\x1b' {
+            visible.push((i, c));
+            continue;
+        }
+
+        match chars.next().map(|(_, c)| c) {
+            // CSI: parameters up to a final byte in @..~
+            Some('[') => {
+                for (_, c) in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC: up to BEL or ESC \
+            Some(']') => {
+                while let Some((_, c)) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek().map(|(_, c)| *c) == Some('\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    visible
+}
+
+// Make links of the file references in a traceback, like IPython's
+// `File /path/to/module.py:42, in f()` and Python's
+// `File "/path/to/module.py", line 42`, with the URL `url` gives for each
+// path and line, or leaves it alone when it gives None. The colors around
+// and inside them are kept as they are.
+pub fn link_file_references(text: &str, url: impl Fn(&str, u64) -> Option<String>) -> String {
+    let visible = visible_chars(text);
+    let plain: String = visible.iter().map(|(_, c)| c).collect();
+    // the byte in `text` for every byte of `plain`
+    let offsets: Vec<usize> = visible
+        .iter()
+        .flat_map(|(i, c)| (0..c.len_utf8()).map(move |b| i + b))
+        .collect();
+
+    let mut linked = String::with_capacity(text.len());
+    let mut copied = 0;
+    for (i, _) in plain.match_indices("File ") {
+        let start = i + "File ".len();
+        let (path, line, len) = match file_reference(&plain[start..]) {
+            Some(reference) => reference,
+            None => continue,
+        };
+        let url = match url(path, line) {
+            Some(url) => url,
+            None => continue,
+        };
+        let (from, to) = (offsets[start], offsets[start + len - 1] + 1);
+        linked += &text[copied..from];
+        linked += &format!("\x1b]8;;{}\x1b\\", url);
+        linked += &text[from..to];
+        linked += "\x1b]8;;\x1b\\";
+        copied = to;
+    }
+    linked += &text[copied..];
+    linked
+}
+
+// The path and line at the start of `text`, after a "File ", and how much
+// of it they take up.
+fn file_reference(text: &str) -> Option<(&str, u64, usize)> {
+    let digits = |text: &str| text.bytes().take_while(u8::is_ascii_digit).count();
+    if let Some(quoted) = text.strip_prefix('"') {
+        let close = quoted.find('"')?;
+        let after = quoted[close + 1..].strip_prefix(", line ")?;
+        let n = digits(after);
+        let line = after[..n].parse().ok()?;
+        return Some((&quoted[..close], line, close + 2 + ", line ".len() + n));
+    }
+    let end = text
+        .find(", in ")
+        .or_else(|| text.find('\n'))
+        .unwrap_or(text.len());
+    let (path, line) = text[..end].trim_end().rsplit_once(':')?;
+    if path.is_empty() || line.is_empty() || digits(line) != line.len() {
+        return None;
+    }
+    Some((path, line.parse().ok()?, path.len() + 1 + line.len()))
+}
+
+// Whether a traceback entry is one of its frames, like IPython's
+// `Cell In[2], line 1` and `File /path/to/mod.py:42, in f()` or Python's
+// `  File "mod.py", line 42, in f`, rather than its header or the error.
+// An entry can take more than one line, with the code around the frame's.
+fn is_frame(entry: &str) -> bool {
+    let plain = strip_ansi(entry);
+    let plain = plain.trim_start();
+    ["File ", "Cell ", "Input In "]
+        .iter()
+        .any(|start| plain.starts_with(start))
+}
+
+// A traceback's entries with only the first frame and the last
+// `max_frames - 1` kept when it has more than `max_frames`, and a line
+// saying how many were left out in place of the rest. The header and the
+// error after the frames stay, and so do their colors. 0 keeps them all.
+pub fn collapse_traceback(traceback: &[&str], max_frames: usize) -> Vec<String> {
+    let frames: Vec<usize> = (0..traceback.len())
+        .filter(|i| is_frame(traceback[*i]))
+        .collect();
+    if max_frames == 0 || frames.len() <= max_frames {
+        return traceback.iter().map(|entry| entry.to_string()).collect();
+    }
+    let tail = max_frames - 1;
+    let hidden = frames.len() - 1 - tail;
+    // the second frame up to the first of the tail, or what's after the
+    // last frame when there's no tail
+    let (from, to) = (
+        frames[1],
+        frames
+            .get(frames.len() - tail)
+            .map_or(frames[frames.len() - 1] + 1, |i| *i),
+    );
+    let mut collapsed: Vec<String> = traceback[..from].iter().map(|e| e.to_string()).collect();
+    collapsed.push(format!(
+        "... {} frames hidden, :traceback to expand ...",
+        hidden
+    ));
+    collapsed.extend(traceback[to..].iter().map(|e| e.to_string()));
+    collapsed
+}
+
+// Show `text` a screen at a time in $PAGER, or `less -R`, which keeps its
+// colors. It's printed as it is when stdout isn't a terminal or there's no
+// pager to run.
+pub fn page(text: &str) -> io::Result<()> {
+    if !io::stdout().is_terminal() {
+        println!("{}", text);
+        return Ok(());
+    }
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| "less -R".to_string());
+    let mut words = pager.split_whitespace();
+    let spawned = Command::new(words.next().unwrap())
+        .args(words)
+        .stdin(Stdio::piped())
+        .spawn();
+    let mut pager = match spawned {
+        Ok(pager) => pager,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            println!("{}", text);
+            return Ok(());
+        }
+        Err(err) => return Err(err),
+    };
+    // quitting the pager before the end closes the pipe, which is fine
+    let _ = writeln!(pager.stdin.take().unwrap(), "{}", text);
+    pager.wait()?;
+    Ok(())
+}
+
+// Milliseconds under a second, seconds with two decimals above.
+pub fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        format!("{} ms", duration.as_millis())
+    } else {
+        format!("{:.2} s", duration.as_secs_f64())
+    }
+}
+
+// Whether the terminal on stdout makes links of OSC 8 escapes, going by
+// what it says it is. FORCE_HYPERLINK=1 or 0 settles it either way.
+pub fn supports_hyperlinks() -> bool {
+    if let Ok(force) = env::var("FORCE_HYPERLINK") {
+        return force != "0";
+    }
+    if !io::stdout().is_terminal() {
+        return false;
+    }
+    let term = env::var("TERM").unwrap_or_default();
+    let vte = env::var("VTE_VERSION")
+        .ok()
+        .and_then(|version| version.parse::<u32>().ok());
+    env::var_os("KITTY_WINDOW_ID").is_some()
+        || env::var_os("WT_SESSION").is_some()
+        || vte.is_some_and(|version| version >= 5000)
+        || ["kitty", "ghostty", "foot", "alacritty"]
+            .iter()
+            .any(|name| term.contains(name))
+        || matches!(
+            env::var("TERM_PROGRAM").as_deref(),
+            Ok("iTerm.app") | Ok("WezTerm") | Ok("vscode") | Ok("ghostty")
+        )
+}
+
+// `text` as an OSC 8 link to `url`.
+pub fn hyperlink(text: &str, url: &str) -> String {
+    format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url, text)
+}
+
+// A file:// URL for an absolute path, escaping what a URL can't hold.
+pub fn file_url(path: &Path) -> String {
+    let path = url_path(path);
+    // C:/... on Windows
+    if path.starts_with('/') {
+        format!("file://{}", path)
+    } else {
+        format!("file:///{}", path)
+    }
+}
+
+// `template` with {path} and {line} filled in, the path escaped like
+// file_url's, for links like vscode://file/{path}:{line}.
+pub fn fill_link_template(template: &str, path: &Path, line: u64) -> String {
+    template
+        .replace("{path}", &url_path(path))
+        .replace("{line}", &line.to_string())
+}
+
+// the path with forward slashes and what a URL can't hold escaped
+fn url_path(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut url = String::new();
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' | b':' => {
+                url.push(byte as char)
+            }
+            byte => url.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    url
+}
+
+// The path, as a link to the file where the terminal supports them.
+pub fn path_link(path: &Path) -> String {
+    let text = path.display().to_string();
+    if supports_hyperlinks() {
+        hyperlink(&text, &file_url(path))
+    } else {
+        text
+    }
+}
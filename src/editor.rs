@@ -0,0 +1,1330 @@
+use crate::display::strip_ansi;
+use crossterm::event::{
+    self, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEvent, KeyEventKind,
+    KeyModifiers,
+};
+use crossterm::style::Stylize;
+use crossterm::terminal::{self, ClearType};
+use crossterm::{cursor, execute, queue, style};
+use serde_json::{Map, Value};
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+use unicode_width::UnicodeWidthChar;
+
+mod vi;
+
+const INDENT: usize = 4;
+
+// most completion candidates shown at once, longer lists scroll
+const MENU_ROWS: usize = 10;
+
+pub trait Helper {
+    // content of the kernel's is_complete_reply for `code`, if it answered
+    fn is_complete(&self, code: &str) -> Option<Map<String, Value>>;
+    // content of the complete_reply, cursor_pos counts unicode characters
+    fn complete(&self, code: &str, cursor_pos: usize) -> Option<Map<String, Value>>;
+    // content of the inspect_reply for the object at cursor_pos
+    fn inspect(&self, code: &str, cursor_pos: usize) -> Option<Map<String, Value>>;
+}
+
+// Parse a key binding like "alt-i", "ctrl-o" or "f1".
+pub fn parse_key(binding: &str) -> Option<(KeyCode, KeyModifiers)> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut parts: Vec<&str> = binding.split('-').collect();
+    let key = parts.pop()?.to_lowercase();
+
+    for part in parts {
+        match part.to_lowercase().as_str() {
+            "alt" | "meta" | "m" => modifiers |= KeyModifiers::ALT,
+            "ctrl" | "control" | "c" => modifiers |= KeyModifiers::CONTROL,
+            "shift" | "s" => modifiers |= KeyModifiers::SHIFT,
+            _ => return None,
+        }
+    }
+
+    let code = match key.as_str() {
+        "tab" => KeyCode::Tab,
+        "enter" => KeyCode::Enter,
+        "esc" => KeyCode::Esc,
+        _ if key.starts_with('f') && key.len() > 1 => KeyCode::F(key[1..].parse().ok()?),
+        _ if key.chars().count() == 1 => KeyCode::Char(key.chars().next()?),
+        _ => return None,
+    };
+    Some((code, modifiers))
+}
+
+#[derive(Debug)]
+struct Menu {
+    // candidate text and its kind (function, module, ...), if known
+    items: Vec<(String, String)>,
+    selected: usize,
+    top: usize,
+    // the part of the buffer the selected candidate replaces
+    start: usize,
+    end: usize,
+}
+
+#[derive(Debug)]
+enum Popup {
+    Menu(Menu),
+    // docstring from an inspect_request
+    Doc(Vec<String>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EditingMode {
+    Emacs,
+    Vi,
+}
+
+impl EditingMode {
+    pub fn parse(name: &str) -> Option<EditingMode> {
+        match name.trim().to_lowercase().as_str() {
+            "emacs" => Some(EditingMode::Emacs),
+            "vi" | "vim" => Some(EditingMode::Vi),
+            _ => None,
+        }
+    }
+}
+
+enum Outcome {
+    Accept,
+    Interrupt,
+    Eof,
+}
+
+#[derive(Debug)]
+pub struct Editor {
+    history: Vec<String>,
+    history_index: usize,
+    saved: String,
+    buffer: String,
+    cursor: usize,
+    prompt: String,
+    continuation: String,
+    // rows between the start of the last render and the cursor / the end
+    cursor_row: usize,
+    end_row: usize,
+    popup: Option<Popup>,
+    inspect_key: (KeyCode, KeyModifiers),
+    editing_mode: EditingMode,
+    // vi normal mode, and the operator (d, c, y, ...) waiting for its motion
+    vi_normal: bool,
+    pending: Option<char>,
+    // last killed or yanked text, whole lines when kill_lines is set
+    kill: String,
+    kill_lines: bool,
+    // buffer and cursor before each vi change
+    undo: Vec<(String, usize)>,
+    // wipe the screen before the next render
+    clear: bool,
+    // give up on a cell after this long without a key press
+    idle_timeout: Option<Duration>,
+    // what the next cell starts out as instead of nothing
+    prefill: Option<String>,
+}
+
+impl Default for Editor {
+    fn default() -> Editor {
+        Editor::new()
+    }
+}
+
+impl Editor {
+    pub fn new() -> Editor {
+        Editor {
+            history: Vec::new(),
+            history_index: 0,
+            saved: String::new(),
+            buffer: String::new(),
+            cursor: 0,
+            prompt: String::new(),
+            continuation: String::new(),
+            cursor_row: 0,
+            end_row: 0,
+            popup: None,
+            inspect_key: (KeyCode::Char('i'), KeyModifiers::ALT),
+            editing_mode: EditingMode::Emacs,
+            vi_normal: false,
+            pending: None,
+            kill: String::new(),
+            kill_lines: false,
+            undo: Vec::new(),
+            clear: false,
+            idle_timeout: None,
+            prefill: None,
+        }
+    }
+
+    pub fn set_inspect_key(&mut self, key: (KeyCode, KeyModifiers)) {
+        self.inspect_key = key;
+    }
+
+    pub fn set_editing_mode(&mut self, mode: EditingMode) {
+        self.editing_mode = mode;
+    }
+
+    // read_cell fails with ErrorKind::TimedOut once nothing has been typed
+    // for `timeout`, only at a terminal
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    // what Up goes back through, oldest first, for a session picked up again
+    pub fn set_history(&mut self, cells: Vec<String>) {
+        self.history = cells;
+        self.history_index = self.history.len();
+    }
+
+    // start the next cell with `text` to edit, at a terminal
+    pub fn prefill(&mut self, text: &str) {
+        self.prefill = Some(text.to_string());
+    }
+
+    // Read one cell, which may span several lines. Returns None on EOF.
+    pub fn read_cell(&mut self, prompt: &str, helper: &dyn Helper) -> io::Result<Option<String>> {
+        self.prompt = prompt.to_string();
+        // every vi cell starts out in insert mode
+        self.continuation = continuation_prompt(&format!("{}{}", self.indicator(), prompt));
+        self.vi_normal = false;
+        self.pending = None;
+        self.undo.clear();
+        self.buffer.clear();
+        self.cursor = 0;
+        self.cursor_row = 0;
+        self.end_row = 0;
+        self.history_index = self.history.len();
+        let prefill = self.prefill.take();
+
+        if !io::stdin().is_terminal() {
+            return self.read_piped(helper);
+        }
+        if let Some(text) = prefill {
+            self.buffer = text;
+            self.cursor = self.buffer.len();
+        }
+
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnableBracketedPaste)?;
+        let result = self.edit(helper);
+        execute!(io::stdout(), DisableBracketedPaste)?;
+        terminal::disable_raw_mode()?;
+
+        let cell = result?;
+        if let Some(cell) = &cell {
+            if !cell.trim().is_empty() {
+                self.history.push(cell.clone());
+            }
+        }
+        Ok(cell)
+    }
+
+    // stdin is not a terminal: read whole lines, continuing while the kernel
+    // says the cell is incomplete. Prompts are left out of redirected output,
+    // where they'd only get in the way of whatever reads it.
+    fn read_piped(&mut self, helper: &dyn Helper) -> io::Result<Option<String>> {
+        let mut out = io::stdout();
+        let prompts = out.is_terminal();
+        if prompts {
+            write!(out, "{}", self.prompt)?;
+            out.flush()?;
+        }
+
+        loop {
+            let mut line = String::new();
+            if io::stdin().read_line(&mut line)? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Ok(Some(self.buffer.clone()));
+            }
+
+            self.buffer
+                .push_str(line.trim_end_matches(&['\r', '\n'][..]));
+            self.cursor = self.buffer.len();
+            if self.buffer.trim().is_empty() || self.next_indent(helper).is_none() {
+                return Ok(Some(self.buffer.clone()));
+            }
+
+            self.buffer.push('\n');
+            if prompts {
+                write!(out, "{}", self.continuation)?;
+                out.flush()?;
+            }
+        }
+    }
+
+    fn edit(&mut self, helper: &dyn Helper) -> io::Result<Option<String>> {
+        self.render()?;
+        let mut last_input = Instant::now();
+
+        loop {
+            if let Some(timeout) = self.idle_timeout {
+                if !event::poll(timeout.saturating_sub(last_input.elapsed()))? {
+                    self.popup = None;
+                    self.render()?;
+                    self.finish()?;
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "no input"));
+                }
+            }
+
+            let outcome = match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => {
+                    last_input = Instant::now();
+                    self.handle_key(key, helper)
+                }
+                // pasted text goes in verbatim, it already carries its own indentation
+                Event::Paste(text) => {
+                    last_input = Instant::now();
+                    self.paste(&text);
+                    None
+                }
+                Event::Resize(..) => None,
+                _ => continue,
+            };
+
+            if outcome.is_some() && self.popup.take().is_some() {
+                self.render()?;
+            }
+
+            match outcome {
+                Some(Outcome::Accept) => {
+                    self.finish()?;
+                    return Ok(Some(self.buffer.clone()));
+                }
+                Some(Outcome::Interrupt) => {
+                    self.finish()?;
+                    return Ok(Some(String::new()));
+                }
+                Some(Outcome::Eof) => {
+                    self.finish()?;
+                    return Ok(None);
+                }
+                None => self.render()?,
+            }
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyEvent, helper: &dyn Helper) -> Option<Outcome> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+
+        if (key.code, key.modifiers) == self.inspect_key {
+            match self.popup {
+                Some(Popup::Doc(_)) => self.popup = None,
+                _ => self.inspect(helper),
+            }
+            return None;
+        }
+
+        // any key dismisses the docstring, Esc does nothing else
+        if let Some(Popup::Doc(_)) = self.popup {
+            self.popup = None;
+            if key.code == KeyCode::Esc {
+                return None;
+            }
+        }
+
+        if let Some(Popup::Menu(menu)) = &mut self.popup {
+            match key.code {
+                KeyCode::Tab | KeyCode::Down => {
+                    menu.selected = (menu.selected + 1) % menu.items.len();
+                    return None;
+                }
+                KeyCode::BackTab | KeyCode::Up => {
+                    menu.selected = (menu.selected + menu.items.len() - 1) % menu.items.len();
+                    return None;
+                }
+                KeyCode::Enter => {
+                    let (start, end) = (menu.start, menu.end);
+                    let text = menu.items[menu.selected].0.clone();
+                    self.popup = None;
+                    self.replace(start, end, &text);
+                    return None;
+                }
+                KeyCode::Esc => {
+                    self.popup = None;
+                    return None;
+                }
+                // anything else closes the menu and edits as usual
+                _ => self.popup = None,
+            }
+        }
+
+        if self.editing_mode == EditingMode::Vi {
+            if self.vi_normal {
+                return self.vi_command(key, helper);
+            }
+            if key.code == KeyCode::Esc {
+                self.vi_normal = true;
+                if self.cursor > self.line_start() {
+                    self.cursor = self.prev_boundary();
+                }
+                return None;
+            }
+        }
+
+        match key.code {
+            KeyCode::Char('c') if ctrl => return Some(Outcome::Interrupt),
+            KeyCode::Char('l') if ctrl => self.clear = true,
+            KeyCode::Char('d') if ctrl => {
+                if self.buffer.is_empty() {
+                    return Some(Outcome::Eof);
+                }
+                self.delete();
+            }
+            KeyCode::Char('a') if ctrl => self.cursor = self.line_start(),
+            KeyCode::Char('e') if ctrl => self.cursor = self.line_end(),
+            KeyCode::Char('b') if ctrl => self.cursor = self.prev_boundary(),
+            KeyCode::Char('f') if ctrl => self.cursor = self.next_boundary(),
+            KeyCode::Char('p') if ctrl => self.move_up(),
+            KeyCode::Char('n') if ctrl => self.move_down(),
+            KeyCode::Char('u') if ctrl => self.cut(self.line_start(), self.cursor, false),
+            KeyCode::Char('k') if ctrl => self.cut(self.cursor, self.line_end(), false),
+            KeyCode::Char('w') if ctrl => {
+                let start = word_start_before(&self.buffer, self.cursor).max(self.line_start());
+                self.cut(start, self.cursor, false);
+            }
+            KeyCode::Char('y') if ctrl => {
+                let kill = self.kill.clone();
+                self.insert(&kill);
+            }
+            KeyCode::Char('b') if alt => self.cursor = word_back(&self.buffer, self.cursor),
+            KeyCode::Char('f') if alt => self.cursor = word_forward(&self.buffer, self.cursor),
+            KeyCode::Char('d') if alt => {
+                let end = word_forward(&self.buffer, self.cursor);
+                self.cut(self.cursor, end, false);
+            }
+            KeyCode::Char(c) if !ctrl && !alt => self.insert(&c.to_string()),
+            KeyCode::Enter => return self.enter(helper),
+            KeyCode::Tab => {
+                let start = self.line_start();
+                if self.buffer[start..self.cursor].trim().is_empty() {
+                    let column = self.cursor - start;
+                    self.insert(&" ".repeat(INDENT - column % INDENT));
+                } else {
+                    self.complete(helper);
+                }
+            }
+            KeyCode::Backspace => self.backspace(),
+            KeyCode::Delete => self.delete(),
+            KeyCode::Left => self.cursor = self.prev_boundary(),
+            KeyCode::Right => self.cursor = self.next_boundary(),
+            KeyCode::Up => self.move_up(),
+            KeyCode::Down => self.move_down(),
+            KeyCode::Home => self.cursor = self.line_start(),
+            KeyCode::End => self.cursor = self.line_end(),
+            _ => {}
+        }
+        None
+    }
+
+    fn enter(&mut self, helper: &dyn Helper) -> Option<Outcome> {
+        if self.buffer.trim().is_empty() {
+            return Some(Outcome::Accept);
+        }
+
+        match self.next_indent(helper) {
+            Some(indent) => {
+                self.insert(&format!("\n{}", " ".repeat(indent)));
+                None
+            }
+            None => Some(Outcome::Accept),
+        }
+    }
+
+    // None if the cell is ready to run, otherwise the indentation for the
+    // continuation line
+    fn next_indent(&self, helper: &dyn Helper) -> Option<usize> {
+        let at_end = self.cursor == self.buffer.len();
+        let before = &self.buffer[..self.cursor];
+
+        if let Some(reply) = helper.is_complete(&self.buffer).map(Value::Object) {
+            match reply["status"].as_str() {
+                Some("complete") | Some("invalid") => return None,
+                Some("incomplete") => {
+                    return match reply["indent"].as_str() {
+                        Some(indent) if at_end => Some(width_of(indent)),
+                        _ => Some(infer_indent(before)),
+                    };
+                }
+                _ => {}
+            }
+        }
+
+        if is_complete(&self.buffer) {
+            None
+        } else {
+            Some(infer_indent(before))
+        }
+    }
+
+    fn complete(&mut self, helper: &dyn Helper) {
+        let cursor_pos = self.buffer[..self.cursor].chars().count();
+        let reply = match helper.complete(&self.buffer, cursor_pos) {
+            Some(reply) => Value::Object(reply),
+            None => return,
+        };
+
+        let matches: Vec<String> = match reply["matches"].as_array() {
+            Some(matches) => matches
+                .iter()
+                .filter_map(|m| m.as_str().map(String::from))
+                .collect(),
+            None => return,
+        };
+        if matches.is_empty() {
+            return;
+        }
+
+        let start = self.offset_of(
+            reply["cursor_start"]
+                .as_u64()
+                .map_or(cursor_pos, |n| n as usize),
+        );
+        let mut end = self.offset_of(
+            reply["cursor_end"]
+                .as_u64()
+                .map_or(cursor_pos, |n| n as usize),
+        );
+
+        if matches.len() == 1 {
+            self.replace(start, end, &matches[0]);
+            return;
+        }
+
+        let prefix = common_prefix(&matches);
+        if prefix.len() > end - start && prefix.starts_with(&self.buffer[start..end]) {
+            self.replace(start, end, &prefix);
+            end = start + prefix.len();
+        }
+
+        // ipykernel describes each match when it has jedi to ask
+        let kinds = reply["metadata"]["_jupyter_types_experimental"].as_array();
+        let kind_of = |text: &str| -> String {
+            kinds
+                .and_then(|kinds| kinds.iter().find(|kind| kind["text"] == text))
+                .and_then(|kind| kind["type"].as_str())
+                .unwrap_or("")
+                .to_string()
+        };
+
+        self.popup = Some(Popup::Menu(Menu {
+            items: matches.iter().map(|m| (m.clone(), kind_of(m))).collect(),
+            selected: 0,
+            top: 0,
+            start,
+            end,
+        }));
+    }
+
+    // no reply (busy kernel, timeout) or nothing found shows nothing
+    fn inspect(&mut self, helper: &dyn Helper) {
+        let cursor_pos = self.buffer[..self.cursor].chars().count();
+        let reply = match helper.inspect(&self.buffer, cursor_pos) {
+            Some(reply) => Value::Object(reply),
+            None => return,
+        };
+
+        if reply["status"] != "ok" || reply["found"] != true {
+            return;
+        }
+        if let Some(text) = reply["data"]["text/plain"].as_str() {
+            let lines = strip_ansi(text).lines().map(String::from).collect();
+            self.popup = Some(Popup::Doc(lines));
+        }
+    }
+
+    // remove start..end into the kill buffer
+    fn cut(&mut self, start: usize, end: usize, lines: bool) {
+        if start == end {
+            return;
+        }
+        if self.vi_normal {
+            self.snapshot();
+        }
+        self.kill = self.buffer.drain(start..end).collect();
+        self.kill_lines = lines;
+        self.cursor = start;
+    }
+
+    // one undo step per change, even when it cuts and then enters insert mode
+    fn snapshot(&mut self) {
+        if self
+            .undo
+            .last()
+            .is_some_and(|(buffer, _)| *buffer == self.buffer)
+        {
+            return;
+        }
+        self.undo.push((self.buffer.clone(), self.cursor));
+    }
+
+    fn undo(&mut self) {
+        if let Some((buffer, cursor)) = self.undo.pop() {
+            self.buffer = buffer;
+            self.cursor = cursor;
+        }
+    }
+
+    fn indicator(&self) -> &'static str {
+        match (self.editing_mode, self.vi_normal) {
+            (EditingMode::Emacs, _) => "",
+            (EditingMode::Vi, false) => "[ins] ",
+            (EditingMode::Vi, true) => "[nav] ",
+        }
+    }
+
+    fn replace(&mut self, start: usize, end: usize, text: &str) {
+        self.buffer.replace_range(start..end, text);
+        self.cursor = start + text.len();
+    }
+
+    // byte offset of the character at index `n` of the buffer
+    fn offset_of(&self, n: usize) -> usize {
+        self.buffer
+            .char_indices()
+            .nth(n)
+            .map_or(self.buffer.len(), |(i, _)| i)
+    }
+
+    fn paste(&mut self, text: &str) {
+        self.popup = None;
+        self.insert(&text.replace("\r\n", "\n").replace('\r', "\n"));
+    }
+
+    fn insert(&mut self, text: &str) {
+        self.buffer.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    fn backspace(&mut self) {
+        let start = self.line_start();
+        let before = &self.buffer[start..self.cursor];
+
+        // dedent one level at the start of an indented continuation line
+        if start > 0 && !before.is_empty() && before.chars().all(|c| c == ' ') {
+            let width = (before.len() - 1) % INDENT + 1;
+            self.buffer.drain(self.cursor - width..self.cursor);
+            self.cursor -= width;
+            return;
+        }
+
+        let prev = self.prev_boundary();
+        self.buffer.drain(prev..self.cursor);
+        self.cursor = prev;
+    }
+
+    fn delete(&mut self) {
+        let next = self.next_boundary();
+        self.buffer.drain(self.cursor..next);
+    }
+
+    fn prev_boundary(&self) -> usize {
+        self.buffer[..self.cursor]
+            .char_indices()
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn next_boundary(&self) -> usize {
+        self.buffer[self.cursor..]
+            .chars()
+            .next()
+            .map_or(self.cursor, |c| self.cursor + c.len_utf8())
+    }
+
+    fn line_start(&self) -> usize {
+        self.buffer[..self.cursor].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    fn line_end(&self) -> usize {
+        self.buffer[self.cursor..]
+            .find('\n')
+            .map_or(self.buffer.len(), |i| self.cursor + i)
+    }
+
+    // byte offset of `column` chars into the line starting at `start`
+    fn offset_at(&self, start: usize, column: usize) -> usize {
+        let line = self.buffer[start..].split('\n').next().unwrap();
+        line.char_indices()
+            .nth(column)
+            .map_or(start + line.len(), |(i, _)| start + i)
+    }
+
+    fn move_up(&mut self) {
+        let start = self.line_start();
+        if start == 0 {
+            self.history_prev();
+            return;
+        }
+
+        let column = self.buffer[start..self.cursor].chars().count();
+        let prev_start = self.buffer[..start - 1].rfind('\n').map_or(0, |i| i + 1);
+        self.cursor = self.offset_at(prev_start, column);
+    }
+
+    fn move_down(&mut self) {
+        let end = self.line_end();
+        if end == self.buffer.len() {
+            self.history_next();
+            return;
+        }
+
+        let column = self.buffer[self.line_start()..self.cursor].chars().count();
+        self.cursor = self.offset_at(end + 1, column);
+    }
+
+    fn history_prev(&mut self) {
+        if self.history_index == 0 {
+            return;
+        }
+        if self.history_index == self.history.len() {
+            self.saved = self.buffer.clone();
+        }
+        self.history_index -= 1;
+        self.buffer = self.history[self.history_index].clone();
+        self.cursor = self.buffer.len();
+    }
+
+    fn history_next(&mut self) {
+        if self.history_index >= self.history.len() {
+            return;
+        }
+        self.history_index += 1;
+        self.buffer = match self.history.get(self.history_index) {
+            Some(cell) => cell.clone(),
+            None => self.saved.clone(),
+        };
+        self.cursor = self.buffer.len();
+    }
+
+    fn render(&mut self) -> io::Result<()> {
+        let (width, height) = terminal::size().map_or((80, 24), |(w, h)| (w as usize, h as usize));
+        let width = width.max(1);
+        let mut out = io::stdout();
+
+        if self.clear {
+            self.clear = false;
+            clear_screen()?;
+        } else if self.cursor_row > 0 {
+            queue!(out, cursor::MoveUp(self.cursor_row as u16))?;
+        }
+        queue!(
+            out,
+            cursor::MoveToColumn(0),
+            terminal::Clear(ClearType::FromCursorDown)
+        )?;
+
+        let mut row = 0;
+        let mut offset = 0;
+        let mut cursor_at = (0, 0);
+        let mut end_at = (0, 0);
+
+        let first = format!("{}{}", self.indicator(), self.prompt);
+        for (i, line) in self.buffer.split('\n').enumerate() {
+            let prompt = if i == 0 { &first } else { &self.continuation };
+            if i > 0 {
+                queue!(out, style::Print("\r\n"))?;
+            }
+            queue!(
+                out,
+                style::Print(prompt),
+                style::Print(line.replace('\t', "    "))
+            )?;
+
+            let prompt_width = width_of(prompt);
+            if self.cursor >= offset && self.cursor <= offset + line.len() {
+                let w = prompt_width + width_of(&line[..self.cursor - offset]);
+                cursor_at = (row + w / width, w % width);
+            }
+
+            let w = prompt_width + width_of(line);
+            end_at = (row + w / width, w % width);
+            row += if w == 0 { 1 } else { w.div_ceil(width) };
+            offset += line.len() + 1;
+        }
+
+        // the terminal holds the cursor on the last column after an exact
+        // fit, push it onto the next row so the arithmetic above holds
+        let pushed = end_at.1 == 0 && end_at.0 > 0;
+        if pushed {
+            queue!(out, style::Print("\r\n"))?;
+        }
+
+        // popups hang below the buffer, printing them scrolls the screen
+        // when we're near the bottom
+        let rows = match &mut self.popup {
+            Some(Popup::Menu(menu)) => menu_rows(menu, width, height),
+            Some(Popup::Doc(lines)) => doc_rows(lines, width, height.saturating_sub(row + 1)),
+            None => Vec::new(),
+        };
+        for (i, popup_row) in rows.iter().enumerate() {
+            if i > 0 || !pushed {
+                queue!(out, style::Print("\r\n"))?;
+            }
+            queue!(out, style::Print(popup_row))?;
+        }
+        let popup_rows = if pushed {
+            rows.len().saturating_sub(1)
+        } else {
+            rows.len()
+        };
+
+        let up = end_at.0 + popup_rows - cursor_at.0;
+        if up > 0 {
+            queue!(out, cursor::MoveUp(up as u16))?;
+        }
+        queue!(out, cursor::MoveToColumn(cursor_at.1 as u16))?;
+        out.flush()?;
+
+        self.cursor_row = cursor_at.0;
+        self.end_row = end_at.0;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let mut out = io::stdout();
+        if self.end_row > self.cursor_row {
+            queue!(
+                out,
+                cursor::MoveDown((self.end_row - self.cursor_row) as u16)
+            )?;
+        }
+        queue!(out, style::Print("\r\n"))?;
+        out.flush()
+    }
+}
+
+// The "...: " prompt for the lines after the first, as wide as `prompt`.
+pub fn continuation_prompt(prompt: &str) -> String {
+    format!("{:>width$}: ", "...", width = width_of(prompt).max(5) - 2)
+}
+
+// Clear the terminal and put the cursor at the top left.
+pub fn clear_screen() -> io::Result<()> {
+    execute!(
+        io::stdout(),
+        terminal::Clear(ClearType::All),
+        cursor::MoveTo(0, 0)
+    )
+}
+
+// Read the answer to a password input_request, like getpass() asks for,
+// without echoing it. None for Ctrl-C. Piped input is read as it is, there
+// is nobody to see it.
+pub fn read_password(prompt: &str) -> io::Result<Option<String>> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    if !io::stdin().is_terminal() {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        return Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string()));
+    }
+
+    terminal::enable_raw_mode()?;
+    let result = read_hidden();
+    terminal::disable_raw_mode()?;
+    println!();
+    result
+}
+
+fn read_hidden() -> io::Result<Option<String>> {
+    let mut secret = String::new();
+    loop {
+        let key = match event::read()? {
+            Event::Key(key) if key.kind != KeyEventKind::Release => key,
+            _ => continue,
+        };
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Enter => return Ok(Some(secret)),
+            KeyCode::Char('c') if ctrl => return Ok(None),
+            KeyCode::Char('u') if ctrl => secret.clear(),
+            KeyCode::Backspace => {
+                secret.pop();
+            }
+            KeyCode::Char(c) if !ctrl => secret.push(c),
+            _ => {}
+        }
+    }
+}
+
+fn menu_rows(menu: &mut Menu, width: usize, height: usize) -> Vec<String> {
+    let count = menu
+        .items
+        .len()
+        .min(MENU_ROWS)
+        .min(height.saturating_sub(2).max(1));
+    if menu.selected < menu.top {
+        menu.top = menu.selected;
+    } else if menu.selected >= menu.top + count {
+        menu.top = menu.selected + 1 - count;
+    }
+
+    let visible = &menu.items[menu.top..menu.top + count];
+    let text_width = visible
+        .iter()
+        .map(|(text, _)| width_of(text))
+        .max()
+        .unwrap_or(0);
+
+    let mut rows = Vec::new();
+    for (i, (text, kind)) in visible.iter().enumerate() {
+        let padding = " ".repeat(text_width - width_of(text));
+        let item = truncate(&format!(" {}{} ", text, padding), width.saturating_sub(1));
+        let mut row = if menu.top + i == menu.selected {
+            item.reverse().to_string()
+        } else {
+            item
+        };
+        if !kind.is_empty() {
+            let kind = truncate(&format!("{} ", kind), width.saturating_sub(text_width + 3));
+            row.push_str(&kind.dim().to_string());
+        }
+        rows.push(row);
+    }
+    rows
+}
+
+// the docstring cut to fit in `available` rows
+fn doc_rows(lines: &[String], width: usize, available: usize) -> Vec<String> {
+    let available = available.max(2);
+    let fits = lines.len() <= available;
+    let shown = if fits { lines.len() } else { available - 1 };
+
+    let mut rows: Vec<String> = lines[..shown]
+        .iter()
+        .map(|line| truncate(&line.replace('\t', "    "), width.saturating_sub(1)))
+        .collect();
+    if !fits {
+        let hint = format!(
+            "... {} more lines, use ? for the full text",
+            lines.len() - shown
+        );
+        rows.push(truncate(&hint, width.saturating_sub(1)).dim().to_string());
+    }
+    rows
+}
+
+fn width_of(text: &str) -> usize {
+    text.chars()
+        .map(|c| {
+            if c == '\t' {
+                INDENT
+            } else {
+                c.width().unwrap_or(0)
+            }
+        })
+        .sum()
+}
+
+// cut `text` down to at most `width` columns
+fn truncate(text: &str, width: usize) -> String {
+    let mut used = 0;
+    text.chars()
+        .take_while(|c| {
+            used += width_of(&c.to_string());
+            used <= width
+        })
+        .collect()
+}
+
+fn common_prefix(words: &[String]) -> String {
+    let mut prefix = words[0].as_str();
+    for word in &words[1..] {
+        let len = prefix
+            .char_indices()
+            .zip(word.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(prefix.len().min(word.len()), |((i, _), _)| i);
+        prefix = &prefix[..len];
+    }
+    prefix.to_string()
+}
+
+// vi's word classes: blanks, identifier characters and punctuation
+fn class(c: char) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+fn chars_of(text: &str, offset: usize) -> (Vec<(usize, char)>, usize) {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let k = chars
+        .iter()
+        .position(|&(i, _)| i >= offset)
+        .unwrap_or(chars.len());
+    (chars, k)
+}
+
+fn byte_at(text: &str, chars: &[(usize, char)], k: usize) -> usize {
+    chars.get(k).map_or(text.len(), |&(i, _)| i)
+}
+
+// vi's w: the start of the next word
+fn word_start_after(text: &str, offset: usize) -> usize {
+    let (chars, mut k) = chars_of(text, offset);
+    if k < chars.len() {
+        let current = class(chars[k].1);
+        while current != 0 && k < chars.len() && class(chars[k].1) == current {
+            k += 1;
+        }
+    }
+    while k < chars.len() && class(chars[k].1) == 0 {
+        k += 1;
+    }
+    byte_at(text, &chars, k)
+}
+
+// vi's b: the start of this or the previous word
+fn word_start_before(text: &str, offset: usize) -> usize {
+    let (chars, mut k) = chars_of(text, offset);
+    while k > 0 && class(chars[k - 1].1) == 0 {
+        k -= 1;
+    }
+    if k > 0 {
+        let current = class(chars[k - 1].1);
+        while k > 0 && class(chars[k - 1].1) == current {
+            k -= 1;
+        }
+    }
+    byte_at(text, &chars, k)
+}
+
+// vi's e: the last character of this or the next word
+fn word_end(text: &str, offset: usize) -> usize {
+    let (chars, mut k) = chars_of(text, offset);
+    k += 1;
+    while k < chars.len() && class(chars[k].1) == 0 {
+        k += 1;
+    }
+    if k >= chars.len() {
+        return byte_at(text, &chars, chars.len().saturating_sub(1));
+    }
+    let current = class(chars[k].1);
+    while k + 1 < chars.len() && class(chars[k + 1].1) == current {
+        k += 1;
+    }
+    byte_at(text, &chars, k)
+}
+
+// emacs' M-f: past the end of the next word
+fn word_forward(text: &str, offset: usize) -> usize {
+    let (chars, mut k) = chars_of(text, offset);
+    while k < chars.len() && class(chars[k].1) != 1 {
+        k += 1;
+    }
+    while k < chars.len() && class(chars[k].1) == 1 {
+        k += 1;
+    }
+    byte_at(text, &chars, k)
+}
+
+// emacs' M-b: the start of this or the previous word
+fn word_back(text: &str, offset: usize) -> usize {
+    let (chars, mut k) = chars_of(text, offset);
+    while k > 0 && class(chars[k - 1].1) != 1 {
+        k -= 1;
+    }
+    while k > 0 && class(chars[k - 1].1) == 1 {
+        k -= 1;
+    }
+    byte_at(text, &chars, k)
+}
+
+fn leading_spaces(line: &str) -> usize {
+    width_of(&line[..line.len() - line.trim_start().len()])
+}
+
+// What a scan of Python-ish source has left open at its end.
+struct Scan {
+    // indentation of the lines that opened the still-unclosed brackets
+    brackets: Vec<usize>,
+    in_string: bool,
+    // indentation of the line that started the last logical line
+    statement_indent: usize,
+    // the last logical line without its comment
+    last_line: String,
+    // whether a logical line before the last one opened a block
+    in_block: bool,
+}
+
+fn scan(text: &str) -> Scan {
+    let mut brackets = Vec::new();
+    let mut quote: Option<(char, bool)> = None;
+    let mut statement_indent = 0;
+    let mut last_line = String::new();
+    let mut in_block = false;
+
+    for line in text.split('\n') {
+        if brackets.is_empty() && quote.is_none() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            in_block |= last_line.trim().ends_with(':');
+            statement_indent = leading_spaces(line);
+            last_line.clear();
+        }
+
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            match quote {
+                Some((q, triple)) => {
+                    if c == '\\' {
+                        i += 1;
+                    } else if c == q {
+                        if !triple {
+                            quote = None;
+                        } else if chars[i..].starts_with(&[q, q, q]) {
+                            quote = None;
+                            i += 2;
+                        }
+                    }
+                }
+                None => match c {
+                    '#' => break,
+                    '\'' | '"' => {
+                        let triple = chars[i..].starts_with(&[c, c, c]);
+                        quote = Some((c, triple));
+                        if triple {
+                            i += 2;
+                        }
+                    }
+                    '(' | '[' | '{' => brackets.push(leading_spaces(line)),
+                    ')' | ']' | '}' => {
+                        brackets.pop();
+                    }
+                    _ => {}
+                },
+            }
+            last_line.push(c);
+            i += 1;
+        }
+
+        // single-quoted strings don't survive the end of a line
+        if let Some((_, false)) = quote {
+            quote = None;
+        }
+        last_line.push('\n');
+    }
+
+    Scan {
+        brackets,
+        in_string: quote.is_some(),
+        statement_indent,
+        last_line: last_line.trim().to_string(),
+        in_block,
+    }
+}
+
+// Indentation for a new line following `text`, used when the kernel
+// doesn't volunteer one.
+fn infer_indent(text: &str) -> usize {
+    let scan = scan(text);
+
+    if scan.in_string {
+        return text.rsplit('\n').next().map_or(0, leading_spaces);
+    }
+    if let Some(indent) = scan.brackets.last() {
+        return indent + INDENT;
+    }
+    if scan.last_line.ends_with(':') {
+        return scan.statement_indent + INDENT;
+    }
+
+    let first_word = scan.last_line.split_whitespace().next().unwrap_or("");
+    match first_word {
+        "return" | "pass" | "break" | "continue" | "raise" => {
+            scan.statement_indent.saturating_sub(INDENT)
+        }
+        _ => scan.statement_indent,
+    }
+}
+
+// Fallback for kernels that can't tell us whether a cell is complete.
+fn is_complete(text: &str) -> bool {
+    let scan = scan(text);
+
+    if scan.in_string || !scan.brackets.is_empty() {
+        return false;
+    }
+    if scan.last_line.ends_with(':') || scan.last_line.ends_with('\\') {
+        return false;
+    }
+
+    // an indented block is only finished by a blank line, lines that only
+    // continue a bracket don't start one
+    !scan.in_block
+        || text
+            .rsplit('\n')
+            .next()
+            .is_none_or(|line| line.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a kernel that answers is_complete with `reply`, or not at all
+    pub(super) struct Kernel(pub(super) Option<Value>);
+
+    impl Helper for Kernel {
+        fn is_complete(&self, _code: &str) -> Option<Map<String, Value>> {
+            self.0.as_ref().and_then(|reply| reply.as_object().cloned())
+        }
+
+        fn complete(&self, _code: &str, _cursor_pos: usize) -> Option<Map<String, Value>> {
+            None
+        }
+
+        fn inspect(&self, _code: &str, _cursor_pos: usize) -> Option<Map<String, Value>> {
+            None
+        }
+    }
+
+    pub(super) fn press(
+        editor: &mut Editor,
+        helper: &dyn Helper,
+        code: KeyCode,
+    ) -> Option<Outcome> {
+        editor.handle_key(KeyEvent::new(code, KeyModifiers::NONE), helper)
+    }
+
+    // type `text` a key at a time, Enter for each newline
+    fn type_in(editor: &mut Editor, helper: &dyn Helper, text: &str) -> Option<Outcome> {
+        let mut outcome = None;
+        for c in text.chars() {
+            let code = if c == '\n' {
+                KeyCode::Enter
+            } else {
+                KeyCode::Char(c)
+            };
+            outcome = press(editor, helper, code);
+        }
+        outcome
+    }
+
+    #[test]
+    fn indents_after_a_colon() {
+        assert_eq!(infer_indent("def f():"), 4);
+        assert_eq!(infer_indent("def f():\n    if x:"), 8);
+        assert_eq!(infer_indent("def f():\n    x = 1"), 4);
+        assert_eq!(infer_indent("for x in y:  # a comment"), 4);
+    }
+
+    #[test]
+    fn dedents_after_a_block_ends() {
+        assert_eq!(infer_indent("def f():\n    if x:\n        return 1"), 4);
+        assert_eq!(infer_indent("while True:\n    break"), 0);
+    }
+
+    #[test]
+    fn indents_inside_brackets() {
+        assert_eq!(infer_indent("x = f(1,"), 4);
+        assert_eq!(infer_indent("def f():\n    x = [1,"), 8);
+        assert_eq!(infer_indent("x = {\n    'a': [1,"), 8);
+        // closed again, back to the statement
+        assert_eq!(infer_indent("def f():\n    x = (1,\n        2)"), 4);
+    }
+
+    #[test]
+    fn brackets_and_quotes_in_strings_and_comments_dont_count() {
+        assert_eq!(infer_indent("x = '('"), 0);
+        assert_eq!(infer_indent("x = 1  # (:"), 0);
+        assert!(is_complete("x = ')'"));
+        assert!(!is_complete("x = \"\"\"open"));
+        // a triple-quoted string keeps the indentation it's at
+        assert_eq!(infer_indent("s = \"\"\"\n  text"), 2);
+    }
+
+    #[test]
+    fn completeness_of_nested_blocks_and_brackets() {
+        assert!(is_complete("x = 1"));
+        assert!(!is_complete("def f():"));
+        assert!(!is_complete("def f():\n    if x:\n        return 1"));
+        assert!(is_complete("def f():\n    if x:\n        return 1\n"));
+        assert!(!is_complete("x = (1,\n2"));
+        assert!(is_complete("x = (1,\n2)"));
+        assert!(!is_complete("x = 1 + \\"));
+    }
+
+    #[test]
+    fn enter_indents_nested_blocks() {
+        let kernel = Kernel(None);
+        let mut editor = Editor::new();
+        assert!(type_in(&mut editor, &kernel, "def f():\nif x:\nreturn 1\n").is_none());
+        assert_eq!(editor.buffer, "def f():\n    if x:\n        return 1\n    ");
+        // a blank line ends the block
+        editor.buffer = "def f():\n    return 1\n".to_string();
+        editor.cursor = editor.buffer.len();
+        assert!(matches!(
+            press(&mut editor, &kernel, KeyCode::Enter),
+            Some(Outcome::Accept)
+        ));
+    }
+
+    #[test]
+    fn enter_indents_brackets_across_lines() {
+        let kernel = Kernel(None);
+        let mut editor = Editor::new();
+        type_in(&mut editor, &kernel, "x = [\n1,\n");
+        assert_eq!(editor.buffer, "x = [\n    1,\n    ");
+        assert!(matches!(
+            type_in(&mut editor, &kernel, "]\n"),
+            Some(Outcome::Accept)
+        ));
+        assert_eq!(editor.buffer, "x = [\n    1,\n    ]");
+    }
+
+    #[test]
+    fn the_kernels_indent_wins() {
+        let kernel = Kernel(Some(
+            serde_json::json!({"status": "incomplete", "indent": "  "}),
+        ));
+        let mut editor = Editor::new();
+        type_in(&mut editor, &kernel, "def f():\n");
+        assert_eq!(editor.buffer, "def f():\n  ");
+
+        let kernel = Kernel(Some(serde_json::json!({"status": "complete"})));
+        let mut editor = Editor::new();
+        assert!(matches!(
+            type_in(&mut editor, &kernel, "def f():\n"),
+            Some(Outcome::Accept)
+        ));
+    }
+
+    #[test]
+    fn backspace_dedents_one_level() {
+        let kernel = Kernel(None);
+        let mut editor = Editor::new();
+        type_in(&mut editor, &kernel, "def f():\nif x:\n");
+        press(&mut editor, &kernel, KeyCode::Backspace);
+        assert_eq!(editor.buffer, "def f():\n    if x:\n    ");
+        press(&mut editor, &kernel, KeyCode::Backspace);
+        assert_eq!(editor.buffer, "def f():\n    if x:\n");
+        // the first line is never dedented, just edited
+        let mut editor = Editor::new();
+        type_in(&mut editor, &kernel, "      ");
+        press(&mut editor, &kernel, KeyCode::Backspace);
+        assert_eq!(editor.buffer, "     ");
+    }
+
+    #[test]
+    fn pasted_text_is_not_reindented() {
+        let mut editor = Editor::new();
+        editor.paste("def f():\r\n  if x:\r\n      return 1\rprint(f())");
+        assert_eq!(
+            editor.buffer,
+            "def f():\n  if x:\n      return 1\nprint(f())"
+        );
+        assert_eq!(editor.cursor, editor.buffer.len());
+    }
+}
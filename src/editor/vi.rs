@@ -0,0 +1,352 @@
+use super::{
+    infer_indent, leading_spaces, word_end, word_start_after, word_start_before, Editor, Outcome,
+};
+use crate::editor::Helper;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+impl Editor {
+    // vi normal mode, the cursor sits on a character rather than between two
+    pub(super) fn vi_command(&mut self, key: KeyEvent, helper: &dyn Helper) -> Option<Outcome> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+        let c = match key.code {
+            KeyCode::Char('c') if ctrl => return Some(Outcome::Interrupt),
+            KeyCode::Char('l') if ctrl => {
+                self.clear = true;
+                return None;
+            }
+            KeyCode::Char('d') if ctrl && self.buffer.is_empty() => return Some(Outcome::Eof),
+            KeyCode::Enter => return self.enter(helper),
+            KeyCode::Up => {
+                self.move_up();
+                self.vi_clamp();
+                return None;
+            }
+            KeyCode::Down => {
+                self.move_down();
+                self.vi_clamp();
+                return None;
+            }
+            KeyCode::Char(_) if ctrl => return None,
+            KeyCode::Char(c) => c,
+            KeyCode::Left | KeyCode::Backspace => 'h',
+            KeyCode::Right => 'l',
+            KeyCode::Home => '0',
+            KeyCode::End => '$',
+            KeyCode::Delete => 'x',
+            _ => return None,
+        };
+
+        if let Some(operator) = self.pending.take() {
+            self.vi_operator(operator, c);
+            self.vi_clamp();
+            return None;
+        }
+
+        match c {
+            'h' if self.cursor > self.line_start() => self.cursor = self.prev_boundary(),
+            'l' if self.next_boundary() < self.line_end() => self.cursor = self.next_boundary(),
+            'j' => self.vi_line(false),
+            'k' => self.vi_line(true),
+            '0' => self.cursor = self.line_start(),
+            '^' => self.cursor = self.first_non_blank(),
+            '$' => self.cursor = self.line_end(),
+            'w' => self.cursor = word_start_after(&self.buffer, self.cursor),
+            'b' => self.cursor = word_start_before(&self.buffer, self.cursor),
+            'e' => self.cursor = word_end(&self.buffer, self.cursor),
+            'G' => self.cursor = self.buffer.rfind('\n').map_or(0, |i| i + 1),
+            'x' => {
+                let end = self.next_boundary().min(self.line_end());
+                self.cut(self.cursor, end, false);
+            }
+            'X' if self.cursor > self.line_start() => {
+                let start = self.prev_boundary();
+                self.cut(start, self.cursor, false);
+            }
+            'i' => self.vi_insert(),
+            'a' => {
+                self.cursor = self.next_boundary().min(self.line_end());
+                self.vi_insert();
+            }
+            'I' => {
+                self.cursor = self.first_non_blank();
+                self.vi_insert();
+            }
+            'A' => {
+                self.cursor = self.line_end();
+                self.vi_insert();
+            }
+            'o' => {
+                self.vi_insert();
+                self.cursor = self.line_end();
+                let indent = infer_indent(&self.buffer[..self.cursor]);
+                self.insert(&format!("\n{}", " ".repeat(indent)));
+            }
+            'O' => {
+                self.vi_insert();
+                let start = self.line_start();
+                let indent = " ".repeat(leading_spaces(&self.buffer[start..self.line_end()]));
+                self.cursor = start;
+                self.insert(&format!("{}\n", indent));
+                self.cursor = start + indent.len();
+            }
+            'D' => self.cut(self.cursor, self.line_end(), false),
+            'C' => {
+                self.cut(self.cursor, self.line_end(), false);
+                self.vi_insert();
+            }
+            'S' => {
+                self.cut(self.line_start(), self.line_end(), true);
+                self.vi_insert();
+            }
+            'p' | 'P' => self.vi_put(c == 'p'),
+            'u' => self.undo(),
+            'd' | 'c' | 'y' | 'r' | 'g' => self.pending = Some(c),
+            _ => {}
+        }
+
+        self.vi_clamp();
+        None
+    }
+
+    fn vi_operator(&mut self, operator: char, motion: char) {
+        let (start, end) = (self.line_start(), self.line_end());
+
+        match (operator, motion) {
+            ('g', 'g') => self.cursor = 0,
+            ('r', c) if self.cursor < end => {
+                let next = self.next_boundary();
+                self.snapshot();
+                self.buffer.replace_range(self.cursor..next, &c.to_string());
+            }
+            ('d', 'd') => {
+                self.snapshot();
+                self.kill = self.buffer[start..end].to_string();
+                self.kill_lines = true;
+                // take one of the line's newlines with it
+                if end < self.buffer.len() {
+                    self.buffer.drain(start..end + 1);
+                } else if start > 0 {
+                    self.buffer.drain(start - 1..end);
+                } else {
+                    self.buffer.clear();
+                }
+                self.cursor = start.min(self.buffer.len());
+                self.cursor = self.line_start();
+            }
+            ('c', 'c') => {
+                self.cut(start, end, true);
+                self.vi_insert();
+            }
+            ('y', 'y') => {
+                self.kill = self.buffer[start..end].to_string();
+                self.kill_lines = true;
+            }
+            ('d', _) | ('c', _) | ('y', _) => {
+                // vim's cw changes to the end of the word, like ce
+                let motion = if operator == 'c' && motion == 'w' {
+                    'e'
+                } else {
+                    motion
+                };
+                let (from, to) = match self.motion_range(motion) {
+                    Some(range) => range,
+                    None => return,
+                };
+
+                match operator {
+                    'y' => {
+                        self.kill = self.buffer[from..to].to_string();
+                        self.kill_lines = false;
+                    }
+                    'd' => self.cut(from, to, false),
+                    _ => {
+                        self.cut(from, to, false);
+                        self.vi_insert();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // the part of the buffer an operator applies to for `motion`
+    fn motion_range(&self, motion: char) -> Option<(usize, usize)> {
+        let end_of = |offset: usize| {
+            self.buffer[offset..]
+                .chars()
+                .next()
+                .map_or(offset, |c| offset + c.len_utf8())
+        };
+
+        let target = match motion {
+            'w' => word_start_after(&self.buffer, self.cursor).min(self.line_end()),
+            'b' => word_start_before(&self.buffer, self.cursor),
+            'e' => end_of(word_end(&self.buffer, self.cursor)),
+            '$' => self.line_end(),
+            '0' => self.line_start(),
+            '^' => self.first_non_blank(),
+            'h' => {
+                if self.cursor == self.line_start() {
+                    return None;
+                }
+                self.prev_boundary()
+            }
+            'l' | ' ' => self.next_boundary().min(self.line_end()),
+            _ => return None,
+        };
+
+        Some((self.cursor.min(target), self.cursor.max(target)))
+    }
+
+    // j and k stay inside a multi-line cell, only a single line scrolls history
+    fn vi_line(&mut self, up: bool) {
+        let single_line = !self.buffer.contains('\n');
+        if up && (single_line || self.line_start() > 0) {
+            self.move_up();
+        } else if !up && (single_line || self.line_end() < self.buffer.len()) {
+            self.move_down();
+        }
+    }
+
+    fn vi_insert(&mut self) {
+        self.snapshot();
+        self.vi_normal = false;
+    }
+
+    fn vi_put(&mut self, after: bool) {
+        if self.kill.is_empty() {
+            return;
+        }
+        self.snapshot();
+
+        let kill = self.kill.clone();
+        if self.kill_lines {
+            if after {
+                self.cursor = self.line_end();
+                self.insert(&format!("\n{}", kill));
+                self.cursor = self.line_start();
+            } else {
+                self.cursor = self.line_start();
+                let start = self.cursor;
+                self.insert(&format!("{}\n", kill));
+                self.cursor = start;
+            }
+        } else {
+            if after {
+                self.cursor = self.next_boundary().min(self.line_end());
+            }
+            self.insert(&kill);
+            self.cursor = self.prev_boundary();
+        }
+    }
+
+    // keep the cursor on a character, it can't rest after the last one
+    fn vi_clamp(&mut self) {
+        if self.vi_normal && self.cursor == self.line_end() && self.cursor > self.line_start() {
+            self.cursor = self.prev_boundary();
+        }
+    }
+
+    fn first_non_blank(&self) -> usize {
+        let start = self.line_start();
+        let line = &self.buffer[start..self.line_end()];
+        start + (line.len() - line.trim_start().len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{press, Kernel};
+    use super::*;
+
+    // a vi editor in normal mode on `buffer`, at the start of line `line`
+    fn normal(buffer: &str, line: usize) -> Editor {
+        let mut editor = Editor::new();
+        editor.set_editing_mode(super::super::EditingMode::Vi);
+        editor.vi_normal = true;
+        editor.buffer = buffer.to_string();
+        editor.cursor = buffer.split('\n').take(line).map(|l| l.len() + 1).sum();
+        editor
+    }
+
+    fn keys(editor: &mut Editor, keys: &str) {
+        for c in keys.chars() {
+            press(editor, &Kernel(None), KeyCode::Char(c));
+        }
+    }
+
+    fn line_of(editor: &Editor) -> usize {
+        editor.buffer[..editor.cursor].matches('\n').count()
+    }
+
+    #[test]
+    fn j_and_k_move_within_a_multi_line_cell() {
+        let mut editor = normal("def f():\n    x = 1\n    return x", 0);
+        editor.set_history(vec!["older".to_string()]);
+        keys(&mut editor, "j");
+        assert_eq!(line_of(&editor), 1);
+        keys(&mut editor, "j");
+        assert_eq!(line_of(&editor), 2);
+        // the last line of the cell, not the history after it
+        keys(&mut editor, "j");
+        assert_eq!(line_of(&editor), 2);
+        keys(&mut editor, "kkk");
+        assert_eq!(line_of(&editor), 0);
+        assert_eq!(editor.buffer, "def f():\n    x = 1\n    return x");
+    }
+
+    #[test]
+    fn k_on_a_single_line_goes_through_history() {
+        let mut editor = normal("x", 0);
+        editor.set_history(vec!["older".to_string()]);
+        keys(&mut editor, "k");
+        assert_eq!(editor.buffer, "older");
+        keys(&mut editor, "j");
+        assert_eq!(editor.buffer, "x");
+    }
+
+    #[test]
+    fn j_keeps_the_column_and_stays_on_a_character() {
+        let mut editor = normal("abcdef\nab\nabcdef", 0);
+        keys(&mut editor, "$");
+        assert_eq!(editor.cursor, 5);
+        keys(&mut editor, "j");
+        // the b, not past the end of the short line
+        assert_eq!(editor.cursor, 8);
+        keys(&mut editor, "j");
+        assert_eq!(line_of(&editor), 2);
+    }
+
+    #[test]
+    fn line_commands_work_on_the_cursors_line() {
+        let mut editor = normal("a = 1\nb = 2\nc = 3", 1);
+        keys(&mut editor, "dd");
+        assert_eq!(editor.buffer, "a = 1\nc = 3");
+        assert_eq!(line_of(&editor), 1);
+        keys(&mut editor, "P");
+        assert_eq!(editor.buffer, "a = 1\nb = 2\nc = 3");
+        keys(&mut editor, "ggG");
+        assert_eq!(line_of(&editor), 2);
+        keys(&mut editor, "u");
+        assert_eq!(editor.buffer, "a = 1\nc = 3");
+    }
+
+    #[test]
+    fn o_opens_an_indented_line() {
+        let mut editor = normal("def f():", 0);
+        keys(&mut editor, "o");
+        assert!(!editor.vi_normal);
+        assert_eq!(editor.buffer, "def f():\n    ");
+    }
+
+    #[test]
+    fn the_prompt_shows_normal_mode() {
+        let mut editor = normal("x", 0);
+        assert_eq!(editor.indicator(), "[nav] ");
+        keys(&mut editor, "i");
+        assert_eq!(editor.indicator(), "[ins] ");
+        press(&mut editor, &Kernel(None), KeyCode::Esc);
+        assert!(editor.vi_normal);
+    }
+}
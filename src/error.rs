@@ -0,0 +1,128 @@
+use crate::client::TimeoutError;
+use crate::socket_options;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Zmq(zmq::Error),
+    Io(io::Error),
+    Json(serde_json::Error),
+    // a saved state file that couldn't be written or read back
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+    // nothing answered in time, names the reply we were waiting for
+    Timeout(&'static str),
+    // a cell collected with a timeout was interrupted, what it sent is in there
+    CellTimeout(TimeoutError),
+    // kill_kernel on a kernel jupyterm didn't start
+    NoProcess,
+    // the ssh tunnel to a remote kernel couldn't be set up, with ssh's reason
+    Ssh(String),
+    // a request to a Jupyter Server's REST API failed
+    Http(ureq::Error),
+    // the kernel's WebSocket on a Jupyter Server
+    WebSocket(tungstenite::Error),
+    // watching a connection file for changes
+    Watch(notify::Error),
+    // every client a ConnectionPool may open is in use, with its max_size
+    PoolExhausted(usize),
+    // a debug_request the kernel's debugger turned down, with its message
+    Debugger(String),
+    // a notebook in a version other than nbformat 4, if it says
+    Nbformat(Option<u64>),
+    // code jupyterm ran in the kernel itself raised, with "ename: evalue"
+    Kernel(String),
+    // set_socket_option for a channel clients don't have
+    Channel(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Zmq(err) => write!(f, "zmq error: {}", err),
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Json(err) => write!(f, "invalid JSON: {}", err),
+            Error::Encode(err) => write!(f, "couldn't encode state: {}", err),
+            Error::Decode(err) => write!(f, "invalid state file: {}", err),
+            Error::Timeout(reply) => write!(f, "timed out waiting for {}", reply),
+            Error::CellTimeout(_) => write!(f, "the cell timed out and was interrupted"),
+            Error::NoProcess => write!(f, "no kernel process to kill"),
+            Error::Ssh(reason) => write!(f, "ssh: {}", reason),
+            Error::Http(err) => write!(f, "jupyter server: {}", err),
+            Error::WebSocket(err) => write!(f, "kernel websocket: {}", err),
+            Error::Watch(err) => write!(f, "couldn't watch the connection file: {}", err),
+            Error::PoolExhausted(max_size) => {
+                write!(f, "all {} connections in the pool are in use", max_size)
+            }
+            Error::Debugger(message) => write!(f, "debugger: {}", message),
+            Error::Nbformat(Some(version)) => {
+                write!(f, "nbformat {} notebooks aren't supported, only 4", version)
+            }
+            Error::Nbformat(None) => write!(f, "not a notebook, it has no nbformat"),
+            Error::Kernel(error) => write!(f, "the kernel raised {}", error),
+            Error::Channel(name) => write!(
+                f,
+                "no {} channel, only {}",
+                name,
+                socket_options::CHANNELS.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<zmq::Error> for Error {
+    fn from(err: zmq::Error) -> Error {
+        Error::Zmq(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::Json(err)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for Error {
+    fn from(err: rmp_serde::encode::Error) -> Error {
+        Error::Encode(err)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for Error {
+    fn from(err: rmp_serde::decode::Error) -> Error {
+        Error::Decode(err)
+    }
+}
+
+impl From<ureq::Error> for Error {
+    fn from(err: ureq::Error) -> Error {
+        Error::Http(err)
+    }
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(err: tungstenite::Error) -> Error {
+        Error::WebSocket(err)
+    }
+}
+
+impl From<TimeoutError> for Error {
+    fn from(err: TimeoutError) -> Error {
+        Error::CellTimeout(err)
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(err: notify::Error) -> Error {
+        Error::Watch(err)
+    }
+}
@@ -0,0 +1,101 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+// An iopub message, picked apart by type. As JSON the kind's fields sit
+// next to msg_type, like {"msg_type": "stream", "name": "stdout", ...}.
+#[derive(Clone, Debug, Serialize)]
+pub struct OutputEvent {
+    pub msg_type: String,
+    // msg_id of the request that caused the output, if the kernel said
+    pub parent_msg_id: Option<String>,
+    #[serde(flatten)]
+    pub kind: OutputKind,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum OutputKind {
+    Status {
+        execution_state: String,
+    },
+    ExecuteInput {
+        code: String,
+        execution_count: Option<i64>,
+    },
+    Stream {
+        name: String,
+        text: String,
+    },
+    ExecuteResult {
+        execution_count: Option<i64>,
+        data: Map<String, Value>,
+        metadata: Map<String, Value>,
+    },
+    DisplayData {
+        data: Map<String, Value>,
+        metadata: Map<String, Value>,
+    },
+    Error {
+        ename: String,
+        evalue: String,
+        traceback: Vec<String>,
+    },
+    Other {
+        content: Map<String, Value>,
+    },
+}
+
+fn string(value: &Value) -> String {
+    value.as_str().unwrap_or("").to_string()
+}
+
+fn object(value: &Value) -> Map<String, Value> {
+    value.as_object().cloned().unwrap_or_default()
+}
+
+impl OutputEvent {
+    pub fn from_msg(msg: &Map<String, Value>) -> OutputEvent {
+        let msg_type = string(&msg["header"]["msg_type"]);
+        let content = &msg["content"];
+
+        let kind = match msg_type.as_str() {
+            "status" => OutputKind::Status {
+                execution_state: string(&content["execution_state"]),
+            },
+            "execute_input" => OutputKind::ExecuteInput {
+                code: string(&content["code"]),
+                execution_count: content["execution_count"].as_i64(),
+            },
+            "stream" => OutputKind::Stream {
+                name: string(&content["name"]),
+                text: string(&content["text"]),
+            },
+            "execute_result" => OutputKind::ExecuteResult {
+                execution_count: content["execution_count"].as_i64(),
+                data: object(&content["data"]),
+                metadata: object(&content["metadata"]),
+            },
+            "display_data" | "update_display_data" => OutputKind::DisplayData {
+                data: object(&content["data"]),
+                metadata: object(&content["metadata"]),
+            },
+            "error" => OutputKind::Error {
+                ename: string(&content["ename"]),
+                evalue: string(&content["evalue"]),
+                traceback: content["traceback"]
+                    .as_array()
+                    .map(|lines| lines.iter().map(string).collect())
+                    .unwrap_or_default(),
+            },
+            _ => OutputKind::Other {
+                content: object(content),
+            },
+        };
+
+        OutputEvent {
+            msg_type,
+            parent_msg_id: msg["parent_header"]["msg_id"].as_str().map(String::from),
+            kind,
+        }
+    }
+}
@@ -0,0 +1,727 @@
+use crate::client::{Cutypr, ExecuteOptions, ReadySocket};
+use crate::editor::Helper;
+use crate::error::Error;
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+use std::io;
+
+// What a kernel says it's doing, from its status messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KernelState {
+    Starting,
+    Busy,
+    Idle,
+}
+
+impl KernelState {
+    pub fn parse(state: &str) -> Option<KernelState> {
+        match state {
+            "starting" => Some(KernelState::Starting),
+            "busy" => Some(KernelState::Busy),
+            "idle" => Some(KernelState::Idle),
+            _ => None,
+        }
+    }
+}
+
+// What run_repl runs cells on: a client, and the In number for the next
+// cell, which run_cell moves on from the kernel's replies. The REPL's
+// sessions are one, and Counted is one for a lone client.
+pub trait ReplSession {
+    fn client(&mut self) -> &mut Cutypr;
+    fn execution_count(&self) -> i64;
+    fn set_execution_count(&mut self, execution_count: i64);
+}
+
+// A client on its own, counting its cells from 1.
+pub struct Counted {
+    pub client: Cutypr,
+    pub execution_count: i64,
+}
+
+impl Counted {
+    pub fn new(client: Cutypr) -> Counted {
+        Counted {
+            client,
+            execution_count: 1,
+        }
+    }
+}
+
+impl ReplSession for Counted {
+    fn client(&mut self) -> &mut Cutypr {
+        &mut self.client
+    }
+
+    fn execution_count(&self) -> i64 {
+        self.execution_count
+    }
+
+    fn set_execution_count(&mut self, execution_count: i64) {
+        self.execution_count = execution_count;
+    }
+}
+
+// What the frontend says to do with a cell it read.
+pub enum Prepared {
+    // run this as the cell, maybe not what was typed
+    Run(String),
+    // it's been dealt with, go on to the next prompt
+    Done,
+    // stop run_repl, the frontend knows why
+    Stop,
+}
+
+// Where cells come from and their outputs go, apart from how they get to
+// and from the kernel. run_repl drives one on an S, the terminal's is in
+// the binary, and a TUI or a test can bring its own.
+//
+// The methods after set_state are hooks into run_repl and run_cell for
+// what a REPL does around the cells, like commands and keeping a record
+// of them. They're given the session, and do nothing unless a frontend
+// says otherwise.
+pub trait Frontend<S: ReplSession = Counted> {
+    // The next cell to run, with `helper` to complete and inspect against
+    // the kernel. None once there are no more, like at Ctrl-D.
+    fn read_cell(&mut self, prompt: &str, helper: &dyn Helper) -> io::Result<Option<String>>;
+
+    // An answer for input() in the kernel, without echoing it for
+    // passwords. None to give up on it, which interrupts the cell.
+    fn read_input(&mut self, prompt: &str, password: bool) -> io::Result<Option<String>>;
+
+    // `name` is stdout or stderr
+    fn show_stream(&mut self, name: &str, text: &str);
+
+    // an execute_result's bundle, by mime type
+    fn show_result(&mut self, execution_count: Option<i64>, data: &Value);
+
+    // a display_data or update_display_data bundle
+    fn show_display(&mut self, data: &Value);
+
+    // the traceback's entries, with the ANSI colors kernels put in
+    fn show_error(&mut self, traceback: &[String]);
+
+    // clear_output, with `wait` until the next output comes
+    fn clear_output(&mut self, wait: bool);
+
+    fn set_state(&mut self, state: KernelState);
+
+    // about the cell rather than from it, like how many input() prompts
+    // are waiting
+    fn show_notice(&mut self, _text: &str) {}
+
+    // something that went wrong running the cell, like an interrupt that
+    // didn't get to the kernel
+    fn show_warning(&mut self, _text: &str) {}
+
+    // before a cell's first output, with the In number it runs as
+    fn start_cell(&mut self, _execution_count: i64) {}
+
+    // whether the user asked to stop the running cell since the last time
+    fn interrupted(&mut self) -> bool {
+        false
+    }
+
+    // before each prompt, false stops run_repl, like for a kernel that's gone
+    fn before_prompt(&mut self, _session: &mut S) -> bool {
+        true
+    }
+
+    fn prompt(&mut self, session: &mut S) -> String {
+        format!("In [{}]: ", session.execution_count())
+    }
+
+    // a cell that was read, before it runs, like to run it as a command
+    fn prepare(&mut self, _session: &mut S, code: String) -> Prepared {
+        Prepared::Run(code)
+    }
+
+    fn execute_options(&mut self, _session: &mut S, _code: &str) -> ExecuteOptions {
+        ExecuteOptions::default()
+    }
+
+    // each time round while a cell runs, false stops waiting for it
+    fn waiting(&mut self, _session: &mut S) -> bool {
+        true
+    }
+
+    // Each iopub message while a cell runs, `ours` for the cell's own,
+    // whether to show it. The others, like comms and debug events, only
+    // come here.
+    fn message(&mut self, _session: &mut S, _msg: &Map<String, Value>, ours: bool) -> bool {
+        ours
+    }
+
+    // the cell's execute_reply
+    fn reply(&mut self, _session: &mut S, _reply: &Map<String, Value>) {}
+
+    fn end_cell(&mut self, _session: &mut S, _code: &str, _reply: &CellReply) {}
+}
+
+// The entries of an error's traceback, or "ename: evalue" from kernels
+// that leave it empty.
+pub fn traceback(content: &Value) -> Vec<String> {
+    let traceback: Vec<String> = content["traceback"]
+        .as_array()
+        .map(|entries| {
+            entries
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    if !traceback.is_empty() {
+        return traceback;
+    }
+    let ename = content["ename"].as_str().unwrap_or("error");
+    let evalue = content["evalue"].as_str().unwrap_or("");
+    vec![format!("{}: {}", ename, evalue)]
+}
+
+// Hand an iopub message to the frontend method for its type, true for
+// errors. Types a frontend has no method for, like comms, are left to
+// whoever's reading the messages.
+pub fn show<S: ReplSession, F: Frontend<S> + ?Sized>(
+    frontend: &mut F,
+    msg: &Map<String, Value>,
+) -> bool {
+    let content = &msg["content"];
+    match msg["header"]["msg_type"].as_str().unwrap_or_default() {
+        "status" => {
+            if let Some(state) = content["execution_state"]
+                .as_str()
+                .and_then(KernelState::parse)
+            {
+                frontend.set_state(state);
+            }
+        }
+        "stream" => frontend.show_stream(
+            content["name"].as_str().unwrap_or_default(),
+            content["text"].as_str().unwrap_or_default(),
+        ),
+        "execute_result" => {
+            frontend.show_result(content["execution_count"].as_i64(), &content["data"])
+        }
+        "display_data" | "update_display_data" => frontend.show_display(&content["data"]),
+        "error" => {
+            frontend.show_error(&traceback(content));
+            return true;
+        }
+        "clear_output" => frontend.clear_output(content["wait"].as_bool().unwrap_or(false)),
+        _ => {}
+    }
+    false
+}
+
+// Ask the frontend for what an input_request wants and send it back. One
+// it gives up on interrupts the cell, and gets an empty answer so the
+// kernel isn't left waiting if that didn't stop it, an interrupt that
+// failed is still an error once it's been answered.
+pub fn answer_input<S: ReplSession, F: Frontend<S> + ?Sized>(
+    client: &Cutypr,
+    frontend: &mut F,
+    input_request: &Map<String, Value>,
+) -> Result<(), Error> {
+    let content = &input_request["content"];
+    let prompt = content["prompt"].as_str().unwrap_or_default();
+    match frontend.read_input(prompt, content["password"] == true)? {
+        Some(value) => client.input_reply(input_request, &value),
+        None => {
+            let interrupted = client.interrupt_kernel();
+            client.input_reply(input_request, "");
+            interrupted?;
+        }
+    }
+    Ok(())
+}
+
+// How a cell run by run_cell went.
+#[derive(Clone, Debug, Default)]
+pub struct CellReply {
+    // "ok", "error" or "aborted", from its execute_reply
+    pub status: Option<String>,
+    pub execution_count: Option<i64>,
+    // whether an error was shown, which a reply may not say
+    pub showed_error: bool,
+    // the frontend stopped waiting for it before it finished
+    pub abandoned: bool,
+}
+
+impl CellReply {
+    pub fn failed(&self) -> bool {
+        self.abandoned || self.showed_error || self.status.as_deref() == Some("error")
+    }
+}
+
+// Run `code` as the session's next cell, showing its outputs and
+// answering its input() through `frontend`, until the kernel is idle and
+// has replied. The session's count moves on from the kernel's reply.
+pub fn run_cell<S: ReplSession, F: Frontend<S> + ?Sized>(
+    session: &mut S,
+    frontend: &mut F,
+    code: &str,
+) -> Result<CellReply, Error> {
+    let options = frontend.execute_options(session, code);
+    let msg_id = session.client().execute_with(code, &options);
+    frontend.start_cell(session.execution_count());
+    let mut reply = CellReply::default();
+    // the execute_reply can come before or after the idle status
+    let mut idle = false;
+    let mut replied = false;
+    while !idle || !replied {
+        if frontend.interrupted() {
+            if let Err(err) = session.client().interrupt_kernel() {
+                frontend.show_warning(&format!("couldn't interrupt the kernel: {}", err));
+            }
+        }
+        if !frontend.waiting(session) {
+            reply.abandoned = true;
+            break;
+        }
+        for socket in session.client().poll_all(10)? {
+            match socket {
+                ReadySocket::Iopub => {
+                    let msg = match session.client().get_msg() {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    // or another request's, like the is_complete checks while
+                    // the cell was typed
+                    let ours = msg["parent_header"]["msg_id"] == msg_id.as_str();
+                    if ours && msg["header"]["msg_type"] == "status" {
+                        idle = msg["content"]["execution_state"] == "idle";
+                    }
+                    if frontend.message(session, &msg, ours) {
+                        reply.showed_error |= show(frontend, &msg);
+                    }
+                }
+                ReadySocket::Shell => {
+                    let msg = match session.client().get_shell_msg() {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    if msg["parent_header"]["msg_id"] == msg_id.as_str() {
+                        replied = true;
+                        reply.status = msg["content"]["status"].as_str().map(String::from);
+                        // The kernel's own count, which an error may or may
+                        // not have moved on, and which other clients of an
+                        // existing kernel move on too
+                        reply.execution_count = msg["content"]["execution_count"].as_i64();
+                        if let Some(count) = reply.execution_count {
+                            session.set_execution_count(count + 1);
+                        }
+                        frontend.reply(session, &msg);
+                    }
+                }
+                ReadySocket::Stdin => {
+                    // in order, each answered before the next is asked
+                    let mut pending: VecDeque<_> = session.client().get_input_requests().into();
+                    while let Some(input_request) = pending.pop_front() {
+                        if !pending.is_empty() {
+                            frontend.show_notice(&format!(
+                                "waiting for input, {} more after this",
+                                pending.len()
+                            ));
+                        }
+                        if let Err(err) = answer_input(session.client(), frontend, &input_request) {
+                            frontend.show_warning(&err.to_string());
+                        }
+                        pending.extend(session.client().get_input_requests());
+                    }
+                }
+                // we never ping, so there's nothing to read here yet
+                ReadySocket::Heartbeat => {}
+            }
+        }
+    }
+    frontend.end_cell(session, code, &reply);
+    Ok(reply)
+}
+
+// Read cells from `frontend` and run them one after the other on
+// `session` until it has no more or a hook stops it.
+pub fn run_repl<S: ReplSession, F: Frontend<S> + ?Sized>(
+    session: &mut S,
+    frontend: &mut F,
+) -> Result<(), Error> {
+    loop {
+        if !frontend.before_prompt(session) {
+            return Ok(());
+        }
+        let prompt = frontend.prompt(session);
+        let code = match frontend.read_cell(&prompt, &*session.client())? {
+            Some(code) => code,
+            None => return Ok(()),
+        };
+        if code.trim().is_empty() {
+            continue;
+        }
+        let code = match frontend.prepare(session, code) {
+            Prepared::Run(code) => code,
+            Prepared::Done => continue,
+            Prepared::Stop => return Ok(()),
+        };
+        run_cell(session, frontend, &code)?;
+    }
+}
+
+// Everything a Recorder was asked to show, in order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shown {
+    Prompt(String),
+    Input {
+        prompt: String,
+        password: bool,
+    },
+    Stream {
+        name: String,
+        text: String,
+    },
+    Result {
+        execution_count: Option<i64>,
+        data: Value,
+    },
+    Display(Value),
+    Error(Vec<String>),
+    Clear {
+        wait: bool,
+    },
+    State(KernelState),
+    Notice(String),
+    Warning(String),
+}
+
+// A Frontend with nobody at it: it types the cells and input() answers
+// it's given, in order, and keeps what it's shown. For running cells
+// headless and for checking what run_repl does with a Frontend.
+#[derive(Debug, Default)]
+pub struct Recorder {
+    pub cells: VecDeque<String>,
+    // input() gets given up on once these run out
+    pub inputs: VecDeque<String>,
+    pub shown: Vec<Shown>,
+}
+
+impl Recorder {
+    pub fn new<I: IntoIterator<Item = String>>(cells: I) -> Recorder {
+        Recorder {
+            cells: cells.into_iter().collect(),
+            ..Recorder::default()
+        }
+    }
+
+    // what was printed to `name`, stdout or stderr
+    pub fn stream(&self, name: &str) -> String {
+        self.shown
+            .iter()
+            .filter_map(|shown| match shown {
+                Shown::Stream { name: to, text } if to == name => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Frontend for Recorder {
+    fn read_cell(&mut self, prompt: &str, _helper: &dyn Helper) -> io::Result<Option<String>> {
+        self.shown.push(Shown::Prompt(prompt.to_string()));
+        Ok(self.cells.pop_front())
+    }
+
+    fn read_input(&mut self, prompt: &str, password: bool) -> io::Result<Option<String>> {
+        self.shown.push(Shown::Input {
+            prompt: prompt.to_string(),
+            password,
+        });
+        Ok(self.inputs.pop_front())
+    }
+
+    fn show_stream(&mut self, name: &str, text: &str) {
+        self.shown.push(Shown::Stream {
+            name: name.to_string(),
+            text: text.to_string(),
+        });
+    }
+
+    fn show_result(&mut self, execution_count: Option<i64>, data: &Value) {
+        self.shown.push(Shown::Result {
+            execution_count,
+            data: data.clone(),
+        });
+    }
+
+    fn show_display(&mut self, data: &Value) {
+        self.shown.push(Shown::Display(data.clone()));
+    }
+
+    fn show_error(&mut self, traceback: &[String]) {
+        self.shown.push(Shown::Error(traceback.to_vec()));
+    }
+
+    fn clear_output(&mut self, wait: bool) {
+        self.shown.push(Shown::Clear { wait });
+    }
+
+    fn set_state(&mut self, state: KernelState) {
+        self.shown.push(Shown::State(state));
+    }
+
+    fn show_notice(&mut self, text: &str) {
+        self.shown.push(Shown::Notice(text.to_string()));
+    }
+
+    fn show_warning(&mut self, text: &str) {
+        self.shown.push(Shown::Warning(text.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_kernel::TestKernel;
+    use serde_json::json;
+
+    fn recorder(cells: &[&str]) -> Recorder {
+        Recorder::new(cells.iter().map(|cell| cell.to_string()))
+    }
+
+    fn prompts(recorder: &Recorder) -> Vec<&str> {
+        recorder
+            .shown
+            .iter()
+            .filter_map(|shown| match shown {
+                Shown::Prompt(prompt) => Some(prompt.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // A Recorder with hooks, the way the REPL's frontend has them: `:skip`
+    // is dealt with without running, `:stop` stops run_repl, and with
+    // `interrupt` the next cell is interrupted once the kernel has it.
+    #[derive(Default)]
+    struct Hooked {
+        recorder: Recorder,
+        interrupt: bool,
+        running: bool,
+        // every message's type, the other requests' ones too
+        messages: Vec<(String, bool)>,
+        ended: Vec<(String, Option<String>)>,
+    }
+
+    impl Frontend for Hooked {
+        fn read_cell(&mut self, prompt: &str, helper: &dyn Helper) -> io::Result<Option<String>> {
+            self.recorder.read_cell(prompt, helper)
+        }
+
+        fn read_input(&mut self, prompt: &str, password: bool) -> io::Result<Option<String>> {
+            self.recorder.read_input(prompt, password)
+        }
+
+        fn show_stream(&mut self, name: &str, text: &str) {
+            self.recorder.show_stream(name, text);
+        }
+
+        fn show_result(&mut self, execution_count: Option<i64>, data: &Value) {
+            self.recorder.show_result(execution_count, data);
+        }
+
+        fn show_display(&mut self, data: &Value) {
+            self.recorder.show_display(data);
+        }
+
+        fn show_error(&mut self, traceback: &[String]) {
+            self.recorder.show_error(traceback);
+        }
+
+        fn clear_output(&mut self, wait: bool) {
+            self.recorder.clear_output(wait);
+        }
+
+        fn set_state(&mut self, state: KernelState) {
+            self.recorder.set_state(state);
+        }
+
+        fn interrupted(&mut self) -> bool {
+            self.running && std::mem::take(&mut self.interrupt)
+        }
+
+        fn prepare(&mut self, _session: &mut Counted, code: String) -> Prepared {
+            match code.as_str() {
+                ":skip" => Prepared::Done,
+                ":stop" => Prepared::Stop,
+                _ => Prepared::Run(code),
+            }
+        }
+
+        fn message(
+            &mut self,
+            _session: &mut Counted,
+            msg: &Map<String, Value>,
+            ours: bool,
+        ) -> bool {
+            let msg_type = msg["header"]["msg_type"].as_str().unwrap_or_default();
+            self.running |= ours && msg_type == "execute_input";
+            self.messages.push((msg_type.to_string(), ours));
+            ours
+        }
+
+        fn end_cell(&mut self, _session: &mut Counted, code: &str, reply: &CellReply) {
+            self.ended.push((code.to_string(), reply.status.clone()));
+        }
+    }
+
+    #[test]
+    fn each_message_goes_to_the_method_for_it() {
+        let kernel = TestKernel::start("secret");
+        let mut session = Counted::new(kernel.client());
+        let mut recorder = recorder(&["1 + 1"]);
+
+        run_repl(&mut session, &mut recorder).unwrap();
+        assert_eq!(
+            recorder.shown,
+            [
+                Shown::Prompt("In [1]: ".to_string()),
+                Shown::State(KernelState::Busy),
+                Shown::Stream {
+                    name: "stdout".to_string(),
+                    text: "ran: 1 + 1\n".to_string(),
+                },
+                Shown::Result {
+                    execution_count: Some(1),
+                    data: json!({"text/plain": "5"}),
+                },
+                Shown::State(KernelState::Idle),
+                Shown::Prompt("In [2]: ".to_string()),
+            ]
+        );
+        assert_eq!(recorder.stream("stdout"), "ran: 1 + 1\n");
+        assert_eq!(recorder.stream("stderr"), "");
+    }
+
+    #[test]
+    fn prompts_follow_the_kernels_counts() {
+        let kernel = TestKernel::start("secret");
+        let mut session = Counted::new(kernel.client());
+        // an error takes a count too, and blank cells aren't run
+        let mut recorder = recorder(&["a", "raise", "  ", "b"]);
+
+        run_repl(&mut session, &mut recorder).unwrap();
+        assert_eq!(
+            prompts(&recorder),
+            ["In [1]: ", "In [2]: ", "In [3]: ", "In [3]: ", "In [4]: "]
+        );
+        assert_eq!(session.execution_count, 4);
+        assert_eq!(kernel.requests_of("execute_request").len(), 3);
+    }
+
+    #[test]
+    fn an_error_is_shown_with_its_traceback() {
+        let kernel = TestKernel::start("secret");
+        let mut session = Counted::new(kernel.client());
+        let mut recorder = Recorder::default();
+
+        let reply = run_cell(&mut session, &mut recorder, "raise").unwrap();
+        assert!(reply.showed_error);
+        assert_eq!(reply.status.as_deref(), Some("error"));
+        assert_eq!(reply.execution_count, Some(1));
+        assert!(reply.failed());
+        assert!(recorder
+            .shown
+            .contains(&Shown::Error(vec!["ValueError: boom".to_string()])));
+    }
+
+    #[test]
+    fn a_traceback_falls_back_to_the_error_itself() {
+        let content = json!({"ename": "NameError", "evalue": "x", "traceback": []});
+        assert_eq!(traceback(&content), ["NameError: x"]);
+        let content = json!({"traceback": ["frame", "NameError: x"]});
+        assert_eq!(traceback(&content), ["frame", "NameError: x"]);
+    }
+
+    #[test]
+    fn input_is_answered_by_the_frontend() {
+        let kernel = TestKernel::start("secret");
+        let mut session = Counted::new(kernel.client());
+        let mut recorder = Recorder {
+            inputs: VecDeque::from(vec!["ada".to_string()]),
+            ..recorder(&["input name: "])
+        };
+
+        run_repl(&mut session, &mut recorder).unwrap();
+        assert!(recorder.shown.contains(&Shown::Input {
+            prompt: "name: ".to_string(),
+            password: false,
+        }));
+        assert_eq!(recorder.stream("stdout"), "got \"ada\"\n");
+        assert!(kernel.requests_of("interrupt_request").is_empty());
+    }
+
+    #[test]
+    fn input_the_frontend_gives_up_on_interrupts_the_cell() {
+        let kernel = TestKernel::start("secret");
+        let mut session = Counted::new(kernel.client());
+        let mut recorder = Recorder::default();
+
+        let reply = run_cell(&mut session, &mut recorder, "input name: ").unwrap();
+        assert!(reply.failed());
+        assert!(recorder
+            .shown
+            .contains(&Shown::Error(vec!["KeyboardInterrupt: ".to_string()])));
+        assert_eq!(kernel.requests_of("interrupt_request").len(), 1);
+        // still answered, so the kernel isn't left waiting
+        let replies = kernel.requests_of("input_reply");
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].content["value"], "");
+    }
+
+    #[test]
+    fn hooks_deal_with_cells_before_they_run() {
+        let kernel = TestKernel::start("secret");
+        let mut session = Counted::new(kernel.client());
+        let mut hooked = Hooked {
+            recorder: recorder(&["a", ":skip", "b", ":stop", "never"]),
+            ..Hooked::default()
+        };
+
+        run_repl(&mut session, &mut hooked).unwrap();
+        let ran: Vec<_> = kernel
+            .requests_of("execute_request")
+            .iter()
+            .map(|request| request.content["code"].clone())
+            .collect();
+        assert_eq!(ran, ["a", "b"]);
+        assert_eq!(
+            hooked.ended,
+            [
+                ("a".to_string(), Some("ok".to_string())),
+                ("b".to_string(), Some("ok".to_string())),
+            ]
+        );
+        // what's left is for whoever stopped it
+        assert_eq!(hooked.recorder.cells, ["never"]);
+        assert!(hooked
+            .messages
+            .iter()
+            .any(|(msg_type, ours)| msg_type == "execute_result" && *ours));
+    }
+
+    #[test]
+    fn an_interrupt_the_frontend_asks_for_goes_to_the_kernel() {
+        let kernel = TestKernel::start("secret");
+        let mut session = Counted::new(kernel.client());
+        let mut hooked = Hooked {
+            interrupt: true,
+            ..Hooked::default()
+        };
+
+        let reply = run_cell(&mut session, &mut hooked, "sleep 10").unwrap();
+        assert_eq!(kernel.requests_of("interrupt_request").len(), 1);
+        assert_eq!(reply.status.as_deref(), Some("error"));
+        assert!(hooked
+            .recorder
+            .shown
+            .contains(&Shown::Error(vec!["KeyboardInterrupt: ".to_string()])));
+    }
+}
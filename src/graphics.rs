@@ -0,0 +1,348 @@
+#[cfg(feature = "svg")]
+use base64::{engine::general_purpose::STANDARD, Engine};
+use crossterm::terminal;
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
+use unicode_width::UnicodeWidthStr;
+
+// kitty takes an image's base64 in pieces of at most this many bytes
+const KITTY_CHUNK: usize = 4096;
+
+// How the terminal can show images inline, going by what it says it is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Graphics {
+    // kitty's graphics protocol, which ghostty speaks too
+    Kitty,
+    // iTerm2's OSC 1337 inline files, which WezTerm speaks too
+    Iterm,
+    // images are saved to temp files instead
+    None,
+}
+
+impl Graphics {
+    pub fn detect() -> Graphics {
+        let term = env::var("TERM").unwrap_or_default();
+        if env::var_os("KITTY_WINDOW_ID").is_some()
+            || term.contains("kitty")
+            || term.contains("ghostty")
+        {
+            return Graphics::Kitty;
+        }
+        match env::var("TERM_PROGRAM").as_deref() {
+            Ok("iTerm.app") | Ok("WezTerm") => Graphics::Iterm,
+            _ => Graphics::None,
+        }
+    }
+}
+
+// A line println_marked printed, to write over later.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mark {
+    // the clear it came after, lines from before one are gone
+    clears: usize,
+    row: usize,
+}
+
+// What a cell has printed on the screen since it last cleared its output,
+// so that clear_output can take it back and a figure redrawn in a loop
+// animates in place instead of stacking up.
+#[derive(Debug)]
+pub struct OutputArea {
+    graphics: Graphics,
+    // escape codes only make sense on a terminal
+    tty: bool,
+    // rows written since the last clear
+    rows: usize,
+    // kitty images placed in those rows, which clearing the text leaves behind
+    images: Vec<u32>,
+    // clear_output(wait=True) waits for the next output to clear
+    pending_clear: bool,
+    // how many times it's been cleared
+    clears: usize,
+}
+
+impl Default for OutputArea {
+    fn default() -> OutputArea {
+        OutputArea::new()
+    }
+}
+
+impl OutputArea {
+    pub fn new() -> OutputArea {
+        let tty = io::stdout().is_terminal();
+        OutputArea {
+            graphics: if tty {
+                Graphics::detect()
+            } else {
+                Graphics::None
+            },
+            tty,
+            rows: 0,
+            images: Vec::new(),
+            pending_clear: false,
+            clears: 0,
+        }
+    }
+
+    // a line of text, to stderr when it's the kernel's stderr
+    pub fn println(&mut self, text: &str, stderr: bool) {
+        self.before_output();
+        if stderr {
+            eprintln!("{}", text);
+        } else {
+            println!("{}", text);
+        }
+        self.rows += rows(text);
+    }
+
+    // A line that rewrite() can write over, it should fit in one row.
+    pub fn println_marked(&mut self, text: &str) -> Mark {
+        self.before_output();
+        let mark = Mark {
+            clears: self.clears,
+            row: self.rows,
+        };
+        self.println(text, false);
+        mark
+    }
+
+    // Write `text` over the line at `mark`, if it's still on the screen:
+    // not cleared away or scrolled off the top.
+    pub fn rewrite(&mut self, mark: Mark, text: &str) {
+        let up = self.rows - mark.row;
+        let height = terminal::size().map_or(24, |(_, h)| h as usize);
+        if !self.tty || mark.clears != self.clears || up >= height {
+            return;
+        }
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "\x1b[{}F\x1b[2K{}\x1b[{}E", up, text, up);
+        let _ = stdout.flush();
+    }
+
+    pub fn shows_images(&self) -> bool {
+        self.graphics != Graphics::None
+    }
+
+    // whether svg() can draw SVGs, only builds with the svg feature can
+    pub fn shows_svg(&self) -> bool {
+        cfg!(feature = "svg") && self.shows_images()
+    }
+
+    // An SVG from a mime bundle, drawn as a PNG as big as image() shows
+    // one. InvalidData means the SVG didn't parse.
+    pub fn svg(&mut self, svg: &str) -> io::Result<()> {
+        #[cfg(feature = "svg")]
+        {
+            let (width, height) = image_pixels();
+            let png = rasterize_svg(svg, width, height)?;
+            self.image(&STANDARD.encode(png))
+        }
+        #[cfg(not(feature = "svg"))]
+        {
+            let _ = svg;
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "jupyterm was built without the svg feature",
+            ))
+        }
+    }
+
+    // A base64 PNG from a mime bundle, inline in a terminal that can show it.
+    pub fn image(&mut self, png: &str) -> io::Result<()> {
+        self.before_output();
+        // Jupyter wraps base64 at 76 columns sometimes
+        let png: String = png.split_whitespace().collect();
+        let height = image_rows();
+
+        let mut stdout = io::stdout();
+        match self.graphics {
+            Graphics::Kitty => {
+                let id = next_id();
+                let chunks: Vec<&[u8]> = png.as_bytes().chunks(KITTY_CHUNK).collect();
+                for (i, chunk) in chunks.iter().enumerate() {
+                    let more = (i + 1 < chunks.len()) as u8;
+                    if i == 0 {
+                        write!(
+                            stdout,
+                            "\x1b_Ga=T,f=100,q=2,i={},r={},m={};",
+                            id, height, more
+                        )?;
+                    } else {
+                        write!(stdout, "\x1b_Gm={};", more)?;
+                    }
+                    stdout.write_all(chunk)?;
+                    write!(stdout, "\x1b\\")?;
+                }
+                writeln!(stdout)?;
+                self.images.push(id);
+                self.rows += height;
+            }
+            Graphics::Iterm => {
+                writeln!(
+                    stdout,
+                    "\x1b]1337;File=inline=1;height={};preserveAspectRatio=1:{}\x07",
+                    height, png
+                )?;
+                self.rows += height;
+            }
+            // SavedOutputs is for those
+            Graphics::None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the terminal can't show images",
+                ))
+            }
+        }
+        stdout.flush()
+    }
+
+    // for a clear_output message
+    pub fn clear(&mut self, wait: bool) {
+        if wait {
+            self.pending_clear = true;
+        } else {
+            self.erase();
+        }
+    }
+
+    fn before_output(&mut self) {
+        if self.pending_clear {
+            self.pending_clear = false;
+            self.erase();
+        }
+    }
+
+    // Back up over what was printed and clear the screen from there. Rows
+    // that have scrolled off the top can't be reached any more.
+    fn erase(&mut self) {
+        if self.tty && self.rows > 0 {
+            let mut stdout = io::stdout();
+            let _ = write!(stdout, "\x1b[{}F\x1b[J", self.rows);
+            for id in &self.images {
+                let _ = write!(stdout, "\x1b_Ga=d,d=I,q=2,i={}\x1b\\", id);
+            }
+            let _ = stdout.flush();
+        }
+        self.rows = 0;
+        self.images.clear();
+        self.clears += 1;
+    }
+}
+
+// rows `text` and its newline take up, with long lines wrapped
+fn rows(text: &str) -> usize {
+    let width = terminal::size().map_or(80, |(w, _)| w.max(1) as usize);
+    text.split('\n')
+        .map(|line| line.width().max(1).div_ceil(width))
+        .sum()
+}
+
+// half the terminal, so a figure and the prompt fit together
+fn image_rows() -> usize {
+    terminal::size().map_or(12, |(_, h)| (h as usize / 2).max(1))
+}
+
+// The size in pixels of the image_rows() an image gets, and the width of
+// the terminal. Terminals that don't say how big their cells are get the
+// usual 10x20.
+#[cfg(feature = "svg")]
+fn image_pixels() -> (u32, u32) {
+    let (columns, lines) = terminal::size().map_or((80, 24), |(w, h)| (w.max(1), h.max(1)));
+    let (cell_width, cell_height) = match terminal::window_size() {
+        Ok(size) if size.width > 0 && size.height > 0 => {
+            (size.width / columns, size.height / lines)
+        }
+        _ => (10, 20),
+    };
+    (
+        columns as u32 * cell_width.max(1) as u32,
+        image_rows() as u32 * cell_height.max(1) as u32,
+    )
+}
+
+// `svg` as a PNG `height` pixels tall, or narrower than that when it would
+// be wider than `max_width`.
+#[cfg(feature = "svg")]
+pub fn rasterize_svg(svg: &str, max_width: u32, height: u32) -> io::Result<Vec<u8>> {
+    use resvg::{tiny_skia, usvg};
+    use std::sync::{Arc, OnceLock};
+
+    // loading the system's fonts takes a while, and graphviz and plotly
+    // output has text in every SVG
+    static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+    let fontdb = FONTS.get_or_init(|| {
+        let mut fonts = usvg::fontdb::Database::new();
+        fonts.load_system_fonts();
+        Arc::new(fonts)
+    });
+    let options = usvg::Options {
+        fontdb: Arc::clone(fontdb),
+        ..usvg::Options::default()
+    };
+    let tree = usvg::Tree::from_str(svg, &options)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    let size = tree.size();
+    let scale = (height as f32 / size.height()).min(max_width as f32 / size.width());
+    let width = (size.width() * scale).ceil() as u32;
+    let height = (size.height() * scale).ceil() as u32;
+    let mut pixmap = tiny_skia::Pixmap::new(width.max(1), height.max(1))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the SVG has no size"))?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    pixmap
+        .encode_png()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+// ids kitty tells this process's images apart by
+fn next_id() -> u32 {
+    static IDS: AtomicU32 = AtomicU32::new(1);
+    IDS.fetch_add(1, Ordering::SeqCst)
+}
+
+#[cfg(all(test, feature = "svg"))]
+mod tests {
+    use super::*;
+    use resvg::tiny_skia::Pixmap;
+
+    const CIRCLE: &str = include_str!("../tests/fixtures/circle.svg");
+
+    #[test]
+    fn rasterizes_an_svg_to_the_height_asked_for() {
+        let png = rasterize_svg(CIRCLE, 1000, 100).unwrap();
+        let pixmap = Pixmap::decode_png(&png).unwrap();
+        assert_eq!((pixmap.width(), pixmap.height()), (200, 100));
+        let drawn = pixmap
+            .pixels()
+            .iter()
+            .filter(|pixel| pixel.alpha() > 0)
+            .count();
+        assert!(drawn > 0);
+        // the circle is on the left, the right half is left empty
+        let right_half = pixmap
+            .pixels()
+            .chunks(200)
+            .flat_map(|row| &row[100..])
+            .filter(|pixel| pixel.alpha() > 0)
+            .count();
+        assert_eq!(right_half, 0);
+    }
+
+    #[test]
+    fn too_wide_svgs_are_scaled_to_fit() {
+        let png = rasterize_svg(CIRCLE, 80, 100).unwrap();
+        let pixmap = Pixmap::decode_png(&png).unwrap();
+        assert_eq!((pixmap.width(), pixmap.height()), (80, 40));
+    }
+
+    #[test]
+    fn malformed_svgs_are_invalid_data() {
+        let err = rasterize_svg("<svg", 100, 100).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::client::{Cutypr, ExecuteOptions, Session};
+use crate::editor::Helper;
+use crate::error::Error;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+use tracing::warn;
+
+pub mod proto {
+    tonic::include_proto!("jupyterm");
+}
+
+use proto::kernel_service_server::{KernelService, KernelServiceServer};
+use proto::output_event::Kind;
+
+// how many events an Execute stream can have waiting for a slow client
+const EVENTS_CAPACITY: usize = 256;
+
+// Serve the KernelService in proto/kernel.proto on every interface, with a
+// session of its own on the kernel, for as long as jupyterm runs. Calls
+// take turns with the kernel.
+pub fn serve(port: u16, session: Session, ports: Value) -> Result<(), Error> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_io()
+        .build()?;
+
+    let mut client = Cutypr::new(session, ports);
+    client.initialize_channels();
+    let service = Kernel {
+        client: Arc::new(Mutex::new(client)),
+    };
+    thread::spawn(move || {
+        runtime.block_on(async move {
+            let incoming = match tokio::net::TcpListener::from_std(listener) {
+                Ok(listener) => TcpListenerStream::new(listener),
+                Err(err) => return warn!(%err, "couldn't listen for grpc"),
+            };
+            if let Err(err) = tonic::transport::Server::builder()
+                .add_service(KernelServiceServer::new(service))
+                .serve_with_incoming(incoming)
+                .await
+            {
+                warn!(%err, "grpc server stopped");
+            }
+        })
+    });
+    Ok(())
+}
+
+struct Kernel {
+    client: Arc<Mutex<Cutypr>>,
+}
+
+impl Kernel {
+    // run `f` with the client on a thread where it can block on the kernel
+    async fn with_client<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Cutypr) -> Option<T> + Send + 'static,
+    {
+        let client = self.client.clone();
+        tokio::task::spawn_blocking(move || f(&mut client.lock().unwrap()))
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?
+            .ok_or_else(|| Status::unavailable("the kernel didn't answer"))
+    }
+}
+
+#[tonic::async_trait]
+impl KernelService for Kernel {
+    type ExecuteStream = ReceiverStream<Result<proto::OutputEvent, Status>>;
+
+    async fn execute(
+        &self,
+        request: Request<proto::ExecuteRequest>,
+    ) -> Result<Response<Self::ExecuteStream>, Status> {
+        let request = request.into_inner();
+        // there is nobody to answer input() over grpc
+        let options = ExecuteOptions {
+            silent: request.silent,
+            store_history: request.store_history.unwrap_or(true),
+            allow_stdin: false,
+            ..ExecuteOptions::default()
+        };
+
+        let (events, receiver) = mpsc::channel(EVENTS_CAPACITY);
+        let client = self.client.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut client = client.lock().unwrap();
+            let msg_id = client.execute_with(&request.code, &options);
+            // a client that went away still leaves the kernel to finish
+            let result = client.for_each_message(&msg_id, |msg| {
+                let _ = events.blocking_send(Ok(output_event(msg)));
+                Ok(())
+            });
+            if let Err(err) = result {
+                let _ = events.blocking_send(Err(Status::unavailable(err.to_string())));
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn complete(
+        &self,
+        request: Request<proto::CompleteRequest>,
+    ) -> Result<Response<proto::CompleteReply>, Status> {
+        let request = request.into_inner();
+        let reply = self
+            .with_client(move |client| client.complete(&request.code, request.cursor_pos as usize))
+            .await?;
+        Ok(Response::new(proto::CompleteReply {
+            matches: reply["matches"]
+                .as_array()
+                .map(|matches| matches.iter().map(text).collect())
+                .unwrap_or_default(),
+            cursor_start: reply["cursor_start"].as_u64().unwrap_or(0) as u32,
+            cursor_end: reply["cursor_end"].as_u64().unwrap_or(0) as u32,
+        }))
+    }
+
+    async fn inspect(
+        &self,
+        request: Request<proto::InspectRequest>,
+    ) -> Result<Response<proto::InspectReply>, Status> {
+        let request = request.into_inner();
+        let reply = self
+            .with_client(move |client| client.inspect(&request.code, request.cursor_pos as usize))
+            .await?;
+        Ok(Response::new(proto::InspectReply {
+            found: reply["found"].as_bool().unwrap_or(false),
+            data: mime_bundle(&reply["data"]),
+        }))
+    }
+
+    async fn shutdown(
+        &self,
+        request: Request<proto::ShutdownRequest>,
+    ) -> Result<Response<proto::ShutdownReply>, Status> {
+        let restart = request.into_inner().restart;
+        let reply = self
+            .with_client(move |client| client.shutdown(restart))
+            .await?;
+        Ok(Response::new(proto::ShutdownReply {
+            restart: reply["content"]["restart"].as_bool().unwrap_or(restart),
+        }))
+    }
+}
+
+fn output_event(msg: &Map<String, Value>) -> proto::OutputEvent {
+    let msg_type = text(&msg["header"]["msg_type"]);
+    let content = &msg["content"];
+    let kind = match msg_type.as_str() {
+        "status" => Kind::Status(proto::Status {
+            execution_state: text(&content["execution_state"]),
+        }),
+        "execute_input" => Kind::ExecuteInput(proto::ExecuteInput {
+            code: text(&content["code"]),
+            execution_count: content["execution_count"].as_i64(),
+        }),
+        "stream" => Kind::Stream(proto::Stream {
+            name: text(&content["name"]),
+            text: text(&content["text"]),
+        }),
+        "execute_result" | "display_data" | "update_display_data" => {
+            Kind::DisplayData(proto::DisplayData {
+                data: mime_bundle(&content["data"]),
+                execution_count: content["execution_count"].as_i64(),
+            })
+        }
+        "error" => Kind::Error(proto::Error {
+            ename: text(&content["ename"]),
+            evalue: text(&content["evalue"]),
+            traceback: content["traceback"]
+                .as_array()
+                .map(|lines| lines.iter().map(text).collect())
+                .unwrap_or_default(),
+        }),
+        "execute_reply" => Kind::ExecuteReply(proto::ExecuteReply {
+            status: text(&content["status"]),
+            execution_count: content["execution_count"].as_i64(),
+        }),
+        _ => Kind::Other(content.to_string()),
+    };
+    proto::OutputEvent {
+        msg_type,
+        kind: Some(kind),
+    }
+}
+
+fn text(value: &Value) -> String {
+    value.as_str().unwrap_or("").to_string()
+}
+
+// strings as they are, anything else like application/json as JSON text
+fn mime_bundle(data: &Value) -> HashMap<String, String> {
+    data.as_object()
+        .map(|data| {
+            data.iter()
+                .map(|(mime, value)| {
+                    let value = match value {
+                        Value::String(text) => text.clone(),
+                        value => value.to_string(),
+                    };
+                    (mime.clone(), value)
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
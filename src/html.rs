@@ -0,0 +1,396 @@
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+// between columns, like pandas' own text repr
+const COLUMN_GAP: &str = "  ";
+
+// One <tr>, with its cells' text.
+struct Row {
+    cells: Vec<Cell>,
+    // in <thead>, or all <th> in a table without one
+    header: bool,
+}
+
+struct Cell {
+    text: String,
+    // <th>, which pandas uses for the index in the body rows
+    th: bool,
+    colspan: usize,
+    rowspan: usize,
+}
+
+// The first <table> in `html` laid out for a terminal `width` columns wide,
+// or None if there's no table in it. Cells wider than `max_column_width`
+// are cut short with an ellipsis, and columns that don't fit are left out
+// from the middle, the index (the <th> columns on the left) always stays.
+// Text after the table, like pandas' "5 rows × 30 columns", comes under it.
+pub fn render_table(html: &str, width: usize, max_column_width: usize) -> Option<String> {
+    // ASCII lowercase keeps the byte offsets the same
+    let lower = html.to_ascii_lowercase();
+    let start = lower.find("<table")?;
+    let (rows, end) = parse_rows(html, &lower, start);
+    let mut grid = layout(&rows);
+    if grid.is_empty() {
+        return None;
+    }
+    let columns = grid.iter().map(|(cells, _)| cells.len()).max().unwrap_or(0);
+    for (cells, _) in &mut grid {
+        cells.resize(columns, (String::new(), false));
+    }
+
+    let max_column_width = max_column_width.max(2);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            grid.iter()
+                .map(|(cells, _)| cells[column].0.width())
+                .max()
+                .unwrap_or(0)
+                .min(max_column_width)
+        })
+        .collect();
+    let body = || grid.iter().filter(|(_, header)| !header);
+    // leading columns that are all <th> in the body
+    let index = (0..columns)
+        .take_while(|column| body().all(|(cells, _)| cells[*column].1))
+        .count();
+    let numeric: Vec<bool> = (0..columns)
+        .map(|column| {
+            let mut values = body()
+                .map(|(cells, _)| cells[column].0.as_str())
+                .filter(|text| !text.is_empty())
+                .peekable();
+            values.peek().is_some() && values.all(is_number)
+        })
+        .collect();
+
+    let (shown, elided) = fit_columns(&widths, index, width);
+    let mut out = String::new();
+    let mut separated = false;
+    for (cells, header) in &grid {
+        if !header && !separated {
+            separated = true;
+            if grid.iter().any(|(_, header)| *header) {
+                let rules: Vec<String> = shown
+                    .iter()
+                    .map(|column| match column {
+                        Some(column) => "─".repeat(widths[*column]),
+                        None => "─".to_string(),
+                    })
+                    .collect();
+                out += &rules.join(COLUMN_GAP);
+                out.push('\n');
+            }
+        }
+        let line: Vec<String> = shown
+            .iter()
+            .map(|column| match column {
+                Some(column) => {
+                    let text = truncate(&cells[*column].0, max_column_width);
+                    pad(&text, widths[*column], numeric[*column])
+                }
+                None => "…".to_string(),
+            })
+            .collect();
+        out += line.join(COLUMN_GAP).trim_end();
+        out.push('\n');
+    }
+    if elided > 0 {
+        out += &format!("[{} of {} columns not shown]\n", elided, columns - index);
+    }
+
+    let after = text_of(&html[end..]);
+    if !after.is_empty() {
+        out += &after;
+        out.push('\n');
+    }
+    Some(out.trim_end().to_string())
+}
+
+// The rows of the table starting at `start`, and where it ends. Tables
+// inside cells are read as part of their cell's text.
+fn parse_rows(html: &str, lower: &str, start: usize) -> (Vec<Row>, usize) {
+    let mut rows = Vec::new();
+    let mut row: Option<Vec<Cell>> = None;
+    let mut cell: Option<Cell> = None;
+    let mut in_head = false;
+    let mut head_rows = Vec::new();
+    let mut depth = 0;
+
+    let mut at = start;
+    while let Some(open) = lower[at..].find('<').map(|i| at + i) {
+        if let Some(cell) = cell.as_mut() {
+            cell.text += &html[at..open];
+        }
+        let close = match lower[open..].find('>') {
+            Some(i) => open + i + 1,
+            None => break,
+        };
+        at = close;
+        let tag = &lower[open + 1..close - 1];
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/' && !tag.starts_with('/'))
+            .next()
+            .unwrap_or("");
+        match name {
+            "table" => depth += 1,
+            "/table" => {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            _ if depth > 1 => {}
+            "thead" => in_head = true,
+            "/thead" => in_head = false,
+            "tr" | "/tr" => {
+                finish_cell(&mut cell, &mut row);
+                if let Some(cells) = row.take() {
+                    head_rows.push(in_head);
+                    rows.push(cells);
+                }
+                if name == "tr" {
+                    row = Some(Vec::new());
+                }
+            }
+            "th" | "td" => {
+                finish_cell(&mut cell, &mut row);
+                cell = Some(Cell {
+                    text: String::new(),
+                    th: name == "th",
+                    colspan: span(&html[open..close], "colspan"),
+                    rowspan: span(&html[open..close], "rowspan"),
+                });
+            }
+            "/th" | "/td" => finish_cell(&mut cell, &mut row),
+            "br" => {
+                if let Some(cell) = cell.as_mut() {
+                    cell.text.push(' ');
+                }
+            }
+            _ => {}
+        }
+    }
+    finish_cell(&mut cell, &mut row);
+    if let Some(cells) = row.take() {
+        head_rows.push(in_head);
+        rows.push(cells);
+    }
+
+    // without a <thead>, the rows of only <th> at the top are the header
+    let has_head = head_rows.iter().any(|head| *head);
+    let mut top = true;
+    let rows = rows
+        .into_iter()
+        .zip(head_rows)
+        .map(|(cells, head)| {
+            top &= !cells.is_empty() && cells.iter().all(|cell| cell.th);
+            Row {
+                header: if has_head { head } else { top },
+                cells,
+            }
+        })
+        .collect();
+    (rows, at.min(html.len()))
+}
+
+fn finish_cell(cell: &mut Option<Cell>, row: &mut Option<Vec<Cell>>) {
+    if let Some(mut cell) = cell.take() {
+        cell.text = text_of(&cell.text);
+        // a cell outside a <tr> still gets a row
+        row.get_or_insert_with(Vec::new).push(cell);
+    }
+}
+
+// colspan or rowspan from a tag, 1 when it's missing
+fn span(tag: &str, attribute: &str) -> usize {
+    let lower = tag.to_ascii_lowercase();
+    let value = match lower.find(attribute) {
+        Some(i) => &lower[i + attribute.len()..],
+        None => return 1,
+    };
+    let digits: String = value
+        .trim_start_matches(|c: char| c == '=' || c == '"' || c == '\'' || c.is_whitespace())
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect();
+    digits.parse().unwrap_or(1).clamp(1, 1000)
+}
+
+// The rows as a grid, with the columns a colspan or rowspan covers filled
+// in empty so multi-index headers and indexes stay lined up. Each cell
+// says whether it was a <th>.
+fn layout(rows: &[Row]) -> Vec<(Vec<(String, bool)>, bool)> {
+    // rows each column is still covered for by a rowspan above
+    let mut covered: Vec<usize> = Vec::new();
+    let mut grid = Vec::new();
+    for row in rows {
+        let mut cells = Vec::new();
+        for cell in &row.cells {
+            take_covered(&mut covered, &mut cells);
+            for i in 0..cell.colspan {
+                let column = cells.len();
+                if covered.len() <= column {
+                    covered.resize(column + 1, 0);
+                }
+                covered[column] = cell.rowspan - 1;
+                let text = if i == 0 {
+                    cell.text.clone()
+                } else {
+                    String::new()
+                };
+                cells.push((text, cell.th));
+            }
+        }
+        take_covered(&mut covered, &mut cells);
+        grid.push((cells, row.header));
+    }
+    grid
+}
+
+// empty cells for the columns a rowspan above still covers, from the next one on
+fn take_covered(covered: &mut [usize], cells: &mut Vec<(String, bool)>) {
+    while covered.get(cells.len()).is_some_and(|rows| *rows > 0) {
+        covered[cells.len()] -= 1;
+        cells.push((String::new(), true));
+    }
+}
+
+// The columns to show, None for the "…" standing in for the ones left out,
+// and how many were. The index columns come first, then as many from the
+// left and right ends as fit, taking turns.
+fn fit_columns(widths: &[usize], index: usize, width: usize) -> (Vec<Option<usize>>, usize) {
+    let gap = COLUMN_GAP.len();
+    let total: usize = widths.iter().sum::<usize>() + gap * widths.len().saturating_sub(1);
+    if total <= width {
+        return ((0..widths.len()).map(Some).collect(), 0);
+    }
+
+    let index = index.min(widths.len());
+    // the "…" column and its gap
+    let mut used: usize = widths[..index].iter().map(|w| w + gap).sum::<usize>() + 1;
+    let (mut left, mut right) = (index, widths.len());
+    let mut from_left = true;
+    while left < right {
+        let column = if from_left { left } else { right - 1 };
+        if used + widths[column] + gap > width {
+            break;
+        }
+        used += widths[column] + gap;
+        if from_left {
+            left += 1;
+        } else {
+            right -= 1;
+        }
+        from_left = !from_left;
+    }
+
+    let mut shown: Vec<Option<usize>> = (0..left).map(Some).collect();
+    shown.push(None);
+    shown.extend((right..widths.len()).map(Some));
+    (shown, right - left)
+}
+
+// `text` cut to `max` columns, ending in an ellipsis if it had to be
+pub(crate) fn truncate(text: &str, max: usize) -> String {
+    if text.width() <= max {
+        return text.to_string();
+    }
+    let mut out = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w > max - 1 {
+            break;
+        }
+        used += w;
+        out.push(c);
+    }
+    out.push('…');
+    out
+}
+
+fn pad(text: &str, width: usize, right: bool) -> String {
+    let padding = " ".repeat(width.saturating_sub(text.width()));
+    if right {
+        padding + text
+    } else {
+        text.to_string() + &padding
+    }
+}
+
+// numbers the way pandas prints them, like 1,024, -0.5, 12% or 1e-05
+fn is_number(text: &str) -> bool {
+    let text = text.trim_end_matches('%').replace(',', "");
+    text.parse::<f64>().is_ok()
+}
+
+// The text in some HTML, without its tags, whitespace collapsed and the
+// common entities decoded. <style> and <script> have none to give.
+pub(crate) fn text_of(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(open) = rest.find('<') {
+        text += &rest[..open];
+        let lower = rest[open..].to_ascii_lowercase();
+        let skip_to = ["style", "script"]
+            .iter()
+            .find(|tag| lower[1..].starts_with(*tag))
+            .and_then(|tag| lower.find(&format!("</{}", tag)));
+        let from = open + skip_to.unwrap_or(0);
+        match rest[from..].find('>') {
+            Some(close) => rest = &rest[from + close + 1..],
+            None => {
+                rest = "";
+                break;
+            }
+        }
+        text.push(' ');
+    }
+    text += rest;
+    let text = decode_entities(&text);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out += &rest[..amp];
+        rest = &rest[amp..];
+        let end = match rest.find(';') {
+            Some(end) if end <= 10 => end,
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            "hellip" => Some('…'),
+            "times" => Some('×'),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|n| n.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out + rest
+}
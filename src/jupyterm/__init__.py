@@ -1 +1,7 @@
 from .__main__ import start_kernel
+
+# the Rust client, in builds made with maturin
+try:
+    from ._client import Client
+except ImportError:
+    pass
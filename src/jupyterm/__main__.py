@@ -1,19 +1,58 @@
 import json
+import os
 
 from jupyter_client import KernelManager
 
 
-def start_kernel():
-    manager = KernelManager()
-    manager.start_kernel()
-
-    port_names = ["shell", "stdin", "iopub", "hb", "control"]
-    ports = dict(list(zip(port_names, manager.ports)))
-
-    kernel_info = {
-        "key": manager.session.key.decode("utf-8"),
-        "ports": ports,
-    }
+def start_kernel(log_fd=None, kernel_name=None, env=None, connection_file=None):
+    # jupyter_client's default kernel, usually python3, without a name
+    if kernel_name is None:
+        manager = KernelManager()
+    else:
+        manager = KernelManager(kernel_name=kernel_name)
+    # the connection file jupyterm wrote, with the ports and key it picked,
+    # instead of one jupyter_client makes up
+    if connection_file is not None:
+        manager.connection_file = connection_file
+        manager.load_connection_file()
+    # the write end of a pipe jupyterm reads the kernel's own stdout and
+    # stderr from, a HANDLE on Windows; closed here once the kernel has it
+    kwargs = {}
+    if log_fd is not None:
+        if os.name == "nt":
+            import msvcrt
+
+            log_fd = msvcrt.open_osfhandle(log_fd, 0)
+        kwargs = {"stdout": log_fd, "stderr": log_fd}
+    # on top of ours, like COLUMNS and LINES for the terminal's size
+    if env is not None:
+        kwargs["env"] = {**os.environ, **env}
+
+    # independent, so the kernel can outlive jupyterm with --keep-kernel,
+    # jupyterm shuts it down itself otherwise
+    try:
+        manager.start_kernel(independent=True, **kwargs)
+    finally:
+        if log_fd is not None:
+            os.close(log_fd)
+
+    # the same fields as the connection file, which is where it is
+    kernel_info = manager.get_connection_info()
+    kernel_info["key"] = manager.session.key.decode("utf-8")
+    kernel_info["connection_file"] = manager.connection_file
+    kernel_info["kernel_name"] = manager.kernel_name
+    # "signal" or "message", how the kernel wants to be interrupted
+    kernel_info["interrupt_mode"] = manager.kernel_spec.interrupt_mode
+    # for killing a kernel that ignores shutdown_request
+    provisioner = getattr(manager, "provisioner", None)
+    if provisioner is not None:
+        kernel_info["pid"] = getattr(provisioner, "pid", None)
+        process = getattr(provisioner, "process", None)
+    else:
+        kernel_info["pid"] = manager.kernel.pid
+        process = manager.kernel
+    # on Windows, the event a signal-mode kernel waits on to be interrupted
+    kernel_info["interrupt_event"] = getattr(process, "win32_interrupt_event", None)
 
     return json.dumps(kernel_info)
 
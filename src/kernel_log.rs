@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, PipeReader, PipeWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// how many lines of kernel output to hold on to
+const KERNEL_LOG_LINES: usize = 500;
+// longer lines are split, so output without newlines can't grow a line forever
+const KERNEL_LOG_LINE_LENGTH: u64 = 4096;
+
+// The end of what a kernel jupyterm started wrote to its own stdout and
+// stderr, which is where a kernel that fails to start or crashes explains
+// itself. Output that went through iopub isn't in here.
+#[derive(Debug)]
+pub struct KernelLog {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    exited: Arc<AtomicBool>,
+}
+
+impl KernelLog {
+    // A pipe for the kernel to write to, read on a background thread and
+    // copied to `file` as well if there is one.
+    pub fn new(file: Option<&Path>) -> io::Result<(KernelLog, PipeWriter)> {
+        let (reader, writer) = io::pipe()?;
+        let file = match file {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+
+        let log = KernelLog {
+            lines: Arc::new(Mutex::new(VecDeque::new())),
+            exited: Arc::new(AtomicBool::new(false)),
+        };
+        let lines = Arc::clone(&log.lines);
+        let exited = Arc::clone(&log.exited);
+        thread::spawn(move || {
+            read_lines(reader, file, &lines);
+            // every copy of the write end is closed, so the kernel is gone
+            exited.store(true, Ordering::SeqCst);
+        });
+        Ok((log, writer))
+    }
+
+    pub fn tail(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.exited.load(Ordering::SeqCst)
+    }
+
+    // the tail under a heading, on stderr where it can't be mistaken for output
+    pub fn report(&self, heading: &str) {
+        let tail = self.tail();
+        if tail.is_empty() {
+            eprintln!("jupyterm: {}, it didn't print anything", heading);
+            return;
+        }
+        eprintln!("jupyterm: {}, its last output was:", heading);
+        for line in tail {
+            eprintln!("    {}", line);
+        }
+    }
+}
+
+fn read_lines(reader: PipeReader, mut file: Option<File>, lines: &Mutex<VecDeque<String>>) {
+    let mut reader = BufReader::new(reader);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match (&mut reader)
+            .take(KERNEL_LOG_LINE_LENGTH)
+            .read_until(b'\n', &mut buf)
+        {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        if let Some(out) = file.as_mut() {
+            // a full disk shouldn't stop the tail
+            let _ = out.write_all(&buf);
+        }
+
+        let line = String::from_utf8_lossy(&buf).trim_end().to_string();
+        let mut lines = lines.lock().unwrap();
+        if lines.len() == KERNEL_LOG_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+}
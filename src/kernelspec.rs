@@ -0,0 +1,195 @@
+use crate::error::Error;
+use crate::paths;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// A kernels/<name>/kernel.json from one of the Jupyter data dirs, which is
+// all jupyter_client needs to start a kernel in any language.
+#[derive(Clone, Debug, Deserialize)]
+pub struct KernelSpec {
+    // the command line, with {connection_file} where the kernel's goes
+    pub argv: Vec<String>,
+    pub display_name: String,
+    #[serde(default)]
+    pub language: String,
+    // "signal" or "message"
+    #[serde(default = "default_interrupt_mode")]
+    pub interrupt_mode: String,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub metadata: Map<String, Value>,
+}
+
+fn default_interrupt_mode() -> String {
+    "signal".to_string()
+}
+
+// Short names for --kernel that stand for a kernelspec installed under
+// another name.
+//
+// irust is evcxr, a Rust kernel, so jupyterm can be a Rust REPL talking to
+// a Rust kernel:
+//
+//     cargo install --locked evcxr_jupyter
+//     evcxr_jupyter --install
+//     jupyterm --kernel irust
+//
+// `evcxr_jupyter --install` writes the kernelspec as "rust" into the user's
+// data dir, ~/.local/share/jupyter/kernels/rust on Linux.
+const PRESETS: &[(&str, &str, &str)] = &[(
+    "irust",
+    "rust",
+    "cargo install --locked evcxr_jupyter && evcxr_jupyter --install",
+)];
+
+impl KernelSpec {
+    pub fn read(path: &Path) -> Result<KernelSpec, Error> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    // The kernel.json for `name`, from the first data dir that has one,
+    // the same one jupyter_client would pick.
+    pub fn find(name: &str) -> Option<(PathBuf, KernelSpec)> {
+        paths::jupyter_data_dirs()
+            .into_iter()
+            .map(|dir| dir.join("kernels").join(name).join("kernel.json"))
+            .find(|path| path.exists())
+            .and_then(|path| {
+                let spec = KernelSpec::read(&path).ok()?;
+                Some((path, spec))
+            })
+    }
+}
+
+// the kernelspec name behind a --kernel preset, or `name` itself
+pub fn resolve(name: &str) -> &str {
+    PRESETS
+        .iter()
+        .find(|(preset, _, _)| *preset == name)
+        .map_or(name, |(_, spec, _)| *spec)
+}
+
+// how to install the kernel behind a preset, if `name` is one
+pub fn install_hint(name: &str) -> Option<&'static str> {
+    PRESETS
+        .iter()
+        .find(|(preset, _, _)| *preset == name)
+        .map(|(_, _, hint)| *hint)
+}
+
+// Something about a kernelspec that's known to go wrong, found before the
+// kernel is started.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompatWarning {
+    // IPython and ipykernel dropped Python 2 years ago
+    Python2,
+    // argv runs a conda env's python, and the env isn't active here or
+    // given a PATH in the spec's env
+    CondaNotActivated(PathBuf),
+    // argv[0] isn't a file, or isn't anywhere on PATH
+    MissingExecutable(String),
+}
+
+impl fmt::Display for CompatWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompatWarning::Python2 => write!(
+                f,
+                "it runs Python 2, which current ipykernel and IPython don't support"
+            ),
+            CompatWarning::CondaNotActivated(env) => write!(
+                f,
+                "it runs the conda env at {} without activating it, so the kernel may not find the env's libraries and commands; activate the env first or set PATH in the kernelspec's env",
+                env.display()
+            ),
+            CompatWarning::MissingExecutable(program) => {
+                write!(f, "{} isn't on PATH, the kernel won't start", program)
+            }
+        }
+    }
+}
+
+// The known problems with starting `spec`, none if it looks fine.
+pub fn check_kernel_spec_compatibility(spec: &KernelSpec) -> Vec<CompatWarning> {
+    let mut warnings = Vec::new();
+    let program = match spec.argv.first() {
+        Some(program) => program,
+        None => return warnings,
+    };
+    let file_name = Path::new(program)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    if spec.language.eq_ignore_ascii_case("python")
+        && (file_name.starts_with("python2") || spec.display_name.contains("Python 2"))
+    {
+        warnings.push(CompatWarning::Python2);
+    }
+
+    // jupyter_client runs its own interpreter for these
+    if program == "python" || program == "python3" {
+        return warnings;
+    }
+    let path = spec
+        .env
+        .get("PATH")
+        .map(OsString::from)
+        .or_else(|| env::var_os("PATH"));
+    let executable = match find_executable(program, path) {
+        Some(executable) => executable,
+        None => {
+            warnings.push(CompatWarning::MissingExecutable(program.clone()));
+            return warnings;
+        }
+    };
+
+    // bin/python in an env on Unix, python.exe at its top on Windows
+    let env_root = executable
+        .ancestors()
+        .skip(1)
+        .take(2)
+        .find(|dir| dir.join("conda-meta").is_dir());
+    if let Some(env_root) = env_root {
+        let active = env::var_os("CONDA_PREFIX")
+            .is_some_and(|prefix| fs::canonicalize(prefix).ok().as_deref() == Some(env_root));
+        // conda run and nb_conda_kernels activate the env themselves
+        let activates = file_name.starts_with("conda")
+            || file_name.contains("mamba")
+            || spec.argv.iter().any(|arg| arg == "nb_conda_kernels");
+        if !active && !activates && !spec.env.contains_key("PATH") {
+            warnings.push(CompatWarning::CondaNotActivated(env_root.to_path_buf()));
+        }
+    }
+    warnings
+}
+
+// `program` as it is if it's a path, otherwise the first match on `path`,
+// with the PATHEXT extensions on Windows
+fn find_executable(program: &str, path: Option<OsString>) -> Option<PathBuf> {
+    if program.contains('/') || program.contains(std::path::MAIN_SEPARATOR) {
+        return fs::canonicalize(program).ok();
+    }
+    let extensions: Vec<String> = if cfg!(windows) {
+        let pathext = env::var("PATHEXT").unwrap_or_else(|_| ".EXE;.BAT;.CMD".to_string());
+        std::iter::once(String::new())
+            .chain(pathext.split(';').map(str::to_string))
+            .collect()
+    } else {
+        vec![String::new()]
+    };
+    env::split_paths(&path?).find_map(|dir| {
+        extensions
+            .iter()
+            .map(|extension| dir.join(format!("{}{}", program, extension)))
+            .find(|candidate| candidate.is_file())
+            .and_then(|candidate| fs::canonicalize(candidate).ok())
+    })
+}
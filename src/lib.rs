@@ -0,0 +1,48 @@
+pub mod aliases;
+pub mod capture;
+pub mod client;
+pub mod clipboard;
+pub mod commands;
+pub mod config;
+pub mod connection;
+pub mod debugger;
+pub mod display;
+pub mod editor;
+pub mod error;
+pub mod events;
+pub mod frontend;
+pub mod graphics;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod html;
+pub mod kernel_log;
+pub mod kernelspec;
+pub mod log_file;
+pub mod markdown;
+pub mod metrics;
+pub mod named_session;
+pub mod notebook;
+pub mod notify;
+pub mod out_cache;
+pub mod outputs;
+pub mod paths;
+pub mod pool;
+pub mod protocol;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod rate_limit;
+pub mod replay;
+pub mod saved_run;
+pub mod server;
+pub mod signing;
+pub mod socket_options;
+pub mod state;
+#[cfg(test)]
+mod test_kernel;
+pub mod throttle;
+pub mod transcript;
+pub mod tunnel;
+pub mod watcher;
+#[cfg(feature = "async")]
+pub mod websocket;
+pub mod widgets;
@@ -0,0 +1,133 @@
+use chrono::Utc;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+
+// how big a day's --log-file gets before it's started again
+pub const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+// The files --log-file writes jupyterm's own log records to, appended to
+// across runs. tracing-appender's daily rotation starts a new one every
+// day, PATH.YYYY-MM-DD by the UTC date like rolling::daily names them. One
+// that passes MAX_LOG_SIZE is moved to PATH.YYYY-MM-DD.1, replacing the
+// one before, and the day goes on in a new one, so a day never takes more
+// than twice that.
+#[derive(Debug)]
+pub struct LogFile {
+    dir: PathBuf,
+    prefix: String,
+    max_size: u64,
+    appender: Mutex<RollingFileAppender>,
+}
+
+impl LogFile {
+    pub fn open(path: &Path) -> io::Result<LogFile> {
+        let prefix = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        Ok(LogFile {
+            appender: Mutex::new(appender(&dir, prefix)?),
+            dir,
+            prefix: prefix.to_string(),
+            max_size: MAX_LOG_SIZE,
+        })
+    }
+
+    // the file the appender is writing today's records to
+    pub fn current(&self) -> PathBuf {
+        let date = Utc::now().format("%Y-%m-%d");
+        self.dir.join(format!("{}.{}", self.prefix, date))
+    }
+
+    fn rotate(&self, appender: &mut RollingFileAppender) -> io::Result<()> {
+        let current = self.current();
+        let mut old = current.clone().into_os_string();
+        old.push(".1");
+        fs::rename(&current, old)?;
+        // it keeps writing to the file it has open, wherever that's moved
+        *appender = self::appender(&self.dir, &self.prefix)?;
+        Ok(())
+    }
+}
+
+// rolling::daily, but failing with an error rather than a panic when the
+// file can't be opened
+fn appender(dir: &Path, prefix: &str) -> io::Result<RollingFileAppender> {
+    RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(prefix)
+        .build(dir)
+        .map_err(io::Error::other)
+}
+
+// each record comes in one write, so a record never straddles two files
+impl Write for &LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut appender = self.appender.lock().unwrap_or_else(|err| err.into_inner());
+        let size = fs::metadata(self.current()).map_or(0, |metadata| metadata.len());
+        if size > 0 && size + buf.len() as u64 > self.max_size {
+            self.rotate(&mut appender)?;
+        }
+        appender.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut appender = self.appender.lock().unwrap_or_else(|err| err.into_inner());
+        appender.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_go_to_the_days_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = LogFile::open(&dir.path().join("jupyterm.log")).unwrap();
+        (&log).write_all(b"{\"one\":1}\n").unwrap();
+        (&log).flush().unwrap();
+        let today = format!("jupyterm.log.{}", Utc::now().format("%Y-%m-%d"));
+        assert_eq!(log.current(), dir.path().join(&today));
+        assert_eq!(fs::read_to_string(log.current()).unwrap(), "{\"one\":1}\n");
+
+        // across runs, it's appended to
+        let log = LogFile::open(&dir.path().join("jupyterm.log")).unwrap();
+        (&log).write_all(b"{\"two\":2}\n").unwrap();
+        (&log).flush().unwrap();
+        assert_eq!(
+            fs::read_to_string(log.current()).unwrap(),
+            "{\"one\":1}\n{\"two\":2}\n"
+        );
+    }
+
+    #[test]
+    fn a_full_file_is_moved_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = LogFile::open(&dir.path().join("jupyterm.log")).unwrap();
+        log.max_size = 25;
+        for record in ["first record\n", "second record\n", "third record\n"] {
+            (&log).write_all(record.as_bytes()).unwrap();
+        }
+        (&log).flush().unwrap();
+        let mut old = log.current().into_os_string();
+        old.push(".1");
+        assert_eq!(fs::read_to_string(old).unwrap(), "second record\n");
+        assert_eq!(fs::read_to_string(log.current()).unwrap(), "third record\n");
+    }
+
+    #[test]
+    fn a_path_that_cant_be_written_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert!(LogFile::open(&file.join("jupyterm.log")).is_err());
+    }
+}
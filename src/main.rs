@@ -1,285 +1,1648 @@
-use hmac::{Hmac, Mac, NewMac};
+use clap::{Parser, Subcommand};
+use crossterm::terminal;
+use jupyterm::aliases::Aliases;
+use jupyterm::client::{Cutypr, InterruptMode, Session};
+use jupyterm::config::Config;
+use jupyterm::connection::{self, ConnectionInfo};
+use jupyterm::display;
+use jupyterm::editor::{self, EditingMode, Editor};
+use jupyterm::kernel_log::KernelLog;
+use jupyterm::kernelspec::{self, KernelSpec};
+use jupyterm::log_file::LogFile;
+use jupyterm::metrics::Metrics;
+use jupyterm::named_session::NamedSession;
+use jupyterm::notebook::{
+    self, diff_notebook, load_notebook, save_notebook, Cell, Notebook, Output, Recording,
+};
+use jupyterm::notify;
+use jupyterm::out_cache::OutCache;
+use jupyterm::outputs::SavedOutputs;
+use jupyterm::paths;
+use jupyterm::protocol;
+use jupyterm::replay::{self, ReplayEngine, TranscriptFile};
+use jupyterm::server::{Kernel, Server};
+use jupyterm::signing::SignatureScheme;
+use jupyterm::socket_options::{self, SocketOption};
+use jupyterm::state::State;
+use jupyterm::transcript::Transcript;
+use jupyterm::tunnel::Tunnel;
+use jupyterm::watcher::ConnectionWatcher;
+#[cfg(feature = "async")]
+use jupyterm::websocket;
+use jupyterm::widgets::Widgets;
 use pyo3::prelude::*;
 use pyo3::types::IntoPyDict;
-use serde_json::{Map, Value};
-use sha2::Sha256;
-use std::io::{self, Write};
-use std::str;
-use zmq;
+use repl::{handle_ctrl_c, Exit, KernelSession, Options, Remote, Repl};
+use serde_json::{json, Map, Value};
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tracing::{field, info_span, Span};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
 
-type HmacSha256 = Hmac<Sha256>;
+mod repl;
+mod run_script;
+mod watch;
 
-fn start_kernel(py: Python) -> Value {
-    let locals = [("jupyterm", py.import("jupyterm").unwrap())].into_py_dict(py);
-    let code = "jupyterm.start_kernel()";
-    let kernel_info_str: &str = py
-        .eval(code, None, Some(&locals))
-        .unwrap()
-        .extract()
-        .unwrap();
-    let kernel_info: Value = serde_json::from_str(kernel_info_str).unwrap();
-    kernel_info
-}
-
-fn make_channel(context: &zmq::Context, ports: &Value, channel_type: &str) -> zmq::Socket {
-    let url = format!("tcp://127.0.0.1:{}", ports[channel_type]);
-    let channel: zmq::Socket;
-
-    match channel_type {
-        "shell" => {
-            channel = context.socket(zmq::DEALER).unwrap();
-            channel.set_linger(1000).unwrap();
-            channel.connect(&url).unwrap();
-        }
-        "iopub" => {
-            channel = context.socket(zmq::SUB).unwrap();
-            channel.set_linger(1000).unwrap();
-            channel.connect(&url).unwrap();
-            channel.set_subscribe(b"").unwrap();
-        }
-        _ => {
-            panic!("Unknown channel type!");
+// how long a new kernel gets to answer its first kernel_info_request
+const KERNEL_READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Parser)]
+#[command(version, about = "A toy Jupyter client")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        num_args = 0..=1,
+        default_missing_value = "",
+        help = "Connect to a running kernel's connection file, or its id with --url, instead of starting one, the newest if none is given"
+    )]
+    existing: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "URL",
+        conflicts_with_all = ["restore", "ssh"],
+        help = "Use the kernels of the Jupyter Server or JupyterHub server at URL, through its REST API and WebSockets"
+    )]
+    url: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        requires = "url",
+        help = "The --url server's API token, $JUPYTER_TOKEN if not given"
+    )]
+    token: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "existing",
+        help = "Pick up a session saved with :save-state, replaying it if its kernel is gone"
+    )]
+    restore: Option<PathBuf>,
+
+    #[arg(
+        long,
+        visible_alias = "ssh-host",
+        value_name = "USER@HOST",
+        requires = "existing",
+        help = "Reach the --existing kernel on another machine through ssh port forwards"
+    )]
+    ssh: Option<String>,
+
+    #[arg(
+        long,
+        requires = "existing",
+        help = "Clear the --existing kernel's variables, imports and history with %reset -f before the first cell"
+    )]
+    reset_on_start: bool,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        conflicts_with = "existing",
+        help = "Start this kernelspec instead of the default, or a preset like irust for evcxr"
+    )]
+    kernel: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Run FILE silently in the kernel before the first prompt, after the config's startup_files"
+    )]
+    startup: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = positive_rate,
+        help = "Send at most N cells a second to the kernel, a fraction like 0.5 for fewer"
+    )]
+    max_executions_per_second: Option<f64>,
+
+    #[arg(long, help = "Leave the kernel running on exit")]
+    keep_kernel: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Append the stdout and stderr of a kernel jupyterm starts to FILE"
+    )]
+    kernel_log_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Append jupyterm's own log records to PATH as JSON lines, starting it again past 10 MB"
+    )]
+    log_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "100",
+        help = "Time N empty requests to the kernel and print latency stats"
+    )]
+    benchmark: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["restore", "benchmark"],
+        help = "Run the code cells of an .ipynb file in order and save their outputs into it, instead of starting a REPL"
+    )]
+    notebook: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "notebook",
+        help = "Clear every cell's outputs and execution count before running the --notebook"
+    )]
+    strip_output: bool,
+
+    #[arg(
+        long,
+        requires = "notebook",
+        conflicts_with = "strip_output",
+        help = "Run the --notebook without saving it and fail if its outputs aren't the ones it has"
+    )]
+    check: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["existing", "url", "restore", "benchmark", "notebook"],
+        help = "Run the cells of a transcript jupyterm printed, saved from the terminal, in a new kernel and fail if what they print isn't what they printed then"
+    )]
+    replay: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["benchmark", "notebook", "replay"],
+        help = "Add every cell run in the REPL, with its outputs, to the .ipynb FILE as it goes"
+    )]
+    record: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["benchmark", "notebook", "replay"],
+        help = "Write every cell run in the REPL and what it printed to FILE as it goes, a transcript --replay can run again"
+    )]
+    output: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = ["benchmark", "notebook", "replay"],
+        help = "Keep the cells run in the REPL under the named session NAME, picking up its transcript and input history where they were left"
+    )]
+    session: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "BACKEND",
+        value_parser = ["inline"],
+        help = "Show matplotlib figures in the terminal, in Python kernels"
+    )]
+    matplotlib: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PORT",
+        help = "Serve Prometheus metrics about cells and the kernel at http://0.0.0.0:PORT/metrics"
+    )]
+    metrics_port: Option<u16>,
+
+    #[cfg(feature = "async")]
+    #[arg(
+        long,
+        value_name = "PORT",
+        help = "Run cells sent as JSON execute_requests to ws://0.0.0.0:PORT, streaming the kernel's messages back"
+    )]
+    ws_port: Option<u16>,
+
+    #[cfg(feature = "grpc")]
+    #[arg(
+        long,
+        value_name = "PORT",
+        help = "Serve the KernelService in proto/kernel.proto over gRPC on 0.0.0.0:PORT"
+    )]
+    grpc_port: Option<u16>,
+
+    #[arg(
+        long,
+        help = "Send cells with store_history off, so the kernel keeps no history of them and its execution count stays put"
+    )]
+    no_store_history: bool,
+
+    #[arg(
+        long,
+        help = "Leave the images and other outputs saved for a terminal that can't show them in their temp dir on exit"
+    )]
+    keep_outputs: bool,
+
+    #[arg(
+        long,
+        help = "Only make links of the files in tracebacks that exist on this machine"
+    )]
+    only_local_links: bool,
+
+    #[arg(long, help = "Don't print the kernel's banner before the first prompt")]
+    quiet: bool,
+
+    #[arg(
+        long,
+        help = "Warn about every message from the kernel that is missing fields the Jupyter protocol requires, or has them with the wrong types"
+    )]
+    strict_protocol: bool,
+
+    #[arg(long, help = "Print how long each cell took to run")]
+    timing: bool,
+
+    #[arg(long, help = "Write every iopub message to stdout as a line of JSON")]
+    json: bool,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Shut down the kernel and exit after this long without input at the prompt"
+    )]
+    repl_timeout: Option<u64>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    #[command(about = "List the kernels in the Jupyter runtime directory, or on the --url server")]
+    List,
+    #[command(about = "Ask a running kernel to exit")]
+    Shutdown {
+        #[arg(
+            required_unless_present = "all",
+            help = "Connection file, a path or a name in the runtime directory, or a kernel id with --url"
+        )]
+        file: Option<String>,
+        #[arg(
+            long,
+            conflicts_with = "file",
+            help = "Every running kernel in the runtime directory, or on the --url server"
+        )]
+        all: bool,
+    },
+    #[command(about = "Interrupt the cell a running kernel is busy with")]
+    Interrupt {
+        #[arg(
+            help = "Connection file, a path or a name in the runtime directory, or a kernel id with --url"
+        )]
+        file: String,
+    },
+    #[command(
+        about = "Run a script in a kernel, and again in the same kernel every time it changes"
+    )]
+    Watch {
+        #[arg(help = "The script to run")]
+        script: PathBuf,
+        #[arg(long, help = "Restart the kernel before every run after the first")]
+        fresh: bool,
+        #[arg(
+            long,
+            value_name = "NAME",
+            help = "Start this kernelspec instead of the default, or a preset like irust for evcxr"
+        )]
+        kernel: Option<String>,
+        #[arg(
+            long,
+            value_name = "FILE",
+            conflicts_with_all = ["fresh", "kernel"],
+            help = "Run it in a running kernel, by its connection file, and leave the kernel running"
+        )]
+        existing: Option<String>,
+    },
+    #[command(
+        about = "Run a script's cells in a kernel, from one # %% or # In[n] line to the next"
+    )]
+    Run {
+        #[arg(help = "The script to run")]
+        script: PathBuf,
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Save what each cell output to FILE, for jupyterm diff --against"
+        )]
+        save_outputs: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "NAME",
+            help = "Start this kernelspec instead of the default, or a preset like irust for evcxr"
+        )]
+        kernel: Option<String>,
+        #[arg(
+            long,
+            value_name = "FILE",
+            conflicts_with = "kernel",
+            help = "Run it in a running kernel, by its connection file, and leave the kernel running"
+        )]
+        existing: Option<String>,
+    },
+    #[command(
+        about = "Run a script like jupyterm run, and fail if its cells' outputs aren't the saved ones"
+    )]
+    Diff {
+        #[arg(help = "The script to run")]
+        script: PathBuf,
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "The outputs saved by jupyterm run --save-outputs"
+        )]
+        against: PathBuf,
+        #[arg(
+            long,
+            value_name = "NAME",
+            help = "Start this kernelspec instead of the default, or a preset like irust for evcxr"
+        )]
+        kernel: Option<String>,
+        #[arg(
+            long,
+            value_name = "FILE",
+            conflicts_with = "kernel",
+            help = "Run it in a running kernel, by its connection file, and leave the kernel running"
+        )]
+        existing: Option<String>,
+    },
+}
+
+fn positive_rate(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!("expected a number above 0, not {:?}", value)),
+    }
+}
+
+// every client gets its own, kernels route replies by it
+fn session_id() -> String {
+    static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+    match CLIENTS.fetch_add(1, Ordering::SeqCst) {
+        0 => format!("jupyterm-{}", process::id()),
+        n => format!("jupyterm-{}-{}", process::id(), n),
+    }
+}
+
+fn session(info: &ConnectionInfo) -> Session {
+    Session {
+        key: info.key.clone(),
+        session_id: session_id(),
+        signature_scheme: SignatureScheme::for_connection(&info.signature_scheme, &info.key),
+    }
+}
+
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).unwrap();
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+fn list_kernels() {
+    let files = connection::connection_files();
+    if files.is_empty() {
+        println!("no kernels in the runtime directory");
+    }
+    for path in files {
+        let status = match ConnectionInfo::read(&path) {
+            Ok(info) if info.is_alive() => "running",
+            Ok(_) => "not responding",
+            Err(_) => "unreadable",
+        };
+        println!("{}  {}", connection::short_name(&path), status);
+    }
+}
+
+// like --existing, an empty name is the newest connection file
+fn find_connection(name: &str) -> Result<(ConnectionInfo, PathBuf), String> {
+    let path = match connection::find_connection_file(name) {
+        Some(path) => path,
+        None if name.is_empty() => {
+            return Err("no connection files in the runtime directory".to_string())
         }
+        None => return Err(format!("no connection file {}", name)),
     };
-    channel
+    match connection::validate_connection_file(&path) {
+        Ok(info) => Ok((info, path)),
+        Err(errors) => {
+            let mut message = format!("{} isn't a usable connection file:", path.display());
+            for error in errors {
+                message += &format!("\n    {}", error);
+            }
+            Err(message)
+        }
+    }
 }
 
-struct Session {
-    // key: Value,
-    session_id: String,
+fn resolve(name: &str) -> (ConnectionInfo, PathBuf) {
+    find_connection(name).unwrap_or_else(|err| {
+        eprintln!("jupyterm: {}", err);
+        process::exit(1);
+    })
 }
 
-struct Cutypr {
-    context: zmq::Context,
-    session: Session,
-    ports: Value,
-    message_count: i32,
-    shell_channel: Option<zmq::Socket>,
-    iopub_channel: Option<zmq::Socket>,
+// a client with only the control channel open
+fn control_client(info: &ConnectionInfo) -> Cutypr {
+    let mut client = Cutypr::new(session(info), info.ports());
+    client.initialize_control_channel();
+    client
 }
 
-impl Cutypr {
-    fn new(session: Session, ports: Value) -> Cutypr {
-        Cutypr {
-            context: zmq::Context::new(),
-            session: session,
-            ports: ports,
-            message_count: 1,
-            shell_channel: None,
-            iopub_channel: None,
-        }
+fn replied_ok(reply: Option<Map<String, Value>>) -> bool {
+    match reply {
+        Some(reply) => Value::Object(reply)["content"]["status"] == "ok",
+        None => false,
     }
+}
 
-    fn initialize_channels(&mut self) {
-        self.shell_channel = Some(make_channel(&self.context, &self.ports, "shell"));
-        self.iopub_channel = Some(make_channel(&self.context, &self.ports, "iopub"));
+// true if the kernel agreed to exit, its connection file goes with it
+fn shutdown_kernel(info: &ConnectionInfo, path: &Path) -> bool {
+    let name = connection::short_name(path);
+    if !replied_ok(control_client(info).shutdown(false)) {
+        eprintln!("jupyterm: {} didn't answer the shutdown_request", name);
+        return false;
     }
+    let _ = fs::remove_file(path);
+    println!("{}  shut down", name);
+    true
+}
 
-    fn make_message(&self, message_type: &str, content: Map<String, Value>) -> Map<String, Value> {
-        let mut msg = Map::new();
+fn shutdown_all() -> bool {
+    let kernels: Vec<(ConnectionInfo, PathBuf)> = connection::connection_files()
+        .into_iter()
+        .filter_map(|path| Some((ConnectionInfo::read(&path).ok()?, path)))
+        .filter(|(info, _)| info.is_alive())
+        .collect();
+    if kernels.is_empty() {
+        println!("no running kernels in the runtime directory");
+        return true;
+    }
 
-        let msg_id = format!("{}_{}", self.session.session_id, self.message_count);
-        // self.message_count += 1;
+    for (_, path) in &kernels {
+        println!("{}", connection::short_name(path));
+    }
+    if !confirm(&format!("shut down these {} kernels?", kernels.len())) {
+        return false;
+    }
 
-        let mut header = Map::new();
-        header.insert("msg_id".to_string(), Value::String(msg_id.clone()));
-        header.insert(
-            "msg_type".to_string(),
-            Value::String(message_type.to_string()),
-        );
-        header.insert("username".to_string(), Value::String("vinayak".to_string()));
-        header.insert(
-            "session".to_string(),
-            Value::String(self.session.session_id.to_string()),
+    let mut all_ok = true;
+    for (info, path) in &kernels {
+        all_ok &= shutdown_kernel(info, path);
+    }
+    all_ok
+}
+
+fn interrupt_kernel(info: &ConnectionInfo, path: &Path) -> bool {
+    if !replied_ok(control_client(info).interrupt()) {
+        eprintln!(
+            "jupyterm: {} didn't answer the interrupt_request",
+            connection::short_name(path)
         );
+        return false;
+    }
+    true
+}
+
+// --url, with the token from the environment like Jupyter's own tools
+fn server(cli: &Cli) -> Option<Server> {
+    let token = cli.token.clone().or_else(|| env::var("JUPYTER_TOKEN").ok());
+    cli.url
+        .as_deref()
+        .map(|url| Server::new(url, token.as_deref()))
+}
 
-        msg.insert("header".to_string(), Value::Object(header));
-        msg.insert("msg_id".to_string(), Value::String(msg_id.clone()));
-        msg.insert(
-            "msg_type".to_string(),
-            Value::String(message_type.to_string()),
+fn list_server_kernels(server: &Server) -> bool {
+    let kernels = match server.kernels() {
+        Ok(kernels) => kernels,
+        Err(err) => {
+            eprintln!("jupyterm: couldn't list the kernels: {}", err);
+            return false;
+        }
+    };
+    if kernels.is_empty() {
+        println!("no kernels on {}", server.url());
+    }
+    for kernel in kernels {
+        println!(
+            "{}  {}  {}, {} connections",
+            kernel.id, kernel.name, kernel.execution_state, kernel.connections
         );
-        msg.insert("content".to_string(), Value::Object(content));
-        msg.insert("metadata".to_string(), Value::Object(Map::new()));
-        msg.insert("parent_header".to_string(), Value::Object(Map::new()));
+    }
+    true
+}
 
-        msg
+// like resolve(), an empty id is the most recently used kernel
+fn find_server_kernel(server: &Server, id: &str) -> Kernel {
+    match server.find_kernel(id) {
+        Ok(Some(kernel)) => kernel,
+        Ok(None) if id.is_empty() => {
+            eprintln!("jupyterm: no kernels on {}", server.url());
+            process::exit(1);
+        }
+        Ok(None) => {
+            eprintln!("jupyterm: no single kernel {} on {}", id, server.url());
+            process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("jupyterm: couldn't list the kernels: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn shutdown_server_kernel(server: &Server, kernel: &Kernel) -> bool {
+    match server.shutdown_kernel(&kernel.id) {
+        Ok(()) => {
+            println!("{}  shut down", kernel.id);
+            true
+        }
+        Err(err) => {
+            eprintln!("jupyterm: couldn't shut down {}: {}", kernel.id, err);
+            false
+        }
     }
+}
 
-    fn sign(&self, msg_list: &Vec<String>) -> String {
-        let mut signature = HmacSha256::new_varkey(self.session.session_id.as_bytes()).unwrap();
-        for message in msg_list {
-            signature.update(message.as_bytes());
+fn shutdown_all_server_kernels(server: &Server) -> bool {
+    let kernels = match server.kernels() {
+        Ok(kernels) => kernels,
+        Err(err) => {
+            eprintln!("jupyterm: couldn't list the kernels: {}", err);
+            return false;
         }
+    };
+    if kernels.is_empty() {
+        println!("no kernels on {}", server.url());
+        return true;
+    }
 
-        let result = signature.finalize().into_bytes();
-        hex::encode(result)
+    for kernel in &kernels {
+        println!("{}  {}", kernel.id, kernel.name);
+    }
+    if !confirm(&format!("shut down these {} kernels?", kernels.len())) {
+        return false;
     }
 
-    fn serialize(&self, msg: Map<String, Value>) -> Vec<String> {
-        let mut msg_list: Vec<String> = Vec::new();
-        msg_list.push(msg["header"].to_string());
-        msg_list.push(msg["parent_header"].to_string());
-        msg_list.push(msg["metadata"].to_string());
-        msg_list.push(msg["content"].to_string());
+    let mut all_ok = true;
+    for kernel in &kernels {
+        all_ok &= shutdown_server_kernel(server, kernel);
+    }
+    all_ok
+}
 
-        // sign
-        let signature = self.sign(&msg_list);
+fn interrupt_server_kernel(server: &Server, kernel: &Kernel) -> bool {
+    if let Err(err) = server.interrupt_kernel(&kernel.id) {
+        eprintln!("jupyterm: couldn't interrupt {}: {}", kernel.id, err);
+        return false;
+    }
+    true
+}
 
-        msg_list.insert(0, String::from(signature));
-        msg_list.insert(0, String::from("<IDS|MSG>"));
-        msg_list
+// the --url kernel for the REPL, and whether jupyterm started it
+fn server_kernel(server: &Server, cli: &Cli, kernel: Option<&str>) -> (Kernel, bool) {
+    if let Some(id) = &cli.existing {
+        return (find_server_kernel(server, id), false);
+    }
+    // the server has its own kernelspecs, only the name is translated
+    let name = kernel.map(kernelspec::resolve);
+    match server.start_kernel(name) {
+        Ok(kernel) => (kernel, true),
+        Err(err) => {
+            eprintln!(
+                "jupyterm: couldn't start a kernel on {}: {}",
+                server.url(),
+                err
+            );
+            process::exit(1);
+        }
     }
+}
+
+// what launch_kernel started
+struct Launched {
+    info: ConnectionInfo,
+    connection_file: PathBuf,
+    // the kernelspec, which jupyter_client picked if --kernel didn't
+    kernel_name: String,
+    // if jupyter_client knows it
+    pid: Option<u32>,
+    interrupt_mode: InterruptMode,
+    // the HANDLE of the event Windows kernels wait on for interrupts
+    #[cfg_attr(not(windows), allow(dead_code))]
+    interrupt_event: Option<i64>,
+    log: Option<KernelLog>,
+}
 
-    fn execute(&self, code: &String) {
-        // make content
-        let mut content = Map::new();
-        content.insert("code".to_string(), Value::String(code.clone()));
-        content.insert("silent".to_string(), Value::Bool(false));
-        content.insert("store_history".to_string(), Value::Bool(true));
-        content.insert("user_expressions".to_string(), Value::Null);
-        content.insert("allow_stdin".to_string(), Value::Bool(true));
-        content.insert("stop_on_error".to_string(), Value::Bool(true));
+// the kernel's end of a pipe or file, as the fd (or HANDLE) Python takes
+#[cfg(unix)]
+fn into_raw<T: std::os::unix::io::IntoRawFd>(sink: T) -> i64 {
+    sink.into_raw_fd() as i64
+}
 
-        // make_message(execute_request, content)
-        let msg = self.make_message("execute_request", content);
+#[cfg(windows)]
+fn into_raw<T: std::os::windows::io::IntoRawHandle>(sink: T) -> i64 {
+    sink.into_raw_handle() as isize as i64
+}
 
-        // serialize
-        let msg_list = self.serialize(msg);
+// Where a kernel we start writes its own stdout and stderr. A kernel that
+// will be left running can't write to a pipe that goes away with us, so it
+// gets the log file itself, or the terminal.
+fn kernel_output(keep_kernel: bool, log_file: Option<&Path>) -> (Option<KernelLog>, Option<i64>) {
+    let sink = if keep_kernel {
+        log_file.map(|path| {
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map(|file| (None, into_raw(file)))
+        })
+    } else {
+        Some(KernelLog::new(log_file).map(|(log, writer)| (Some(log), into_raw(writer))))
+    };
+    match sink {
+        Some(Ok((log, fd))) => (log, Some(fd)),
+        Some(Err(err)) => {
+            eprintln!("jupyterm: not capturing the kernel's output: {}", err);
+            (None, None)
+        }
+        None => (None, None),
+    }
+}
 
-        // send_multipart
-        self.shell_channel
-            .as_ref()
-            .unwrap()
-            .send_multipart(&msg_list, 0)
-            .unwrap();
+// Start a kernel through jupyter_client and make sure its connection file is
+// on disk, so a kernel we leave running can be found again. None once it's
+// said why it couldn't.
+fn launch_kernel(
+    kernel: Option<&str>,
+    keep_kernel: bool,
+    log_file: Option<&Path>,
+) -> Option<Launched> {
+    let kernel_name = match kernel {
+        Some(name) => Some(kernel_name(name)?),
+        None => None,
+    };
+    // jupyter_client starts python3 when it isn't given a name
+    if let Some((_, spec)) = KernelSpec::find(kernel_name.unwrap_or("python3")) {
+        for warning in kernelspec::check_kernel_spec_compatibility(&spec) {
+            eprintln!("jupyterm: {}: {}", spec.display_name, warning);
+        }
     }
+    let ports = match connection::find_free_ports(5) {
+        Ok(ports) => ports,
+        Err(err) => {
+            eprintln!("jupyterm: couldn't find ports for the kernel: {}", err);
+            return None;
+        }
+    };
+    // the connection file is jupyterm's, jupyter_client starts the kernel with it
+    let info = ConnectionInfo {
+        shell_port: ports[0],
+        iopub_port: ports[1],
+        stdin_port: ports[2],
+        control_port: ports[3],
+        hb_port: ports[4],
+        ip: "127.0.0.1".to_string(),
+        key: connection::random_key(),
+        transport: "tcp".to_string(),
+        signature_scheme: "hmac-sha256".to_string(),
+        kernel_name: kernel_name.unwrap_or_default().to_string(),
+    };
+    let dir = paths::runtime_dir().unwrap_or_default();
+    let path = match connection::write_connection_file(&info, &dir) {
+        Ok(path) => path,
+        Err(err) => {
+            eprintln!(
+                "jupyterm: couldn't write a connection file in {}: {}",
+                dir.display(),
+                err
+            );
+            return None;
+        }
+    };
+    let (log, log_fd) = kernel_output(keep_kernel, log_file);
+    let kernel_info = match Python::with_gil(|py| start_kernel(py, kernel_name, log_fd, &path)) {
+        Some(kernel_info) => kernel_info,
+        None => {
+            eprintln!("jupyterm: couldn't start the kernel");
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+    };
 
-    fn deserialize(&self, msg_frames: &[Vec<u8>]) -> Map<String, Value> {
-        let header = serde_json::from_str(str::from_utf8(&msg_frames[0]).unwrap()).unwrap();
-        let parent_header = serde_json::from_str(str::from_utf8(&msg_frames[1]).unwrap()).unwrap();
-        let metadata = serde_json::from_str(str::from_utf8(&msg_frames[2]).unwrap()).unwrap();
-        let content = serde_json::from_str(str::from_utf8(&msg_frames[3]).unwrap()).unwrap();
+    Some(Launched {
+        info,
+        connection_file: path,
+        kernel_name: kernel_info["kernel_name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        pid: kernel_info["pid"].as_u64().map(|pid| pid as u32),
+        // the kernelspec default, and what Python kernels expect
+        interrupt_mode: kernel_info["interrupt_mode"]
+            .as_str()
+            .and_then(InterruptMode::parse)
+            .unwrap_or(InterruptMode::Signal),
+        interrupt_event: kernel_info["interrupt_event"].as_i64(),
+        log,
+    })
+}
 
-        let mut msg = Map::new();
-        msg.insert("header".to_string(), Value::Object(header));
-        msg.insert("parent_header".to_string(), Value::Object(parent_header));
-        msg.insert("metadata".to_string(), Value::Object(metadata));
-        msg.insert("content".to_string(), Value::Object(content));
+// Start a kernel on the connection file jupyterm wrote. None once Python
+// has printed why it couldn't, like a kernelspec that isn't installed.
+fn start_kernel(
+    py: Python,
+    kernel_name: Option<&str>,
+    log_fd: Option<i64>,
+    connection_file: &Path,
+) -> Option<Value> {
+    let locals = [("jupyterm", py.import("jupyterm").unwrap())].into_py_dict(py);
+    let log_fd = log_fd.map_or("None".to_string(), |fd| fd.to_string());
+    // a JSON string is a Python string literal too
+    let kernel_name = kernel_name.map_or("None".to_string(), |name| json!(name).to_string());
+    let connection_file = connection_file.to_string_lossy();
+    let mut env = Map::new();
+    // where the kernel can find its own connection file
+    env.insert("JPY_SESSION_NAME".to_string(), json!(connection_file));
+    // reprs that fit the terminal, for pandas and numpy through shutil
+    if let Ok((columns, lines)) = terminal::size() {
+        env.insert("COLUMNS".to_string(), json!(columns.to_string()));
+        env.insert("LINES".to_string(), json!(lines.to_string()));
+    }
+    let code = format!(
+        "jupyterm.start_kernel({}, {}, {}, {})",
+        log_fd,
+        kernel_name,
+        Value::Object(env),
+        json!(connection_file)
+    );
+    let kernel_info_str: &str = match py.eval(&code, None, Some(locals)) {
+        Ok(kernel_info) => kernel_info.extract().unwrap(),
+        Err(err) => {
+            err.print(py);
+            return None;
+        }
+    };
+    Some(serde_json::from_str(kernel_info_str).unwrap())
+}
 
-        msg
+// the kernelspec name for --kernel, making sure a preset's kernel is installed
+fn kernel_name(name: &str) -> Option<&str> {
+    let spec = kernelspec::resolve(name);
+    if let Some(hint) = kernelspec::install_hint(name) {
+        if KernelSpec::find(spec).is_none() {
+            eprintln!(
+                "jupyterm: {} needs the {:?} kernelspec, install it with\n    {}",
+                name, spec, hint
+            );
+            return None;
+        }
     }
+    Some(spec)
+}
+
+// The config's socket_options, read once for every session. Broken ones
+// are reported and none of them set.
+fn socket_options() -> &'static [(String, SocketOption)] {
+    static OPTIONS: OnceLock<Vec<(String, SocketOption)>> = OnceLock::new();
+    OPTIONS.get_or_init(|| {
+        socket_options::from_table(&Config::load().socket_options).unwrap_or_else(|err| {
+            eprintln!("jupyterm: ignoring socket_options in the config: {}", err);
+            Vec::new()
+        })
+    })
+}
 
-    fn msg_ready(&self) -> bool {
-        self.iopub_channel
-            .as_ref()
-            .unwrap()
-            .poll(zmq::POLLIN, 10)
-            .expect("client failed polling")
-            > 0
+// A session for the kernel `info` describes, reached through `ports` when
+// they're forwarded, once it has answered a kernel_info_request. None once
+// it's said why the kernel didn't.
+fn open_session(
+    name: &str,
+    info: ConnectionInfo,
+    connection_file: PathBuf,
+    ports: Value,
+    launched: Option<Launched>,
+    owned: bool,
+) -> Option<KernelSession> {
+    let mut client = Cutypr::new(session(&info), ports);
+    for (channel, option) in socket_options() {
+        if let Err(err) = client.set_socket_option(channel, *option) {
+            eprintln!("jupyterm: {}", err);
+        }
+    }
+    client.initialize_channels();
+    // existing kernels can be anywhere, they keep the interrupt_request default
+    let mut kernel_log = None;
+    // what a kernel jupyterm started got in its environment
+    let terminal_size = launched.as_ref().and_then(|_| terminal::size().ok());
+    let started = launched.is_some();
+    if let Some(kernel) = launched {
+        client.set_kernel_name(&kernel.kernel_name);
+        if let Some(pid) = kernel.pid {
+            client.set_kernel_pid(pid);
+        }
+        client.set_interrupt_mode(kernel.interrupt_mode);
+        #[cfg(windows)]
+        if let Some(event) = kernel.interrupt_event {
+            client.set_interrupt_event(event as isize);
+        }
+        kernel_log = kernel.log;
     }
 
-    fn get_msg(&self) -> Map<String, Value> {
-        let msg_list = self
-            .iopub_channel
-            .as_ref()
-            .unwrap()
-            .recv_multipart(0)
-            .unwrap();
+    let kernel_info = match client.wait_for_kernel_ready(KERNEL_READY_TIMEOUT) {
+        Ok(reply) => {
+            // nothing says which kernelspec an existing kernel came from
+            if !started {
+                client.set_kernel_name(&reply.implementation);
+            }
+            if let Some(warning) = reply.protocol_warning() {
+                eprintln!("jupyterm: {}", warning);
+            }
+            reply
+        }
+        Err(err) => {
+            let heading = format!("kernel is not responding: {}", err);
+            match &kernel_log {
+                Some(log) => log.report(&heading),
+                None => eprintln!("jupyterm: {}", heading),
+            }
+            return None;
+        }
+    };
 
-        // https://gitlab.com/srwalker101/rust-jupyter-client/-/blob/dev/src/wire.rs#L28
-        let delim_idx = msg_list
-            .iter()
-            .position(|r| String::from_utf8(r.to_vec()).unwrap() == "<IDS|MSG>")
-            .unwrap();
-        let msg_frames = &msg_list[delim_idx + 2..];
+    let root = Span::current();
+    root.record("kernel.name", client.kernel_name());
+    root.record("session.id", client.session_id());
 
-        // deserialize
-        let msg = self.deserialize(&msg_frames);
+    // kernels jupyterm started are only restarted by jupyterm
+    let watcher = if owned || connection_file.as_os_str().is_empty() {
+        None
+    } else {
+        ConnectionWatcher::new(&connection_file)
+            .map_err(|err| eprintln!("jupyterm: {}", err))
+            .ok()
+    };
 
-        msg
+    Some(KernelSession {
+        name: name.to_string(),
+        client,
+        connection: info,
+        connection_file,
+        owned,
+        kernel_info,
+        terminal_size,
+        execution_count: 1,
+        transcript: Transcript::new(),
+        kernel_log,
+        tunnel: None,
+        remote: None,
+        missed: Vec::new(),
+        watches: Vec::new(),
+        watcher,
+        debugger: None,
+        last_output: None,
+        last_cell_text: None,
+        last_traceback: None,
+        widgets: Widgets::default(),
+        out_cache: OutCache::new(Config::default().out_cache_size),
+        cells: Vec::new(),
+    })
+}
+
+// for :session new
+fn launch_session(
+    name: &str,
+    kernel: Option<&str>,
+    keep_kernel: bool,
+    log_file: Option<&Path>,
+) -> Option<KernelSession> {
+    let launched = launch_kernel(kernel, keep_kernel, log_file)?;
+    let info = launched.info.clone();
+    let path = launched.connection_file.clone();
+    let ports = info.ports();
+    open_session(name, info, path, ports, Some(launched), true)
+}
+
+// for :session attach
+fn attach_session(name: &str, file: &str) -> Option<KernelSession> {
+    let (info, path) = match find_connection(file) {
+        Ok(found) => found,
+        Err(err) => {
+            eprintln!("jupyterm: {}", err);
+            return None;
+        }
+    };
+    let ports = info.ports();
+    open_session(name, info, path, ports, None, false)
+}
+
+fn benchmark(client: &mut Cutypr, n: u32) {
+    let mut latencies = match client.roundtrip_latencies(n) {
+        Ok(latencies) => latencies,
+        Err(err) => {
+            eprintln!("jupyterm: benchmark failed: {}", err);
+            process::exit(1);
+        }
+    };
+    if latencies.is_empty() {
+        return;
     }
+    latencies.sort();
+
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let mean = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+    println!("roundtrip latency over {} requests", latencies.len());
+    println!("  min     {:8.3} ms", ms(latencies[0]));
+    println!("  median  {:8.3} ms", ms(latencies[latencies.len() / 2]));
+    println!("  mean    {:8.3} ms", ms(mean));
+    println!("  max     {:8.3} ms", ms(latencies[latencies.len() - 1]));
+}
+
+// Spans like execute_and_collect's, sent to the OTLP collector at
+// $OTEL_EXPORTER_OTLP_ENDPOINT, localhost:4318 without one. Each goes out as
+// it ends, since jupyterm often exits without running destructors.
+#[cfg(feature = "opentelemetry")]
+fn otel_layer<S>() -> Option<impl tracing_subscriber::Layer<S>>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+
+    let exporter = match opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(err) => {
+            eprintln!("jupyterm: not sending traces: {}", err);
+            return None;
+        }
+    };
+    let provider = SdkTracerProvider::builder()
+        .with_resource(Resource::builder().with_service_name("jupyterm").build())
+        .with_simple_exporter(exporter)
+        .build();
+    let tracer = provider.tracer("jupyterm");
+    // only jupyterm's own spans, not those of the crates it uses
+    let filter =
+        tracing_subscriber::filter::Targets::new().with_target("jupyterm", tracing::Level::INFO);
+    Some(
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(filter),
+    )
+}
+
+// JSON lines for --log-file, with the fields of the span they're in, which
+// has the kernel, session and cell for the root one main enters. RUST_LOG filters them too,
+// without it they're jupyterm's own from info up.
+fn log_file_layer<S>(path: &Path) -> Option<impl tracing_subscriber::Layer<S>>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    let file = match LogFile::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("jupyterm: not logging to {}: {}", path.display(), err);
+            return None;
+        }
+    };
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("jupyterm=info"));
+    Some(
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(Arc::new(file))
+            .with_filter(filter),
+    )
 }
 
 fn main() {
-    let mut kernel_info: Value = serde_json::from_str("{}").unwrap();
+    let cli = Cli::parse();
+
+    let mut filter = EnvFilter::from_default_env();
+    // its warnings are the point of it, whatever RUST_LOG says
+    if cli.strict_protocol {
+        protocol::set_strict(true);
+        filter = filter.add_directive("jupyterm::protocol=warn".parse().unwrap());
+    }
+    let registry = tracing_subscriber::registry().with(
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_filter(filter),
+    );
+    let registry = registry.with(cli.log_file.as_deref().and_then(log_file_layer));
+    #[cfg(feature = "opentelemetry")]
+    let registry = registry.with(otel_layer());
+    registry.init();
+    // Every record from here on is in it, so --log-file's all say which
+    // kernel and cell they're about, once there's a session to say it.
+    let root = info_span!(
+        "jupyterm",
+        kernel.name = field::Empty,
+        session.id = field::Empty,
+        execution_count = field::Empty,
+    );
+    let _root = root.enter();
+
+    let ok = match (&cli.command, server(&cli)) {
+        (Some(Commands::List), Some(server)) => list_server_kernels(&server),
+        (Some(Commands::Shutdown { all: true, .. }), Some(server)) => {
+            shutdown_all_server_kernels(&server)
+        }
+        (Some(Commands::Shutdown { file, .. }), Some(server)) => {
+            let kernel = find_server_kernel(&server, file.as_deref().unwrap_or_default());
+            shutdown_server_kernel(&server, &kernel)
+        }
+        (Some(Commands::Interrupt { file }), Some(server)) => {
+            let kernel = find_server_kernel(&server, file);
+            interrupt_server_kernel(&server, &kernel)
+        }
+        (Some(Commands::List), None) => {
+            list_kernels();
+            true
+        }
+        (Some(Commands::Shutdown { all: true, .. }), None) => shutdown_all(),
+        (Some(Commands::Shutdown { file, .. }), None) => {
+            let (info, path) = resolve(file.as_deref().unwrap_or_default());
+            shutdown_kernel(&info, &path)
+        }
+        (Some(Commands::Interrupt { file }), None) => {
+            let (info, path) = resolve(file);
+            interrupt_kernel(&info, &path)
+        }
+        (Some(Commands::Watch { .. }), Some(_)) => {
+            eprintln!("jupyterm: watch starts a kernel of its own, not one on a --url server");
+            false
+        }
+        (Some(Commands::Run { .. } | Commands::Diff { .. }), Some(_)) => {
+            eprintln!(
+                "jupyterm: run and diff start a kernel of their own, not one on a --url server"
+            );
+            false
+        }
+        (
+            Some(Commands::Run {
+                script,
+                save_outputs,
+                kernel,
+                existing,
+            }),
+            None,
+        ) => run_script::run(
+            script,
+            save_outputs.as_deref(),
+            kernel.as_deref(),
+            existing.as_deref(),
+            cli.kernel_log_file.as_deref(),
+        ),
+        (
+            Some(Commands::Diff {
+                script,
+                against,
+                kernel,
+                existing,
+            }),
+            None,
+        ) => run_script::diff(
+            script,
+            against,
+            kernel.as_deref(),
+            existing.as_deref(),
+            cli.kernel_log_file.as_deref(),
+        ),
+        (
+            Some(Commands::Watch {
+                script,
+                fresh,
+                kernel,
+                existing,
+            }),
+            None,
+        ) => watch::watch(
+            script,
+            *fresh,
+            kernel.as_deref(),
+            existing.as_deref(),
+            cli.kernel_log_file.as_deref(),
+        ),
+        (None, _) => {
+            run(&cli);
+            true
+        }
+    };
+    if !ok {
+        process::exit(1);
+    }
+}
 
-    // start the Python kernel
-    // TODO: also shut it down
-    Python::with_gil(|py| {
-        kernel_info = start_kernel(py);
+fn run(cli: &Cli) {
+    let restored = cli.restore.as_ref().map(|path| match State::load(path) {
+        Ok(state) => state,
+        Err(err) => {
+            eprintln!("jupyterm: couldn't restore {}: {}", path.display(), err);
+            process::exit(1);
+        }
     });
 
-    let session = Session {
-        // key: kernel_info["key"].clone(),
-        session_id: String::from("rust"),
+    let notebook = cli.notebook.as_ref().map(|path| match load_notebook(path) {
+        Ok(nb) => nb,
+        Err(err) => {
+            eprintln!("jupyterm: couldn't read {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    });
+    let transcript = cli
+        .replay
+        .as_ref()
+        .map(|path| match fs::read_to_string(path) {
+            Ok(text) => replay::parse_transcript(&text),
+            Err(err) => {
+                eprintln!("jupyterm: couldn't read {}: {}", path.display(), err);
+                process::exit(1);
+            }
+        });
+    if transcript.as_ref().is_some_and(Vec::is_empty) {
+        eprintln!("jupyterm: there are no In [N] cells in the transcript");
+        process::exit(1);
+    }
+    // a notebook brings the name of the kernel it was written with
+    let kernel = cli.kernel.as_deref().or_else(|| {
+        let spec = notebook.as_ref()?.metadata.kernelspec.as_ref()?;
+        Some(spec.name.as_str())
+    });
+
+    // only kernels we started are ours to shut down
+    let mut launched = None;
+    let mut remote = None;
+    let (info, connection_file, owned) = match (server(cli), &restored, &cli.existing) {
+        // nothing to write a connection file for, the local end goes away with us
+        (Some(server), _, _) => {
+            let (kernel, owned) = server_kernel(&server, cli, kernel);
+            let (bridge, info) = match server.connect(&kernel, &session_id()) {
+                Ok(connected) => connected,
+                Err(err) => {
+                    eprintln!(
+                        "jupyterm: couldn't connect to kernel {}: {}",
+                        kernel.id, err
+                    );
+                    if owned {
+                        let _ = server.shutdown_kernel(&kernel.id);
+                    }
+                    process::exit(1);
+                }
+            };
+            remote = Some(Remote {
+                server,
+                kernel,
+                bridge,
+            });
+            (info, PathBuf::new(), owned)
+        }
+        (None, Some(state), _) if state.connection.is_alive() => (
+            state.connection.clone(),
+            state.connection_file.clone(),
+            false,
+        ),
+        (None, Some(_), _) | (None, None, None) => {
+            let kernel = launch_kernel(kernel, cli.keep_kernel, cli.kernel_log_file.as_deref())
+                .unwrap_or_else(|| process::exit(1));
+            let started = (kernel.info.clone(), kernel.connection_file.clone(), true);
+            launched = Some(kernel);
+            started
+        }
+        (None, None, Some(name)) => {
+            let (info, path) = resolve(name);
+            (info, path, false)
+        }
     };
 
-    let mut client = Cutypr::new(session, kernel_info["ports"].clone());
-    client.initialize_channels();
+    // the connection file stays as it is, the client talks to the local ends
+    let (tunnel, ports) = match &cli.ssh {
+        Some(host) => match Tunnel::open(host, &info) {
+            Ok((tunnel, local)) => (Some(tunnel), local.ports()),
+            Err(err) => {
+                eprintln!(
+                    "jupyterm: couldn't reach the kernel through {}: {}",
+                    host, err
+                );
+                process::exit(1);
+            }
+        },
+        None => (None, info.ports()),
+    };
+
+    // websocket clients get sessions of their own on the same kernel
+    #[cfg(feature = "async")]
+    let ws_bridge = cli
+        .ws_port
+        .map(|port| (port, session(&info), ports.clone()));
+    #[cfg(feature = "grpc")]
+    let grpc = cli
+        .grpc_port
+        .map(|port| (port, session(&info), ports.clone()));
+    let mut session = match open_session("main", info, connection_file, ports, launched, owned) {
+        Some(session) => session,
+        None => process::exit(1),
+    };
+    // the tunnel goes to the ports the kernel had, new ones need a new tunnel
+    if tunnel.is_some() {
+        session.watcher = None;
+    }
+    session.tunnel = tunnel;
+    if let Some(remote) = &remote {
+        session.client.set_kernel_name(&remote.kernel.name);
+    }
+    session.client.set_rate_limit(cli.max_executions_per_second);
+    session.remote = remote;
+    if cli.reset_on_start {
+        match session.client.reset_kernel_state() {
+            Ok(()) => session.transcript.clear(),
+            Err(err) => eprintln!("jupyterm: couldn't reset the kernel: {}", err),
+        }
+    }
+
+    if let Some(n) = cli.benchmark {
+        benchmark(&mut session.client, n);
+        if owned && !cli.keep_kernel {
+            close_session(&mut session, false, false);
+        }
+        return;
+    }
+
+    if let (Some(mut nb), Some(path)) = (notebook, &cli.notebook) {
+        let saved = nb.clone();
+        let mut ok = run_notebook(&mut session.client, &mut nb, cli.strip_output);
+        if cli.check {
+            let diff = diff_notebook(&saved, &nb);
+            if !diff.is_empty() {
+                eprintln!("jupyterm: {} has different outputs now", path.display());
+                print!("{}", diff);
+                ok = false;
+            }
+        } else if let Err(err) = save_notebook(&nb, path) {
+            eprintln!("jupyterm: couldn't save {}: {}", path.display(), err);
+        }
+        if owned {
+            close_session(&mut session, cli.keep_kernel, false);
+        }
+        if !ok {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(cells) = transcript {
+        let ok = match ReplayEngine::new(&mut session.client).run(&cells) {
+            Ok(mismatches) => {
+                for mismatch in &mismatches {
+                    print!("{}", mismatch);
+                }
+                if !mismatches.is_empty() {
+                    eprintln!(
+                        "jupyterm: {} of {} cells printed something else",
+                        mismatches.len(),
+                        cells.len()
+                    );
+                }
+                mismatches.is_empty()
+            }
+            Err(err) => {
+                eprintln!("jupyterm: replaying the transcript failed: {}", err);
+                false
+            }
+        };
+        if owned {
+            close_session(&mut session, cli.keep_kernel, false);
+        }
+        if !ok {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // a restored session keeps the settings it was saved with
+    let mut config = match &restored {
+        Some(state) => state.config.clone(),
+        None => Config::load(),
+    };
+    for file in &cli.startup {
+        if !config.startup_files.contains(file) {
+            config.startup_files.push(file.clone());
+        }
+    }
+    if cli.matplotlib.is_some() {
+        session.matplotlib_inline();
+    }
+    // kernels that were already running have been set up before
+    if owned {
+        session.run_startup(&config);
+    }
+    match restored {
+        Some(state) if owned => session.transcript = replay(&mut session.client, &state.transcript),
+        Some(state) => session.transcript = state.transcript,
+        None => {}
+    }
+    session.execution_count = session.transcript.last().map_or(1, |(n, _)| n as i32 + 1);
+    session.out_cache.set_capacity(config.out_cache_size);
+
+    let mut editor = Editor::new();
+    match editor::parse_key(&config.inspect_key) {
+        Some(key) => editor.set_inspect_key(key),
+        None => eprintln!("jupyterm: unknown inspect_key {:?}", config.inspect_key),
+    }
+    let editing_mode = config.editing_mode();
+    match EditingMode::parse(&editing_mode) {
+        Some(mode) => editor.set_editing_mode(mode),
+        None => eprintln!("jupyterm: unknown editing mode {:?}", editing_mode),
+    }
+    let options = Options {
+        timing: cli.timing || config.timing,
+        timing_threshold: Duration::from_millis(config.timing_threshold_ms),
+        notify: config.notify,
+        notify_threshold: Duration::from_millis(config.notify_threshold_ms),
+        json: cli.json,
+        repl_timeout: cli.repl_timeout.map(Duration::from_secs),
+        matplotlib_inline: cli.matplotlib.is_some(),
+        store_history: !cli.no_store_history,
+        // piped cells are for their output only
+        banner: !cli.quiet && !cli.json && io::stdin().is_terminal(),
+        max_column_width: config.max_column_width,
+        traceback_links: display::supports_hyperlinks() && env::var_os("NO_COLOR").is_none(),
+        link_template: config.traceback_link_template.clone(),
+        only_local_links: cli.only_local_links,
+        traceback_frames: config.traceback_frames,
+        max_output_lines: config.max_output_lines_per_second,
+        max_output_bytes: config.max_output_bytes_per_second,
+        max_executions_per_second: cli.max_executions_per_second,
+    };
+    editor.set_idle_timeout(options.repl_timeout);
+
+    let mut repl = Repl {
+        sessions: vec![session],
+        active: 0,
+        editor,
+        aliases: Aliases {
+            sigil: config.alias_sigil.clone(),
+            templates: config.aliases.clone(),
+        },
+        options,
+        notifiers: notify::notifiers(config.notify_command.as_deref()),
+        config,
+        keep_kernel: cli.keep_kernel,
+        kernel_log_file: cli.kernel_log_file.clone(),
+        metrics: None,
+        listed_history: Vec::new(),
+        saved_outputs: SavedOutputs::new(cli.keep_outputs),
+        capture: None,
+        recording: None,
+        output: None,
+        named: None,
+    };
+    if let Some(name) = &cli.session {
+        match NamedSession::open(name) {
+            Ok(named) => {
+                let cells = named.cells().iter().map(|cell| cell.source.clone());
+                repl.editor.set_history(cells.collect());
+                repl.named = Some(named);
+            }
+            Err(err) => {
+                eprintln!("jupyterm: couldn't open the {} session: {}", name, err);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = &cli.record {
+        match Recording::open(path, repl.sessions[0].notebook_metadata()) {
+            Ok(recording) => repl.recording = Some(recording),
+            Err(err) => {
+                eprintln!("jupyterm: couldn't record to {}: {}", path.display(), err);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = &cli.output {
+        match TranscriptFile::create(path) {
+            Ok(output) => repl.output = Some(output),
+            Err(err) => {
+                eprintln!("jupyterm: couldn't write {}: {}", path.display(), err);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(port) = cli.metrics_port {
+        let metrics = Metrics::new();
+        match metrics.serve(port) {
+            Ok(()) => repl.metrics = Some(metrics),
+            Err(err) => eprintln!("jupyterm: couldn't serve metrics on port {}: {}", port, err),
+        }
+    }
+    #[cfg(feature = "async")]
+    if let Some((port, session, ports)) = ws_bridge {
+        if let Err(err) = websocket::serve(port, session, ports) {
+            eprintln!(
+                "jupyterm: couldn't listen for websockets on port {}: {}",
+                port, err
+            );
+        }
+    }
+    #[cfg(feature = "grpc")]
+    if let Some((port, session, ports)) = grpc {
+        if let Err(err) = jupyterm::grpc::serve(port, session, ports) {
+            eprintln!("jupyterm: couldn't serve grpc on port {}: {}", port, err);
+        }
+    }
+
+    if repl.options.banner {
+        repl.sessions[0].print_banner();
+        repl.print_resumed();
+    }
+    handle_ctrl_c();
+    let exit = repl.run();
+    // only the sessions whose kernels are still there are left
+    let named = repl.sessions.len() > 1;
+    for session in &mut repl.sessions {
+        let keep = match exit {
+            // idle kernels are shut down no matter who started them
+            Exit::Idle => false,
+            Exit::Detach => true,
+            Exit::Eof | Exit::KernelDied => cli.keep_kernel || !session.owned,
+        };
+        close_session(session, keep, named);
+    }
+    if cli.keep_outputs && repl.saved_outputs.dir().exists() {
+        eprintln!(
+            "jupyterm: saved outputs are in {}",
+            repl.saved_outputs.dir().display()
+        );
+    }
+    if let Exit::KernelDied = exit {
+        // exit skips destructors, and the saved outputs with them
+        drop(repl);
+        process::exit(1);
+    }
+}
 
-    let mut execution_state;
-    let mut execution_count: i32 = 1;
-    let mut code = String::new();
+// Leave the session's kernel running and say how to get back to it, or
+// shut it down. `named` says which session it was, when there were several.
+fn close_session(session: &mut KernelSession, keep: bool, named: bool) {
+    let kernel = if named {
+        format!("the {} kernel", session.name)
+    } else {
+        "the kernel".to_string()
+    };
+    match (&session.remote, keep) {
+        (Some(remote), true) => eprintln!(
+            "jupyterm: {} is still running, reattach with\n    jupyterm --url {} --existing {}",
+            kernel,
+            remote.server.url(),
+            remote.kernel.id
+        ),
+        (Some(remote), false) => {
+            shutdown_server_kernel(&remote.server, &remote.kernel);
+        }
+        (None, true) => {
+            let ssh = match &session.tunnel {
+                Some(tunnel) => format!(" --ssh {}", tunnel.host()),
+                None => String::new(),
+            };
+            eprintln!(
+                "jupyterm: {} is still running, reattach with\n    jupyterm --existing {}{}",
+                kernel,
+                connection::short_name(&session.connection_file),
+                ssh
+            );
+        }
+        (None, false) => shutdown(&mut session.client, &session.connection_file),
+    }
+}
 
-    loop {
-        code.clear();
+// Run a saved session's cells in a fresh kernel, up to the first one that fails.
+fn replay(client: &mut Cutypr, saved: &Transcript) -> Transcript {
+    let mut transcript = Transcript::new();
+    let cells: Vec<(i64, &str)> = saved.cells().collect();
+    if !cells.is_empty() {
+        eprintln!(
+            "jupyterm: the saved kernel is gone, replaying {} cells in a new one",
+            cells.len()
+        );
+    }
 
-        print!("In [{}]: ", execution_count);
-        io::stdout().flush().unwrap();
+    for (n, source) in cells {
+        let result = match client.execute_and_collect(source) {
+            Ok(result) => result,
+            Err(err) => {
+                eprintln!("jupyterm: replaying In [{}] failed: {}", n, err);
+                break;
+            }
+        };
+        if let Some(count) = result.execution_count {
+            transcript.record(count, source);
+            if result.status.as_deref() == Some("error") {
+                transcript.mark_failed(count);
+            }
+        }
+        if result.status.as_deref() != Some("ok") {
+            eprintln!("jupyterm: In [{}] failed, not replaying the rest", n);
+            break;
+        }
+    }
+    transcript
+}
 
-        io::stdin().read_line(&mut code).unwrap();
+// Run the notebook's code cells in order, replacing their outputs and
+// execution counts, up to the first one that fails. Whether they all ran.
+fn run_notebook(client: &mut Cutypr, nb: &mut Notebook, strip_output: bool) -> bool {
+    if strip_output {
+        for cell in &mut nb.cells {
+            if let Cell::Code {
+                execution_count,
+                outputs,
+                ..
+            } = cell
+            {
+                *execution_count = None;
+                outputs.clear();
+            }
+        }
+    }
 
-        if code.trim().is_empty() {
-            continue;
+    for (i, cell) in nb.cells.iter_mut().enumerate() {
+        let (source, execution_count, outputs) = match cell {
+            Cell::Code {
+                source,
+                execution_count,
+                outputs,
+                ..
+            } => (source, execution_count, outputs),
+            _ => continue,
+        };
+        let result = match client.execute_and_collect(source) {
+            Ok(result) => result,
+            Err(err) => {
+                eprintln!("jupyterm: running cell {} failed: {}", i + 1, err);
+                return false;
+            }
         };
+        *execution_count = result.execution_count;
+        *outputs = notebook::outputs(&result);
 
-        client.execute(&code);
-        execution_state = "busy";
-
-        while execution_state != "idle" {
-            while client.msg_ready() {
-                let msg = client.get_msg();
-                let msg_type = msg["header"]["msg_type"].as_str().unwrap();
-
-                match msg_type {
-                    "status" => {
-                        // couldn't save contents of msg["content"]["execution_state"]
-                        // directly into execution_state
-                        let _execution_state = msg["content"]["execution_state"].as_str().unwrap();
-                        match _execution_state {
-                            "starting" => execution_state = "starting",
-                            "idle" => execution_state = "idle",
-                            "busy" => execution_state = "busy",
-                            _ => {
-                                panic!("Unknown execution state");
-                            }
-                        };
-                    }
-                    "stream" => {
-                        let stream_name = msg["content"]["name"].as_str().unwrap();
-
-                        match stream_name {
-                            "stdout" => {
-                                println!("{}", msg["content"]["text"].to_string());
-                            }
-                            "stderr" => {
-                                eprintln!("{}", msg["content"]["text"].to_string());
-                            }
-                            _ => println!("Unknown stream name"),
-                        };
-                    }
-                    "execute_input" => {
-                        execution_count += 1;
-                    }
-                    "error" => {
-                        println!("error!");
-                    }
-                    _ => {
-                        println!("Unknown message type");
-                    }
-                };
+        if result.status.as_deref() != Some("ok") {
+            let error = outputs.iter().find_map(|output| match output {
+                Output::Error { ename, evalue, .. } => Some(format!("{}: {}", ename, evalue)),
+                _ => None,
+            });
+            eprintln!(
+                "jupyterm: cell {} failed{}, not running the rest",
+                i + 1,
+                error
+                    .map(|error| format!(" with {}", error))
+                    .unwrap_or_default()
+            );
+            return false;
+        }
+    }
+    true
+}
+
+fn shutdown(client: &mut Cutypr, connection_file: &Path) {
+    if client.shutdown(false).is_none() {
+        match client.get_kernel_pid() {
+            Some(pid) => {
+                eprintln!(
+                    "jupyterm: the kernel didn't answer the shutdown_request, killing it (pid {})",
+                    pid
+                );
+                if let Err(err) = client.kill_kernel() {
+                    eprintln!("jupyterm: couldn't kill the kernel: {}", err);
+                }
             }
+            None => eprintln!("jupyterm: the kernel didn't answer the shutdown_request"),
         }
     }
+    // nothing to reattach to any more
+    let _ = fs::remove_file(connection_file);
 }
@@ -0,0 +1,164 @@
+use crate::display::strip_ansi;
+use crate::error::Error;
+use crate::notebook::{self, Cell, Notebook, Output};
+use crate::outputs;
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+// A notebook as Markdown, for pasting into issues and docs: each code cell
+// in a fenced block tagged with its language, what it printed and returned
+// in plain ones after it, and its images as links to files of their own.
+#[derive(Debug)]
+pub struct MarkdownExport {
+    pub document: String,
+    // the files the document links to, by name, to go next to it
+    pub images: Vec<(String, Vec<u8>)>,
+}
+
+pub fn to_markdown(nb: &Notebook) -> Result<MarkdownExport, Error> {
+    let language = nb
+        .metadata
+        .language_info
+        .as_ref()
+        .and_then(|info| info.get("name")?.as_str())
+        .or_else(|| nb.metadata.kernelspec.as_ref()?.language.as_deref())
+        .unwrap_or_default();
+    let mut blocks = Vec::new();
+    let mut images = Vec::new();
+    // named like the ones saved while it ran, the first out_3_fig<N> free
+    let mut names = HashSet::new();
+    for cell in &nb.cells {
+        let (source, execution_count, outputs) = match cell {
+            Cell::Code {
+                source,
+                execution_count,
+                outputs,
+                ..
+            } => (source, *execution_count, outputs),
+            Cell::Markdown { source, .. } => {
+                blocks.push(source.trim_end().to_string());
+                continue;
+            }
+            Cell::Raw { source, .. } => {
+                blocks.push(fenced(source, ""));
+                continue;
+            }
+        };
+        blocks.push(fenced(source, language));
+        for output in outputs {
+            let data = match output {
+                Output::Stream { text, .. } => {
+                    blocks.push(fenced(text, ""));
+                    continue;
+                }
+                Output::Error {
+                    ename,
+                    evalue,
+                    traceback,
+                } => {
+                    let text = if traceback.is_empty() {
+                        format!("{}: {}", ename, evalue)
+                    } else {
+                        strip_ansi(&traceback.join("\n"))
+                    };
+                    blocks.push(fenced(&text, ""));
+                    continue;
+                }
+                Output::DisplayData { data, .. } | Output::ExecuteResult { data, .. } => data,
+            };
+            // the text types are split into lines in notebooks
+            let data: Map<String, Value> = data
+                .iter()
+                .map(|(mime, value)| (mime.clone(), Value::from(notebook::joined(value))))
+                .collect();
+            let data = Value::Object(data);
+            let image = outputs::saved_mime(&data).filter(|(mime, _)| mime.starts_with("image/"));
+            if let Some((mime, payload)) = image {
+                let name = (1..)
+                    .filter_map(|n| outputs::file_name(mime, execution_count, n))
+                    .find(|name| !names.contains(name))
+                    .unwrap();
+                images.push((name.clone(), outputs::decode(mime, payload)?));
+                blocks.push(format!("![{}]({})", name, name));
+                names.insert(name);
+            } else if let Some(text) = data["text/plain"].as_str() {
+                blocks.push(fenced(text, ""));
+            }
+        }
+    }
+    let mut document = blocks.join("\n\n");
+    document.push('\n');
+    Ok(MarkdownExport { document, images })
+}
+
+// `text` between fences longer than any run of backticks in it, so nothing
+// in it can close the block early.
+fn fenced(text: &str, info: &str) -> String {
+    let longest = text
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or_default();
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!(
+        "{}{}\n{}\n{}",
+        fence,
+        info,
+        text.trim_end_matches('\n'),
+        fence
+    )
+}
+
+// Write the Markdown for `nb` to `path` and its images next to it, in place
+// of any from before. The number of images written.
+pub fn export_markdown(nb: &Notebook, path: &Path) -> Result<usize, Error> {
+    let export = to_markdown(nb)?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    for (name, bytes) in &export.images {
+        fs::write(dir.join(name), bytes)?;
+    }
+    fs::write(path, export.document)?;
+    Ok(export.images.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION: &str = include_str!("../tests/fixtures/session.ipynb");
+    const GOLDEN: &str = include_str!("../tests/fixtures/session.md");
+
+    fn session() -> Notebook {
+        serde_json::from_str(SESSION).unwrap()
+    }
+
+    #[test]
+    fn the_session_exports_as_the_golden_file() {
+        let export = to_markdown(&session()).unwrap();
+        assert_eq!(export.document, GOLDEN);
+        let names: Vec<&str> = export
+            .images
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["out_3_fig1.png", "out_3_fig2.png"]);
+        assert_eq!(export.images[0].1, b"\x89PNG\r\n\x1a\n");
+    }
+
+    #[test]
+    fn images_are_written_next_to_the_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.md");
+        assert_eq!(export_markdown(&session(), &path).unwrap(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap(), GOLDEN);
+        assert_eq!(
+            fs::read(dir.path().join("out_3_fig2.png")).unwrap(),
+            b"\x89PNG\r\n\x1a\n\n"
+        );
+        // again, over the files from before
+        assert_eq!(export_markdown(&session(), &path).unwrap(), 2);
+        assert!(!dir.path().join("out_3_fig3.png").exists());
+    }
+}
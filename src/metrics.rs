@@ -0,0 +1,93 @@
+use crate::error::Error;
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, Registry, TextEncoder};
+use std::io;
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Response, Server};
+
+// Counts for --metrics-port, served for Prometheus to scrape at /metrics.
+// Clones share the same metrics.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Registry,
+    executions: IntCounter,
+    errors: IntCounter,
+    duration: Histogram,
+    kernel_alive: IntGauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let executions =
+            IntCounter::new("jupyterm_executions_total", "Cells sent to the kernel").unwrap();
+        let errors =
+            IntCounter::new("jupyterm_errors_total", "Cells that ended in an error").unwrap();
+        // from 10 ms to about 45 minutes, cells can be slow
+        let duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "jupyterm_execution_duration_seconds",
+                "How long cells took to run",
+            )
+            .buckets(prometheus::exponential_buckets(0.01, 4.0, 10).unwrap()),
+        )
+        .unwrap();
+        let kernel_alive = IntGauge::new(
+            "jupyterm_kernel_alive",
+            "Kernels of jupyterm's sessions that are up, 1 or 0 with a single session",
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(executions.clone())).unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        registry.register(Box::new(duration.clone())).unwrap();
+        registry.register(Box::new(kernel_alive.clone())).unwrap();
+        Metrics {
+            registry,
+            executions,
+            errors,
+            duration,
+            kernel_alive,
+        }
+    }
+
+    pub fn record_execution(&self, elapsed: Duration, failed: bool) {
+        self.executions.inc();
+        if failed {
+            self.errors.inc();
+        }
+        self.duration.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn set_kernels_alive(&self, n: usize) {
+        self.kernel_alive.set(n as i64);
+    }
+
+    // Listen on every interface, answering on a thread of its own for as
+    // long as jupyterm runs.
+    pub fn serve(&self, port: u16) -> Result<(), Error> {
+        let server = Server::http(("0.0.0.0", port)).map_err(io::Error::other)?;
+        let registry = self.registry.clone();
+        thread::spawn(move || {
+            let content_type =
+                Header::from_bytes("Content-Type", TextEncoder::new().format_type()).unwrap();
+            for request in server.incoming_requests() {
+                if request.url() != "/metrics" {
+                    let _ = request.respond(Response::empty(404));
+                    continue;
+                }
+                let mut body = Vec::new();
+                let _ = TextEncoder::new().encode(&registry.gather(), &mut body);
+                let _ =
+                    request.respond(Response::from_data(body).with_header(content_type.clone()));
+            }
+        });
+        Ok(())
+    }
+}
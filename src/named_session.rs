@@ -0,0 +1,145 @@
+use crate::error::Error;
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// What --session NAME keeps under sessions_dir()/NAME: when it was first
+// opened, and every cell run in it since, whichever kernel ran it.
+const INFO_FILE: &str = "session.json";
+const TRANSCRIPT_FILE: &str = "transcript.jsonl";
+
+// jupyterm's own data dir, not Jupyter's, which is for kernels and such
+pub fn sessions_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("jupyterm").join("sessions"))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Info {
+    name: String,
+    // RFC 3339, the local time it was made at
+    created: String,
+}
+
+// One cell of a named session's transcript, a line of JSON. The counts
+// start over with each kernel, so they're kept as the kernel gave them and
+// the cells in the order they ran.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LoggedCell {
+    pub execution_count: i64,
+    pub source: String,
+    pub time: String,
+    #[serde(default)]
+    pub failed: bool,
+}
+
+// A session that outlives its kernel: the cells are appended as they run,
+// so a crash loses none of them, and reopening it picks up where it was.
+#[derive(Debug)]
+pub struct NamedSession {
+    pub name: String,
+    dir: PathBuf,
+    created: DateTime<FixedOffset>,
+    cells: Vec<LoggedCell>,
+    // how many of `cells` were run before this time
+    previous: usize,
+    log: File,
+}
+
+impl NamedSession {
+    // Open the session called `name`, making its directory the first time.
+    // Names are single path components, so they can't reach outside it.
+    pub fn open(name: &str) -> Result<NamedSession, Error> {
+        if name.is_empty()
+            || name.starts_with('.')
+            || name.contains(|c: char| c == '/' || c == '\\' || c.is_control())
+        {
+            return Err(invalid(format!("{:?} isn't a session name", name)));
+        }
+        let dir = sessions_dir()
+            .ok_or_else(|| invalid("there's no data dir to keep sessions in".to_string()))?
+            .join(name);
+        fs::create_dir_all(&dir)?;
+
+        let info_path = dir.join(INFO_FILE);
+        let info: Info = match fs::read_to_string(&info_path) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let info = Info {
+                    name: name.to_string(),
+                    created: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+                };
+                fs::write(&info_path, serde_json::to_string_pretty(&info)?)?;
+                info
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let created = DateTime::parse_from_rfc3339(&info.created)
+            .map_err(|err| invalid(format!("{}: {}", info_path.display(), err)))?;
+
+        let transcript_path = dir.join(TRANSCRIPT_FILE);
+        let cells: Vec<LoggedCell> = match fs::read_to_string(&transcript_path) {
+            // a line cut short by a crash is left out
+            Ok(text) => text
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&transcript_path)?;
+        Ok(NamedSession {
+            name: name.to_string(),
+            dir,
+            created,
+            previous: cells.len(),
+            cells,
+            log,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn created(&self) -> DateTime<FixedOffset> {
+        self.created
+    }
+
+    // every cell, from before too
+    pub fn cells(&self) -> &[LoggedCell] {
+        &self.cells
+    }
+
+    // the cells from the times it was opened before this one
+    pub fn previous(&self) -> &[LoggedCell] {
+        &self.cells[..self.previous]
+    }
+
+    pub fn append(
+        &mut self,
+        execution_count: i64,
+        source: &str,
+        failed: bool,
+    ) -> Result<(), Error> {
+        let cell = LoggedCell {
+            execution_count,
+            source: source.to_string(),
+            time: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+            failed,
+        };
+        let mut line = serde_json::to_string(&cell)?;
+        line.push('\n');
+        self.log.write_all(line.as_bytes())?;
+        self.cells.push(cell);
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
@@ -0,0 +1,644 @@
+use crate::client::ExecutionResult;
+use crate::error::Error;
+use crate::events::{OutputEvent, OutputKind};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+// An .ipynb file, in nbformat 4, the only version Jupyter has written
+// since 2015.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Notebook {
+    pub cells: Vec<Cell>,
+    pub metadata: NotebookMetadata,
+    pub nbformat: u64,
+    pub nbformat_minor: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct NotebookMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernelspec: Option<KernelspecMetadata>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language_info: Option<Map<String, Value>>,
+    // whatever else extensions put here, kept as it is
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+// the kernel the notebook was last run with
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KernelspecMetadata {
+    pub name: String,
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+// Sources and stream texts are stored either as one string or as a list
+// of lines, they're joined back into one string here.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "cell_type", rename_all = "lowercase")]
+pub enum Cell {
+    Code {
+        // since nbformat 4.5
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(default)]
+        metadata: Map<String, Value>,
+        #[serde(with = "multiline")]
+        source: String,
+        execution_count: Option<i64>,
+        #[serde(default)]
+        outputs: Vec<Output>,
+    },
+    Markdown {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(default)]
+        metadata: Map<String, Value>,
+        #[serde(with = "multiline")]
+        source: String,
+        // images pasted into the cell, by file name
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attachments: Option<Map<String, Value>>,
+    },
+    Raw {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(default)]
+        metadata: Map<String, Value>,
+        #[serde(with = "multiline")]
+        source: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attachments: Option<Map<String, Value>>,
+    },
+}
+
+// What a code cell printed or displayed. The mime bundles in `data` are
+// kept as they are in the file, where text types can be lists of lines too.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "output_type", rename_all = "snake_case")]
+pub enum Output {
+    Stream {
+        name: String,
+        #[serde(with = "multiline")]
+        text: String,
+    },
+    DisplayData {
+        data: Map<String, Value>,
+        #[serde(default)]
+        metadata: Map<String, Value>,
+    },
+    ExecuteResult {
+        execution_count: Option<i64>,
+        data: Map<String, Value>,
+        #[serde(default)]
+        metadata: Map<String, Value>,
+    },
+    Error {
+        ename: String,
+        evalue: String,
+        traceback: Vec<String>,
+    },
+}
+
+impl Cell {
+    pub fn source(&self) -> &str {
+        match self {
+            Cell::Code { source, .. }
+            | Cell::Markdown { source, .. }
+            | Cell::Raw { source, .. } => source,
+        }
+    }
+}
+
+pub fn load_notebook(path: &Path) -> Result<Notebook, Error> {
+    let json: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+    // older notebooks nest their cells in worksheets, nothing else would
+    // parse anyway
+    match json["nbformat"].as_u64() {
+        Some(4) => Ok(serde_json::from_value(json)?),
+        version => Err(Error::Nbformat(version)),
+    }
+}
+
+impl Output {
+    // the notebook output for an iopub message, None for ones that aren't
+    // outputs, like clear_output
+    pub fn from_event(event: &OutputEvent) -> Option<Output> {
+        Some(match &event.kind {
+            OutputKind::Stream { name, text } => Output::Stream {
+                name: name.clone(),
+                text: text.clone(),
+            },
+            OutputKind::DisplayData { data, metadata } => Output::DisplayData {
+                data: split_lines(data),
+                metadata: metadata.clone(),
+            },
+            OutputKind::ExecuteResult {
+                execution_count,
+                data,
+                metadata,
+            } => Output::ExecuteResult {
+                execution_count: *execution_count,
+                data: split_lines(data),
+                metadata: metadata.clone(),
+            },
+            OutputKind::Error {
+                ename,
+                evalue,
+                traceback,
+            } => Output::Error {
+                ename: ename.clone(),
+                evalue: evalue.clone(),
+                traceback: traceback.clone(),
+            },
+            OutputKind::Status { .. }
+            | OutputKind::ExecuteInput { .. }
+            | OutputKind::Other { .. } => return None,
+        })
+    }
+}
+
+// A cell's outputs as Jupyter saves them, with a run of stream messages to
+// the same stream joined into one output.
+pub fn outputs(result: &ExecutionResult) -> Vec<Output> {
+    outputs_of(&result.outputs)
+}
+
+// Like outputs, from a cell's iopub messages as they came. A clear_output
+// takes back what came before it, with wait=True only once there's more.
+pub fn outputs_of(events: &[OutputEvent]) -> Vec<Output> {
+    let mut outputs: Vec<Output> = Vec::new();
+    let mut pending_clear = false;
+    for event in events {
+        if event.msg_type == "clear_output" {
+            match &event.kind {
+                OutputKind::Other { content }
+                    if content.get("wait") == Some(&Value::Bool(true)) =>
+                {
+                    pending_clear = true
+                }
+                _ => outputs.clear(),
+            }
+            continue;
+        }
+        let output = match Output::from_event(event) {
+            Some(output) => output,
+            None => continue,
+        };
+        if mem::take(&mut pending_clear) {
+            outputs.clear();
+        }
+        if let (
+            Some(Output::Stream { name, text }),
+            Output::Stream {
+                name: next,
+                text: more,
+            },
+        ) = (outputs.last_mut(), &output)
+        {
+            if name == next {
+                text.push_str(more);
+                continue;
+            }
+        }
+        outputs.push(output);
+    }
+    outputs
+}
+
+// text types in mime bundles are written as lists of lines too
+fn split_lines(data: &Map<String, Value>) -> Map<String, Value> {
+    data.iter()
+        .map(|(mime, value)| match value {
+            Value::String(text) if mime.starts_with("text/") || mime == "image/svg+xml" => (
+                mime.clone(),
+                text.split_inclusive('\n').map(Value::from).collect(),
+            ),
+            _ => (mime.clone(), value.clone()),
+        })
+        .collect()
+}
+
+// Written the way Jupyter writes notebooks, sorted keys and a one space
+// indent, so saving one jupyterm didn't change keeps the diff small. It goes
+// to a .tmp file first and is renamed over `path`, so an interrupt halfway
+// through can't leave a broken notebook behind.
+pub fn save_notebook(nb: &Notebook, path: &Path) -> Result<(), Error> {
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b" ");
+    let mut json = Vec::new();
+    let mut serializer = serde_json::Serializer::with_formatter(&mut json, formatter);
+    // through a Value, whose maps are sorted
+    serde_json::to_value(nb)?.serialize(&mut serializer)?;
+    json.push(b'\n');
+
+    let mut tmp = OsString::from(path);
+    tmp.push(".tmp");
+    fs::write(&tmp, json)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// The notebook --record and :record add the session's cells to, rewritten
+// after every one so a crash loses at most the cell that was running. One
+// that's there already is added to, and gets cell ids if its nbformat
+// version has them.
+#[derive(Debug)]
+pub struct Recording {
+    path: PathBuf,
+    notebook: Notebook,
+    // the cells added since it was opened
+    recorded: usize,
+}
+
+impl Recording {
+    pub fn open(path: &Path, metadata: NotebookMetadata) -> Result<Recording, Error> {
+        let notebook = if path.exists() {
+            load_notebook(path)?
+        } else {
+            Notebook {
+                cells: Vec::new(),
+                metadata,
+                nbformat: 4,
+                nbformat_minor: 5,
+            }
+        };
+        Ok(Recording {
+            path: path.to_path_buf(),
+            notebook,
+            recorded: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn recorded(&self) -> usize {
+        self.recorded
+    }
+
+    // a cell that ran, with the iopub messages it sent
+    pub fn record(
+        &mut self,
+        source: &str,
+        execution_count: Option<i64>,
+        events: &[OutputEvent],
+    ) -> Result<(), Error> {
+        let id = if self.notebook.nbformat_minor >= 5 {
+            Some(self.new_id())
+        } else {
+            None
+        };
+        self.notebook.cells.push(Cell::Code {
+            id,
+            metadata: Map::new(),
+            source: source.to_string(),
+            execution_count,
+            outputs: outputs_of(events),
+        });
+        self.recorded += 1;
+        save_notebook(&self.notebook, &self.path)
+    }
+
+    // "cell-N", the first N no cell has
+    fn new_id(&self) -> String {
+        (self.notebook.cells.len() + 1..)
+            .map(|n| format!("cell-{}", n))
+            .find(|id| !self.notebook.cells.iter().any(|cell| cell.id() == Some(id)))
+            .unwrap()
+    }
+}
+
+// How two notebooks differ, cell by cell, for --notebook --check.
+#[derive(Clone, Debug, Default)]
+pub struct NotebookDiff {
+    pub cells: Vec<CellDiff>,
+}
+
+// Cells are numbered from 1, in `after` except for removed ones. Diffs are
+// empty when that part didn't change, otherwise they have every line.
+#[derive(Clone, Debug)]
+pub enum CellDiff {
+    Added {
+        index: usize,
+        cell: Cell,
+    },
+    Removed {
+        index: usize,
+        cell: Cell,
+    },
+    Modified {
+        index: usize,
+        source_diff: Vec<LineDiff>,
+        output_diff: Vec<LineDiff>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LineDiff {
+    Same(String),
+    Added(String),
+    Removed(String),
+}
+
+impl Cell {
+    fn id(&self) -> Option<&str> {
+        match self {
+            Cell::Code { id, .. } | Cell::Markdown { id, .. } | Cell::Raw { id, .. } => {
+                id.as_deref()
+            }
+        }
+    }
+
+    fn output_lines(&self) -> Vec<String> {
+        match self {
+            Cell::Code { outputs, .. } => output_lines(outputs),
+            _ => Vec::new(),
+        }
+    }
+}
+
+// What outputs look like as text. Images only say they're there, since
+// plotting libraries don't draw the same bytes twice, and execution counts
+// are left out, they change from one run to the next.
+pub fn output_lines(outputs: &[Output]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for output in outputs {
+        match output {
+            Output::Stream { text, .. } => lines.extend(text.lines().map(String::from)),
+            Output::DisplayData { data, .. } | Output::ExecuteResult { data, .. } => {
+                match data.get("text/plain") {
+                    Some(text) => lines.extend(joined(text).lines().map(String::from)),
+                    None => lines.extend(data.keys().map(|mime| format!("<{}>", mime))),
+                }
+            }
+            Output::Error { ename, evalue, .. } => lines.push(format!("{}: {}", ename, evalue)),
+        }
+    }
+    lines
+}
+
+// a mime bundle's text, which can be a list of lines
+pub fn joined(text: &Value) -> String {
+    match text {
+        Value::String(text) => text.clone(),
+        Value::Array(lines) => lines.iter().filter_map(Value::as_str).collect(),
+        _ => String::new(),
+    }
+}
+
+// Cells pair up by id when both notebooks have them, by source otherwise,
+// and paired cells whose source or outputs differ are modified. Without ids
+// an edited cell is one removed next to one added, which go together.
+pub fn diff_notebook(before: &Notebook, after: &Notebook) -> NotebookDiff {
+    let with_ids = before
+        .cells
+        .iter()
+        .chain(&after.cells)
+        .all(|cell| cell.id().is_some());
+    let key = |cell: &Cell| {
+        if with_ids {
+            cell.id().unwrap_or_default().to_string()
+        } else {
+            cell.source().to_string()
+        }
+    };
+    let keys_before: Vec<String> = before.cells.iter().map(key).collect();
+    let keys_after: Vec<String> = after.cells.iter().map(key).collect();
+
+    let mut diff = NotebookDiff::default();
+    let (mut i, mut j) = (0, 0);
+    // a run of removed and added cells, up to the next pair
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    let changes = lcs(&keys_before, &keys_after);
+    for change in changes.iter().map(Some).chain([None]) {
+        match change {
+            Some(Change::Removed) => {
+                removed.push(i);
+                i += 1;
+                continue;
+            }
+            Some(Change::Added) => {
+                added.push(j);
+                j += 1;
+                continue;
+            }
+            Some(Change::Same) | None => {}
+        }
+
+        // in order, and only cells of the same type
+        let mut pairs = Vec::new();
+        if !with_ids {
+            let mut next = 0;
+            for &old in &removed {
+                let kind = mem::discriminant(&before.cells[old]);
+                let same_type = added[next..]
+                    .iter()
+                    .position(|&new| mem::discriminant(&after.cells[new]) == kind);
+                if let Some(k) = same_type {
+                    pairs.push((old, added[next + k]));
+                    next += k + 1;
+                }
+            }
+        }
+        for &old in removed
+            .iter()
+            .filter(|old| !pairs.iter().any(|p| p.0 == **old))
+        {
+            diff.cells.push(CellDiff::Removed {
+                index: old + 1,
+                cell: before.cells[old].clone(),
+            });
+        }
+        for &(old, new) in &pairs {
+            diff.cells
+                .extend(modified(&before.cells[old], &after.cells[new], new));
+        }
+        for &new in added
+            .iter()
+            .filter(|new| !pairs.iter().any(|p| p.1 == **new))
+        {
+            diff.cells.push(CellDiff::Added {
+                index: new + 1,
+                cell: after.cells[new].clone(),
+            });
+        }
+        removed.clear();
+        added.clear();
+
+        if change.is_some() {
+            diff.cells
+                .extend(modified(&before.cells[i], &after.cells[j], j));
+            i += 1;
+            j += 1;
+        }
+    }
+    diff
+}
+
+// `index` is the cell's place in the notebook after
+fn modified(old: &Cell, new: &Cell, index: usize) -> Option<CellDiff> {
+    let source_diff = diff_lines(
+        &old.source().lines().map(String::from).collect::<Vec<_>>(),
+        &new.source().lines().map(String::from).collect::<Vec<_>>(),
+    );
+    let output_diff = diff_lines(&old.output_lines(), &new.output_lines());
+    if source_diff.is_empty() && output_diff.is_empty() {
+        return None;
+    }
+    Some(CellDiff::Modified {
+        index: index + 1,
+        source_diff,
+        output_diff,
+    })
+}
+
+impl NotebookDiff {
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+}
+
+impl fmt::Display for NotebookDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let source =
+            |cell: &Cell| -> Vec<String> { cell.source().lines().map(String::from).collect() };
+        for cell in &self.cells {
+            match cell {
+                CellDiff::Added { index, cell } => {
+                    writeln!(f, "cell {} added:", index)?;
+                    write_lines(f, &source(cell), "+ ")?;
+                }
+                CellDiff::Removed { index, cell } => {
+                    writeln!(f, "cell {} removed:", index)?;
+                    write_lines(f, &source(cell), "- ")?;
+                }
+                CellDiff::Modified {
+                    index,
+                    source_diff,
+                    output_diff,
+                } => {
+                    writeln!(f, "cell {} changed:", index)?;
+                    for (what, lines) in [("source", source_diff), ("outputs", output_diff)] {
+                        if lines.is_empty() {
+                            continue;
+                        }
+                        writeln!(f, "  {}:", what)?;
+                        for line in lines {
+                            match line {
+                                LineDiff::Same(line) => writeln!(f, "      {}", line)?,
+                                LineDiff::Added(line) => writeln!(f, "    + {}", line)?,
+                                LineDiff::Removed(line) => writeln!(f, "    - {}", line)?,
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_lines(f: &mut fmt::Formatter, lines: &[String], marker: &str) -> fmt::Result {
+    for line in lines {
+        writeln!(f, "    {}{}", marker, line)?;
+    }
+    Ok(())
+}
+
+// every line of both, if they're any different
+pub(crate) fn diff_lines(before: &[String], after: &[String]) -> Vec<LineDiff> {
+    if before == after {
+        return Vec::new();
+    }
+    let (mut i, mut j) = (0, 0);
+    lcs(before, after)
+        .into_iter()
+        .map(|change| match change {
+            Change::Same => {
+                i += 1;
+                j += 1;
+                LineDiff::Same(after[j - 1].clone())
+            }
+            Change::Removed => {
+                i += 1;
+                LineDiff::Removed(before[i - 1].clone())
+            }
+            Change::Added => {
+                j += 1;
+                LineDiff::Added(after[j - 1].clone())
+            }
+        })
+        .collect()
+}
+
+enum Change {
+    Same,
+    Removed,
+    Added,
+}
+
+// The steps from `a` to `b` along a longest common subsequence, removals
+// before additions where both would do.
+fn lcs<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Change> {
+    // longest[i][j] is the length for a[i..] and b[j..]
+    let mut longest = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            longest[i][j] = if a[i] == b[j] {
+                longest[i + 1][j + 1] + 1
+            } else {
+                longest[i + 1][j].max(longest[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            changes.push(Change::Same);
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && longest[i + 1][j] >= longest[i][j + 1]) {
+            changes.push(Change::Removed);
+            i += 1;
+        } else {
+            changes.push(Change::Added);
+            j += 1;
+        }
+    }
+    changes
+}
+
+// a string or a list of strings to be joined, written back as lines the way
+// Jupyter writes them
+mod multiline {
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Multiline {
+        One(String),
+        Lines(Vec<String>),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        Ok(match Multiline::deserialize(deserializer)? {
+            Multiline::One(text) => text,
+            Multiline::Lines(lines) => lines.concat(),
+        })
+    }
+
+    pub fn serialize<S: Serializer>(text: &str, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(text.split_inclusive('\n'))
+    }
+}
@@ -0,0 +1,116 @@
+use crate::display::format_duration;
+use std::io::{self, Write};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+// A cell that ran long enough to be worth telling someone about.
+#[derive(Debug)]
+pub struct Finished {
+    pub execution_count: i32,
+    pub elapsed: Duration,
+    pub succeeded: bool,
+}
+
+impl Finished {
+    pub fn status(&self) -> &'static str {
+        if self.succeeded {
+            "ok"
+        } else {
+            "error"
+        }
+    }
+
+    pub fn message(&self) -> String {
+        let outcome = if self.succeeded {
+            "finished in"
+        } else {
+            "failed after"
+        };
+        format!(
+            "In [{}] {} {}",
+            self.execution_count,
+            outcome,
+            format_duration(self.elapsed)
+        )
+    }
+}
+
+pub trait Notifier {
+    fn notify(&self, finished: &Finished);
+}
+
+// the terminal bell, most terminals flag the tab or window on it
+#[derive(Debug)]
+pub struct Bell;
+
+impl Notifier for Bell {
+    fn notify(&self, _finished: &Finished) {
+        print!("\x07");
+        let _ = io::stdout().flush();
+    }
+}
+
+#[cfg(feature = "desktop-notifications")]
+#[derive(Debug)]
+pub struct Desktop;
+
+#[cfg(feature = "desktop-notifications")]
+impl Notifier for Desktop {
+    fn notify(&self, finished: &Finished) {
+        if let Err(err) = notify_rust::Notification::new()
+            .summary("jupyterm")
+            .body(&finished.message())
+            .show()
+        {
+            tracing::debug!(%err, "desktop notification failed");
+        }
+    }
+}
+
+// Runs notify_command through the shell after filling in {count},
+// {status} (ok or error), {duration} and {message}.
+#[derive(Debug)]
+pub struct Command {
+    pub command: String,
+}
+
+impl Notifier for Command {
+    fn notify(&self, finished: &Finished) {
+        let command = self
+            .command
+            .replace("{count}", &finished.execution_count.to_string())
+            .replace("{status}", finished.status())
+            .replace("{duration}", &format_duration(finished.elapsed))
+            .replace("{message}", &finished.message());
+
+        let shell = if cfg!(windows) {
+            process::Command::new("cmd").args(["/C", &command]).spawn()
+        } else {
+            process::Command::new("sh").args(["-c", &command]).spawn()
+        };
+        // don't hold up the prompt, but don't leave a zombie behind either
+        match shell {
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            }
+            Err(err) => eprintln!("jupyterm: notify_command failed: {}", err),
+        }
+    }
+}
+
+// The bell, plus notify_command if there is one or a desktop notification
+// where jupyterm was built with them.
+pub fn notifiers(command: Option<&str>) -> Vec<Box<dyn Notifier>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = vec![Box::new(Bell)];
+    match command {
+        Some(command) => notifiers.push(Box::new(Command {
+            command: command.to_string(),
+        })),
+        #[cfg(feature = "desktop-notifications")]
+        None => notifiers.push(Box::new(Desktop)),
+        #[cfg(not(feature = "desktop-notifications"))]
+        None => {}
+    }
+    notifiers
+}
@@ -0,0 +1,61 @@
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+
+// What the last cells printed and returned, kept as the iopub messages they
+// came in so :out can put them through the display again as they were
+// shown. Only the `capacity` most recent cells are kept, 0 keeps none.
+#[derive(Debug)]
+pub struct OutCache {
+    capacity: usize,
+    // by execution count, oldest first
+    cells: VecDeque<(i64, Vec<Map<String, Value>>)>,
+}
+
+impl OutCache {
+    pub fn new(capacity: usize) -> OutCache {
+        OutCache {
+            capacity,
+            cells: VecDeque::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    // A cell's outputs, in place of any kept for the same count, like a
+    // restarted kernel's In [1] is.
+    pub fn insert(&mut self, execution_count: i64, outputs: Vec<Map<String, Value>>) {
+        self.cells.retain(|(n, _)| *n != execution_count);
+        self.cells.push_back((execution_count, outputs));
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.cells.len() > self.capacity {
+            self.cells.pop_front();
+        }
+    }
+
+    // None when the cell isn't kept, empty when it had no output
+    pub fn get(&self, execution_count: i64) -> Option<&[Map<String, Value>]> {
+        self.cells
+            .iter()
+            .find(|(n, _)| *n == execution_count)
+            .map(|(_, outputs)| outputs.as_slice())
+    }
+
+    // the latest cell that had output
+    pub fn latest(&self) -> Option<(i64, &[Map<String, Value>])> {
+        self.cells
+            .iter()
+            .rev()
+            .find(|(_, outputs)| !outputs.is_empty())
+            .map(|(n, outputs)| (*n, outputs.as_slice()))
+    }
+}
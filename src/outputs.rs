@@ -0,0 +1,332 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+
+// The mime types saved to files when the terminal can't show them, most
+// wanted first, with their extension and what their files are called.
+// Everything but SVG comes base64 encoded.
+const SAVED: &[(&str, &str, &str)] = &[
+    ("image/png", "png", "fig"),
+    ("image/jpeg", "jpg", "fig"),
+    ("image/gif", "gif", "fig"),
+    ("image/svg+xml", "svg", "fig"),
+    ("audio/wav", "wav", "audio"),
+    ("audio/x-wav", "wav", "audio"),
+    ("audio/mpeg", "mp3", "audio"),
+    ("audio/ogg", "ogg", "audio"),
+    ("video/mp4", "mp4", "video"),
+    ("video/webm", "webm", "video"),
+];
+
+// The text types only :open-last saves, they're printed when they come in.
+const TEXT: &[(&str, &str, &str)] = &[
+    ("text/html", "html", "page"),
+    ("text/markdown", "md", "text"),
+    ("text/latex", "tex", "text"),
+    ("text/plain", "txt", "text"),
+];
+
+fn lookup(mime: &str) -> Option<&'static (&'static str, &'static str, &'static str)> {
+    SAVED
+        .iter()
+        .chain(TEXT)
+        .find(|(saved, _, _)| *saved == mime)
+}
+
+// the extension for a saved mime type
+pub fn extension(mime: &str) -> Option<&'static str> {
+    lookup(mime).map(|(_, extension, _)| *extension)
+}
+
+// "image", "audio" or "video", for saying what was saved
+pub fn describe(mime: &str) -> &str {
+    mime.split('/').next().unwrap_or(mime)
+}
+
+// The first mime type of a bundle's data that gets saved, and its payload.
+pub fn saved_mime(data: &Value) -> Option<(&'static str, &str)> {
+    SAVED
+        .iter()
+        .find_map(|(mime, _, _)| Some((*mime, data[*mime].as_str()?)))
+}
+
+// The richest representation in a bundle's data, for :open-last: HTML,
+// then what would be saved if it was shown, then the other text types.
+pub fn richest_mime(data: &Value) -> Option<(&'static str, &str)> {
+    let payload = |mime: &'static str| Some((mime, data[mime].as_str()?));
+    payload("text/html")
+        .or_else(|| saved_mime(data))
+        .or_else(|| TEXT.iter().find_map(|(mime, _, _)| payload(mime)))
+}
+
+// The representation in a bundle's data that `name` asks for, by its mime
+// type, extension or subtype, so "html", "png", "jpeg" and "svg" all work.
+pub fn find_mime<'a>(data: &'a Value, name: &str) -> Option<(&'static str, &'a str)> {
+    SAVED.iter().chain(TEXT).find_map(|(mime, extension, _)| {
+        let subtype = mime.split('/').nth(1).unwrap_or("");
+        let named = *mime == name
+            || *extension == name
+            || subtype == name
+            || subtype.split('+').next() == Some(name);
+        if named {
+            Some((*mime, data[*mime].as_str()?))
+        } else {
+            None
+        }
+    })
+}
+
+// Open `path` in whatever the desktop opens its kind of file with.
+pub fn open(path: &Path) -> io::Result<()> {
+    let mut opener = if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else if cfg!(windows) {
+        let mut start = Command::new("cmd");
+        // the empty title keeps start from taking a quoted path for one
+        start.args(["/C", "start", ""]);
+        start
+    } else {
+        Command::new("xdg-open")
+    };
+    let status = opener
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => io::Error::new(
+                err.kind(),
+                format!("no {:?} to open it with", opener.get_program()),
+            ),
+            _ => err,
+        })?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "the opener exited with {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+// The files outputs were saved to, in a temp dir of jupyterm's own that
+// goes away with it unless it's kept.
+#[derive(Debug)]
+pub struct SavedOutputs {
+    dir: PathBuf,
+    keep: bool,
+}
+
+impl SavedOutputs {
+    pub fn new(keep: bool) -> SavedOutputs {
+        SavedOutputs {
+            dir: env::temp_dir().join(format!("jupyterm-{}", process::id())),
+            keep,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Write `payload` of a mime type in SAVED to a file named after the
+    // cell, like out_3_fig1.png for the first figure of In [3], and return
+    // its path.
+    pub fn save(
+        &self,
+        mime: &str,
+        payload: &str,
+        execution_count: Option<i64>,
+    ) -> io::Result<PathBuf> {
+        let bytes = decode(mime, payload)?;
+        fs::create_dir_all(&self.dir)?;
+        // the same In number comes around again with --no-store-history
+        let path = (1..)
+            .filter_map(|n| file_name(mime, execution_count, n))
+            .map(|name| self.dir.join(name))
+            .find(|path| !path.exists())
+            .unwrap();
+        fs::write(&path, bytes)?;
+        Ok(path)
+    }
+}
+
+impl Drop for SavedOutputs {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+fn unsupported(mime: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, mime.to_string())
+}
+
+// What the `n`th saved output of its kind from a cell is called, like
+// out_3_fig1.png, None for mime types that aren't saved.
+pub fn file_name(mime: &str, execution_count: Option<i64>, n: usize) -> Option<String> {
+    let (_, extension, kind) = lookup(mime)?;
+    Some(match execution_count {
+        Some(count) => format!("out_{}_{}{}.{}", count, kind, n, extension),
+        None => format!("out_{}{}.{}", kind, n, extension),
+    })
+}
+
+// The bytes of the file for `payload` of a mime type in SAVED.
+pub fn decode(mime: &str, payload: &str) -> io::Result<Vec<u8>> {
+    lookup(mime).ok_or_else(|| unsupported(mime))?;
+    if mime == "image/svg+xml" || mime.starts_with("text/") {
+        return Ok(payload.as_bytes().to_vec());
+    }
+    // Jupyter wraps base64 at 76 columns sometimes
+    let payload: String = payload.split_whitespace().collect();
+    STANDARD
+        .decode(payload)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn saved_in(dir: &Path, keep: bool) -> SavedOutputs {
+        SavedOutputs {
+            dir: dir.join("outputs"),
+            keep,
+        }
+    }
+
+    #[test]
+    fn each_mime_type_has_its_extension() {
+        let extensions: Vec<(&str, Option<&str>)> = [
+            "image/png",
+            "image/jpeg",
+            "image/gif",
+            "image/svg+xml",
+            "audio/wav",
+            "audio/x-wav",
+            "audio/mpeg",
+            "audio/ogg",
+            "video/mp4",
+            "video/webm",
+            "text/html",
+            "text/plain",
+            "application/pdf",
+        ]
+        .iter()
+        .map(|mime| (*mime, extension(mime)))
+        .collect();
+        assert_eq!(
+            extensions,
+            [
+                ("image/png", Some("png")),
+                ("image/jpeg", Some("jpg")),
+                ("image/gif", Some("gif")),
+                ("image/svg+xml", Some("svg")),
+                ("audio/wav", Some("wav")),
+                ("audio/x-wav", Some("wav")),
+                ("audio/mpeg", Some("mp3")),
+                ("audio/ogg", Some("ogg")),
+                ("video/mp4", Some("mp4")),
+                ("video/webm", Some("webm")),
+                ("text/html", Some("html")),
+                ("text/plain", Some("txt")),
+                ("application/pdf", None),
+            ]
+        );
+        assert_eq!(describe("audio/mpeg"), "audio");
+    }
+
+    #[test]
+    fn file_names_are_keyed_to_the_cell() {
+        assert_eq!(
+            file_name("image/png", Some(3), 1).as_deref(),
+            Some("out_3_fig1.png")
+        );
+        assert_eq!(
+            file_name("audio/ogg", Some(12), 2).as_deref(),
+            Some("out_12_audio2.ogg")
+        );
+        assert_eq!(
+            file_name("video/webm", None, 1).as_deref(),
+            Some("out_video1.webm")
+        );
+        assert_eq!(file_name("application/pdf", Some(1), 1), None);
+    }
+
+    #[test]
+    fn saving_again_doesnt_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved = saved_in(dir.path(), false);
+        let png = STANDARD.encode(b"\x89PNG one");
+        let first = saved.save("image/png", &png, Some(3)).unwrap();
+        let second = saved
+            .save("image/png", &STANDARD.encode(b"two"), Some(3))
+            .unwrap();
+        let svg = saved.save("image/svg+xml", "<svg/>", Some(3)).unwrap();
+        let other_cell = saved.save("image/png", &png, Some(4)).unwrap();
+
+        let name = |path: &Path| path.file_name().unwrap().to_str().unwrap().to_string();
+        assert_eq!(name(&first), "out_3_fig1.png");
+        assert_eq!(name(&second), "out_3_fig2.png");
+        assert_eq!(name(&svg), "out_3_fig1.svg");
+        assert_eq!(name(&other_cell), "out_4_fig1.png");
+        assert_eq!(fs::read(&first).unwrap(), b"\x89PNG one");
+        assert_eq!(fs::read(&second).unwrap(), b"two");
+        assert_eq!(fs::read(&svg).unwrap(), b"<svg/>");
+    }
+
+    #[test]
+    fn payloads_are_decoded_by_their_mime_type() {
+        // wrapped the way Jupyter wraps it
+        let wrapped = "aGVsbG8g\nd29ybGQ=\n";
+        assert_eq!(decode("audio/wav", wrapped).unwrap(), b"hello world");
+        assert_eq!(decode("text/html", "<b>hi</b>").unwrap(), b"<b>hi</b>");
+        let invalid = decode("image/png", "not base64!").unwrap_err();
+        assert_eq!(invalid.kind(), io::ErrorKind::InvalidData);
+        let unsupported = decode("application/pdf", "").unwrap_err();
+        assert_eq!(unsupported.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn the_directory_goes_away_unless_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved = saved_in(dir.path(), false);
+        saved.save("image/svg+xml", "<svg/>", Some(1)).unwrap();
+        let outputs = saved.dir().to_path_buf();
+        drop(saved);
+        assert!(!outputs.exists());
+
+        let saved = saved_in(dir.path(), true);
+        let path = saved.save("image/svg+xml", "<svg/>", Some(1)).unwrap();
+        drop(saved);
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn representations_are_found_by_any_of_their_names() {
+        let data = json!({
+            "text/plain": "<Figure>",
+            "image/svg+xml": "<svg/>",
+            "image/jpeg": "/9j/",
+        });
+        assert_eq!(saved_mime(&data), Some(("image/jpeg", "/9j/")));
+        assert_eq!(richest_mime(&data), Some(("image/jpeg", "/9j/")));
+        for name in ["svg", "svg+xml", "image/svg+xml"] {
+            assert_eq!(find_mime(&data, name), Some(("image/svg+xml", "<svg/>")));
+        }
+        assert_eq!(find_mime(&data, "jpg"), Some(("image/jpeg", "/9j/")));
+        assert_eq!(find_mime(&data, "jpeg"), Some(("image/jpeg", "/9j/")));
+        assert_eq!(find_mime(&data, "html"), None);
+        let html = json!({"text/html": "<p>", "image/png": "iVBO"});
+        assert_eq!(richest_mime(&html), Some(("text/html", "<p>")));
+    }
+}
@@ -0,0 +1,63 @@
+use std::env;
+use std::path::PathBuf;
+
+// Jupyter's own directories, found the way jupyter_core finds them.
+
+// The user's data dir: JUPYTER_DATA_DIR, or ~/Library/Jupyter on macOS,
+// %APPDATA%\jupyter on Windows and $XDG_DATA_HOME/jupyter (by default
+// ~/.local/share/jupyter) elsewhere.
+pub fn user_data_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("JUPYTER_DATA_DIR") {
+        return Some(dir.into());
+    }
+    if cfg!(target_os = "macos") {
+        dirs::home_dir().map(|home| home.join("Library").join("Jupyter"))
+    } else {
+        dirs::data_dir().map(|dir| dir.join("jupyter"))
+    }
+}
+
+// Every dir that can hold kernels/ and other shared data, most specific
+// first, like jupyter_core's jupyter_path(): JUPYTER_PATH, the user's data
+// dir, the active virtualenv or conda env, then the system-wide ones.
+pub fn jupyter_data_dirs() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = match env::var_os("JUPYTER_PATH") {
+        Some(paths) => env::split_paths(&paths)
+            .filter(|path| !path.as_os_str().is_empty())
+            .collect(),
+        None => Vec::new(),
+    };
+    dirs.extend(user_data_dir());
+
+    // jupyter_core uses sys.prefix, which these point at when an env is active
+    for prefix in ["VIRTUAL_ENV", "CONDA_PREFIX"] {
+        if let Some(prefix) = env::var_os(prefix) {
+            dirs.push(PathBuf::from(prefix).join("share").join("jupyter"));
+        }
+    }
+
+    if cfg!(windows) {
+        if let Some(program_data) = env::var_os("PROGRAMDATA") {
+            dirs.push(PathBuf::from(program_data).join("jupyter"));
+        }
+    } else {
+        dirs.push(PathBuf::from("/usr/local/share/jupyter"));
+        dirs.push(PathBuf::from("/usr/share/jupyter"));
+    }
+
+    let mut seen = Vec::new();
+    dirs.retain(|dir| {
+        let new = !seen.contains(dir);
+        seen.push(dir.clone());
+        new
+    });
+    dirs
+}
+
+// Where Jupyter keeps connection files, like jupyter_core's jupyter_runtime_dir().
+pub fn runtime_dir() -> Option<PathBuf> {
+    match env::var_os("JUPYTER_RUNTIME_DIR") {
+        Some(dir) => Some(dir.into()),
+        None => user_data_dir().map(|dir| dir.join("runtime")),
+    }
+}
@@ -0,0 +1,116 @@
+use crate::client::{Cutypr, Session};
+use crate::error::Error;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::ops::{Deref, DerefMut};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+#[derive(Debug)]
+pub struct PoolConfig {
+    // most clients the pool opens, idle and busy together
+    pub max_size: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig { max_size: 8 }
+    }
+}
+
+// Clients of one kernel for programs that embed jupyterm and run a lot of
+// requests, so they don't pay for new sockets and a new session each time.
+// Each client is a session of its own on the kernel, like the --ws-port
+// ones, and goes back to the pool when its PooledCutypr is dropped.
+#[derive(Debug)]
+pub struct ConnectionPool {
+    idle: Vec<(usize, Cutypr)>,
+    // ids of the clients handed out and not yet back
+    busy: HashSet<usize>,
+    config: PoolConfig,
+    session: Session,
+    ports: Value,
+    next_id: usize,
+    // dropped clients come back through here, from whatever thread they were on
+    returned: Sender<(usize, Cutypr)>,
+    returns: Receiver<(usize, Cutypr)>,
+}
+
+#[derive(Debug)]
+pub struct PooledCutypr {
+    id: usize,
+    // only None once it's on its way back
+    client: Option<Cutypr>,
+    pool: Sender<(usize, Cutypr)>,
+}
+
+impl ConnectionPool {
+    // `session` is the kernel's key and the start of the clients' session
+    // ids, `ports` its connection file's ports
+    pub fn new(session: Session, ports: Value, config: PoolConfig) -> ConnectionPool {
+        let (returned, returns) = mpsc::channel();
+        ConnectionPool {
+            idle: Vec::new(),
+            busy: HashSet::new(),
+            config,
+            session,
+            ports,
+            next_id: 1,
+            returned,
+            returns,
+        }
+    }
+
+    // An idle client, or a new one while the pool is smaller than max_size.
+    pub fn acquire(&mut self) -> Result<PooledCutypr, Error> {
+        for (id, client) in self.returns.try_iter() {
+            self.busy.remove(&id);
+            self.idle.push((id, client));
+        }
+
+        let (id, client) = match self.idle.pop() {
+            Some(idle) => idle,
+            None if self.busy.len() < self.config.max_size => {
+                let id = self.next_id;
+                self.next_id += 1;
+                let session = Session {
+                    key: self.session.key.clone(),
+                    session_id: format!("{}-pool-{}", self.session.session_id, id),
+                    signature_scheme: self.session.signature_scheme,
+                };
+                let mut client = Cutypr::new(session, self.ports.clone());
+                client.initialize_channels();
+                (id, client)
+            }
+            None => return Err(Error::PoolExhausted(self.config.max_size)),
+        };
+        self.busy.insert(id);
+        Ok(PooledCutypr {
+            id,
+            client: Some(client),
+            pool: self.returned.clone(),
+        })
+    }
+}
+
+impl Deref for PooledCutypr {
+    type Target = Cutypr;
+
+    fn deref(&self) -> &Cutypr {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledCutypr {
+    fn deref_mut(&mut self) -> &mut Cutypr {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledCutypr {
+    fn drop(&mut self) {
+        // nowhere to go back to if the pool was dropped first
+        if let Some(client) = self.client.take() {
+            let _ = self.pool.send((self.id, client));
+        }
+    }
+}
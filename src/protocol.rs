@@ -0,0 +1,451 @@
+use serde_json::{Map, Value};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::warn;
+
+// --strict-protocol, for every client in the process
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_strict(strict: bool) {
+    STRICT.store(strict, Ordering::Relaxed);
+}
+
+pub fn is_strict() -> bool {
+    STRICT.load(Ordering::Relaxed)
+}
+
+// what a field of a message should hold
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Expect {
+    Str,
+    Int,
+    Bool,
+    Object,
+    Array,
+    // a string, one of these
+    OneOf(&'static [&'static str]),
+}
+
+use Expect::*;
+
+type Fields = &'static [(&'static str, Expect)];
+
+// every message's header
+const HEADER: Fields = &[
+    ("msg_id", Str),
+    ("msg_type", Str),
+    ("session", Str),
+    ("username", Str),
+    ("date", Str),
+    ("version", Str),
+];
+
+const REPLY_STATUS: Expect = OneOf(&["ok", "error", "aborted"]);
+
+// The content fields the protocol requires, by msg_type, for the messages
+// a client gets from a kernel. Replies are checked against these when
+// their status is ok.
+const CONTENT: &[(&str, Fields)] = &[
+    (
+        "status",
+        &[("execution_state", OneOf(&["starting", "busy", "idle"]))],
+    ),
+    (
+        "stream",
+        &[("name", OneOf(&["stdout", "stderr"])), ("text", Str)],
+    ),
+    ("execute_input", &[("code", Str), ("execution_count", Int)]),
+    (
+        "execute_result",
+        &[
+            ("execution_count", Int),
+            ("data", Object),
+            ("metadata", Object),
+        ],
+    ),
+    ("display_data", &[("data", Object), ("metadata", Object)]),
+    (
+        "update_display_data",
+        &[
+            ("data", Object),
+            ("metadata", Object),
+            ("transient", Object),
+        ],
+    ),
+    (
+        "error",
+        &[("ename", Str), ("evalue", Str), ("traceback", Array)],
+    ),
+    ("clear_output", &[("wait", Bool)]),
+    ("input_request", &[("prompt", Str), ("password", Bool)]),
+    (
+        "execute_reply",
+        &[("status", REPLY_STATUS), ("execution_count", Int)],
+    ),
+    (
+        "kernel_info_reply",
+        &[
+            ("status", REPLY_STATUS),
+            ("protocol_version", Str),
+            ("implementation", Str),
+            ("implementation_version", Str),
+            ("language_info", Object),
+            ("banner", Str),
+        ],
+    ),
+    (
+        "complete_reply",
+        &[
+            ("status", REPLY_STATUS),
+            ("matches", Array),
+            ("cursor_start", Int),
+            ("cursor_end", Int),
+            ("metadata", Object),
+        ],
+    ),
+    (
+        "inspect_reply",
+        &[
+            ("status", REPLY_STATUS),
+            ("found", Bool),
+            ("data", Object),
+            ("metadata", Object),
+        ],
+    ),
+    (
+        "is_complete_reply",
+        &[(
+            "status",
+            OneOf(&["complete", "incomplete", "invalid", "unknown"]),
+        )],
+    ),
+    (
+        "history_reply",
+        &[("status", REPLY_STATUS), ("history", Array)],
+    ),
+    (
+        "comm_info_reply",
+        &[("status", REPLY_STATUS), ("comms", Object)],
+    ),
+    (
+        "shutdown_reply",
+        &[("status", REPLY_STATUS), ("restart", Bool)],
+    ),
+    ("interrupt_reply", &[("status", REPLY_STATUS)]),
+];
+
+// the content of any reply whose status is error
+const ERROR_REPLY: Fields = &[
+    ("status", REPLY_STATUS),
+    ("ename", Str),
+    ("evalue", Str),
+    ("traceback", Array),
+];
+
+// One way a message doesn't follow the protocol.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Problem {
+    pub msg_type: String,
+    // like "content.execution_count"
+    pub field: String,
+    // None when the field is missing
+    pub found: Option<Value>,
+    pub expected: Expect,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} ", self.msg_type, self.field)?;
+        let found = match &self.found {
+            Some(found) => found,
+            None => return write!(f, "is missing"),
+        };
+        let mut found = found.to_string();
+        if found.chars().count() > 60 {
+            found = found.chars().take(57).collect::<String>() + "...";
+        }
+        match self.expected {
+            OneOf(values) => write!(f, "is {}, expected one of {}", found, values.join(", ")),
+            expected => write!(f, "is {}, expected {}", found, expected.describe()),
+        }
+    }
+}
+
+impl Expect {
+    fn describe(self) -> &'static str {
+        match self {
+            Str | OneOf(_) => "a string",
+            Int => "an integer",
+            Bool => "a boolean",
+            Object => "an object",
+            Array => "an array",
+        }
+    }
+
+    fn matches(self, value: &Value) -> bool {
+        match self {
+            Str => value.is_string(),
+            Int => value.is_i64() || value.is_u64(),
+            Bool => value.is_boolean(),
+            Object => value.is_object(),
+            Array => value.is_array(),
+            OneOf(values) => value.as_str().is_some_and(|value| values.contains(&value)),
+        }
+    }
+}
+
+// Everything about `msg` the tables above disagree with. Message types
+// they don't know only get their header checked.
+pub fn validate(msg: &Map<String, Value>) -> Vec<Problem> {
+    let msg_type = msg["header"]["msg_type"].as_str().unwrap_or("?");
+    let mut problems = Vec::new();
+    check_fields(msg_type, "header", &msg["header"], HEADER, &mut problems);
+
+    let content = &msg["content"];
+    let fields = if msg_type.ends_with("_reply") && content["status"] == "error" {
+        Some(ERROR_REPLY)
+    } else if msg_type.ends_with("_reply") && content["status"] == "aborted" {
+        None
+    } else {
+        CONTENT
+            .iter()
+            .find(|(name, _)| *name == msg_type)
+            .map(|(_, fields)| *fields)
+    };
+    if let Some(fields) = fields {
+        check_fields(msg_type, "content", content, fields, &mut problems);
+    }
+    problems
+}
+
+// Warn about each of the problems validate finds in `msg`.
+pub fn check(msg: &Map<String, Value>) {
+    for problem in validate(msg) {
+        warn!(msg_id = msg["header"]["msg_id"].as_str(), "{}", problem);
+    }
+}
+
+fn check_fields(
+    msg_type: &str,
+    part: &str,
+    value: &Value,
+    fields: Fields,
+    problems: &mut Vec<Problem>,
+) {
+    for (name, expected) in fields {
+        let found = value.get(*name);
+        if !found.is_some_and(|found| expected.matches(found)) {
+            problems.push(Problem {
+                msg_type: msg_type.to_string(),
+                field: format!("{}.{}", part, name),
+                found: found.cloned(),
+                expected: *expected,
+            });
+        }
+    }
+}
+
+// The execution counts of execute_input and execute_result messages on
+// iopub only go up, or stay the same for cells run without storing history.
+// One lower than a count before means messages came out of order, or the
+// kernel or a proxy in between got mixed up. A restarted kernel counts from
+// 1 again, and says so with a starting status.
+#[derive(Debug, Default)]
+pub struct CountOrder {
+    // the highest count so far, and the msg_id of the message with it
+    highest: Option<(i64, String)>,
+}
+
+// An execution count lower than one that came before it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutOfOrder {
+    pub msg_type: String,
+    pub execution_count: i64,
+    pub highest: i64,
+    // of the message with the highest count
+    pub after: String,
+}
+
+impl fmt::Display for OutOfOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} with execution_count {} came after one with {}",
+            self.msg_type, self.execution_count, self.highest
+        )
+    }
+}
+
+impl CountOrder {
+    pub fn new() -> CountOrder {
+        CountOrder::default()
+    }
+
+    // for a kernel whose count starts over, like after %reset
+    pub fn reset(&mut self) {
+        self.highest = None;
+    }
+
+    pub fn observe(&mut self, msg: &Map<String, Value>) -> Option<OutOfOrder> {
+        let msg_type = msg["header"]["msg_type"].as_str().unwrap_or_default();
+        if msg_type == "status" && msg["content"]["execution_state"] == "starting" {
+            self.reset();
+            return None;
+        }
+        if msg_type != "execute_input" && msg_type != "execute_result" {
+            return None;
+        }
+        let count = msg["content"]["execution_count"].as_i64()?;
+        let msg_id = msg["header"]["msg_id"].as_str().unwrap_or_default();
+        match &self.highest {
+            Some((highest, after)) if count < *highest => Some(OutOfOrder {
+                msg_type: msg_type.to_string(),
+                execution_count: count,
+                highest: *highest,
+                after: after.clone(),
+            }),
+            _ => {
+                self.highest = Some((count, msg_id.to_string()));
+                None
+            }
+        }
+    }
+
+    // Warn about `msg` if its count is out of order, the message stays as it
+    // is either way.
+    pub fn check(&mut self, msg: &Map<String, Value>) {
+        if let Some(out_of_order) = self.observe(msg) {
+            warn!(
+                msg_id = msg["header"]["msg_id"].as_str(),
+                after = out_of_order.after.as_str(),
+                "{}",
+                out_of_order
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(msg_type: &str, content: Value) -> Map<String, Value> {
+        let msg = json!({
+            "header": {
+                "msg_id": "1",
+                "msg_type": msg_type,
+                "session": "s",
+                "username": "u",
+                "date": "2026-01-01T00:00:00Z",
+                "version": "5.3",
+            },
+            "content": content,
+        });
+        msg.as_object().unwrap().clone()
+    }
+
+    fn problems(msg: &Map<String, Value>) -> Vec<String> {
+        validate(msg).iter().map(Problem::to_string).collect()
+    }
+
+    #[test]
+    fn messages_that_follow_the_spec_have_no_problems() {
+        let stream = message("stream", json!({"name": "stdout", "text": "hi\n"}));
+        assert_eq!(problems(&stream), Vec::<String>::new());
+        let reply = message(
+            "execute_reply",
+            json!({"status": "ok", "execution_count": 3, "user_expressions": {}}),
+        );
+        assert_eq!(problems(&reply), Vec::<String>::new());
+    }
+
+    #[test]
+    fn missing_and_mistyped_fields() {
+        let result = message(
+            "execute_result",
+            json!({"execution_count": "3", "data": {"text/plain": "3"}}),
+        );
+        assert_eq!(
+            problems(&result),
+            [
+                "execute_result: content.execution_count is \"3\", expected an integer",
+                "execute_result: content.metadata is missing",
+            ]
+        );
+        let status = message("status", json!({"execution_state": "sleeping"}));
+        assert_eq!(
+            problems(&status),
+            ["status: content.execution_state is \"sleeping\", expected one of starting, busy, idle"]
+        );
+    }
+
+    #[test]
+    fn the_header_is_checked_for_every_message() {
+        let mut msg = message("made_up_reply", json!("not even an object"));
+        let header = msg["header"].as_object_mut().unwrap();
+        header.remove("username");
+        header.insert("version".to_string(), json!(5));
+        assert_eq!(
+            validate(&msg),
+            [
+                Problem {
+                    msg_type: "made_up_reply".to_string(),
+                    field: "header.username".to_string(),
+                    found: None,
+                    expected: Str,
+                },
+                Problem {
+                    msg_type: "made_up_reply".to_string(),
+                    field: "header.version".to_string(),
+                    found: Some(json!(5)),
+                    expected: Str,
+                },
+            ]
+        );
+
+        let mut msg = message("stream", json!({"name": "stdout", "text": ""}));
+        msg.insert("header".to_string(), Value::Null);
+        assert_eq!(problems(&msg).len(), HEADER.len());
+        assert!(problems(&msg)[0].starts_with("?: header.msg_id is missing"));
+    }
+
+    #[test]
+    fn error_replies_need_the_error_fields_and_aborted_ones_nothing() {
+        let error = message(
+            "execute_reply",
+            json!({"status": "error", "ename": "ValueError", "traceback": "not a list"}),
+        );
+        assert_eq!(
+            problems(&error),
+            [
+                "execute_reply: content.evalue is missing",
+                "execute_reply: content.traceback is \"not a list\", expected an array",
+            ]
+        );
+        let aborted = message("execute_reply", json!({"status": "aborted"}));
+        assert_eq!(problems(&aborted), Vec::<String>::new());
+        let unknown = message(
+            "shutdown_reply",
+            json!({"status": "done", "restart": false}),
+        );
+        assert_eq!(
+            problems(&unknown),
+            ["shutdown_reply: content.status is \"done\", expected one of ok, error, aborted"]
+        );
+    }
+
+    #[test]
+    fn long_values_are_cut_short() {
+        let text = "x".repeat(100);
+        let stream = message("stream", json!({"name": "stdout", "text": [text]}));
+        let problem = &problems(&stream)[0];
+        let found = problem
+            .strip_prefix("stream: content.text is ")
+            .unwrap()
+            .strip_suffix(", expected a string")
+            .unwrap();
+        assert_eq!(found.chars().count(), 60);
+        assert!(found.ends_with("..."));
+    }
+}
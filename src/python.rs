@@ -0,0 +1,232 @@
+use crate::client::{Cutypr, ExecuteOptions, RequestMessages, Session};
+use crate::connection::ConnectionInfo;
+use crate::error::Error;
+use crate::events::{OutputEvent, OutputKind};
+use crate::signing::SignatureScheme;
+use pyo3::class::iter::PyIterProtocol;
+use pyo3::exceptions::{PyOSError, PyRuntimeError, PyTimeoutError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use serde_json::{Map, Value};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// how long to wait on the sockets, GIL released, before letting Python
+// look at its signals again
+const POLL_MS: i64 = 100;
+
+// The client as the jupyterm._client extension module, for Python code
+// that wants to talk to a kernel without a subprocess. Built with maturin
+// and the python feature; see pyproject.toml.
+#[pymodule]
+fn _client(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Client>()?;
+    m.add_class::<Messages>()?;
+    Ok(())
+}
+
+// Client(connection_file, timeout=30.0): a session on the running kernel
+// the connection file is for, ready once it's made. It runs one cell at a
+// time, messages about any other are dropped while one is being read, but
+// interrupt() and shutdown() go over a control channel of their own so
+// another thread can send them while a cell runs.
+#[pyclass(module = "jupyterm._client")]
+#[derive(Debug)]
+pub struct Client {
+    client: Mutex<Cutypr>,
+    control: Mutex<Cutypr>,
+}
+
+#[pymethods]
+impl Client {
+    #[new]
+    #[args(timeout = "30.0")]
+    fn new(py: Python, connection_file: &str, timeout: f64) -> PyResult<Client> {
+        let info = ConnectionInfo::read(Path::new(connection_file)).map_err(py_err)?;
+        let mut client = Cutypr::new(session(&info), info.ports());
+        client.initialize_channels();
+        let mut control = Cutypr::new(session(&info), info.ports());
+        control.initialize_control_channel();
+        py.allow_threads(|| client.wait_for_kernel_ready(Duration::from_secs_f64(timeout)))
+            .map_err(py_err)?;
+        Ok(Client {
+            client: Mutex::new(client),
+            control: Mutex::new(control),
+        })
+    }
+
+    // Run `code` and return {"status", "execution_count", "outputs"}, the
+    // outputs as dicts with msg_type next to their fields, like
+    // {"msg_type": "stream", "name": "stdout", "text": ...}. Ctrl-C
+    // interrupts the kernel and raises KeyboardInterrupt.
+    fn execute(&self, py: Python, code: &str) -> PyResult<PyObject> {
+        let mut messages = self.send(code);
+        let mut status = Value::Null;
+        let mut execution_count = Value::Null;
+        let mut outputs = Vec::new();
+        while let Some(msg) = self.next_message(py, &mut messages)? {
+            if msg["header"]["msg_type"] == "execute_reply" {
+                status = msg["content"]["status"].clone();
+                execution_count = msg["content"]["execution_count"].clone();
+                continue;
+            }
+            let event = OutputEvent::from_msg(&msg);
+            match event.kind {
+                OutputKind::Status { .. } | OutputKind::ExecuteInput { .. } => {}
+                _ => outputs.push(serde_json::to_value(event).unwrap()),
+            }
+        }
+
+        let mut result = Map::new();
+        result.insert("status".to_string(), status);
+        result.insert("execution_count".to_string(), execution_count);
+        result.insert("outputs".to_string(), Value::Array(outputs));
+        to_python(py, &Value::Object(result))
+    }
+
+    // Run `code` and yield every message about it as it comes, as dicts
+    // with the header, parent_header, metadata and content, and msg_id and
+    // msg_type from the header as jupyter_client has them, mostly the iopub
+    // ones and then the execute_reply. The client is shared with it until the
+    // kernel is done with the cell.
+    fn execute_iter(slf: PyRef<Self>, code: &str) -> Messages {
+        let messages = slf.send(code);
+        Messages {
+            client: slf.into(),
+            messages,
+        }
+    }
+
+    // interrupt the cell that's running, an error if no interrupt_reply comes
+    fn interrupt(&self, py: Python) -> PyResult<()> {
+        py.allow_threads(|| self.control.lock().unwrap().interrupt_kernel())
+            .map_err(py_err)
+    }
+
+    // ask the kernel to exit, or to restart with restart=True
+    #[args(restart = "false")]
+    fn shutdown(&self, py: Python, restart: bool) -> PyResult<()> {
+        match py.allow_threads(|| self.control.lock().unwrap().shutdown(restart)) {
+            Some(_) => Ok(()),
+            None => Err(py_err(Error::Timeout("shutdown_reply"))),
+        }
+    }
+}
+
+impl Client {
+    fn send(&self, code: &str) -> RequestMessages {
+        // nobody is there to answer input()
+        let options = ExecuteOptions {
+            allow_stdin: false,
+            ..ExecuteOptions::default()
+        };
+        let msg_id = self.client.lock().unwrap().execute_with(code, &options);
+        RequestMessages::new(&msg_id)
+    }
+
+    // The next of `messages`, with the GIL released while waiting for it.
+    // A signal handler that raised, like Ctrl-C's, interrupts the kernel.
+    fn next_message(
+        &self,
+        py: Python,
+        messages: &mut RequestMessages,
+    ) -> PyResult<Option<Map<String, Value>>> {
+        while !messages.done() {
+            let msg = py
+                .allow_threads(|| messages.next(&mut self.client.lock().unwrap(), POLL_MS))
+                .map_err(py_err)?;
+            if msg.is_some() {
+                return Ok(msg);
+            }
+            if let Err(err) = py.check_signals() {
+                let _ = py.allow_threads(|| self.control.lock().unwrap().interrupt_kernel());
+                return Err(err);
+            }
+        }
+        Ok(None)
+    }
+}
+
+// what Client.execute_iter returns
+#[pyclass(module = "jupyterm._client")]
+#[derive(Debug)]
+pub struct Messages {
+    client: Py<Client>,
+    messages: RequestMessages,
+}
+
+#[pyproto]
+impl PyIterProtocol for Messages {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<Option<PyObject>> {
+        // held already, slf.py() would keep slf borrowed
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let client = slf.client.clone_ref(py);
+        let client = client.borrow(py);
+        match client.next_message(py, &mut slf.messages)? {
+            Some(mut msg) => {
+                let header = msg["header"].clone();
+                msg.insert("msg_id".to_string(), header["msg_id"].clone());
+                msg.insert("msg_type".to_string(), header["msg_type"].clone());
+                Ok(Some(to_python(py, &Value::Object(msg))?))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+fn session(info: &ConnectionInfo) -> Session {
+    static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+    Session {
+        key: info.key.clone(),
+        session_id: format!(
+            "jupyterm-python-{}-{}",
+            process::id(),
+            CLIENTS.fetch_add(1, Ordering::SeqCst)
+        ),
+        signature_scheme: SignatureScheme::for_connection(&info.signature_scheme, &info.key),
+    }
+}
+
+// the exception Python code would expect for each
+fn py_err(err: Error) -> PyErr {
+    match err {
+        Error::Timeout(_) => PyTimeoutError::new_err(err.to_string()),
+        Error::Io(_) => PyOSError::new_err(err.to_string()),
+        _ => PyRuntimeError::new_err(err.to_string()),
+    }
+}
+
+fn to_python(py: Python, value: &Value) -> PyResult<PyObject> {
+    Ok(match value {
+        Value::Null => py.None(),
+        Value::Bool(b) => b.into_py(py),
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(n), _) => n.into_py(py),
+            (None, Some(n)) => n.into_py(py),
+            (None, None) => n.as_f64().unwrap_or_default().into_py(py),
+        },
+        Value::String(s) => s.into_py(py),
+        Value::Array(items) => {
+            let items = items
+                .iter()
+                .map(|item| to_python(py, item))
+                .collect::<PyResult<Vec<_>>>()?;
+            PyList::new(py, items).into_py(py)
+        }
+        Value::Object(map) => {
+            let dict = PyDict::new(py);
+            for (key, value) in map {
+                dict.set_item(key, to_python(py, value)?)?;
+            }
+            dict.into_py(py)
+        }
+    })
+}
@@ -0,0 +1,63 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A token bucket for execute_requests: `rate` tokens a second, up to a
+// second's worth saved up, so a burst after a quiet spell goes out at once
+// and anything faster than the rate waits its turn.
+#[derive(Debug)]
+pub struct RateLimiter {
+    tokens: u32,
+    last_refill: Instant,
+    rate: f64,
+}
+
+impl RateLimiter {
+    // `rate` a second, anything under 0.001 is taken as that
+    pub fn new(rate: f64) -> RateLimiter {
+        let rate = if rate.is_finite() {
+            rate.max(0.001)
+        } else {
+            0.001
+        };
+        let mut limiter = RateLimiter {
+            tokens: 0,
+            last_refill: Instant::now(),
+            rate,
+        };
+        limiter.tokens = limiter.capacity();
+        limiter
+    }
+
+    fn capacity(&self) -> u32 {
+        (self.rate.ceil() as u32).max(1)
+    }
+
+    // the tokens earned since the last refill, keeping what's left over of
+    // a token's time for the next one
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = (now - self.last_refill).as_secs_f64() * self.rate;
+        let whole = earned.floor().min(u32::MAX as f64) as u32;
+        if whole == 0 {
+            return;
+        }
+        self.tokens = self.tokens.saturating_add(whole);
+        if self.tokens >= self.capacity() {
+            self.tokens = self.capacity();
+            self.last_refill = now;
+        } else {
+            self.last_refill += Duration::from_secs_f64(whole as f64 / self.rate);
+        }
+    }
+
+    // Take a token, sleeping until there is one.
+    pub fn acquire(&mut self) {
+        self.refill();
+        while self.tokens == 0 {
+            let next = self.last_refill + Duration::from_secs_f64(1.0 / self.rate);
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+            self.refill();
+        }
+        self.tokens -= 1;
+    }
+}
@@ -0,0 +1,2285 @@
+use crossterm::style::Stylize;
+use crossterm::terminal;
+use jupyterm::aliases::Aliases;
+use jupyterm::capture::{self, Capture};
+use jupyterm::client::{
+    self, Cutypr, ExecuteOptions, ExpressionResult, HistoryEntry, HistoryQuery, InterruptMode,
+    KernelInfoReply, KernelTiming, ReadySocket,
+};
+use jupyterm::clipboard;
+use jupyterm::commands::{
+    self, Command, ExportFormat, HistoryCommand, RecordCommand, SessionCommand, DEFAULT_HISTORY,
+};
+use jupyterm::config::Config;
+use jupyterm::connection::ConnectionInfo;
+use jupyterm::debugger::{self, Debugger, StackFrame, Variable};
+use jupyterm::display::{self, format_duration};
+use jupyterm::editor::{self, EditingMode, Editor, Helper};
+use jupyterm::error::Error;
+use jupyterm::events::{OutputEvent, OutputKind};
+use jupyterm::frontend::{self, CellReply, Frontend, KernelState, Prepared, ReplSession};
+use jupyterm::graphics::OutputArea;
+use jupyterm::html;
+use jupyterm::kernel_log::KernelLog;
+use jupyterm::markdown;
+use jupyterm::metrics::Metrics;
+use jupyterm::named_session::NamedSession;
+use jupyterm::notebook::{self, Cell, KernelspecMetadata, Notebook, NotebookMetadata, Recording};
+use jupyterm::notify::{Finished, Notifier};
+use jupyterm::out_cache::OutCache;
+use jupyterm::outputs::{self, SavedOutputs};
+use jupyterm::replay::{self, Outcome, OutputFilter, ReplayReport, TranscriptFile};
+use jupyterm::server::{Bridge, Kernel, Server};
+use jupyterm::state::State;
+use jupyterm::throttle::OutputThrottle;
+use jupyterm::transcript::{self, Transcript};
+use jupyterm::tunnel::Tunnel;
+use jupyterm::watcher::ConnectionWatcher;
+use jupyterm::widgets::{self, Widgets};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{info, warn, Span};
+
+// Ctrl-C while a cell runs, when the terminal isn't in raw mode and sends
+// us SIGINT. The kernel doesn't get it, it's in a process group of its own.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// :load asks first for files longer than this
+const LOAD_WARN_LINES: usize = 200;
+
+// the cells of a --session picked up again shown when it starts
+const RESUMED_CELLS: usize = 5;
+
+// Make Ctrl-C interrupt the kernel while a cell runs. Anywhere else it quits
+// like it always has, the prompt reads it as a key instead.
+pub fn handle_ctrl_c() {
+    let handler = ctrlc::set_handler(|| {
+        if RUNNING.load(Ordering::SeqCst) {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+        } else {
+            process::exit(130);
+        }
+    });
+    if let Err(err) = handler {
+        eprintln!("jupyterm: Ctrl-C won't interrupt the kernel: {}", err);
+    }
+}
+
+// settings that :set can change while the REPL runs
+pub struct Options {
+    pub timing: bool,
+    pub timing_threshold: Duration,
+    pub notify: bool,
+    pub notify_threshold: Duration,
+    // write iopub messages as JSON instead of displaying them
+    pub json: bool,
+    // shut down after this long at the prompt without typing anything
+    pub repl_timeout: Option<Duration>,
+    // --matplotlib inline, set up in every session's kernel
+    pub matplotlib_inline: bool,
+    // off with --no-store-history, so kernels keep no %history of cells
+    pub store_history: bool,
+    // the kernel's banner before the first prompt and a line about it on
+    // every switch, off with --quiet and when stdin isn't a terminal
+    pub banner: bool,
+    // for the cells of HTML tables
+    pub max_column_width: usize,
+    // make links of the files in tracebacks, off where the terminal can't
+    // show links or NO_COLOR is set
+    pub traceback_links: bool,
+    // like vscode://file/{path}:{line}, file:// URLs without one
+    pub link_template: Option<String>,
+    // --only-local-links, leave files that aren't on this machine alone
+    pub only_local_links: bool,
+    // tracebacks with more frames than this are shown collapsed, 0 never
+    pub traceback_frames: usize,
+    // streams faster than this a second are summed up instead, 0 for no limit
+    pub max_output_lines: u64,
+    pub max_output_bytes: u64,
+    // --max-executions-per-second, for every session's kernel
+    pub max_executions_per_second: Option<f64>,
+}
+
+// why run() stopped
+pub enum Exit {
+    // end of input, Ctrl-D
+    Eof,
+    // :detach, leave the kernel running
+    Detach,
+    // --repl-timeout ran out
+    Idle,
+    // the kernels of every session went away
+    KernelDied,
+}
+
+// One kernel the REPL can send cells to, with its own count and transcript.
+pub struct KernelSession {
+    pub name: String,
+    pub client: Cutypr,
+    pub connection: ConnectionInfo,
+    pub connection_file: PathBuf,
+    // started by jupyterm, so shut down on exit
+    pub owned: bool,
+    // what the kernel said about itself when it was ready
+    pub kernel_info: KernelInfoReply,
+    // the COLUMNS and LINES the kernel was last told about
+    pub terminal_size: Option<(u16, u16)>,
+    pub execution_count: i32,
+    pub transcript: Transcript,
+    // None for kernels jupyterm didn't start
+    pub kernel_log: Option<KernelLog>,
+    // with --ssh
+    pub tunnel: Option<Tunnel>,
+    // with --url
+    pub remote: Option<Remote>,
+    // iopub messages that came in while another session was active
+    pub missed: Vec<Map<String, Value>>,
+    // :watch expressions, shown after every cell
+    pub watches: Vec<String>,
+    // for kernels jupyterm attached to, which others may restart
+    pub watcher: Option<ConnectionWatcher>,
+    // the kernel's debugger, from the first :break on
+    pub debugger: Option<Debugger>,
+    // the data of the last display_data or execute_result, and the In
+    // number of its cell, for :open-last
+    pub last_output: Option<(Value, Option<i64>)>,
+    // the text the last cell printed and returned, for :copy
+    pub last_cell_text: Option<String>,
+    // the entries of the last error's traceback, whole, for :traceback
+    pub last_traceback: Option<Vec<String>>,
+    // the kernel's ipywidgets, for the lines shown in their place
+    pub widgets: Widgets,
+    // the outputs of the last cells, for :out
+    pub out_cache: OutCache,
+    // every cell run in it and what came of it, for :export
+    pub cells: Vec<Cell>,
+}
+
+// a kernel on a Jupyter Server and the bridge to its WebSocket
+pub struct Remote {
+    pub server: Server,
+    pub kernel: Kernel,
+    pub bridge: Bridge,
+}
+
+impl KernelSession {
+    // what a notebook recorded from this session says it was run with
+    pub fn notebook_metadata(&self) -> NotebookMetadata {
+        let language = &self.kernel_info.language_info;
+        let name = self.client.kernel_name().to_string();
+        let language_info = [
+            ("name", &language.name),
+            ("version", &language.version),
+            ("mimetype", &language.mimetype),
+            ("file_extension", &language.file_extension),
+        ]
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| (key.to_string(), Value::from(value.as_str())))
+        .collect();
+        NotebookMetadata {
+            kernelspec: Some(KernelspecMetadata {
+                display_name: name.clone(),
+                name,
+                language: Some(language.name.clone()).filter(|name| !name.is_empty()),
+            }),
+            language_info: Some(language_info),
+            other: Map::new(),
+        }
+    }
+
+    // the kernel's own greeting, and a line saying what it runs
+    pub fn print_banner(&self) {
+        let banner = self.kernel_info.banner.trim_end();
+        if !banner.is_empty() {
+            println!("{}", banner);
+        }
+        println!("{}", self.kernel_info.summary().dim());
+    }
+
+    // When the connection file has new ports or a new key, because
+    // something restarted the kernel on them, move over to them.
+    fn follow_connection_file(&mut self) {
+        if !self
+            .watcher
+            .as_ref()
+            .is_some_and(ConnectionWatcher::changed)
+        {
+            return;
+        }
+        // half written, the rest comes with another change
+        let info = match ConnectionInfo::read(&self.connection_file) {
+            Ok(info) => info,
+            Err(_) => return,
+        };
+        if info.ports() == self.connection.ports() && info.key == self.connection.key {
+            return;
+        }
+
+        warn!(
+            session = self.name.as_str(),
+            file = %self.connection_file.display(),
+            "connection file changed, reconnecting"
+        );
+        self.client.reconnect(&info.key, info.ports());
+        // a new kernel process with a debugger nobody has set up
+        self.debugger = None;
+        match self
+            .client
+            .wait_for_kernel_ready(crate::KERNEL_READY_TIMEOUT)
+        {
+            Ok(reply) => self.kernel_info = reply,
+            Err(err) => eprintln!(
+                "jupyterm: {} didn't come back on its new ports: {}",
+                self.name, err
+            ),
+        }
+        self.connection = info;
+    }
+
+    // :break, starting the kernel's debugger the first time
+    fn set_breakpoint(&mut self, file: &str, line: u64) {
+        if !self.kernel_info.debugger {
+            eprintln!("debugger not supported by this kernel");
+            return;
+        }
+        // the kernel of a local session runs where we do, and may not
+        // have the same working directory
+        let path = if self.tunnel.is_none() && self.remote.is_none() {
+            fs::canonicalize(file).unwrap_or_else(|_| PathBuf::from(file))
+        } else {
+            PathBuf::from(file)
+        };
+        let path = path.to_string_lossy();
+        if self.debugger.is_none() {
+            let language = &self.kernel_info.language_info.name;
+            match Debugger::start(&self.client, language) {
+                Ok(debugger) => self.debugger = Some(debugger),
+                Err(err) => return eprintln!("jupyterm: couldn't start the debugger: {}", err),
+            }
+        }
+        let debugger = self.debugger.as_mut().unwrap();
+        match debugger.set_breakpoint(&self.client, &path, line) {
+            Ok(set) if set.verified => println!("breakpoint at {}:{}", path, set.line),
+            Ok(set) => println!(
+                "breakpoint at {}:{}, not verified yet, the file may not be loaded",
+                path, set.line
+            ),
+            Err(err) => eprintln!("jupyterm: {}", err),
+        }
+    }
+
+    // which kernel is behind the session, when it's switched to
+    pub fn print_summary(&self) {
+        let line = format!("{}: {}", self.name, self.kernel_info.summary());
+        println!("{}", line.dim());
+    }
+
+    // Have matplotlib send figures as display_data PNGs instead of opening
+    // windows on the kernel's machine, which takes IPython's magics.
+    pub fn matplotlib_inline(&mut self) {
+        if self.kernel_info.language_info.name != "python" {
+            eprintln!(
+                "jupyterm: --matplotlib inline only works with Python kernels, not {:?}",
+                self.kernel_info.language_info.name
+            );
+            return;
+        }
+        match self.client.execute_silent_and_collect("%matplotlib inline") {
+            Ok(result) if result.status.as_deref() == Some("ok") => {}
+            Ok(_) => eprintln!("jupyterm: %matplotlib inline failed, is matplotlib installed?"),
+            Err(err) => eprintln!("jupyterm: %matplotlib inline failed: {}", err),
+        }
+    }
+
+    // Tell a Python kernel about a new terminal size through COLUMNS and
+    // LINES, which shutil.get_terminal_size and so pandas go by.
+    fn sync_terminal_size(&mut self) {
+        let size = terminal::size().ok();
+        if self.kernel_info.language_info.name != "python"
+            || size.is_none()
+            || size == self.terminal_size
+        {
+            return;
+        }
+        let (columns, lines) = size.unwrap();
+        let code = format!(
+            "import os; os.environ['COLUMNS'] = '{}'; os.environ['LINES'] = '{}'",
+            columns, lines
+        );
+        match self.client.execute_silent_and_collect(&code) {
+            Ok(_) => self.terminal_size = size,
+            Err(err) => eprintln!("jupyterm: couldn't update the kernel's COLUMNS: {}", err),
+        }
+    }
+
+    // The config's startup code, as silent cells that get no execution count
+    // and stay out of the history. What they print is shown, and a startup
+    // file that fails is reported without stopping the rest.
+    pub fn run_startup(&mut self, config: &Config) {
+        let mut scripts = Vec::new();
+        if config.pythonstartup && self.kernel_info.language_info.name == "python" {
+            if let Some(path) = env::var_os("PYTHONSTARTUP") {
+                scripts.push(PathBuf::from(path));
+            }
+        }
+        scripts.extend(config.startup_files.iter().cloned());
+
+        let mut cells = Vec::new();
+        for path in scripts {
+            match fs::read_to_string(&path) {
+                Ok(code) => cells.push((path.display().to_string(), code)),
+                Err(err) => eprintln!(
+                    "jupyterm: couldn't read startup file {}: {}",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+        if let Some(code) = &config.startup_code {
+            cells.push(("startup_code".to_string(), code.clone()));
+        }
+
+        for (name, code) in cells {
+            let result = match self.client.execute_silent_and_collect(&code) {
+                Ok(result) => result,
+                Err(err) => {
+                    eprintln!("jupyterm: running {} failed: {}", name, err);
+                    continue;
+                }
+            };
+            let mut failed = result.status.as_deref() == Some("error");
+            for output in &result.outputs {
+                match &output.kind {
+                    OutputKind::Stream { name, text } if name == "stderr" => eprint!("{}", text),
+                    OutputKind::Stream { text, .. } => print!("{}", text),
+                    OutputKind::Error {
+                        ename,
+                        evalue,
+                        traceback,
+                    } => {
+                        let heading =
+                            format!("jupyterm: {} failed with {}: {}", name, ename, evalue);
+                        eprintln!("{}", heading.red().bold());
+                        for line in traceback {
+                            eprintln!("{}", line);
+                        }
+                        failed = false;
+                    }
+                    _ => {}
+                }
+            }
+            // kernels that don't send an error for silent cells
+            if failed {
+                eprintln!("{}", format!("jupyterm: {} failed", name).red().bold());
+            }
+        }
+    }
+
+    // nothing will come from the kernel again
+    fn is_gone(&self) -> bool {
+        self.kernel_log.as_ref().is_some_and(KernelLog::has_exited)
+            || self
+                .remote
+                .as_ref()
+                .is_some_and(|remote| remote.bridge.is_closed())
+    }
+
+    fn report_gone(&self) {
+        match &self.kernel_log {
+            Some(log) if log.has_exited() => log.report("the kernel exited"),
+            _ => eprintln!("jupyterm: the Jupyter server closed the connection to the kernel"),
+        }
+    }
+
+    // What a session in the background gets up to, kept for when it's
+    // switched to. Its kernel can't be asked for input() until then.
+    fn drain(&mut self) {
+        loop {
+            let ready = match self.client.poll_all(0) {
+                Ok(ready) => ready,
+                Err(_) => return,
+            };
+            let mut read = false;
+            for socket in ready {
+                match socket {
+                    ReadySocket::Iopub => {
+                        read = true;
+                        if let Some(msg) = self.client.get_msg() {
+                            let msg_type = msg["header"]["msg_type"].as_str().unwrap_or("");
+                            if msg_type != "status" && msg_type != "execute_input" {
+                                self.missed.push(msg);
+                            }
+                        }
+                    }
+                    ReadySocket::Shell => {
+                        read = true;
+                        self.client.get_shell_msg();
+                    }
+                    ReadySocket::Stdin | ReadySocket::Heartbeat => {}
+                }
+            }
+            if !read {
+                return;
+            }
+        }
+    }
+}
+
+impl ReplSession for KernelSession {
+    fn client(&mut self) -> &mut Cutypr {
+        &mut self.client
+    }
+
+    fn execution_count(&self) -> i64 {
+        self.execution_count as i64
+    }
+
+    fn set_execution_count(&mut self, execution_count: i64) {
+        self.execution_count = execution_count as i32;
+    }
+}
+
+pub struct Repl {
+    pub sessions: Vec<KernelSession>,
+    // the one cells go to
+    pub active: usize,
+    pub config: Config,
+    pub editor: Editor,
+    pub aliases: Aliases,
+    pub options: Options,
+    pub notifiers: Vec<Box<dyn Notifier>>,
+    // how :session new starts kernels, like the first one
+    pub keep_kernel: bool,
+    pub kernel_log_file: Option<PathBuf>,
+    // with --metrics-port
+    pub metrics: Option<Metrics>,
+    // what :history listed last, for :history load N
+    pub listed_history: Vec<HistoryEntry>,
+    // images and media the terminal couldn't show
+    pub saved_outputs: SavedOutputs,
+    // set by :capture, taken by the next cell
+    pub capture: Option<Capture>,
+    // with --record or :record start
+    pub recording: Option<Recording>,
+    // with --output FILE, every cell and what it printed, for --replay
+    pub output: Option<TranscriptFile>,
+    // with --session NAME, every cell appended to its transcript
+    pub named: Option<NamedSession>,
+}
+
+fn parse_switch(value: &str) -> Option<bool> {
+    match value {
+        "on" | "true" | "yes" => Some(true),
+        "off" | "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+// Bring a dead ssh tunnel back up, false if that didn't work.
+fn revive(tunnel: &mut Option<Tunnel>) -> bool {
+    let tunnel = match tunnel {
+        Some(tunnel) => tunnel,
+        None => return true,
+    };
+    if tunnel.is_alive() {
+        return true;
+    }
+    eprintln!(
+        "jupyterm: the ssh tunnel to {} went down, reconnecting",
+        tunnel.host()
+    );
+    match tunnel.reopen() {
+        Ok(()) => true,
+        Err(err) => {
+            eprintln!("jupyterm: couldn't reconnect: {}", err);
+            false
+        }
+    }
+}
+
+// The terminal as a Frontend: cells come from the editor, and their
+// outputs go through an OutputArea, with widgets described in a line and
+// what the terminal can't show saved to files. `execution_count` is the In
+// number of the cell the outputs are from, when it's known, for naming the
+// files.
+pub struct Terminal<'a> {
+    pub editor: &'a mut Editor,
+    pub area: &'a mut OutputArea,
+    pub widgets: &'a mut Widgets,
+    pub saved: &'a SavedOutputs,
+    pub options: &'a Options,
+    pub execution_count: Option<i64>,
+}
+
+impl Terminal<'_> {
+    // text for a bundle, as Out[n] for an execute_result
+    fn show_text(&mut self, execution_count: Option<i64>, text: &str) {
+        match execution_count {
+            Some(n) => self.area.println(&format!("Out[{}]: {}", n, text), false),
+            None => self.area.println(text, false),
+        }
+    }
+
+    fn show_bundle(&mut self, execution_count: Option<i64>, data: &Value) {
+        let png = data["image/png"].as_str();
+        let svg = data["image/svg+xml"].as_str();
+        if let Some(id) = data[widgets::VIEW_MIME]["model_id"].as_str() {
+            let mark = self
+                .area
+                .println_marked(&self.widgets.describe(id, terminal_width()));
+            self.widgets.show(id, mark);
+        } else if let Some(png) = png.filter(|_| self.area.shows_images()) {
+            if let Err(err) = self.area.image(png) {
+                eprintln!("jupyterm: couldn't show an image: {}", err);
+            }
+        } else if let Some(svg) = svg.filter(|_| self.area.shows_svg()) {
+            if let Err(err) = self.area.svg(svg) {
+                eprintln!("jupyterm: couldn't draw an SVG: {}", err);
+                if let Some(text) = data["text/plain"].as_str() {
+                    self.show_text(execution_count, text);
+                }
+            }
+        } else if let Some((mime, payload)) = outputs::saved_mime(data) {
+            match self.saved.save(mime, payload, self.execution_count) {
+                Ok(path) => self.area.println(
+                    &format!(
+                        "[{} saved to {}]",
+                        outputs::describe(mime),
+                        display::path_link(&path)
+                    ),
+                    false,
+                ),
+                Err(err) => eprintln!("jupyterm: couldn't save the {} output: {}", mime, err),
+            }
+        } else if let Some(table) = data["text/html"].as_str().and_then(|html| {
+            html::render_table(html, terminal_width(), self.options.max_column_width)
+        }) {
+            self.show_text(execution_count, &format!("\n{}", table));
+        } else if let Some(text) = data["text/plain"].as_str() {
+            self.show_text(execution_count, text);
+        }
+    }
+}
+
+impl Frontend for Terminal<'_> {
+    fn read_cell(&mut self, prompt: &str, helper: &dyn Helper) -> io::Result<Option<String>> {
+        self.editor.read_cell(prompt, helper)
+    }
+
+    // kept out of the editor's history, and the kernel doesn't echo it either
+    fn read_input(&mut self, prompt: &str, password: bool) -> io::Result<Option<String>> {
+        if password {
+            // Ctrl-C gives up on it
+            return Ok(editor::read_password(prompt).ok().flatten());
+        }
+        print!("{}", prompt);
+        io::stdout().flush()?;
+
+        let mut value = String::new();
+        io::stdin().read_line(&mut value)?;
+        Ok(Some(value.trim_end_matches(&['\r', '\n'][..]).to_string()))
+    }
+
+    fn show_stream(&mut self, name: &str, text: &str) {
+        // quoted, like the REPL always has
+        let text = Value::from(text).to_string();
+        match name {
+            "stdout" => self.area.println(&text, false),
+            "stderr" => self.area.println(&text, true),
+            _ => println!("Unknown stream name"),
+        }
+    }
+
+    fn show_result(&mut self, execution_count: Option<i64>, data: &Value) {
+        self.show_bundle(execution_count, data);
+    }
+
+    fn show_display(&mut self, data: &Value) {
+        self.show_bundle(None, data);
+    }
+
+    fn show_error(&mut self, traceback: &[String]) {
+        let traceback: Vec<&str> = traceback.iter().map(String::as_str).collect();
+        for entry in display::collapse_traceback(&traceback, self.options.traceback_frames) {
+            self.area
+                .println(&link_traceback(&entry, self.options), false);
+        }
+    }
+
+    fn clear_output(&mut self, wait: bool) {
+        self.area.clear(wait);
+    }
+
+    // the prompt coming back is all it shows of that
+    fn set_state(&mut self, _state: KernelState) {}
+}
+
+// Why the REPL's frontend stopped run_repl, for Repl::run to deal with.
+enum Stop {
+    Gone,
+    Exit(Exit),
+    // it needs the whole Repl, with the session back in it
+    Command(Command),
+}
+
+// What the REPL keeps track of while a cell runs, for after it.
+struct CellState {
+    code: String,
+    execution_count: i64,
+    // a ';' at the end hides its result
+    quiet: bool,
+    started: Instant,
+    area: OutputArea,
+    throttle: OutputThrottle,
+    capture: Option<Capture>,
+    cell_text: String,
+    // what gets recorded and exported
+    events: Vec<OutputEvent>,
+    // what :out shows again
+    cached: Vec<Map<String, Value>>,
+    recorded_count: Option<i64>,
+    // like %load's, for the next prompt
+    next_input: Option<String>,
+    watched: BTreeMap<String, ExpressionResult>,
+    timing: Option<KernelTiming>,
+    // the thread the debugger stopped in
+    stopped: Option<i64>,
+}
+
+// The REPL as run_repl's frontend on its active session, which is taken
+// out of `repl.sessions` meanwhile, so those are the others. The session's
+// widgets are here while it runs, for the terminal to show them.
+struct ReplFrontend<'a> {
+    repl: &'a mut Repl,
+    widgets: Widgets,
+    cell: Option<CellState>,
+    stop: Option<Stop>,
+}
+
+impl ReplFrontend<'_> {
+    fn cell(&mut self) -> &mut CellState {
+        self.cell.as_mut().expect("only while a cell runs")
+    }
+
+    fn terminal(&mut self) -> Terminal<'_> {
+        let cell = self.cell.as_mut().expect("only while a cell runs");
+        Terminal {
+            editor: &mut self.repl.editor,
+            area: &mut cell.area,
+            widgets: &mut self.widgets,
+            saved: &self.repl.saved_outputs,
+            options: &self.repl.options,
+            execution_count: Some(cell.execution_count),
+        }
+    }
+
+    fn follow_connection_files(&mut self, session: &mut KernelSession) {
+        session.follow_connection_file();
+        self.repl.follow_connection_files();
+    }
+}
+
+impl Frontend<KernelSession> for ReplFrontend<'_> {
+    fn read_cell(&mut self, prompt: &str, helper: &dyn Helper) -> io::Result<Option<String>> {
+        self.repl.editor.read_cell(prompt, helper)
+    }
+
+    fn read_input(&mut self, prompt: &str, password: bool) -> io::Result<Option<String>> {
+        self.terminal().read_input(prompt, password)
+    }
+
+    fn show_stream(&mut self, name: &str, text: &str) {
+        self.terminal().show_stream(name, text);
+    }
+
+    fn show_result(&mut self, execution_count: Option<i64>, data: &Value) {
+        self.terminal().show_result(execution_count, data);
+    }
+
+    fn show_display(&mut self, data: &Value) {
+        self.terminal().show_display(data);
+    }
+
+    fn show_error(&mut self, traceback: &[String]) {
+        self.terminal().show_error(traceback);
+    }
+
+    fn clear_output(&mut self, wait: bool) {
+        self.terminal().clear_output(wait);
+    }
+
+    fn set_state(&mut self, state: KernelState) {
+        self.terminal().set_state(state);
+    }
+
+    fn show_notice(&mut self, text: &str) {
+        println!("[{}]", text);
+    }
+
+    fn show_warning(&mut self, text: &str) {
+        eprintln!("jupyterm: {}", text);
+    }
+
+    fn start_cell(&mut self, execution_count: i64) {
+        self.cell().execution_count = execution_count;
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        RUNNING.store(true, Ordering::SeqCst);
+    }
+
+    fn interrupted(&mut self) -> bool {
+        INTERRUPTED.swap(false, Ordering::SeqCst)
+    }
+
+    fn before_prompt(&mut self, session: &mut KernelSession) -> bool {
+        // before a restarted kernel's old ports make it look gone
+        self.follow_connection_files(session);
+        if session.is_gone() {
+            self.stop = Some(Stop::Gone);
+            return false;
+        }
+        // the kernel itself is fine, so leave it be
+        if !revive(&mut session.tunnel) {
+            self.stop = Some(Stop::Exit(Exit::Detach));
+            return false;
+        }
+        for other in &mut self.repl.sessions {
+            other.drain();
+        }
+        if let Some(metrics) = &self.repl.metrics {
+            metrics.set_kernels_alive(self.repl.sessions.len() + 1);
+        }
+        true
+    }
+
+    fn prompt(&mut self, session: &mut KernelSession) -> String {
+        // main's root span, kept up to date with the session and cell at
+        // the prompt for every record on the way
+        let span = Span::current();
+        span.record("kernel.name", session.client.kernel_name());
+        span.record("session.id", session.client.session_id());
+        span.record("execution_count", session.execution_count);
+        prompt(session, !self.repl.sessions.is_empty())
+    }
+
+    fn prepare(&mut self, session: &mut KernelSession, code: String) -> Prepared {
+        // the file may have changed while the prompt waited
+        self.follow_connection_files(session);
+
+        if let Some(command) = commands::parse(&code) {
+            match command {
+                Ok(Command::Detach) => self.stop = Some(Stop::Exit(Exit::Detach)),
+                Ok(command) => self.stop = Some(Stop::Command(command)),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return Prepared::Done;
+                }
+            }
+            return Prepared::Stop;
+        }
+
+        // run what the alias stands for, and show it so the transcript adds up
+        match self.repl.aliases.expand(&code) {
+            Ok(Some(expanded)) => {
+                let prompt = prompt(session, !self.repl.sessions.is_empty());
+                self.repl.echo(&prompt, &expanded);
+                Prepared::Run(expanded)
+            }
+            Ok(None) => Prepared::Run(code),
+            Err(err) => {
+                eprintln!("{}", err);
+                Prepared::Done
+            }
+        }
+    }
+
+    fn execute_options(&mut self, session: &mut KernelSession, code: &str) -> ExecuteOptions {
+        // a resize only matters to the reprs of the next cell
+        if self.repl.config.sync_terminal_size {
+            session.sync_terminal_size();
+        }
+        self.widgets.forget_shown();
+        let options = &self.repl.options;
+        self.cell = Some(CellState {
+            code: code.to_string(),
+            execution_count: session.execution_count as i64,
+            quiet: ends_with_semicolon(code),
+            started: Instant::now(),
+            area: OutputArea::new(),
+            throttle: OutputThrottle::new(options.max_output_lines, options.max_output_bytes),
+            capture: self.repl.capture.take(),
+            cell_text: String::new(),
+            events: Vec::new(),
+            cached: Vec::new(),
+            recorded_count: None,
+            next_input: None,
+            watched: BTreeMap::new(),
+            timing: None,
+            stopped: None,
+        });
+        ExecuteOptions {
+            store_history: options.store_history,
+            // each expression is its own name
+            user_expressions: session
+                .watches
+                .iter()
+                .map(|expr| (expr.clone(), Value::String(expr.clone())))
+                .collect(),
+            ..ExecuteOptions::default()
+        }
+    }
+
+    fn waiting(&mut self, session: &mut KernelSession) -> bool {
+        if let Some(thread) = self.cell().stopped.take() {
+            debug_prompt(session, &mut self.repl.editor, thread);
+        }
+        // nothing is coming, run() says why
+        if session.is_gone() || !revive(&mut session.tunnel) {
+            return false;
+        }
+        for other in &mut self.repl.sessions {
+            other.drain();
+        }
+        let cell = self.cell();
+        if let Some(summary) = cell.throttle.tick() {
+            cell.area.println(&summary.dim().to_string(), false);
+        }
+        true
+    }
+
+    fn message(
+        &mut self,
+        session: &mut KernelSession,
+        msg: &Map<String, Value>,
+        ours: bool,
+    ) -> bool {
+        let json = self.repl.options.json;
+        if json {
+            println!("{}", json_line(msg));
+        }
+        let cell = self.cell.as_mut().expect("only while a cell runs");
+        let msg_type = msg["header"]["msg_type"].as_str().unwrap_or_default();
+        // the debugger sends these without a parent
+        if msg_type == "debug_event" {
+            cell.stopped = cell
+                .stopped
+                .or_else(|| debugger::stopped_thread(&msg["content"]));
+            return false;
+        }
+        // widgets change from threads of their own too, whatever the parent
+        if msg_type.starts_with("comm_") {
+            widget_msg(&mut self.widgets, &mut cell.area, msg_type, &msg["content"]);
+            return false;
+        }
+        // skip messages for other requests, like the is_complete checks
+        if !ours {
+            return false;
+        }
+        let quiet = cell.quiet;
+        if msg_type == "display_data" || msg_type == "execute_result" {
+            session.last_output =
+                Some((msg["content"]["data"].clone(), Some(cell.execution_count)));
+        }
+        if msg_type == "error" {
+            session.last_traceback = Some(frontend::traceback(&msg["content"]));
+        }
+        if !(quiet && msg_type == "execute_result") {
+            cell.events.push(OutputEvent::from_msg(msg));
+        }
+        if matches!(
+            msg_type,
+            "stream" | "display_data" | "update_display_data" | "error"
+        ) || (msg_type == "execute_result" && !quiet)
+        {
+            cell.cached.push(msg.clone());
+        }
+        // a result left off with a ';' is left out of these too
+        let text = capture::text_of(msg_type, &msg["content"])
+            .filter(|_| !(quiet && msg_type == "execute_result"));
+        if let Some(text) = &text {
+            cell.cell_text.push_str(text);
+        }
+        if let (Some(file), Some(text)) = (&mut cell.capture, &text) {
+            match file.write(text) {
+                Ok(()) if !file.display => return false,
+                Ok(()) => {}
+                Err(err) => {
+                    eprintln!(
+                        "jupyterm: stopped capturing to {}: {}",
+                        file.path().display(),
+                        err
+                    );
+                    cell.capture = None;
+                }
+            }
+        }
+        // --json has already written it, only keep track of the state
+        if json && msg_type != "status" && msg_type != "execute_input" {
+            return false;
+        }
+        match msg_type {
+            // the kernel may have left it out already
+            "execute_result" => !quiet,
+            // a flood, the throttle sums it up once a second
+            "stream" => cell
+                .throttle
+                .admit(msg["content"]["text"].as_str().unwrap_or("")),
+            _ => true,
+        }
+    }
+
+    fn reply(&mut self, session: &mut KernelSession, reply: &Map<String, Value>) {
+        let cell = self.cell.as_mut().expect("only while a cell runs");
+        cell.watched = client::user_expressions(&reply["content"]);
+        cell.next_input = cell
+            .next_input
+            .take()
+            .or_else(|| client::next_input(&reply["content"]));
+        cell.timing = client::kernel_timing(reply);
+        let count = match reply["content"]["execution_count"].as_i64() {
+            Some(count) => count,
+            None => return,
+        };
+        let errored = reply["content"]["status"] == "error";
+        cell.recorded_count = Some(count);
+        session.transcript.record(count, &cell.code);
+        if errored {
+            session.transcript.mark_failed(count);
+        }
+        if let Some(named) = &mut self.repl.named {
+            if let Err(err) = named.append(count, &cell.code, errored) {
+                eprintln!(
+                    "jupyterm: couldn't add to the {} session, no longer keeping it: {}",
+                    named.name, err
+                );
+                self.repl.named = None;
+            }
+        }
+    }
+
+    fn end_cell(&mut self, session: &mut KernelSession, code: &str, reply: &CellReply) {
+        RUNNING.store(false, Ordering::SeqCst);
+        let mut cell = self.cell.take().expect("only while a cell runs");
+        let repl = &mut *self.repl;
+        let failed = reply.failed();
+        if let Some(summary) = cell.throttle.finish() {
+            cell.area.println(&summary.dim().to_string(), false);
+        }
+        session.last_cell_text = Some(cell.cell_text);
+        if let Some(count) = cell.recorded_count {
+            session.out_cache.insert(count, cell.cached);
+        }
+        if let Some(text) = cell.next_input {
+            repl.editor.prefill(&text);
+        }
+        if let Some(recording) = &mut repl.recording {
+            if let Err(err) = recording.record(code, cell.recorded_count, &cell.events) {
+                eprintln!(
+                    "jupyterm: couldn't write {}: {}",
+                    recording.path().display(),
+                    err
+                );
+            }
+        }
+        if let Some(output) = &mut repl.output {
+            if let Err(err) = output.write_cell(cell.recorded_count, code, &cell.events) {
+                eprintln!(
+                    "jupyterm: couldn't write {}, no longer writing the transcript: {}",
+                    output.path().display(),
+                    err
+                );
+                repl.output = None;
+            }
+        }
+        session.cells.push(Cell::Code {
+            id: None,
+            metadata: Map::new(),
+            source: code.to_string(),
+            execution_count: cell.recorded_count,
+            outputs: notebook::outputs_of(&cell.events),
+        });
+        if let Some(file) = cell.capture {
+            println!(
+                "{}",
+                format!(
+                    "captured {} bytes to {}",
+                    file.written(),
+                    file.path().display()
+                )
+                .dim()
+            );
+        }
+        let options = &repl.options;
+        if !options.json && !cell.watched.is_empty() {
+            println!("{}", watch_footer(&session.watches, &cell.watched).dim());
+        }
+
+        // measured up to this cell's own idle, queued cells don't add up
+        let elapsed = cell.started.elapsed();
+        if options.timing && !options.json {
+            if let Some(footer) = timing_footer(elapsed, cell.timing, options.timing_threshold) {
+                println!("{}", footer.dim());
+            }
+        }
+        if let Some(metrics) = &repl.metrics {
+            metrics.record_execution(elapsed, failed);
+        }
+        info!(
+            status = if failed { "error" } else { "ok" },
+            duration_ms = elapsed.as_millis() as u64,
+            "cell finished"
+        );
+        if options.notify && elapsed >= options.notify_threshold {
+            let finished = Finished {
+                execution_count: cell.execution_count as i32,
+                elapsed,
+                succeeded: !failed,
+            };
+            for notifier in &repl.notifiers {
+                notifier.notify(&finished);
+            }
+        }
+    }
+}
+
+// Keep up with a widget's comm_open, comm_msg or comm_close, and write the
+// lines that show it again when it changes.
+fn widget_msg(widgets: &mut Widgets, area: &mut OutputArea, msg_type: &str, content: &Value) {
+    match msg_type {
+        "comm_open" => widgets.open(content),
+        "comm_msg" => {
+            if let Some(id) = widgets.update(content) {
+                for (shown, mark) in widgets.shown_with(&id) {
+                    area.rewrite(mark, &widgets.describe(&shown, terminal_width()));
+                }
+            }
+        }
+        "comm_close" => widgets.close(content),
+        _ => {}
+    }
+}
+
+// A traceback entry with its file references made links, to the files on
+// this machine only with --only-local-links, when links are on.
+fn link_traceback(text: &str, options: &Options) -> String {
+    if !options.traceback_links {
+        return text.to_string();
+    }
+    display::link_file_references(text, |path, line| {
+        // IPython shortens paths in the home directory
+        let path = match path.strip_prefix("~/").zip(dirs::home_dir()) {
+            Some((rest, home)) => home.join(rest),
+            None => PathBuf::from(path),
+        };
+        if !path.is_absolute() || options.only_local_links && !path.exists() {
+            return None;
+        }
+        Some(match &options.link_template {
+            Some(template) => display::fill_link_template(template, &path, line),
+            None => display::file_url(&path),
+        })
+    })
+}
+
+// what tables and widgets are fit to, 80 when the output isn't a terminal
+// or it doesn't say how wide it is
+fn terminal_width() -> usize {
+    match terminal::size() {
+        Ok((columns, _)) if columns > 0 => columns as usize,
+        _ => 80,
+    }
+}
+
+// Whether the cell's last line ends in a `;`, which hides its result in
+// IPython. Comments don't count, strings that span lines fool it.
+fn ends_with_semicolon(code: &str) -> bool {
+    let line = match code.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(line) => line,
+        None => return false,
+    };
+    let mut quote = None;
+    let mut escaped = false;
+    let mut end = line.len();
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '#') => {
+                end = i;
+                break;
+            }
+            _ => {}
+        }
+    }
+    line[..end].trim_end().ends_with(';')
+}
+
+// `len(df)=1042  x=NameError`, in the order they were watched, with only the
+// first line of long reprs
+fn watch_footer(watches: &[String], results: &BTreeMap<String, ExpressionResult>) -> String {
+    watches
+        .iter()
+        .filter_map(|expr| {
+            let value = match results.get(expr)? {
+                ExpressionResult::Ok(text) => match text.split_once('\n') {
+                    Some((first, _)) => format!("{}…", first),
+                    None => text.clone(),
+                },
+                ExpressionResult::Error { ename, .. } => ename.clone(),
+            };
+            Some(format!("{}={}", expr, value))
+        })
+        .collect::<Vec<_>>()
+        .join("  ")
+}
+
+// How long the cell ran by the kernel's clock, and how long it waited for
+// the kernel when that's known, or by ours for kernels that don't say.
+// None for cells quicker than the threshold.
+fn timing_footer(
+    elapsed: Duration,
+    timing: Option<KernelTiming>,
+    threshold: Duration,
+) -> Option<String> {
+    let ran = timing.map_or(elapsed, |timing| timing.ran);
+    if ran < threshold {
+        return None;
+    }
+    Some(match timing.and_then(|timing| timing.queued) {
+        Some(queued) => format!(
+            "⏱ {}, queued {}",
+            format_duration(ran),
+            format_duration(queued)
+        ),
+        None => format!("⏱ {}", format_duration(ran)),
+    })
+}
+
+// The kernel stopped in the debugger partway through a cell: take debugger
+// commands until one lets it go on.
+fn debug_prompt(session: &mut KernelSession, editor: &mut Editor, thread: i64) {
+    // another client's breakpoint, it can deal with it
+    if session.debugger.is_none() {
+        return;
+    }
+    let client = &session.client;
+    match session.debugger.as_mut().unwrap().stack(client, thread) {
+        Ok(frames) => {
+            if let Some(frame) = frames.first() {
+                println!("stopped in {}", frame_line(frame));
+            }
+        }
+        Err(err) => eprintln!("jupyterm: {}", err),
+    }
+
+    loop {
+        let line = editor.read_cell("(debug) ", &Stopped);
+        let command = match line {
+            Ok(Some(line)) if line.trim().is_empty() => continue,
+            Ok(Some(line)) => commands::parse(&line).unwrap_or_else(|| {
+                Err(
+                    "the kernel is stopped in the debugger, try :bt, :locals, :step or :continue"
+                        .to_string(),
+                )
+            }),
+            // there'd be nobody left to let it go on
+            Ok(None) | Err(_) => Ok(Command::Continue),
+        };
+        if let Ok(Command::Break(file, line)) = &command {
+            session.set_breakpoint(file, *line);
+            continue;
+        }
+
+        let client = &session.client;
+        let debugger = session.debugger.as_mut().unwrap();
+        let result = match command {
+            Ok(Command::Backtrace) => debugger.stack(client, thread).map(|frames| {
+                for (i, frame) in frames.iter().enumerate() {
+                    println!("#{} {}", i, frame_line(frame));
+                }
+            }),
+            Ok(Command::Locals) => {
+                let frames = debugger.stack(client, thread);
+                frames
+                    .and_then(|frames| match frames.first() {
+                        Some(frame) => debugger.locals(client, frame.id),
+                        None => Ok(Vec::new()),
+                    })
+                    .map(|variables| {
+                        for variable in variables {
+                            println!("{}", variable_line(&variable));
+                        }
+                    })
+            }
+            Ok(Command::Continue) => match debugger.resume(client, thread) {
+                Ok(()) => return,
+                Err(err) => Err(err),
+            },
+            // it stops again on the next line, with another debug_event
+            Ok(Command::Step) => match debugger.step(client, thread) {
+                Ok(()) => return,
+                Err(err) => Err(err),
+            },
+            Ok(_) => {
+                eprintln!("not while the kernel is stopped in the debugger");
+                Ok(())
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                Ok(())
+            }
+        };
+        if let Err(err) = result {
+            eprintln!("jupyterm: {}", err);
+        }
+    }
+}
+
+// "f at /home/me/file.py:12"
+fn frame_line(frame: &StackFrame) -> String {
+    format!("{} at {}:{}", frame.name, frame.source.path, frame.line)
+}
+
+// "x: int = 3"
+fn variable_line(variable: &Variable) -> String {
+    if variable.kind.is_empty() {
+        format!("{} = {}", variable.name, variable.value)
+    } else {
+        format!("{}: {} = {}", variable.name, variable.kind, variable.value)
+    }
+}
+
+// The editor's helper while the kernel is stopped: its shell is busy with
+// the cell, so it can't answer completion or is_complete requests.
+struct Stopped;
+
+impl Helper for Stopped {
+    fn is_complete(&self, _code: &str) -> Option<Map<String, Value>> {
+        None
+    }
+
+    fn complete(&self, _code: &str, _cursor_pos: usize) -> Option<Map<String, Value>> {
+        None
+    }
+
+    fn inspect(&self, _code: &str, _cursor_pos: usize) -> Option<Map<String, Value>> {
+        None
+    }
+}
+
+// one iopub message for --json, a single line for tools like jq
+fn json_line(msg: &Map<String, Value>) -> String {
+    let msg = Value::Object(msg.clone());
+    json!({
+        "msg_type": msg["header"]["msg_type"],
+        "msg_id": msg["header"]["msg_id"],
+        "parent_msg_id": msg["parent_header"]["msg_id"],
+        "timestamp": msg["header"]["date"],
+        "content": msg["content"],
+    })
+    .to_string()
+}
+
+// with more than one session it says which one is active
+fn prompt(session: &KernelSession, several: bool) -> String {
+    let prompt = format!("In [{}]: ", session.execution_count);
+    if several {
+        format!("[{}] {}", session.name, prompt)
+    } else {
+        prompt
+    }
+}
+
+impl Repl {
+    pub fn run(&mut self) -> Exit {
+        loop {
+            let (ran, stop) = self.drive(|session, frontend| frontend::run_repl(session, frontend));
+            match stop {
+                Some(Stop::Gone) => {
+                    let session = self.sessions.remove(self.active);
+                    session.report_gone();
+                    let _ = fs::remove_file(&session.connection_file);
+                    if let Some(metrics) = &self.metrics {
+                        metrics.set_kernels_alive(self.sessions.len());
+                    }
+                    if self.sessions.is_empty() {
+                        return Exit::KernelDied;
+                    }
+                    self.switch_to(0);
+                    continue;
+                }
+                Some(Stop::Exit(exit)) => return exit,
+                Some(Stop::Command(command)) => {
+                    self.command(command);
+                    continue;
+                }
+                None => {}
+            }
+            return match ran {
+                Ok(()) => Exit::Eof,
+                Err(Error::Io(err)) if err.kind() == io::ErrorKind::TimedOut => {
+                    let timeout = self.options.repl_timeout.unwrap_or_default();
+                    eprintln!(
+                        "jupyterm: no input for {} s, shutting down the kernel",
+                        timeout.as_secs()
+                    );
+                    Exit::Idle
+                }
+                Err(err) => {
+                    eprintln!("jupyterm: {}", err);
+                    Exit::Eof
+                }
+            };
+        }
+    }
+
+    // Run `run` on the active session with the REPL as its frontend, and
+    // put the session back after, with why the frontend stopped if it did.
+    fn drive<T>(
+        &mut self,
+        run: impl FnOnce(&mut KernelSession, &mut ReplFrontend) -> T,
+    ) -> (T, Option<Stop>) {
+        let mut session = self.sessions.remove(self.active);
+        let mut frontend = ReplFrontend {
+            widgets: mem::take(&mut session.widgets),
+            repl: self,
+            cell: None,
+            stop: None,
+        };
+        let ran = run(&mut session, &mut frontend);
+        let stop = frontend.stop;
+        session.widgets = frontend.widgets;
+        self.sessions.insert(self.active, session);
+        (ran, stop)
+    }
+
+    fn session(&self) -> &KernelSession {
+        &self.sessions[self.active]
+    }
+
+    fn session_mut(&mut self) -> &mut KernelSession {
+        &mut self.sessions[self.active]
+    }
+
+    fn prompt(&self) -> String {
+        prompt(self.session(), self.sessions.len() > 1)
+    }
+
+    fn command(&mut self, command: Command) {
+        match command {
+            Command::Aliases => self.list_aliases(),
+            Command::Break(file, line) => self.session_mut().set_breakpoint(&file, line),
+            Command::Backtrace | Command::Locals | Command::Continue | Command::Step => {
+                eprintln!("the kernel isn't stopped in the debugger")
+            }
+            Command::Clear => editor::clear_screen().unwrap(),
+            Command::Kernel => self.print_kernel_info(),
+            Command::Links => self.print_help_links(),
+            Command::OpenLast(mime) => self.open_last(mime.as_deref()),
+            Command::Out(n) => self.show_out(n),
+            Command::Traceback => self.traceback(),
+            Command::Copy { input } => self.copy(input),
+            Command::Record(RecordCommand::Status) => match &self.recording {
+                Some(recording) => println!(
+                    "recording to {}, {} cells so far",
+                    recording.path().display(),
+                    recording.recorded()
+                ),
+                None => println!("not recording"),
+            },
+            Command::Record(RecordCommand::Start(path)) => self.start_recording(Path::new(&path)),
+            Command::Record(RecordCommand::Stop) => match self.recording.take() {
+                Some(recording) => println!(
+                    "recorded {} cells to {}",
+                    recording.recorded(),
+                    recording.path().display()
+                ),
+                None => eprintln!("not recording"),
+            },
+            Command::Capture {
+                path,
+                append,
+                display,
+            } => match Capture::open(Path::new(&path), append, display) {
+                Ok(capture) => {
+                    println!("the next cell's output goes to {}", path);
+                    self.capture = Some(capture);
+                }
+                Err(err) => eprintln!("jupyterm: couldn't open {}: {}", path, err),
+            },
+            Command::History(HistoryCommand::Tail(n)) => self.list_history(&HistoryQuery::Tail(n)),
+            Command::History(HistoryCommand::Search(pattern)) => {
+                self.list_history(&HistoryQuery::Search(pattern, DEFAULT_HISTORY))
+            }
+            Command::History(HistoryCommand::Outputs(n)) => self.list_output_history(n),
+            Command::History(HistoryCommand::Grep { pattern, unique }) => {
+                self.grep_history(&pattern, unique)
+            }
+            Command::History(HistoryCommand::Load(n)) => match self.listed_history.get(n - 1) {
+                Some(entry) => self.editor.prefill(&entry.source),
+                None => eprintln!("no entry {} in the last :history listing", n),
+            },
+            Command::KernelLog => self.print_kernel_log(),
+            Command::Interrupt => self.interrupt(),
+            Command::Set(option, value) => self.set_option(&option, &value),
+            Command::Rerun(ranges) => self.rerun(&ranges),
+            Command::Replay {
+                ranges,
+                stop_on_diff,
+            } => self.replay(&ranges, stop_on_diff),
+            Command::Load { path, lines } => self.load(&path, lines),
+            Command::SaveHistory {
+                path,
+                include_errors,
+            } => self.save_history(&path, include_errors),
+            Command::SaveState(path) => match self.save_state(Path::new(&path)) {
+                Ok(()) => println!("saved, pick it up with jupyterm --restore {}", path),
+                Err(err) => eprintln!("couldn't save {}: {}", path, err),
+            },
+            Command::Export(ExportFormat::Markdown, path) => self.export_markdown(Path::new(&path)),
+            Command::Env(name, value) => self.env(&name, value.as_deref()),
+            Command::CommInfo(target) => self.comm_info(target.as_deref()),
+            Command::Session(SessionCommand::List) => self.list_sessions(),
+            Command::Session(SessionCommand::New { name, kernel }) => {
+                let name = self.session_name(name);
+                if let Some(mut session) = crate::launch_session(
+                    &name,
+                    kernel.as_deref(),
+                    self.keep_kernel,
+                    self.kernel_log_file.as_deref(),
+                ) {
+                    session.run_startup(&self.config);
+                    self.add_session(session);
+                }
+            }
+            Command::Session(SessionCommand::Info) => self.named_session_info(),
+            Command::Session(SessionCommand::Attach { file, name }) => {
+                let name = self.session_name(name);
+                if let Some(session) = crate::attach_session(&name, &file) {
+                    self.add_session(session);
+                }
+            }
+            Command::Watch(None) => {
+                for expr in &self.session().watches {
+                    println!("{}", expr);
+                }
+            }
+            Command::Watch(Some(expr)) => {
+                let watches = &mut self.session_mut().watches;
+                if !watches.contains(&expr) {
+                    watches.push(expr);
+                }
+            }
+            Command::Unwatch(None) => self.session_mut().watches.clear(),
+            Command::Unwatch(Some(expr)) => {
+                let watches = &mut self.session_mut().watches;
+                match watches.iter().position(|watched| *watched == expr) {
+                    Some(i) => {
+                        watches.remove(i);
+                    }
+                    None => eprintln!("not watching {:?}, :watch lists what is", expr),
+                }
+            }
+            Command::Switch(name) => match self.sessions.iter().position(|s| s.name == name) {
+                Some(i) if i == self.active => println!("already in {}", name),
+                Some(i) => self.switch_to(i),
+                None => eprintln!("no session {:?}, :session lists them", name),
+            },
+            // run() stops before it gets here
+            Command::Detach => {}
+        }
+    }
+
+    fn follow_connection_files(&mut self) {
+        for session in &mut self.sessions {
+            session.follow_connection_file();
+        }
+    }
+
+    // `name` if no session has it yet, or the lowest free number, counting
+    // the first session as 1
+    fn session_name(&self, name: Option<String>) -> String {
+        let taken = |name: &str| self.sessions.iter().any(|s| s.name == name);
+        match name {
+            Some(name) if !taken(&name) => name,
+            Some(name) => {
+                let free = (2..).map(|n| format!("{}-{}", name, n));
+                free.into_iter().find(|name| !taken(name)).unwrap()
+            }
+            None => (2..)
+                .map(|n: usize| n.to_string())
+                .find(|name| !taken(name))
+                .unwrap(),
+        }
+    }
+
+    fn add_session(&mut self, mut session: KernelSession) {
+        session
+            .client
+            .set_rate_limit(self.options.max_executions_per_second);
+        if self.options.matplotlib_inline {
+            session.matplotlib_inline();
+        }
+        session.out_cache.set_capacity(self.config.out_cache_size);
+        println!(
+            "session {} is active, :switch {} goes back",
+            session.name,
+            self.session().name
+        );
+        if self.options.banner {
+            session.print_summary();
+        }
+        self.sessions.push(session);
+        self.active = self.sessions.len() - 1;
+    }
+
+    fn switch_to(&mut self, i: usize) {
+        self.active = i;
+        let json = self.options.json;
+        let banner = self.options.banner;
+        let session = &mut self.sessions[i];
+        if banner {
+            session.print_summary();
+        }
+        if session.missed.is_empty() {
+            return;
+        }
+        eprintln!(
+            "jupyterm: {} had this output while in the background:",
+            session.name
+        );
+        let mut area = OutputArea::new();
+        session.widgets.forget_shown();
+        for msg in session.missed.drain(..) {
+            let msg_type = msg["header"]["msg_type"].as_str().unwrap_or("");
+            if msg_type.starts_with("comm_") {
+                widget_msg(&mut session.widgets, &mut area, msg_type, &msg["content"]);
+            }
+            if msg_type == "display_data" || msg_type == "execute_result" {
+                session.last_output = Some((msg["content"]["data"].clone(), None));
+            }
+            if msg_type == "error" {
+                session.last_traceback = Some(frontend::traceback(&msg["content"]));
+            }
+            if json {
+                println!("{}", json_line(&msg));
+            } else if !msg_type.starts_with("comm_") {
+                let mut terminal = Terminal {
+                    editor: &mut self.editor,
+                    area: &mut area,
+                    widgets: &mut session.widgets,
+                    saved: &self.saved_outputs,
+                    options: &self.options,
+                    execution_count: None,
+                };
+                frontend::show(&mut terminal, &msg);
+            }
+        }
+    }
+
+    fn list_sessions(&self) {
+        for (i, session) in self.sessions.iter().enumerate() {
+            let marker = if i == self.active { "*" } else { " " };
+            let kernel = match &session.remote {
+                Some(remote) => remote.kernel.id.clone(),
+                None => crate::connection::short_name(&session.connection_file),
+            };
+            let how = if session.owned {
+                "started by jupyterm"
+            } else {
+                "attached"
+            };
+            println!("{} {}  {}  {}", marker, session.name, kernel, how);
+        }
+    }
+
+    fn named_session_info(&self) {
+        let named = match &self.named {
+            Some(named) => named,
+            None => {
+                println!("not a named session, start jupyterm with --session NAME for one");
+                return;
+            }
+        };
+        println!("session: {}", named.name);
+        println!("directory: {}", named.dir().display());
+        println!(
+            "cells: {}, {} of them this time",
+            named.cells().len(),
+            named.cells().len() - named.previous().len()
+        );
+        println!("created: {}", named.created().format("%Y-%m-%d %H:%M"));
+    }
+
+    // The last few cells of a named session picked up again, since its
+    // kernel is new and has none of what they did.
+    pub fn print_resumed(&self) {
+        let named = match &self.named {
+            Some(named) if !named.previous().is_empty() => named,
+            _ => return,
+        };
+        let previous = named.previous();
+        let notice = format!(
+            "resuming session {}, {} cells since {}",
+            named.name,
+            previous.len(),
+            named.created().format("%Y-%m-%d")
+        );
+        println!("{}", notice.dim());
+        for cell in &previous[previous.len().saturating_sub(RESUMED_CELLS)..] {
+            let prompt = format!("In [{}]: ", cell.execution_count);
+            let indent = format!("\n{}...: ", " ".repeat(prompt.len() - 5));
+            let source = cell.source.trim_end().replace('\n', &indent);
+            println!("{}", format!("{}{}", prompt, source).dim());
+        }
+    }
+
+    fn list_aliases(&self) {
+        if self.aliases.templates.is_empty() {
+            println!("no aliases, add them to [aliases] in config.toml");
+        }
+        for (name, template) in &self.aliases.templates {
+            println!("{}{}", self.aliases.sigil, name);
+            for line in template.trim_end().lines() {
+                println!("    {}", line);
+            }
+        }
+    }
+
+    fn interrupt(&self) {
+        let client = &self.session().client;
+        let result = client.interrupt_kernel();
+        match (client.interrupt_mode(), result) {
+            (InterruptMode::Signal, Ok(())) => {
+                println!("sent SIGINT to the kernel's process group")
+            }
+            (InterruptMode::Signal, Err(err)) => {
+                eprintln!("couldn't send SIGINT to the kernel: {}", err)
+            }
+            (InterruptMode::Message, Ok(())) => {
+                println!("sent an interrupt_request, the kernel replied")
+            }
+            (InterruptMode::Message, Err(_)) => {
+                eprintln!("sent an interrupt_request, no interrupt_reply came back")
+            }
+        }
+    }
+
+    // for bug reports, what's on the other end
+    fn print_kernel_info(&self) {
+        let info = &self.session().kernel_info;
+        let language = &info.language_info;
+        println!(
+            "implementation: {} {}",
+            info.implementation, info.implementation_version
+        );
+        println!("language: {} {}", language.name, language.version);
+        println!(
+            "protocol: {} (jupyterm speaks {})",
+            info.protocol_version,
+            client::PROTOCOL_VERSION
+        );
+    }
+
+    fn list_history(&mut self, query: &HistoryQuery) {
+        let entries = match self.session().client.history(query) {
+            Some(entries) => entries,
+            None => {
+                eprintln!("jupyterm: the kernel didn't answer the history_request");
+                return;
+            }
+        };
+        if entries.is_empty() {
+            let notice = match query {
+                HistoryQuery::Tail(_) => "no history, the kernel may not keep one",
+                HistoryQuery::Search(..) => "nothing in the kernel's history matches",
+            };
+            println!("{}", notice);
+            return;
+        }
+        self.print_history(entries);
+    }
+
+    // %history -g, like IPython's: PATTERN anywhere in the cell, globs and all
+    fn grep_history(&mut self, pattern: &str, unique: bool) {
+        let pattern = format!("*{}*", pattern);
+        let mut entries = match self.session().client.search_history(&pattern) {
+            Ok(entries) => entries,
+            Err(err) => {
+                eprintln!("jupyterm: couldn't search the history: {}", err);
+                return;
+            }
+        };
+        if unique {
+            // the latest run of each, where the kernel has it
+            let mut seen = HashSet::new();
+            entries.reverse();
+            entries.retain(|entry| seen.insert(entry.source.clone()));
+            entries.reverse();
+        }
+        if entries.is_empty() {
+            println!("nothing in the kernel's history matches");
+            return;
+        }
+        self.print_history(entries);
+    }
+
+    // numbered for :history load N, with the session/line each ran as
+    fn print_history(&mut self, entries: Vec<HistoryEntry>) {
+        let width = entries.len().to_string().len();
+        for (i, entry) in entries.iter().enumerate() {
+            let number = format!("{:>width$}", i + 1, width = width);
+            let place = format!("{}/{}", entry.session, entry.line);
+            let indent = " ".repeat(width + place.len() + 4);
+            let source = entry
+                .source
+                .trim_end()
+                .replace('\n', &format!("\n{}", indent));
+            println!("{}  {}  {}", number.bold(), place.dim(), source);
+        }
+        self.listed_history = entries;
+    }
+
+    // %history -o, each cell by its In number with its Out[N] under it
+    fn list_output_history(&mut self, n: usize) {
+        let entries = match self.session().client.get_output_history(None, n) {
+            Ok(entries) => entries,
+            Err(err) => {
+                eprintln!("jupyterm: couldn't get the history: {}", err);
+                return;
+            }
+        };
+        if entries.is_empty() {
+            println!("no history, the kernel may not keep one");
+            return;
+        }
+        for entry in &entries {
+            let prompt = format!("In [{}]: ", entry.line);
+            let indent = format!("\n{}...: ", " ".repeat(prompt.len() - 5));
+            let source = entry.source.trim_end().replace('\n', &indent);
+            println!("{}{}", prompt.green(), source);
+            if let Some(output) = &entry.output {
+                let prompt = format!("Out[{}]: ", entry.line);
+                println!("{}{}", prompt.red(), output.trim_end());
+            }
+        }
+        self.listed_history = entries;
+    }
+
+    fn print_help_links(&self) {
+        let links = &self.session().kernel_info.help_links;
+        if links.is_empty() {
+            println!("the kernel has no help links");
+        }
+        for link in links {
+            println!("{}: {}", link.text, link.url);
+        }
+    }
+
+    // :load PATH [START-END], as it is in the file, the editor indents
+    // nothing it didn't type itself
+    fn load(&mut self, path: &str, lines: Option<(usize, usize)>) {
+        if !io::stdin().is_terminal() {
+            eprintln!(":load needs a terminal to edit the cell in");
+            return;
+        }
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text.replace("\r\n", "\n"),
+            Err(err) => {
+                eprintln!("jupyterm: couldn't read {}: {}", path, err);
+                return;
+            }
+        };
+        let text = match lines {
+            Some((from, to)) => {
+                let selected: Vec<&str> = text.lines().skip(from - 1).take(to - from + 1).collect();
+                if selected.is_empty() {
+                    eprintln!("{} has only {} lines", path, text.lines().count());
+                    return;
+                }
+                selected.join("\n")
+            }
+            None => text.trim_end_matches('\n').to_string(),
+        };
+        let count = text.lines().count();
+        if count > LOAD_WARN_LINES
+            && !crate::confirm(&format!(
+                "{} lines is a lot to edit, load them anyway?",
+                count
+            ))
+        {
+            return;
+        }
+        self.editor.prefill(&text);
+    }
+
+    // :save-history PATH, with the kernel language's extension when PATH has none
+    fn save_history(&self, path: &str, include_errors: bool) {
+        let session = self.session();
+        let language = &session.kernel_info.language_info;
+        let mut path = PathBuf::from(path);
+        if path.extension().is_none() {
+            let extension = match language.file_extension.trim_start_matches('.') {
+                "" => "py",
+                extension => extension,
+            };
+            path.set_extension(extension);
+        }
+        let comment = transcript::comment_marker(&language.name);
+        let script = session.transcript.to_script(comment, include_errors);
+        match fs::write(&path, script) {
+            Ok(()) => println!("saved the history to {}", path.display()),
+            Err(err) => eprintln!("jupyterm: couldn't write {}: {}", path.display(), err),
+        }
+    }
+
+    // :export md PATH, as a notebook of the session's cells would be
+    fn export_markdown(&self, path: &Path) {
+        let session = self.session();
+        let nb = Notebook {
+            cells: session.cells.clone(),
+            metadata: session.notebook_metadata(),
+            nbformat: 4,
+            nbformat_minor: 5,
+        };
+        match markdown::export_markdown(&nb, path) {
+            Ok(0) => println!("exported {} cells to {}", nb.cells.len(), path.display()),
+            Ok(images) => println!(
+                "exported {} cells to {}, with {} {} next to it",
+                nb.cells.len(),
+                path.display(),
+                images,
+                if images == 1 { "image" } else { "images" }
+            ),
+            Err(err) => eprintln!("jupyterm: couldn't export {}: {}", path.display(), err),
+        }
+    }
+
+    // :record start PATH, in place of the notebook being recorded to before
+    pub fn start_recording(&mut self, path: &Path) {
+        match Recording::open(path, self.session().notebook_metadata()) {
+            Ok(recording) => {
+                println!("recording the cells from now on to {}", path.display());
+                self.recording = Some(recording);
+            }
+            Err(err) => eprintln!("jupyterm: couldn't record to {}: {}", path.display(), err),
+        }
+    }
+
+    // %comm_info [TARGET], by target, with the widget each one is when we know
+    fn comm_info(&self, target: Option<&str>) {
+        let session = self.session();
+        let reply = match session.client.get_comm_info(target) {
+            Ok(reply) => reply,
+            Err(err) => {
+                eprintln!("jupyterm: couldn't get the kernel's comms: {}", err);
+                return;
+            }
+        };
+        if reply.comms.is_empty() {
+            println!("no comms open");
+            return;
+        }
+        let mut comms: Vec<(&str, &str)> = reply
+            .comms
+            .iter()
+            .map(|(id, comm)| (comm.target_name.as_str(), id.as_str()))
+            .collect();
+        comms.sort();
+        for (target_name, id) in comms {
+            match session.widgets.model_name(id) {
+                Some(model) => println!("{}  {}  {}", id, target_name, model.dim()),
+                None => println!("{}  {}", id, target_name),
+            }
+        }
+    }
+
+    // :copy [in]
+    fn copy(&self, input: bool) {
+        let session = self.session();
+        let text = if input {
+            match session.transcript.last() {
+                Some((_, code)) => code,
+                None => {
+                    eprintln!("no cell to copy yet");
+                    return;
+                }
+            }
+        } else {
+            match session.last_cell_text.as_deref() {
+                None => {
+                    eprintln!("no output to copy yet");
+                    return;
+                }
+                Some(text) if text.trim().is_empty() => {
+                    eprintln!("the last cell has nothing textual to copy");
+                    return;
+                }
+                Some(text) => text.trim_end_matches('\n'),
+            }
+        };
+        match clipboard::copy(text) {
+            Ok(how) => {
+                let lines = text.lines().count();
+                let noun = if lines == 1 { "line" } else { "lines" };
+                println!("copied {} {} with {}", lines, noun, how);
+            }
+            Err(err) => eprintln!("jupyterm: couldn't copy: {}", err),
+        }
+    }
+
+    // :out, from the cache only, the kernel's Out has only the results
+    fn show_out(&mut self, n: Option<i64>) {
+        let session = &mut self.sessions[self.active];
+        let (n, outputs) = match n {
+            None => match session.out_cache.latest() {
+                Some((n, outputs)) => (n, outputs.to_vec()),
+                None => {
+                    println!("no output to show yet");
+                    return;
+                }
+            },
+            Some(n) => match session.out_cache.get(n) {
+                Some([]) => {
+                    println!("In [{}] had no output", n);
+                    return;
+                }
+                Some(outputs) => (n, outputs.to_vec()),
+                None if n < session.execution_count as i64 => {
+                    println!(
+                        "the output of In [{}] isn't kept any more, see out_cache_size ({} cells)",
+                        n,
+                        session.out_cache.capacity()
+                    );
+                    return;
+                }
+                None => {
+                    println!("there's no In [{}] yet", n);
+                    return;
+                }
+            },
+        };
+        let mut area = OutputArea::new();
+        session.widgets.forget_shown();
+        let mut terminal = Terminal {
+            editor: &mut self.editor,
+            area: &mut area,
+            widgets: &mut session.widgets,
+            saved: &self.saved_outputs,
+            options: &self.options,
+            execution_count: Some(n),
+        };
+        for msg in &outputs {
+            frontend::show(&mut terminal, msg);
+        }
+    }
+
+    // Save a representation of the last rich output, the richest unless
+    // `mime` names one, and open it outside the terminal.
+    fn open_last(&self, mime: Option<&str>) {
+        let (data, execution_count) = match &self.session().last_output {
+            Some(last) => last,
+            None => {
+                eprintln!("no output to open yet");
+                return;
+            }
+        };
+        let found = match mime {
+            Some(mime) => outputs::find_mime(data, mime),
+            None => outputs::richest_mime(data),
+        };
+        let (mime, payload) = match (found, mime) {
+            (Some(found), _) => found,
+            (None, Some(asked)) => {
+                let has: Vec<&str> = data
+                    .as_object()
+                    .map(|data| data.keys().map(String::as_str).collect())
+                    .unwrap_or_default();
+                eprintln!("the last output has no {}, only {}", asked, has.join(", "));
+                return;
+            }
+            (None, None) => {
+                eprintln!("the last output has nothing jupyterm can open");
+                return;
+            }
+        };
+        let path = match self.saved_outputs.save(mime, payload, *execution_count) {
+            Ok(path) => path,
+            Err(err) => {
+                eprintln!("jupyterm: couldn't save the {} output: {}", mime, err);
+                return;
+            }
+        };
+        if let Err(err) = outputs::open(&path) {
+            eprintln!(
+                "jupyterm: couldn't open {}: {}",
+                display::path_link(&path),
+                err
+            );
+        }
+    }
+
+    // the whole of the last traceback, however many frames it had
+    fn traceback(&self) {
+        let traceback = match &self.session().last_traceback {
+            Some(traceback) => traceback,
+            None => {
+                eprintln!("no traceback yet");
+                return;
+            }
+        };
+        let text: Vec<String> = traceback
+            .iter()
+            .map(|entry| link_traceback(entry, &self.options))
+            .collect();
+        if let Err(err) = display::page(&text.join("\n")) {
+            eprintln!("jupyterm: couldn't run the pager: {}", err);
+        }
+    }
+
+    fn print_kernel_log(&self) {
+        let log = match &self.session().kernel_log {
+            Some(log) => log,
+            None => {
+                eprintln!("jupyterm didn't start this kernel, so it has no log");
+                return;
+            }
+        };
+        let tail = log.tail();
+        if tail.is_empty() {
+            println!("the kernel hasn't written anything to its stdout or stderr");
+        }
+        for line in tail {
+            println!("{}", line);
+        }
+    }
+
+    fn set_option(&mut self, option: &str, value: &str) {
+        let options = &mut self.options;
+        match option {
+            "editing-mode" => match EditingMode::parse(value) {
+                Some(mode) => self.editor.set_editing_mode(mode),
+                None => eprintln!("unknown editing mode {:?}, use emacs or vi", value),
+            },
+            "timing" => match parse_switch(value) {
+                Some(timing) => options.timing = timing,
+                None => eprintln!("timing is either on or off, not {:?}", value),
+            },
+            "timing-threshold" => match value.parse() {
+                Ok(ms) => options.timing_threshold = Duration::from_millis(ms),
+                Err(_) => eprintln!("timing-threshold takes milliseconds, not {:?}", value),
+            },
+            "notify" => match parse_switch(value) {
+                Some(notify) => options.notify = notify,
+                None => eprintln!("notify is either on or off, not {:?}", value),
+            },
+            "notify-threshold" => match value.parse() {
+                Ok(ms) => options.notify_threshold = Duration::from_millis(ms),
+                Err(_) => eprintln!("notify-threshold takes milliseconds, not {:?}", value),
+            },
+            "max-column-width" => match value.parse() {
+                Ok(width) => options.max_column_width = width,
+                Err(_) => eprintln!("max-column-width takes a number, not {:?}", value),
+            },
+            "traceback-frames" => match value.parse() {
+                Ok(frames) => options.traceback_frames = frames,
+                Err(_) => eprintln!("traceback-frames takes a number, not {:?}", value),
+            },
+            _ => eprintln!("unknown option {:?}", option),
+        }
+    }
+
+    pub fn save_state(&self, path: &Path) -> Result<(), jupyterm::error::Error> {
+        let session = self.session();
+        let state = State {
+            connection: session.connection.clone(),
+            connection_file: session.connection_file.clone(),
+            config: self.config.clone(),
+            transcript: session.transcript.clone(),
+        };
+        state.save(path)
+    }
+
+    // done with os.environ, so it takes a Python kernel
+    fn env(&mut self, name: &str, value: Option<&str>) {
+        // JSON strings double as Python string literals
+        let name_literal = json!(name).to_string();
+        let code = match value {
+            Some(value) => format!("import os; os.environ[{}] = {}", name_literal, json!(value)),
+            None => format!("import os; print(os.environ.get({}, ''))", name_literal),
+        };
+
+        let result = match self.session_mut().client.execute_silent_and_collect(&code) {
+            Ok(result) => result,
+            Err(err) => {
+                eprintln!("jupyterm: %env failed: {}", err);
+                return;
+            }
+        };
+        for output in &result.outputs {
+            match &output.kind {
+                OutputKind::Stream { name, text } if name == "stdout" => {
+                    print!("{}", text)
+                }
+                OutputKind::Error { ename, evalue, .. } => {
+                    eprintln!("{}: {}", ename, evalue)
+                }
+                _ => {}
+            }
+        }
+        if let (Some(value), Some("ok")) = (value, result.status.as_deref()) {
+            println!("env: {}={}", name, value);
+        }
+    }
+
+    // an empty list reruns the last cell
+    fn rerun(&mut self, ranges: &[(i64, i64)]) {
+        let last = match self.session().transcript.last() {
+            Some((n, _)) => n,
+            None => {
+                eprintln!("nothing to rerun yet");
+                return;
+            }
+        };
+        let counts = if ranges.is_empty() {
+            vec![last]
+        } else {
+            self.counts_in(ranges, last)
+        };
+
+        for n in counts {
+            if self.session().is_gone() {
+                return;
+            }
+            let source = match self.session().transcript.get(n) {
+                Some(source) => source.to_string(),
+                None => {
+                    eprintln!("skipping In [{}], it's not in this session", n);
+                    continue;
+                }
+            };
+            self.echo(&self.prompt(), &source);
+            self.run_cell(&source);
+        }
+    }
+
+    // The In numbers in `ranges`, up to `last`. The cells run from them
+    // count up from `last`, they're not part of it.
+    fn counts_in(&self, ranges: &[(i64, i64)], last: i64) -> Vec<i64> {
+        if ranges.iter().any(|&(_, to)| to > last) {
+            eprintln!(
+                "skipping everything after In [{}], the last cell so far",
+                last
+            );
+        }
+        ranges
+            .iter()
+            .flat_map(|&(from, to)| from..=to.min(last))
+            .collect()
+    }
+
+    // :replay [N | N-M ...] [--stop-on-diff], the cells run again like
+    // :rerun runs them, then a report of which ones printed or returned
+    // something else than the first time, or raised when they didn't.
+    fn replay(&mut self, ranges: &[(i64, i64)], stop_on_diff: bool) {
+        let filter = match OutputFilter::new(&self.config.replay_filters) {
+            Ok(filter) => filter,
+            Err(err) => {
+                eprintln!("jupyterm: replay_filters in the config: {}", err);
+                return;
+            }
+        };
+        let last = match self.session().transcript.last() {
+            Some((n, _)) => n,
+            None => {
+                eprintln!("nothing to replay yet");
+                return;
+            }
+        };
+        let counts = if ranges.is_empty() {
+            (1..=last).collect()
+        } else {
+            self.counts_in(ranges, last)
+        };
+        // Taken before any of them runs, since the runs get In numbers
+        // of their own, which after a restart are the ones to replay.
+        let mut first_runs = Vec::new();
+        for n in counts {
+            let cell = self
+                .session()
+                .cells
+                .iter()
+                .rev()
+                .find_map(|cell| match cell {
+                    Cell::Code {
+                        source,
+                        execution_count: Some(count),
+                        outputs,
+                        ..
+                    } if *count == n => Some((source.clone(), outputs.clone())),
+                    _ => None,
+                });
+            match cell {
+                Some((source, outputs)) => first_runs.push((n, source, outputs)),
+                // like --restore'd ones, whose outputs weren't saved
+                None if self.session().transcript.get(n).is_some() => {
+                    eprintln!("skipping In [{}], what it printed isn't known", n)
+                }
+                None if !ranges.is_empty() => {
+                    eprintln!("skipping In [{}], it's not in this session", n)
+                }
+                None => {}
+            }
+        }
+
+        let mut report = ReplayReport::default();
+        let mut stopped = false;
+        for (n, source, before) in first_runs {
+            if stopped || self.session().is_gone() {
+                report.cells.push((n, source, Outcome::NotRun));
+                continue;
+            }
+            self.echo(&self.prompt(), &source);
+            let ran = self.session().cells.len();
+            self.run_cell(&source);
+            let outcome = match self.session().cells.get(ran) {
+                Some(Cell::Code { outputs, .. }) => replay::compare(&before, outputs, &filter),
+                _ => Outcome::NotRun,
+            };
+            stopped = stop_on_diff && !matches!(outcome, Outcome::Matched);
+            report.cells.push((n, source, outcome));
+        }
+        print!("{}", report);
+    }
+
+    // show `source` as if it had been typed at `prompt`
+    fn echo(&self, prompt: &str, source: &str) {
+        if self.options.json {
+            return;
+        }
+        let continuation = editor::continuation_prompt(prompt);
+        for (i, line) in source.split('\n').enumerate() {
+            let prompt = if i == 0 { prompt } else { &continuation };
+            println!("{}{}", prompt, line);
+        }
+    }
+
+    fn run_cell(&mut self, code: &str) {
+        let (ran, _) = self.drive(|session, frontend| frontend::run_cell(session, frontend, code));
+        if let Err(err) = ran {
+            RUNNING.store(false, Ordering::SeqCst);
+            eprintln!("jupyterm: {}", err);
+        }
+    }
+}
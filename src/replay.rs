@@ -0,0 +1,468 @@
+use crate::client::{Cutypr, ExecutionResult};
+use crate::display::strip_ansi;
+use crate::error::Error;
+use crate::events::{OutputEvent, OutputKind};
+use crate::notebook::{self, diff_lines, LineDiff, Output};
+use crossterm::style::Stylize;
+use regex::Regex;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// One In [N] block of a transcript: the cell, with its "...:" lines, and
+// the lines printed after it up to the next prompt.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedCell {
+    pub execution_count: i64,
+    pub source: String,
+    pub output: Vec<String>,
+}
+
+// A transcript as jupyterm prints it, saved from the terminal with
+// `script` or the scrollback. Lines are taken as the terminal left them,
+// and prompts with nothing after them and jupyterm's own :commands are
+// dropped.
+pub fn parse_transcript(text: &str) -> Vec<RecordedCell> {
+    let mut cells: Vec<RecordedCell> = Vec::new();
+    // whether the lines so far are still the cell's source
+    let mut in_source = false;
+    for line in text.lines() {
+        let line = shown(line);
+        if let Some((execution_count, first)) = prompt(&line) {
+            cells.push(RecordedCell {
+                execution_count,
+                source: first.to_string(),
+                output: Vec::new(),
+            });
+            in_source = true;
+            continue;
+        }
+        let cell = match cells.last_mut() {
+            Some(cell) => cell,
+            // whatever came before the first prompt, like the banner
+            None => continue,
+        };
+        let continued = line.trim_start().strip_prefix("...:");
+        match continued {
+            Some(rest) if in_source => {
+                cell.source.push('\n');
+                cell.source.push_str(rest.strip_prefix(' ').unwrap_or(rest));
+            }
+            _ => {
+                in_source = false;
+                cell.output.extend(recorded_lines(&line));
+            }
+        }
+    }
+    for cell in &mut cells {
+        while cell.output.last().is_some_and(|line| line.is_empty()) {
+            cell.output.pop();
+        }
+    }
+    cells.retain(|cell| !cell.source.trim().is_empty() && !cell.source.starts_with(':'));
+    cells
+}
+
+// What's left of a line from `script` once the terminal is done with it:
+// the editor redraws the line from column 1 at every key, and progress
+// bars go back to the start with a carriage return.
+fn shown(line: &str) -> String {
+    let line = line.trim_end_matches('\r');
+    let last = line
+        .rsplit('\r')
+        .next()
+        .and_then(|rest| rest.rsplit("\x1b[1G").next())
+        .unwrap_or(line);
+    strip_ansi(last)
+}
+
+// "In [12]: x = 1" as 12 and "x = 1"
+fn prompt(line: &str) -> Option<(i64, &str)> {
+    let rest = line.strip_prefix("In [")?;
+    let (count, rest) = rest.split_once("]:")?;
+    let count = count.trim().parse().ok()?;
+    Some((count, rest.strip_prefix(' ').unwrap_or(rest)))
+}
+
+// A printed line as what the kernel sent. Streams are printed as JSON
+// strings, newlines and all, and the Out[N] in front of a result is left
+// off, the count is different in a new kernel.
+fn recorded_lines(line: &str) -> Vec<String> {
+    if line.len() >= 2 && line.starts_with('"') && line.ends_with('"') {
+        if let Ok(text) = serde_json::from_str::<String>(line) {
+            return text.lines().map(String::from).collect();
+        }
+    }
+    let line = match line
+        .strip_prefix("Out[")
+        .and_then(|rest| rest.split_once("]: "))
+    {
+        Some((count, rest)) if count.parse::<i64>().is_ok() => rest,
+        _ => line,
+    };
+    vec![line.to_string()]
+}
+
+// The lines a cell's outputs came to, to hold against the recorded ones:
+// its streams, the text of its results and its traceback.
+fn output_lines(result: &ExecutionResult) -> Vec<String> {
+    event_lines(&result.outputs)
+}
+
+fn event_lines(events: &[OutputEvent]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for event in events {
+        match &event.kind {
+            OutputKind::Stream { text, .. } => {
+                lines.extend(strip_ansi(text).lines().map(String::from))
+            }
+            OutputKind::ExecuteResult { data, .. } | OutputKind::DisplayData { data, .. } => {
+                if let Some(text) = data.get("text/plain").and_then(|text| text.as_str()) {
+                    lines.extend(strip_ansi(text).lines().map(String::from));
+                }
+            }
+            OutputKind::Error { traceback, .. } => {
+                for entry in traceback {
+                    lines.extend(strip_ansi(entry).lines().map(String::from));
+                }
+            }
+            _ => {}
+        }
+    }
+    lines
+}
+
+// A cell as In [N] and its ...: lines, then what it printed, the way
+// parse_transcript reads it back. Results get their Out[N] in front.
+pub fn format_cell(execution_count: i64, source: &str, outputs: &[OutputEvent]) -> String {
+    let prompt = format!("In [{}]:", execution_count);
+    let continued = format!("{:>width$}", "...:", width = prompt.len());
+    let mut text = String::new();
+    for (i, line) in source.trim_end().lines().enumerate() {
+        let prompt = if i == 0 { &prompt } else { &continued };
+        text.push_str(format!("{} {}", prompt, line).trim_end());
+        text.push('\n');
+    }
+    for event in outputs {
+        let lines = event_lines(std::slice::from_ref(event));
+        let result = matches!(event.kind, OutputKind::ExecuteResult { .. });
+        for (i, line) in lines.iter().enumerate() {
+            if result && i == 0 {
+                text.push_str(&format!("Out[{}]: ", execution_count));
+            }
+            text.push_str(line);
+            text.push('\n');
+        }
+    }
+    text.push('\n');
+    text
+}
+
+// --output FILE, the REPL's cells and what they printed written down as
+// they run, without the colours, for --replay to run again later.
+#[derive(Debug)]
+pub struct TranscriptFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl TranscriptFile {
+    // in place of whatever was at `path`
+    pub fn create(path: &Path) -> io::Result<TranscriptFile> {
+        Ok(TranscriptFile {
+            file: File::create(path)?,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Cells the kernel never gave a count, like ones aborted after an
+    // error, have no In [N] to go under and are left out.
+    pub fn write_cell(
+        &mut self,
+        execution_count: Option<i64>,
+        source: &str,
+        outputs: &[OutputEvent],
+    ) -> io::Result<()> {
+        match execution_count {
+            Some(count) => {
+                self.file
+                    .write_all(format_cell(count, source, outputs).as_bytes())?;
+                self.file.flush()
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+// A replayed cell whose output isn't the recorded one.
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    // the In number in the transcript
+    pub execution_count: i64,
+    pub source: String,
+    pub diff: Vec<LineDiff>,
+}
+
+// Runs the cells of a transcript again, in order, and holds what they print
+// now against what they printed then. Meant for a new kernel, since the
+// cells count on what the ones before them left behind.
+#[derive(Debug)]
+pub struct ReplayEngine<'a> {
+    client: &'a mut Cutypr,
+}
+
+impl<'a> ReplayEngine<'a> {
+    pub fn new(client: &'a mut Cutypr) -> ReplayEngine<'a> {
+        ReplayEngine { client }
+    }
+
+    // The cells whose output changed, all of them run whatever the ones
+    // before did.
+    pub fn run(&mut self, cells: &[RecordedCell]) -> Result<Vec<Mismatch>, Error> {
+        let mut mismatches = Vec::new();
+        for cell in cells {
+            let result = self.client.execute_and_collect(&cell.source)?;
+            let diff = diff_lines(&cell.output, &output_lines(&result));
+            if !diff.is_empty() {
+                mismatches.push(Mismatch {
+                    execution_count: cell.execution_count,
+                    source: cell.source.clone(),
+                    diff,
+                });
+            }
+        }
+        Ok(mismatches)
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let heading = format!("In [{}] printed something else", self.execution_count);
+        write_diff(f, &heading, &self.source, &self.diff)
+    }
+}
+
+// the cell under `heading`, then the lines of output before and after
+fn write_diff(
+    f: &mut fmt::Formatter,
+    heading: &str,
+    source: &str,
+    diff: &[LineDiff],
+) -> fmt::Result {
+    writeln!(f, "{}:", heading)?;
+    for line in source.lines() {
+        writeln!(f, "    {}", line)?;
+    }
+    for line in diff {
+        match line {
+            LineDiff::Same(line) => writeln!(f, "      {}", line)?,
+            LineDiff::Added(line) => writeln!(f, "    + {}", line)?,
+            LineDiff::Removed(line) => writeln!(f, "    - {}", line)?,
+        }
+    }
+    Ok(())
+}
+
+// What :replay leaves out of outputs before holding them against each
+// other: every match of any of the patterns, like memory addresses and
+// timestamps, is put in place of one "…".
+#[derive(Debug)]
+pub struct OutputFilter {
+    patterns: Vec<Regex>,
+}
+
+impl OutputFilter {
+    pub fn new(patterns: &[String]) -> Result<OutputFilter, regex::Error> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<_, _>>()?;
+        Ok(OutputFilter { patterns })
+    }
+
+    pub fn apply(&self, lines: Vec<String>) -> Vec<String> {
+        lines
+            .into_iter()
+            .map(|line| {
+                self.patterns.iter().fold(line, |line, pattern| {
+                    pattern.replace_all(&line, "…").into_owned()
+                })
+            })
+            .collect()
+    }
+}
+
+// How a cell :replay ran again came out next to its first run.
+#[derive(Clone, Debug)]
+pub enum Outcome {
+    Matched,
+    // what it printed and returned, with every line of both
+    Changed(Vec<LineDiff>),
+    // it raised this time and didn't the first, and how its output changed
+    Errored(Vec<LineDiff>),
+    // after --stop-on-diff stopped, or the kernel went away
+    NotRun,
+}
+
+// Hold the outputs of a cell's second run against its first's.
+pub fn compare(before: &[Output], after: &[Output], filter: &OutputFilter) -> Outcome {
+    let raised = |outputs: &[Output]| {
+        outputs
+            .iter()
+            .any(|output| matches!(output, Output::Error { .. }))
+    };
+    let diff = diff_lines(
+        &filter.apply(notebook::output_lines(before)),
+        &filter.apply(notebook::output_lines(after)),
+    );
+    if raised(after) && !raised(before) {
+        Outcome::Errored(diff)
+    } else if diff.is_empty() {
+        Outcome::Matched
+    } else {
+        Outcome::Changed(diff)
+    }
+}
+
+// The cells of one :replay, by their In numbers the first time, in the
+// order they ran.
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+    pub cells: Vec<(i64, String, Outcome)>,
+}
+
+impl ReplayReport {
+    // whether every cell ran and matched
+    pub fn passed(&self) -> bool {
+        self.cells
+            .iter()
+            .all(|(_, _, outcome)| matches!(outcome, Outcome::Matched))
+    }
+
+    fn count(&self, which: fn(&Outcome) -> bool) -> usize {
+        self.cells
+            .iter()
+            .filter(|(_, _, outcome)| which(outcome))
+            .count()
+    }
+}
+
+// The diffs of the cells that changed, then a line a cell and the totals.
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (n, source, outcome) in &self.cells {
+            let (what, diff) = match outcome {
+                Outcome::Changed(diff) => ("printed something else", diff),
+                Outcome::Errored(diff) => ("raised", diff),
+                Outcome::Matched | Outcome::NotRun => continue,
+            };
+            write_diff(f, &format!("In [{}] {}", n, what), source, diff)?;
+        }
+        for (n, _, outcome) in &self.cells {
+            let outcome = match outcome {
+                Outcome::Matched => "matched".green(),
+                Outcome::Changed(_) => "changed".yellow(),
+                Outcome::Errored(_) => "errored".red(),
+                Outcome::NotRun => "not run".dim(),
+            };
+            writeln!(f, "  {:<10}{}", format!("In [{}]", n), outcome)?;
+        }
+        let totals = format!(
+            "{} matched, {} changed, {} errored, {} not run",
+            self.count(|outcome| matches!(outcome, Outcome::Matched)),
+            self.count(|outcome| matches!(outcome, Outcome::Changed(_))),
+            self.count(|outcome| matches!(outcome, Outcome::Errored(_))),
+            self.count(|outcome| matches!(outcome, Outcome::NotRun)),
+        );
+        if self.passed() {
+            writeln!(f, "replay {}: {}", "ok".green(), totals)
+        } else {
+            writeln!(f, "replay {}: {}", "FAILED".red().bold(), totals)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn event(msg_type: &str, content: Value) -> OutputEvent {
+        let msg = json!({
+            "header": {"msg_type": msg_type},
+            "parent_header": {},
+            "content": content,
+        });
+        OutputEvent::from_msg(msg.as_object().unwrap())
+    }
+
+    fn outputs() -> Vec<OutputEvent> {
+        vec![
+            event(
+                "stream",
+                json!({"name": "stdout", "text": "héllo\nwörld\n"}),
+            ),
+            event(
+                "execute_result",
+                json!({"execution_count": 12, "data": {"text/plain": "[1,\n 2]"}, "metadata": {}}),
+            ),
+        ]
+    }
+
+    #[test]
+    fn cells_are_written_as_the_repl_shows_them() {
+        let source = "def f():\n    print('héllo')\n\n    return [1, 2]\nf()\n";
+        assert_eq!(
+            format_cell(12, source, &outputs()),
+            "In [12]: def f():\n    \
+             ...:     print('héllo')\n    \
+             ...:\n    \
+             ...:     return [1, 2]\n    \
+             ...: f()\n\
+             héllo\n\
+             wörld\n\
+             Out[12]: [1,\n 2]\n\n"
+        );
+    }
+
+    #[test]
+    fn written_transcripts_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.txt");
+        let mut file = TranscriptFile::create(&path).unwrap();
+        file.write_cell(Some(1), "x = 1", &[]).unwrap();
+        file.write_cell(None, "aborted", &[]).unwrap();
+        let raised = event(
+            "error",
+            json!({
+                "ename": "NameError",
+                "evalue": "y",
+                "traceback": ["\u{1b}[0;31mNameError\u{1b}[0m: name 'y' is not defined"],
+            }),
+        );
+        file.write_cell(Some(2), "y", &[raised]).unwrap();
+        file.write_cell(Some(3), "if x:\n    f()", &outputs())
+            .unwrap();
+        drop(file);
+
+        let cells = parse_transcript(&std::fs::read_to_string(&path).unwrap());
+        let recorded = |execution_count, source: &str, output: &[&str]| RecordedCell {
+            execution_count,
+            source: source.to_string(),
+            output: output.iter().map(|line| line.to_string()).collect(),
+        };
+        assert_eq!(
+            cells,
+            [
+                recorded(1, "x = 1", &[]),
+                recorded(2, "y", &["NameError: name 'y' is not defined"]),
+                recorded(3, "if x:\n    f()", &["héllo", "wörld", "[1,", " 2]"]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use crate::repl::KernelSession;
+use crossterm::style::Stylize;
+use jupyterm::config::Config;
+use jupyterm::notebook::{self, Output};
+use jupyterm::replay::OutputFilter;
+use jupyterm::saved_run::{self, SavedCell, SavedRun};
+use jupyterm::transcript;
+use std::fs;
+use std::path::Path;
+
+// jupyterm run SCRIPT: run its cells one after the other, printing what
+// they print, and with `save_outputs` keep what each one output for
+// jupyterm diff. False if it couldn't run or a cell raised.
+pub fn run(
+    script: &Path,
+    save_outputs: Option<&Path>,
+    kernel: Option<&str>,
+    existing: Option<&str>,
+    log_file: Option<&Path>,
+) -> bool {
+    let config = Config::load();
+    let run = match run_cells(script, kernel, existing, log_file, &config, true) {
+        Some(run) => run,
+        None => return false,
+    };
+    if let Some(path) = save_outputs {
+        if let Err(err) = run.save(path) {
+            eprintln!("jupyterm: couldn't write {}: {}", path.display(), err);
+            return false;
+        }
+        let saved = format!(
+            "saved the outputs of {} cells to {}",
+            run.cells.len(),
+            path.display()
+        );
+        println!("{}", saved.dim());
+    }
+    !run.cells.iter().any(|cell| raised(&cell.outputs))
+}
+
+// jupyterm diff SCRIPT --against SAVED: run it as jupyterm run does, without
+// printing anything, and show how each cell's outputs differ from the ones
+// saved. False if any do, or it couldn't run.
+pub fn diff(
+    script: &Path,
+    against: &Path,
+    kernel: Option<&str>,
+    existing: Option<&str>,
+    log_file: Option<&Path>,
+) -> bool {
+    let saved = match SavedRun::load(against) {
+        Ok(saved) => saved,
+        Err(err) => {
+            eprintln!("jupyterm: couldn't read {}: {}", against.display(), err);
+            return false;
+        }
+    };
+    let config = Config::load();
+    let filter = match OutputFilter::new(&config.replay_filters) {
+        Ok(filter) => filter,
+        Err(err) => {
+            eprintln!("jupyterm: replay_filters in the config: {}", err);
+            return false;
+        }
+    };
+    let now = match run_cells(script, kernel, existing, log_file, &config, false) {
+        Some(now) => now,
+        None => return false,
+    };
+
+    let diffs = saved_run::diff_runs(&saved, &now, &filter);
+    for diff in &diffs {
+        print!("{}", diff);
+    }
+    if !diffs.is_empty() {
+        eprintln!(
+            "jupyterm: {} of {} cells differ from {}",
+            diffs.len(),
+            now.cells.len().max(saved.cells.len()),
+            against.display()
+        );
+        return false;
+    }
+    let same = format!(
+        "the outputs of all {} cells are the ones in {}",
+        now.cells.len(),
+        against.display()
+    );
+    println!("{}", same.dim());
+    true
+}
+
+// The script's cells and what each one output, run in a kernel of its own,
+// or `existing` left running after. None once it's said why it couldn't.
+fn run_cells(
+    script: &Path,
+    kernel: Option<&str>,
+    existing: Option<&str>,
+    log_file: Option<&Path>,
+    config: &Config,
+    echo: bool,
+) -> Option<SavedRun> {
+    let code = match fs::read_to_string(script) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("jupyterm: couldn't read {}: {}", script.display(), err);
+            return None;
+        }
+    };
+    let mut session = match existing {
+        Some(file) => crate::attach_session("run", file)?,
+        None => start(kernel, log_file, config)?,
+    };
+    let comment = transcript::comment_marker(&session.kernel_info.language_info.name);
+
+    let mut run = SavedRun::default();
+    let mut failed = false;
+    for source in saved_run::split_cells(&code, comment) {
+        let result = match session.client.execute_and_collect(&source) {
+            Ok(result) => result,
+            Err(err) => {
+                eprintln!("jupyterm: running {} failed: {}", script.display(), err);
+                failed = true;
+                break;
+            }
+        };
+        let outputs = notebook::outputs(&result);
+        if echo {
+            print_outputs(&outputs);
+        }
+        run.cells.push(SavedCell { source, outputs });
+    }
+    let keep = !session.owned;
+    crate::close_session(&mut session, keep, false);
+    if failed {
+        return None;
+    }
+    Some(run)
+}
+
+fn start(kernel: Option<&str>, log_file: Option<&Path>, config: &Config) -> Option<KernelSession> {
+    let mut session = crate::launch_session("run", kernel, false, log_file)?;
+    session.run_startup(config);
+    Some(session)
+}
+
+// their text, tracebacks whole on stderr
+fn print_outputs(outputs: &[Output]) {
+    for output in outputs {
+        match output {
+            Output::Stream { name, text } if name == "stderr" => eprint!("{}", text),
+            Output::Stream { text, .. } => print!("{}", text),
+            Output::DisplayData { data, .. } | Output::ExecuteResult { data, .. } => {
+                if let Some(text) = data.get("text/plain") {
+                    println!("{}", notebook::joined(text).trim_end());
+                }
+            }
+            Output::Error { traceback, .. } => {
+                for entry in traceback {
+                    eprintln!("{}", entry);
+                }
+            }
+        }
+    }
+}
+
+fn raised(outputs: &[Output]) -> bool {
+    outputs
+        .iter()
+        .any(|output| matches!(output, Output::Error { .. }))
+}
@@ -0,0 +1,243 @@
+use crate::error::Error;
+use crate::notebook::{self, diff_lines, LineDiff, Output};
+use crate::outputs;
+use crate::replay::OutputFilter;
+use crossterm::style::Stylize;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// lines of context around each change, as diff -u has
+const CONTEXT: usize = 3;
+
+// The cells of a script and what each of them output, as `jupyterm run
+// --save-outputs` writes them for `jupyterm diff` to hold a later run
+// against. Outputs are kept as a notebook keeps them.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SavedRun {
+    pub cells: Vec<SavedCell>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SavedCell {
+    pub source: String,
+    pub outputs: Vec<Output>,
+}
+
+impl SavedRun {
+    pub fn load(path: &Path) -> Result<SavedRun, Error> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        fs::write(path, json)?;
+        Ok(())
+    }
+}
+
+// A script's cells, split at the lines that start one, like `# %%` or the
+// `# In[3]` ones :save-history writes, with `comment` the language's
+// comment marker. A script without any is one cell.
+pub fn split_cells(script: &str, comment: &str) -> Vec<String> {
+    let starts_cell = |line: &str| {
+        line.strip_prefix(comment)
+            .map(str::trim_start)
+            .is_some_and(|rest| rest.starts_with("%%") || rest.starts_with("In["))
+    };
+    let mut cells = vec![String::new()];
+    for line in script.lines() {
+        if starts_cell(line) {
+            cells.push(String::new());
+            continue;
+        }
+        let cell = cells.last_mut().unwrap();
+        cell.push_str(line);
+        cell.push('\n');
+    }
+    cells
+        .into_iter()
+        .map(|cell| cell.trim().to_string())
+        .filter(|cell| !cell.is_empty())
+        .collect()
+}
+
+// How a cell's outputs this run compare with the saved ones.
+#[derive(Clone, Debug)]
+pub struct OutputDiff {
+    // from 1, in the run now, or the saved one for cells it doesn't have
+    pub index: usize,
+    pub source: String,
+    // the text outputs, with every line of both, empty if they're the same
+    pub text: Vec<LineDiff>,
+    // None without images either time, otherwise whether any changed
+    pub images_changed: Option<bool>,
+    pub kind: Change,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    Changed,
+    // only in the run now
+    Added,
+    // only in the saved run
+    Removed,
+}
+
+// Pair the cells up in order and keep the ones whose outputs differ, the
+// text ones once `filter` has taken out what changes from run to run and
+// images by a hash of their bytes.
+pub fn diff_runs(saved: &SavedRun, now: &SavedRun, filter: &OutputFilter) -> Vec<OutputDiff> {
+    let mut diffs = Vec::new();
+    let longest = saved.cells.len().max(now.cells.len());
+    for i in 0..longest {
+        let (before, after) = (saved.cells.get(i), now.cells.get(i));
+        let outputs_before = before.map_or(&[][..], |cell| &cell.outputs[..]);
+        let outputs_after = after.map_or(&[][..], |cell| &cell.outputs[..]);
+        let text = diff_lines(
+            &filter.apply(text_lines(outputs_before)),
+            &filter.apply(text_lines(outputs_after)),
+        );
+        let hashes_before = image_hashes(outputs_before);
+        let hashes_after = image_hashes(outputs_after);
+        let images_changed = if hashes_before.is_empty() && hashes_after.is_empty() {
+            None
+        } else {
+            Some(hashes_before != hashes_after)
+        };
+        let (kind, source) = match (before, after) {
+            (Some(_), Some(cell)) => (Change::Changed, cell.source.clone()),
+            (None, Some(cell)) => (Change::Added, cell.source.clone()),
+            (Some(cell), None) => (Change::Removed, cell.source.clone()),
+            (None, None) => unreachable!(),
+        };
+        if kind == Change::Changed && text.is_empty() && images_changed != Some(true) {
+            continue;
+        }
+        diffs.push(OutputDiff {
+            index: i + 1,
+            source,
+            text,
+            images_changed,
+            kind,
+        });
+    }
+    diffs
+}
+
+// the outputs as notebook::output_lines has them, less the images, which
+// are held up against each other by hash instead
+fn text_lines(outputs: &[Output]) -> Vec<String> {
+    let text: Vec<Output> = outputs
+        .iter()
+        .filter(|output| image(output).is_none())
+        .cloned()
+        .collect();
+    notebook::output_lines(&text)
+}
+
+fn image_hashes(outputs: &[Output]) -> Vec<String> {
+    outputs
+        .iter()
+        .filter_map(image)
+        .map(|(mime, payload)| {
+            // undecodable ones by their text, they still compare
+            let bytes = outputs::decode(mime, &payload).unwrap_or_else(|_| payload.into_bytes());
+            hex::encode(Sha256::digest(&bytes))
+        })
+        .collect()
+}
+
+// the mime type and payload of the image an output shows, if it's one
+fn image(output: &Output) -> Option<(&'static str, String)> {
+    let data = match output {
+        Output::DisplayData { data, .. } | Output::ExecuteResult { data, .. } => data,
+        _ => return None,
+    };
+    let data: Map<String, Value> = data
+        .iter()
+        .map(|(mime, value)| (mime.clone(), Value::from(notebook::joined(value))))
+        .collect();
+    let data = Value::Object(data);
+    outputs::saved_mime(&data)
+        .filter(|(mime, _)| mime.starts_with("image/"))
+        .map(|(mime, payload)| (mime, payload.to_string()))
+}
+
+// A heading for the cell, then its text outputs as a unified diff, saved
+// before now, and what happened to its images.
+impl fmt::Display for OutputDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let first_line = self.source.lines().next().unwrap_or_default();
+        let what = match self.kind {
+            Change::Changed => "changed",
+            Change::Added => "is new",
+            Change::Removed => "is gone",
+        };
+        let heading = format!("cell {} {}: {}", self.index, what, first_line);
+        writeln!(f, "{}", heading.bold())?;
+        for line in unified(&self.text) {
+            let line = match line.chars().next() {
+                Some('@') => line.cyan(),
+                Some('-') => line.red(),
+                Some('+') => line.green(),
+                _ => line.stylize(),
+            };
+            writeln!(f, "{}", line)?;
+        }
+        match self.images_changed {
+            Some(true) => writeln!(f, "{}", "images changed".yellow()),
+            Some(false) => writeln!(f, "images unchanged"),
+            None => Ok(()),
+        }
+    }
+}
+
+// A diff with every line as hunks of the changed ones and CONTEXT lines
+// around them, each under its @@ -start,count +start,count @@ line.
+fn unified(diff: &[LineDiff]) -> Vec<String> {
+    let changed: Vec<usize> = (0..diff.len())
+        .filter(|&i| !matches!(diff[i], LineDiff::Same(_)))
+        .collect();
+    let mut lines = Vec::new();
+    let mut i = 0;
+    while i < changed.len() {
+        // changes closer together than twice the context share a hunk
+        let start = changed[i].saturating_sub(CONTEXT);
+        let mut end = changed[i];
+        while i + 1 < changed.len() && changed[i + 1] - end <= 2 * CONTEXT {
+            i += 1;
+            end = changed[i];
+        }
+        let end = (end + CONTEXT + 1).min(diff.len());
+        i += 1;
+
+        // Line numbers from 1 on either side, counting what came before. A
+        // side with no lines in the hunk starts at the line before it, as
+        // diff -u has it.
+        let before = |line: &LineDiff| !matches!(line, LineDiff::Added(_));
+        let after = |line: &LineDiff| !matches!(line, LineDiff::Removed(_));
+        let old_count = diff[start..end].iter().filter(|line| before(line)).count();
+        let new_count = diff[start..end].iter().filter(|line| after(line)).count();
+        let old_start =
+            diff[..start].iter().filter(|line| before(line)).count() + usize::from(old_count > 0);
+        let new_start =
+            diff[..start].iter().filter(|line| after(line)).count() + usize::from(new_count > 0);
+        lines.push(format!(
+            "@@ -{},{} +{},{} @@",
+            old_start, old_count, new_start, new_count
+        ));
+        for line in &diff[start..end] {
+            lines.push(match line {
+                LineDiff::Same(line) => format!(" {}", line),
+                LineDiff::Removed(line) => format!("-{}", line),
+                LineDiff::Added(line) => format!("+{}", line),
+            });
+        }
+    }
+    lines
+}
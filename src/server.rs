@@ -0,0 +1,425 @@
+use crate::connection::{self, ConnectionInfo};
+use crate::error::Error;
+use crate::signing::{SignatureScheme, Signer};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::io;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{trace, warn};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{ClientRequestBuilder, Message, WebSocket};
+
+type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+// how long the bridge waits on each side in turn, in milliseconds
+const BRIDGE_POLL: i64 = 5;
+
+// A Jupyter Server, or a JupyterHub user's server like
+// https://hub.example.com/user/me, reached through its REST API. Its
+// kernels' zmq ports stay on the server, each kernel only has a WebSocket
+// at /api/kernels/<id>/channels.
+pub struct Server {
+    url: String,
+    token: Option<String>,
+    agent: ureq::Agent,
+}
+
+// without the token
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("url", &self.url)
+            .field("token", &self.token.as_ref().map(|_| "..."))
+            .finish_non_exhaustive()
+    }
+}
+
+// a kernel as /api/kernels describes it
+#[derive(Clone, Debug, Deserialize)]
+pub struct Kernel {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub last_activity: String,
+    #[serde(default)]
+    pub execution_state: String,
+    #[serde(default)]
+    pub connections: u64,
+}
+
+impl Server {
+    pub fn new(url: &str, token: Option<&str>) -> Server {
+        Server {
+            url: url.trim_end_matches('/').to_string(),
+            token: token.map(String::from),
+            agent: ureq::agent(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn api(&self, path: &str) -> String {
+        format!("{}/api/{}", self.url, path)
+    }
+
+    fn authorize<B>(&self, request: ureq::RequestBuilder<B>) -> ureq::RequestBuilder<B> {
+        match &self.token {
+            Some(token) => request.header("Authorization", format!("token {}", token)),
+            None => request,
+        }
+    }
+
+    pub fn kernels(&self) -> Result<Vec<Kernel>, Error> {
+        let request = self.authorize(self.agent.get(self.api("kernels")));
+        Ok(request.call()?.body_mut().read_json()?)
+    }
+
+    // Look a kernel up by its id or the start of it, the most recently
+    // active one for an empty id.
+    pub fn find_kernel(&self, id: &str) -> Result<Option<Kernel>, Error> {
+        let kernels = self.kernels()?;
+        if id.is_empty() {
+            // ISO 8601 timestamps in the same zone sort as strings
+            return Ok(kernels
+                .into_iter()
+                .max_by(|a, b| a.last_activity.cmp(&b.last_activity)));
+        }
+        let mut matches = kernels
+            .into_iter()
+            .filter(|kernel| kernel.id.starts_with(id));
+        match (matches.next(), matches.next()) {
+            (Some(kernel), None) => Ok(Some(kernel)),
+            _ => Ok(None),
+        }
+    }
+
+    // start a kernel from one of the server's kernelspecs, its default one
+    // without a name
+    pub fn start_kernel(&self, name: Option<&str>) -> Result<Kernel, Error> {
+        let body = match name {
+            Some(name) => json!({ "name": name }),
+            None => json!({}),
+        };
+        let request = self.authorize(self.agent.post(self.api("kernels")));
+        Ok(request.send_json(body)?.body_mut().read_json()?)
+    }
+
+    // Shut a kernel down through the server. A shutdown_request over the
+    // WebSocket would look like a crash to the server, which restarts it.
+    pub fn shutdown_kernel(&self, id: &str) -> Result<(), Error> {
+        let path = format!("kernels/{}", id);
+        self.authorize(self.agent.delete(self.api(&path))).call()?;
+        Ok(())
+    }
+
+    // the server interrupts the way the kernelspec asks for
+    pub fn interrupt_kernel(&self, id: &str) -> Result<(), Error> {
+        let path = format!("kernels/{}/interrupt", id);
+        self.authorize(self.agent.post(self.api(&path)))
+            .send_empty()?;
+        Ok(())
+    }
+
+    // Open the kernel's WebSocket and relay it to zmq sockets on localhost,
+    // returning the bridge and the connection info for them, so the client
+    // talks to the kernel like any other.
+    pub fn connect(
+        &self,
+        kernel: &Kernel,
+        session_id: &str,
+    ) -> Result<(Bridge, ConnectionInfo), Error> {
+        let url = format!(
+            "{}/api/kernels/{}/channels?session_id={}",
+            self.url, kernel.id, session_id
+        );
+        let url = match url.strip_prefix("http") {
+            // http:// becomes ws:// and https:// wss://
+            Some(rest) => format!("ws{}", rest),
+            None => url,
+        };
+        let uri = url
+            .parse()
+            .map_err(|err: tungstenite::http::uri::InvalidUri| {
+                tungstenite::Error::from(tungstenite::http::Error::from(err))
+            })?;
+        let mut request = ClientRequestBuilder::new(uri);
+        if let Some(token) = &self.token {
+            request = request.with_header("Authorization", format!("token {}", token));
+        }
+        let (websocket, _) = tungstenite::connect(request)?;
+        Bridge::start(websocket, &kernel.name)
+    }
+}
+
+// The local end of a kernel's WebSocket: zmq sockets bound on localhost
+// that stand in for the kernel's, and a thread shuttling messages between
+// them and the WebSocket, sorted by the channel each JSON message names.
+// The thread stops when the Bridge is dropped or the server hangs up.
+#[derive(Debug)]
+pub struct Bridge {
+    stop: Arc<AtomicBool>,
+    closed: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// the kernel's side of each channel, in the roles a kernel binds them in
+struct Sockets {
+    shell: zmq::Socket,
+    control: zmq::Socket,
+    stdin: zmq::Socket,
+    iopub: zmq::Socket,
+    hb: zmq::Socket,
+}
+
+impl Bridge {
+    fn start(websocket: Socket, kernel_name: &str) -> Result<(Bridge, ConnectionInfo), Error> {
+        // reads give up after a moment, so the thread can look at zmq in between
+        let stream = match websocket.get_ref() {
+            MaybeTlsStream::Plain(stream) => stream,
+            MaybeTlsStream::Rustls(stream) => stream.get_ref(),
+            _ => unreachable!("no other TLS backend is enabled"),
+        };
+        stream.set_read_timeout(Some(Duration::from_millis(BRIDGE_POLL as u64)))?;
+
+        let context = zmq::Context::new();
+        let bind = |kind| -> Result<(zmq::Socket, u16), Error> {
+            let socket = context.socket(kind)?;
+            socket.set_linger(0)?;
+            socket.bind("tcp://127.0.0.1:*")?;
+            let endpoint = socket.get_last_endpoint()?.unwrap_or_default();
+            let port = endpoint
+                .rsplit(':')
+                .next()
+                .and_then(|port| port.parse().ok());
+            Ok((socket, port.unwrap_or_default()))
+        };
+        let (shell, shell_port) = bind(zmq::ROUTER)?;
+        let (control, control_port) = bind(zmq::ROUTER)?;
+        let (stdin, stdin_port) = bind(zmq::ROUTER)?;
+        let (iopub, iopub_port) = bind(zmq::PUB)?;
+        let (hb, hb_port) = bind(zmq::REP)?;
+
+        let info = ConnectionInfo {
+            shell_port,
+            iopub_port,
+            stdin_port,
+            control_port,
+            hb_port,
+            ip: "127.0.0.1".to_string(),
+            key: connection::random_key(),
+            transport: "tcp".to_string(),
+            signature_scheme: BRIDGE_SCHEME.name().to_string(),
+            kernel_name: kernel_name.to_string(),
+        };
+        let sockets = Sockets {
+            shell,
+            control,
+            stdin,
+            iopub,
+            hb,
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let closed = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            let closed = Arc::clone(&closed);
+            let key = info.key.clone();
+            thread::spawn(move || {
+                // the sockets go with the context, which has to outlive them
+                let _context = context;
+                relay(websocket, &sockets, &key, &stop);
+                closed.store(true, Ordering::SeqCst);
+            })
+        };
+        let bridge = Bridge {
+            stop,
+            closed,
+            thread: Some(thread),
+        };
+        Ok((bridge, info))
+    }
+
+    // the server closed the WebSocket, nothing gets through any more
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// what the bridge's connection info has the client sign with
+const BRIDGE_SCHEME: SignatureScheme = SignatureScheme::HmacSha256;
+
+fn signer(key: &str) -> Box<dyn Signer> {
+    BRIDGE_SCHEME.signer(key.as_bytes())
+}
+
+fn as_frames(parts: &[String]) -> Vec<&[u8]> {
+    parts.iter().map(|part| part.as_bytes()).collect()
+}
+
+fn relay(mut websocket: Socket, sockets: &Sockets, key: &str, stop: &AtomicBool) {
+    // the client's zmq identity, the same on shell, control and stdin
+    let mut peer: Option<Vec<u8>> = None;
+    let channels = [
+        ("shell", &sockets.shell),
+        ("control", &sockets.control),
+        ("stdin", &sockets.stdin),
+    ];
+
+    while !stop.load(Ordering::SeqCst) {
+        // everything the server has sent, until a read times out
+        let mut busy = false;
+        loop {
+            match websocket.read() {
+                Ok(Message::Text(text)) => {
+                    busy = true;
+                    deliver(&text, sockets, peer.as_deref(), key);
+                }
+                // buffers for things like widgets, which the client doesn't use
+                Ok(Message::Binary(_)) => trace!("dropping a binary websocket message"),
+                Ok(Message::Close(_)) => return,
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(err))
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                Err(err) => {
+                    warn!(%err, "kernel websocket closed");
+                    return;
+                }
+            }
+        }
+
+        let mut items = [
+            sockets.shell.as_poll_item(zmq::POLLIN),
+            sockets.control.as_poll_item(zmq::POLLIN),
+            sockets.stdin.as_poll_item(zmq::POLLIN),
+            sockets.hb.as_poll_item(zmq::POLLIN),
+        ];
+        match zmq::poll(&mut items, if busy { 0 } else { BRIDGE_POLL }) {
+            Ok(_) | Err(zmq::Error::EINTR) => {}
+            Err(err) => {
+                warn!(%err, "bridge failed polling");
+                return;
+            }
+        }
+
+        for (i, (channel, socket)) in channels.iter().enumerate() {
+            if !items[i].is_readable() {
+                continue;
+            }
+            let frames = match socket.recv_multipart(0) {
+                Ok(frames) => frames,
+                Err(_) => continue,
+            };
+            peer = frames.first().cloned();
+            let msg = match to_json(&frames, channel, key) {
+                Some(msg) => msg,
+                None => continue,
+            };
+            if let Err(err) = websocket.send(Message::text(msg.to_string())) {
+                warn!(%err, "kernel websocket closed");
+                return;
+            }
+        }
+
+        // the server keeps the real heartbeat to itself, this one says the
+        // WebSocket is still up
+        if items[3].is_readable() {
+            if let Ok(ping) = sockets.hb.recv_bytes(0) {
+                let _ = sockets.hb.send(ping, 0);
+            }
+        }
+    }
+    let _ = websocket.close(None);
+    let _ = websocket.flush();
+}
+
+// A message from the client's zmq frames, as the JSON the WebSocket takes.
+// None for anything not signed with our key.
+fn to_json(frames: &[Vec<u8>], channel: &str, key: &str) -> Option<Value> {
+    let delimiter = frames.iter().position(|frame| frame == b"<IDS|MSG>")?;
+    let signature = String::from_utf8_lossy(frames.get(delimiter + 1)?);
+    let parts: Vec<String> = frames
+        .get(delimiter + 2..delimiter + 6)?
+        .iter()
+        .map(|part| String::from_utf8_lossy(part).into_owned())
+        .collect();
+    if parts.len() != 4 || !signer(key).verify(&signature, &as_frames(&parts)) {
+        warn!(channel, "dropping a message with a bad signature");
+        return None;
+    }
+
+    let parse = |part: &str| serde_json::from_str::<Value>(part).ok();
+    Some(json!({
+        "header": parse(&parts[0])?,
+        "parent_header": parse(&parts[1])?,
+        "metadata": parse(&parts[2])?,
+        "content": parse(&parts[3])?,
+        "buffers": [],
+        "channel": channel,
+    }))
+}
+
+// Send a message from the WebSocket to the client, on the zmq socket for
+// the channel it names.
+fn deliver(text: &str, sockets: &Sockets, peer: Option<&[u8]>, key: &str) {
+    let msg = match serde_json::from_str::<Value>(text) {
+        Ok(Value::Object(msg)) => Value::Object(msg),
+        _ => {
+            warn!("dropping a websocket message that isn't a JSON object");
+            return;
+        }
+    };
+    let empty = Value::Object(Map::new());
+    let part = |name: &str| msg.get(name).unwrap_or(&empty).to_string();
+    let parts = vec![
+        part("header"),
+        part("parent_header"),
+        part("metadata"),
+        part("content"),
+    ];
+
+    let (socket, first) = match msg["channel"].as_str() {
+        Some("iopub") => (&sockets.iopub, b"kernel.iopub".to_vec()),
+        // replies nobody asked for yet have nowhere to go
+        Some(channel) => match (channel, peer) {
+            ("shell", Some(peer)) => (&sockets.shell, peer.to_vec()),
+            ("control", Some(peer)) => (&sockets.control, peer.to_vec()),
+            ("stdin", Some(peer)) => (&sockets.stdin, peer.to_vec()),
+            _ => {
+                trace!(channel, "dropping a websocket message");
+                return;
+            }
+        },
+        None => return,
+    };
+
+    let mut frames = vec![
+        first,
+        b"<IDS|MSG>".to_vec(),
+        signer(key).sign(&as_frames(&parts)).into_bytes(),
+    ];
+    frames.extend(parts.into_iter().map(String::into_bytes));
+    let _ = socket.send_multipart(frames, 0);
+}
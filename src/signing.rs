@@ -0,0 +1,138 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Sha256, Sha512};
+use std::fmt;
+
+// The signature_scheme values of a connection file jupyterm can sign and
+// check messages with, and None for connections with no key, whose
+// messages aren't signed whatever the file says.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SignatureScheme {
+    #[default]
+    HmacSha256,
+    HmacSha512,
+    None,
+}
+
+impl SignatureScheme {
+    // the ones a connection file can name
+    pub const ALL: &'static [SignatureScheme] =
+        &[SignatureScheme::HmacSha256, SignatureScheme::HmacSha512];
+
+    // What a connection with `key` signs with: None without a key, otherwise
+    // `scheme`, the default if it's one jupyterm doesn't know.
+    pub fn for_connection(scheme: &str, key: &str) -> SignatureScheme {
+        if key.is_empty() {
+            return SignatureScheme::None;
+        }
+        SignatureScheme::parse(scheme).unwrap_or_default()
+    }
+
+    pub fn parse(scheme: &str) -> Option<SignatureScheme> {
+        SignatureScheme::ALL
+            .iter()
+            .copied()
+            .find(|known| known.name() == scheme)
+    }
+
+    // as a connection file has it
+    pub fn name(self) -> &'static str {
+        match self {
+            SignatureScheme::HmacSha256 => "hmac-sha256",
+            SignatureScheme::HmacSha512 => "hmac-sha512",
+            SignatureScheme::None => "none",
+        }
+    }
+
+    pub fn signer(self, key: &[u8]) -> Box<dyn Signer> {
+        if self == SignatureScheme::None || key.is_empty() {
+            return Box::new(Unsigned);
+        }
+        Box::new(HmacSigner::new(self, key))
+    }
+}
+
+// Signs a message's header, parent header, metadata and content frames,
+// and checks the signature of ones that come in.
+pub trait Signer: Send {
+    fn sign(&self, frames: &[&[u8]]) -> String;
+    fn verify(&self, sig: &str, frames: &[&[u8]]) -> bool;
+}
+
+// For connections with no key: an empty signature, and any goes.
+#[derive(Debug)]
+pub struct Unsigned;
+
+impl Signer for Unsigned {
+    fn sign(&self, _frames: &[&[u8]]) -> String {
+        String::new()
+    }
+
+    fn verify(&self, _sig: &str, _frames: &[&[u8]]) -> bool {
+        true
+    }
+}
+
+// HMAC with the scheme's hash over the frames, hex-encoded
+pub struct HmacSigner {
+    scheme: SignatureScheme,
+    key: Vec<u8>,
+}
+
+// without the key
+impl fmt::Debug for HmacSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HmacSigner")
+            .field("scheme", &self.scheme)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HmacSigner {
+    pub fn new(scheme: SignatureScheme, key: &[u8]) -> HmacSigner {
+        HmacSigner {
+            scheme,
+            key: key.to_vec(),
+        }
+    }
+}
+
+fn mac<M: Mac + NewMac>(key: &[u8], frames: &[&[u8]]) -> M {
+    // HMAC takes keys of any length
+    let mut mac = M::new_varkey(key).unwrap();
+    for frame in frames {
+        mac.update(frame);
+    }
+    mac
+}
+
+impl Signer for HmacSigner {
+    fn sign(&self, frames: &[&[u8]]) -> String {
+        match self.scheme {
+            SignatureScheme::HmacSha256 => hex::encode(
+                mac::<Hmac<Sha256>>(&self.key, frames)
+                    .finalize()
+                    .into_bytes(),
+            ),
+            SignatureScheme::HmacSha512 => hex::encode(
+                mac::<Hmac<Sha512>>(&self.key, frames)
+                    .finalize()
+                    .into_bytes(),
+            ),
+            SignatureScheme::None => String::new(),
+        }
+    }
+
+    // in constant time, by the MAC's own verify
+    fn verify(&self, sig: &str, frames: &[&[u8]]) -> bool {
+        let sig = match hex::decode(sig) {
+            Ok(sig) => sig,
+            Err(_) => return false,
+        };
+        match self.scheme {
+            SignatureScheme::HmacSha256 => mac::<Hmac<Sha256>>(&self.key, frames).verify(&sig),
+            SignatureScheme::HmacSha512 => mac::<Hmac<Sha512>>(&self.key, frames).verify(&sig),
+            SignatureScheme::None => Ok(()),
+        }
+        .is_ok()
+    }
+}
@@ -0,0 +1,99 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+// the channels a client has sockets for, by the names connection files
+// give their ports
+pub const CHANNELS: &[&str] = &["shell", "iopub", "stdin", "control", "hb"];
+
+// A ZMQ socket option for one of a client's channels, for tuning them
+// beyond what jupyterm sets itself. Sizes are in messages for the high
+// water marks and bytes for the buffers, times in milliseconds, and the
+// TCP keepalive ones take -1 for the OS default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SocketOption {
+    SndHwm(i32),
+    RcvHwm(i32),
+    SndBuf(i32),
+    RcvBuf(i32),
+    Linger(i32),
+    ReconnectIvl(i32),
+    ReconnectIvlMax(i32),
+    TcpKeepalive(i32),
+    TcpKeepaliveIdle(i32),
+    TcpKeepaliveCnt(i32),
+    TcpKeepaliveIntvl(i32),
+    Immediate(bool),
+    // only for ROUTER sockets, the others turn it down
+    RouterMandatory(bool),
+}
+
+impl SocketOption {
+    // `name` as in the ZMQ docs without the ZMQ_, in any case, like rcvhwm
+    pub fn parse(name: &str, value: &Value) -> Result<SocketOption, String> {
+        let int = || match value.as_i64().map(i32::try_from) {
+            Some(Ok(n)) => Ok(n),
+            _ => Err(format!("{} takes a number, not {}", name, value)),
+        };
+        let flag = || {
+            value
+                .as_bool()
+                .ok_or_else(|| format!("{} takes true or false, not {}", name, value))
+        };
+        Ok(match name.to_lowercase().as_str() {
+            "sndhwm" => SocketOption::SndHwm(int()?),
+            "rcvhwm" => SocketOption::RcvHwm(int()?),
+            "sndbuf" => SocketOption::SndBuf(int()?),
+            "rcvbuf" => SocketOption::RcvBuf(int()?),
+            "linger" => SocketOption::Linger(int()?),
+            "reconnect_ivl" => SocketOption::ReconnectIvl(int()?),
+            "reconnect_ivl_max" => SocketOption::ReconnectIvlMax(int()?),
+            "tcp_keepalive" => SocketOption::TcpKeepalive(int()?),
+            "tcp_keepalive_idle" => SocketOption::TcpKeepaliveIdle(int()?),
+            "tcp_keepalive_cnt" => SocketOption::TcpKeepaliveCnt(int()?),
+            "tcp_keepalive_intvl" => SocketOption::TcpKeepaliveIntvl(int()?),
+            "immediate" => SocketOption::Immediate(flag()?),
+            "router_mandatory" => SocketOption::RouterMandatory(flag()?),
+            _ => return Err(format!("unknown socket option {}", name)),
+        })
+    }
+
+    pub fn apply(self, socket: &zmq::Socket) -> zmq::Result<()> {
+        match self {
+            SocketOption::SndHwm(n) => socket.set_sndhwm(n),
+            SocketOption::RcvHwm(n) => socket.set_rcvhwm(n),
+            SocketOption::SndBuf(n) => socket.set_sndbuf(n),
+            SocketOption::RcvBuf(n) => socket.set_rcvbuf(n),
+            SocketOption::Linger(n) => socket.set_linger(n),
+            SocketOption::ReconnectIvl(n) => socket.set_reconnect_ivl(n),
+            SocketOption::ReconnectIvlMax(n) => socket.set_reconnect_ivl_max(n),
+            SocketOption::TcpKeepalive(n) => socket.set_tcp_keepalive(n),
+            SocketOption::TcpKeepaliveIdle(n) => socket.set_tcp_keepalive_idle(n),
+            SocketOption::TcpKeepaliveCnt(n) => socket.set_tcp_keepalive_cnt(n),
+            SocketOption::TcpKeepaliveIntvl(n) => socket.set_tcp_keepalive_intvl(n),
+            SocketOption::Immediate(on) => socket.set_immediate(on),
+            SocketOption::RouterMandatory(on) => socket.set_router_mandatory(on),
+        }
+    }
+}
+
+// The socket_options table of the config, like `iopub.rcvhwm = 1000`, as
+// the options for each channel.
+pub fn from_table(
+    table: &BTreeMap<String, BTreeMap<String, Value>>,
+) -> Result<Vec<(String, SocketOption)>, String> {
+    let mut options = Vec::new();
+    for (channel, settings) in table {
+        if !CHANNELS.contains(&channel.as_str()) {
+            return Err(format!(
+                "no {} channel, only {}",
+                channel,
+                CHANNELS.join(", ")
+            ));
+        }
+        for (name, value) in settings {
+            options.push((channel.clone(), SocketOption::parse(name, value)?));
+        }
+    }
+    Ok(options)
+}
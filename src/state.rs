@@ -0,0 +1,42 @@
+use crate::config::Config;
+use crate::connection::ConnectionInfo;
+use crate::error::Error;
+use crate::transcript::Transcript;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// A session as :save-state writes it, as MessagePack. --restore reattaches
+// to the kernel if it's still running, or replays the transcript in a new one.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct State {
+    pub connection: ConnectionInfo,
+    pub connection_file: PathBuf,
+    pub config: Config,
+    pub transcript: Transcript,
+}
+
+impl State {
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_msgpack()?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<State, Error> {
+        State::from_msgpack(&fs::read(path)?)
+    }
+
+    // What save() writes, for handing a session to another process without
+    // a file. With field names, so a Config saved before a new setting still
+    // loads.
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, Error> {
+        Ok(rmp_serde::to_vec_named(self)?)
+    }
+
+    pub fn from_msgpack(bytes: &[u8]) -> Result<State, Error> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
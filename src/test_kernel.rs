@@ -0,0 +1,473 @@
+use crate::client::{Cutypr, Session};
+use crate::connection::ConnectionInfo;
+use crate::signing::{HmacSigner, SignatureScheme, Signer};
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// A kernel in a thread of the test, on ports of its own, for the tests of
+// anything that talks to one. It answers kernel_info, execute, history,
+// interrupt and shutdown requests much like ipykernel, and keeps every
+// request it was sent. Cells it knows:
+//   sleep N     runs for N seconds, or until interrupted
+//   raise       fails with a ValueError, its count still taken
+//   input P     asks for input() with prompt P and prints what it got,
+//               or fails with a KeyboardInterrupt if interrupted first
+//   anything    prints "ran: " and the code, and results in its length
+//               unless it ends with a ;
+pub struct TestKernel {
+    pub info: ConnectionInfo,
+    requests: Arc<Mutex<Vec<Request>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// A request as the kernel got it.
+#[derive(Clone, Debug)]
+pub struct Request {
+    // shell, control or stdin
+    pub channel: &'static str,
+    pub header: Value,
+    pub content: Value,
+    pub signature: String,
+    // the header, parent header, metadata and content, as they were signed
+    pub frames: Vec<Vec<u8>>,
+}
+
+impl Request {
+    pub fn msg_type(&self) -> &str {
+        self.header["msg_type"].as_str().unwrap_or_default()
+    }
+
+    pub fn signed_with(&self, key: &str) -> bool {
+        let frames: Vec<&[u8]> = self.frames.iter().map(Vec::as_slice).collect();
+        HmacSigner::new(SignatureScheme::HmacSha256, key.as_bytes())
+            .verify(&self.signature, &frames)
+    }
+}
+
+impl TestKernel {
+    pub fn start(key: &str) -> TestKernel {
+        let context = zmq::Context::new();
+        let bind = |kind| {
+            let socket = context.socket(kind).unwrap();
+            socket.set_linger(0).unwrap();
+            socket.bind("tcp://127.0.0.1:*").unwrap();
+            let endpoint = socket.get_last_endpoint().unwrap().unwrap();
+            let port = endpoint.rsplit(':').next().unwrap().parse().unwrap();
+            (socket, port)
+        };
+        let (shell, shell_port) = bind(zmq::ROUTER);
+        let (control, control_port) = bind(zmq::ROUTER);
+        let (stdin, stdin_port) = bind(zmq::ROUTER);
+        let (iopub, iopub_port) = bind(zmq::PUB);
+        let (hb, hb_port) = bind(zmq::REP);
+        let info = ConnectionInfo {
+            shell_port,
+            iopub_port,
+            stdin_port,
+            control_port,
+            hb_port,
+            ip: "127.0.0.1".to_string(),
+            key: key.to_string(),
+            transport: "tcp".to_string(),
+            signature_scheme: "hmac-sha256".to_string(),
+            kernel_name: "test".to_string(),
+        };
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut kernel = Kernel {
+            shell,
+            control,
+            stdin,
+            iopub,
+            hb,
+            signer: HmacSigner::new(SignatureScheme::HmacSha256, key.as_bytes()),
+            requests: requests.clone(),
+            execution_count: 0,
+            history: Vec::new(),
+        };
+        let stopping = stop.clone();
+        let thread = thread::spawn(move || {
+            // the sockets go with the thread, the context has to outlive them
+            let _context = context;
+            kernel.serve(&stopping);
+        });
+        TestKernel {
+            info,
+            requests,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    // a session on it, with a new id every time
+    pub fn session(&self) -> Session {
+        static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+        Session {
+            key: self.info.key.clone(),
+            session_id: format!("test-{}", SESSIONS.fetch_add(1, Ordering::SeqCst)),
+            signature_scheme: SignatureScheme::for_connection(
+                &self.info.signature_scheme,
+                &self.info.key,
+            ),
+        }
+    }
+
+    // a client with all its channels up, once the kernel has answered it
+    pub fn client(&self) -> Cutypr {
+        let mut client = Cutypr::new(self.session(), self.info.ports());
+        client.initialize_channels();
+        client
+            .wait_for_kernel_ready(Duration::from_secs(5))
+            .unwrap();
+        client
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    // the requests of `msg_type` it's been sent, oldest first
+    pub fn requests_of(&self, msg_type: &str) -> Vec<Request> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.msg_type() == msg_type)
+            .collect()
+    }
+}
+
+impl Drop for TestKernel {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Kernel {
+    shell: zmq::Socket,
+    control: zmq::Socket,
+    stdin: zmq::Socket,
+    iopub: zmq::Socket,
+    hb: zmq::Socket,
+    signer: HmacSigner,
+    requests: Arc<Mutex<Vec<Request>>>,
+    execution_count: i64,
+    history: Vec<String>,
+}
+
+// a request and the ROUTER identities to answer it at
+struct Incoming {
+    ids: Vec<Vec<u8>>,
+    request: Request,
+}
+
+impl Kernel {
+    fn serve(&mut self, stop: &AtomicBool) {
+        while !stop.load(Ordering::SeqCst) {
+            let mut items = [
+                self.shell.as_poll_item(zmq::POLLIN),
+                self.control.as_poll_item(zmq::POLLIN),
+                self.hb.as_poll_item(zmq::POLLIN),
+            ];
+            zmq::poll(&mut items, 20).unwrap();
+            let (shell, control, hb) = (
+                items[0].is_readable(),
+                items[1].is_readable(),
+                items[2].is_readable(),
+            );
+            if hb {
+                let ping = self.hb.recv_bytes(0).unwrap();
+                self.hb.send(ping, 0).unwrap();
+            }
+            if control {
+                let incoming = self.recv("control");
+                if !self.control_request(&incoming) {
+                    return;
+                }
+            }
+            if shell {
+                let incoming = self.recv("shell");
+                self.shell_request(&incoming);
+            }
+        }
+    }
+
+    fn recv(&self, channel: &'static str) -> Incoming {
+        let socket = match channel {
+            "shell" => &self.shell,
+            "control" => &self.control,
+            _ => &self.stdin,
+        };
+        let frames = socket.recv_multipart(0).unwrap();
+        let delimiter = frames.iter().position(|f| f == b"<IDS|MSG>").unwrap();
+        let parse = |frame: &[u8]| serde_json::from_slice::<Value>(frame).unwrap();
+        let request = Request {
+            channel,
+            header: parse(&frames[delimiter + 2]),
+            content: parse(&frames[delimiter + 5]),
+            signature: String::from_utf8_lossy(&frames[delimiter + 1]).into_owned(),
+            frames: frames[delimiter + 2..delimiter + 6].to_vec(),
+        };
+        self.requests.lock().unwrap().push(request.clone());
+        Incoming {
+            ids: frames[..delimiter].to_vec(),
+            request,
+        }
+    }
+
+    fn send(
+        &self,
+        socket: &zmq::Socket,
+        ids: &[Vec<u8>],
+        msg_type: &str,
+        parent: &Value,
+        content: Value,
+    ) {
+        static MESSAGES: AtomicUsize = AtomicUsize::new(0);
+        let header = json!({
+            "msg_id": format!("kernel-{}", MESSAGES.fetch_add(1, Ordering::SeqCst)),
+            "msg_type": msg_type,
+            "session": "test-kernel",
+            "username": "test",
+            "version": "5.3",
+            "date": Utc::now().to_rfc3339(),
+        });
+        let parts = [
+            header.to_string(),
+            parent.to_string(),
+            "{}".to_string(),
+            content.to_string(),
+        ];
+        let signed: Vec<&[u8]> = parts.iter().map(String::as_bytes).collect();
+        let mut frames = ids.to_vec();
+        frames.push(b"<IDS|MSG>".to_vec());
+        frames.push(self.signer.sign(&signed).into_bytes());
+        frames.extend(parts.iter().map(|part| part.as_bytes().to_vec()));
+        socket.send_multipart(frames, 0).unwrap();
+    }
+
+    fn publish(&self, msg_type: &str, parent: &Value, content: Value) {
+        self.send(
+            &self.iopub,
+            &[msg_type.as_bytes().to_vec()],
+            msg_type,
+            parent,
+            content,
+        );
+    }
+
+    fn status(&self, state: &str, parent: &Value) {
+        self.publish("status", parent, json!({ "execution_state": state }));
+    }
+
+    // false once it's been told to shut down for good
+    fn control_request(&mut self, incoming: &Incoming) -> bool {
+        let parent = &incoming.request.header;
+        let content = &incoming.request.content;
+        match incoming.request.msg_type() {
+            "interrupt_request" => self.send(
+                &self.control,
+                &incoming.ids,
+                "interrupt_reply",
+                parent,
+                json!({"status": "ok"}),
+            ),
+            "shutdown_request" => {
+                let restart = content["restart"].as_bool().unwrap_or(false);
+                self.send(
+                    &self.control,
+                    &incoming.ids,
+                    "shutdown_reply",
+                    parent,
+                    json!({"status": "ok", "restart": restart}),
+                );
+                return restart;
+            }
+            "kernel_info_request" => self.kernel_info(&self.control, incoming),
+            _ => {}
+        }
+        true
+    }
+
+    fn kernel_info(&self, socket: &zmq::Socket, incoming: &Incoming) {
+        let parent = &incoming.request.header;
+        self.status("busy", parent);
+        self.send(
+            socket,
+            &incoming.ids,
+            "kernel_info_reply",
+            parent,
+            json!({
+                "status": "ok",
+                "protocol_version": "5.3",
+                "implementation": "test",
+                "implementation_version": "0.1",
+                "language_info": {"name": "python", "version": "3", "file_extension": ".py"},
+                "banner": "test kernel",
+            }),
+        );
+        self.status("idle", parent);
+    }
+
+    fn shell_request(&mut self, incoming: &Incoming) {
+        let parent = &incoming.request.header;
+        match incoming.request.msg_type() {
+            "kernel_info_request" => self.kernel_info(&self.shell, incoming),
+            "execute_request" => self.execute(incoming),
+            "history_request" => {
+                let content = &incoming.request.content;
+                let n = content["n"].as_u64().unwrap_or(10) as usize;
+                let output = content["output"].as_bool().unwrap_or(false);
+                let history: Vec<Value> = self
+                    .history
+                    .iter()
+                    .enumerate()
+                    .map(|(i, code)| {
+                        if output {
+                            json!([1, i + 1, [code, code.len().to_string()]])
+                        } else {
+                            json!([1, i + 1, code])
+                        }
+                    })
+                    .collect();
+                let history = history[history.len().saturating_sub(n)..].to_vec();
+                self.status("busy", parent);
+                self.send(
+                    &self.shell,
+                    &incoming.ids,
+                    "history_reply",
+                    parent,
+                    json!({"status": "ok", "history": history}),
+                );
+                self.status("idle", parent);
+            }
+            _ => {}
+        }
+    }
+
+    fn execute(&mut self, incoming: &Incoming) {
+        let parent = &incoming.request.header;
+        let content = &incoming.request.content;
+        let code = content["code"].as_str().unwrap_or_default().to_string();
+        let silent = content["silent"].as_bool().unwrap_or(false);
+        self.status("busy", parent);
+        if !silent {
+            // like IPython, only cells stored in the history take a count
+            if content["store_history"].as_bool().unwrap_or(true) {
+                self.execution_count += 1;
+                self.history.push(code.clone());
+            }
+            self.publish(
+                "execute_input",
+                parent,
+                json!({"code": code, "execution_count": self.execution_count}),
+            );
+        }
+
+        let error = if let Some(secs) = code.strip_prefix("sleep ") {
+            let interrupted = self.sleep(secs.trim().parse().unwrap_or(1.0));
+            interrupted.then_some(("KeyboardInterrupt", ""))
+        } else if code == "raise" {
+            Some(("ValueError", "boom"))
+        } else if let Some(prompt) = code.strip_prefix("input ") {
+            let session = parent["session"].as_str().unwrap_or_default();
+            self.send(
+                &self.stdin,
+                &[session.as_bytes().to_vec()],
+                "input_request",
+                parent,
+                json!({"prompt": prompt, "password": false}),
+            );
+            let (reply, interrupted) = self.wait_for_input();
+            if interrupted {
+                Some(("KeyboardInterrupt", ""))
+            } else {
+                let text = format!("got {}\n", reply.request.content["value"]);
+                self.publish("stream", parent, json!({"name": "stdout", "text": text}));
+                None
+            }
+        } else {
+            if !code.is_empty() && !silent {
+                let text = format!("ran: {}\n", code);
+                self.publish("stream", parent, json!({"name": "stdout", "text": text}));
+                if !code.ends_with(';') {
+                    self.publish(
+                        "execute_result",
+                        parent,
+                        json!({
+                            "execution_count": self.execution_count,
+                            "data": {"text/plain": code.len().to_string()},
+                            "metadata": {},
+                        }),
+                    );
+                }
+            }
+            None
+        };
+
+        let reply = match error {
+            Some((ename, evalue)) => {
+                let traceback = vec![format!("{}: {}", ename, evalue)];
+                self.publish(
+                    "error",
+                    parent,
+                    json!({"ename": ename, "evalue": evalue, "traceback": traceback}),
+                );
+                json!({
+                    "status": "error",
+                    "execution_count": self.execution_count,
+                    "ename": ename,
+                    "evalue": evalue,
+                    "traceback": traceback,
+                })
+            }
+            None => json!({
+                "status": "ok",
+                "execution_count": self.execution_count,
+                "user_expressions": {},
+                "payload": [],
+            }),
+        };
+        self.send(&self.shell, &incoming.ids, "execute_reply", parent, reply);
+        self.status("idle", parent);
+    }
+
+    // the input_reply, and whether an interrupt_request, which is
+    // answered, came while it was waited for
+    fn wait_for_input(&mut self) -> (Incoming, bool) {
+        let mut interrupted = false;
+        loop {
+            if self.stdin.poll(zmq::POLLIN, 10).unwrap() > 0 {
+                return (self.recv("stdin"), interrupted);
+            }
+            if self.control.poll(zmq::POLLIN, 0).unwrap() > 0 {
+                let incoming = self.recv("control");
+                interrupted |= incoming.request.msg_type() == "interrupt_request";
+                self.control_request(&incoming);
+            }
+        }
+    }
+
+    // true if an interrupt_request cut it short, which is answered
+    fn sleep(&mut self, secs: f64) -> bool {
+        let deadline = Instant::now() + Duration::from_secs_f64(secs);
+        while Instant::now() < deadline {
+            if self.control.poll(zmq::POLLIN, 10).unwrap() > 0 {
+                let incoming = self.recv("control");
+                let interrupt = incoming.request.msg_type() == "interrupt_request";
+                self.control_request(&incoming);
+                if interrupt {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
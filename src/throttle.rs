@@ -0,0 +1,105 @@
+use std::time::{Duration, Instant};
+
+// how long the rate is measured over
+const WINDOW: Duration = Duration::from_secs(1);
+
+// Keeps a cell that prints in a tight loop from flooding the terminal.
+// Once its streams go over `max_lines` lines or `max_bytes` bytes in a
+// second, what's printed is a line a second saying how much was left out,
+// until a second stays under both again. The messages are still read, so
+// nothing piles up and Ctrl-C gets noticed. 0 turns a limit off.
+#[derive(Debug)]
+pub struct OutputThrottle {
+    max_lines: u64,
+    max_bytes: u64,
+    // the start of this second, and what has come in during it
+    window: Instant,
+    lines: u64,
+    bytes: u64,
+    suppressed: u64,
+    summarizing: bool,
+}
+
+impl OutputThrottle {
+    pub fn new(max_lines: u64, max_bytes: u64) -> OutputThrottle {
+        OutputThrottle {
+            max_lines,
+            max_bytes,
+            window: Instant::now(),
+            lines: 0,
+            bytes: 0,
+            suppressed: 0,
+            summarizing: false,
+        }
+    }
+
+    // Whether to print a stream message's `text`.
+    pub fn admit(&mut self, text: &str) -> bool {
+        let lines = text.lines().count().max(1) as u64;
+        self.lines += lines;
+        self.bytes += text.len() as u64;
+        if !self.summarizing && self.over() {
+            self.summarizing = true;
+        }
+        if self.summarizing {
+            self.suppressed += lines;
+        }
+        !self.summarizing
+    }
+
+    // The line to print in place of what was left out, once a second is
+    // up. Called while nothing comes in too, so the last one isn't held back.
+    pub fn tick(&mut self) -> Option<String> {
+        if self.window.elapsed() < WINDOW {
+            return None;
+        }
+        let summary = self.summary();
+        self.summarizing &= self.over();
+        self.window = Instant::now();
+        self.lines = 0;
+        self.bytes = 0;
+        summary
+    }
+
+    // what was left out since the last summary, when the cell is done
+    pub fn finish(&mut self) -> Option<String> {
+        self.summarizing = false;
+        self.summary()
+    }
+
+    fn over(&self) -> bool {
+        self.max_lines > 0 && self.lines > self.max_lines
+            || self.max_bytes > 0 && self.bytes > self.max_bytes
+    }
+
+    fn summary(&mut self) -> Option<String> {
+        if self.suppressed == 0 {
+            return None;
+        }
+        let lines = if self.suppressed == 1 {
+            "line"
+        } else {
+            "lines"
+        };
+        let summary = format!(
+            "[... {} {} suppressed in the last second ...]",
+            thousands(self.suppressed),
+            lines
+        );
+        self.suppressed = 0;
+        Some(summary)
+    }
+}
+
+// 12340 as "12,340"
+fn thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(digit);
+    }
+    out
+}
@@ -0,0 +1,146 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Source of every cell the kernel ran this session, by execution count.
+// Local commands never get a count, so they leave no entry.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Transcript {
+    cells: BTreeMap<i64, String>,
+    // the ones that raised, state files from before don't have it
+    #[serde(default)]
+    failed: BTreeSet<i64>,
+}
+
+impl Transcript {
+    pub fn new() -> Transcript {
+        Transcript::default()
+    }
+
+    pub fn record(&mut self, execution_count: i64, source: &str) {
+        self.cells.insert(execution_count, source.to_string());
+    }
+
+    // the cell `execution_count` raised
+    pub fn mark_failed(&mut self, execution_count: i64) {
+        self.failed.insert(execution_count);
+    }
+
+    // for a kernel whose history was reset
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.failed.clear();
+    }
+
+    pub fn get(&self, execution_count: i64) -> Option<&str> {
+        self.cells.get(&execution_count).map(String::as_str)
+    }
+
+    pub fn last(&self) -> Option<(i64, &str)> {
+        self.cells
+            .iter()
+            .next_back()
+            .map(|(n, source)| (*n, source.as_str()))
+    }
+
+    // in execution order
+    pub fn cells(&self) -> impl Iterator<Item = (i64, &str)> {
+        self.cells.iter().map(|(n, source)| (*n, source.as_str()))
+    }
+
+    // The cells as a script to run again, each after a `# In[n]` line
+    // with `comment` as the comment marker, the ones that raised only with
+    // `include_errors`.
+    pub fn to_script(&self, comment: &str, include_errors: bool) -> String {
+        let cells: Vec<String> = self
+            .cells()
+            .filter(|(n, _)| include_errors || !self.failed.contains(n))
+            .map(|(n, source)| format!("{} In[{}]\n{}\n", comment, n, source.trim_end()))
+            .collect();
+        cells.join("\n")
+    }
+}
+
+// What starts a line comment in `language`, a language_info name, "#" for
+// the ones it isn't known for.
+pub fn comment_marker(language: &str) -> &'static str {
+    match language.to_lowercase().as_str() {
+        "rust" | "c" | "c++" | "cpp" | "go" | "java" | "javascript" | "typescript" | "scala"
+        | "kotlin" | "swift" | "c#" | "csharp" | "dart" => "//",
+        "sql" | "haskell" | "lua" => "--",
+        "matlab" | "octave" => "%",
+        "clojure" | "scheme" | "common lisp" | "racket" => ";;",
+        _ => "#",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcript() -> Transcript {
+        let mut transcript = Transcript::new();
+        transcript.record(1, "import math\n");
+        transcript.record(2, "def area(r):\n    return math.pi * r ** 2\n\n");
+        transcript.record(3, "area(\"ün\")");
+        transcript.mark_failed(3);
+        transcript.record(4, "print(\"π ≈\", round(area(1), 2))  # 円");
+        transcript
+    }
+
+    #[test]
+    fn the_script_skips_cells_that_raised() {
+        assert_eq!(
+            transcript().to_script("#", false),
+            "# In[1]\nimport math\n\
+             \n\
+             # In[2]\ndef area(r):\n    return math.pi * r ** 2\n\
+             \n\
+             # In[4]\nprint(\"π ≈\", round(area(1), 2))  # 円\n"
+        );
+    }
+
+    #[test]
+    fn the_script_can_include_errors() {
+        let script = transcript().to_script("//", true);
+        assert_eq!(
+            script
+                .lines()
+                .filter(|line| line.starts_with("// In["))
+                .collect::<Vec<_>>(),
+            ["// In[1]", "// In[2]", "// In[3]", "// In[4]"]
+        );
+        assert!(script.contains("// In[3]\narea(\"ün\")\n\n// In[4]"));
+    }
+
+    #[test]
+    fn cells_are_in_execution_order() {
+        let mut transcript = Transcript::new();
+        transcript.record(10, "b");
+        transcript.record(2, "a");
+        // a cell run again under the same count replaces it
+        transcript.record(10, "c");
+        assert_eq!(
+            transcript.to_script("#", false),
+            "# In[2]\na\n\n# In[10]\nc\n"
+        );
+        assert_eq!(transcript.last(), Some((10, "c")));
+        transcript.clear();
+        assert_eq!(transcript.to_script("#", true), "");
+    }
+
+    #[test]
+    fn comment_markers_come_from_the_language() {
+        assert_eq!(comment_marker("python"), "#");
+        assert_eq!(comment_marker("Rust"), "//");
+        assert_eq!(comment_marker("SQL"), "--");
+        assert_eq!(comment_marker("octave"), "%");
+        assert_eq!(comment_marker("racket"), ";;");
+        assert_eq!(comment_marker("R"), "#");
+    }
+
+    #[test]
+    fn transcripts_saved_before_failures_were_kept_load() {
+        let transcript: Transcript = serde_json::from_str(r#"{"cells": {"1": "x = 1"}}"#).unwrap();
+        assert_eq!(transcript.to_script("#", false), "# In[1]\nx = 1\n");
+    }
+}
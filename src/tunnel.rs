@@ -0,0 +1,173 @@
+use crate::connection::{self, ConnectionInfo};
+use crate::error::Error;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// how long ssh gets to log in and start listening on the local ports
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(15);
+// lines of ssh's stderr kept for explaining why it failed
+const SSH_ERROR_LINES: usize = 20;
+
+// Local port forwards to a kernel on another machine, through `ssh -N -L`
+// for each of its five ports. ssh runs with BatchMode, so logging in has to
+// work without a password prompt, with a key or an agent. The ssh process
+// is killed when the Tunnel is dropped.
+#[derive(Debug)]
+pub struct Tunnel {
+    host: String,
+    // the connection file's ip, as seen from `host`
+    remote_ip: String,
+    // (local, remote) ports
+    forwards: Vec<(u16, u16)>,
+    ssh: Ssh,
+}
+
+#[derive(Debug)]
+struct Ssh {
+    child: Child,
+    stderr: Arc<Mutex<VecDeque<String>>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl Tunnel {
+    // Forward the ports in `info` from `host`, returning the tunnel and the
+    // connection info to use instead, which points at the local ends.
+    pub fn open(host: &str, info: &ConnectionInfo) -> Result<(Tunnel, ConnectionInfo), Error> {
+        let local = connection::find_free_ports(5)?;
+        let remote = [
+            info.shell_port,
+            info.iopub_port,
+            info.stdin_port,
+            info.control_port,
+            info.hb_port,
+        ];
+
+        let mut local_info = info.clone();
+        local_info.ip = "127.0.0.1".to_string();
+        local_info.shell_port = local[0];
+        local_info.iopub_port = local[1];
+        local_info.stdin_port = local[2];
+        local_info.control_port = local[3];
+        local_info.hb_port = local[4];
+
+        let forwards: Vec<(u16, u16)> = local.iter().copied().zip(remote).collect();
+        let mut tunnel = Tunnel {
+            host: host.to_string(),
+            remote_ip: info.ip.clone(),
+            ssh: spawn_ssh(host, &info.ip, &forwards)?,
+            forwards,
+        };
+        tunnel.wait_until_listening()?;
+        Ok((tunnel, local_info))
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn is_alive(&mut self) -> bool {
+        matches!(self.ssh.child.try_wait(), Ok(None))
+    }
+
+    // Start ssh again on the same local ports, zmq reconnects by itself.
+    pub fn reopen(&mut self) -> Result<(), Error> {
+        self.ssh.stop();
+        self.ssh = spawn_ssh(&self.host, &self.remote_ip, &self.forwards)?;
+        self.wait_until_listening()
+    }
+
+    // until ssh listens on every local port, it sets them up one by one
+    fn wait_until_listening(&mut self) -> Result<(), Error> {
+        let started = Instant::now();
+        let mut waiting: Vec<SocketAddr> = self
+            .forwards
+            .iter()
+            .map(|(local, _)| SocketAddr::from(([127, 0, 0, 1], *local)))
+            .collect();
+        loop {
+            if let Some(status) = self.ssh.child.try_wait()? {
+                // everything ssh said before exiting, like why it couldn't log in
+                if let Some(reader) = self.ssh.reader.take() {
+                    let _ = reader.join();
+                }
+                let stderr = self.ssh.stderr.lock().unwrap();
+                let reason = match stderr.back() {
+                    Some(line) => line.clone(),
+                    None => format!("ssh exited with {}", status),
+                };
+                return Err(Error::Ssh(reason));
+            }
+            waiting.retain(|addr| {
+                TcpStream::connect_timeout(addr, Duration::from_millis(200)).is_err()
+            });
+            if waiting.is_empty() {
+                return Ok(());
+            }
+            if started.elapsed() > TUNNEL_TIMEOUT {
+                return Err(Error::Timeout("the ssh tunnel"));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.ssh.stop();
+    }
+}
+
+impl Ssh {
+    fn stop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn spawn_ssh(host: &str, remote_ip: &str, forwards: &[(u16, u16)]) -> Result<Ssh, Error> {
+    let mut command = Command::new("ssh");
+    command.args([
+        "-N",
+        "-o",
+        "BatchMode=yes",
+        "-o",
+        "ExitOnForwardFailure=yes",
+    ]);
+    for (local, remote) in forwards {
+        command
+            .arg("-L")
+            .arg(format!("127.0.0.1:{}:{}:{}", local, remote_ip, remote));
+    }
+    command.arg(host);
+
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| Error::Ssh(format!("couldn't run ssh: {}", err)))?;
+
+    // drained all along, so a chatty ssh can't block on a full pipe
+    let stderr = Arc::new(Mutex::new(VecDeque::new()));
+    let lines = Arc::clone(&stderr);
+    let pipe = child.stderr.take().unwrap();
+    let reader = thread::spawn(move || {
+        for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+            let mut lines = lines.lock().unwrap();
+            if lines.len() == SSH_ERROR_LINES {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+    });
+    Ok(Ssh {
+        child,
+        stderr,
+        reader: Some(reader),
+    })
+}
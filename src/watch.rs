@@ -0,0 +1,194 @@
+use crate::repl::KernelSession;
+use chrono::Local;
+use crossterm::style::Stylize;
+use jupyterm::capture;
+use jupyterm::client::{Cutypr, ExecuteOptions, ReadySocket};
+use jupyterm::config::Config;
+use jupyterm::display;
+use jupyterm::watcher::ConnectionWatcher;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Ctrl-C while the script runs interrupts it, a second one, or one while
+// waiting for a change, stops watching.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static STOP: AtomicBool = AtomicBool::new(false);
+
+// how often the file is looked at between runs, and how long an editor
+// gets to finish saving before the script is read again
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+fn handle_ctrl_c() {
+    let handler = ctrlc::set_handler(|| {
+        if RUNNING.load(Ordering::SeqCst) && !INTERRUPTED.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        STOP.store(true, Ordering::SeqCst);
+    });
+    if let Err(err) = handler {
+        eprintln!("jupyterm: Ctrl-C won't interrupt the script: {}", err);
+    }
+}
+
+// jupyterm watch SCRIPT: run it in a kernel, then again every time it's
+// saved, in the same kernel or with `fresh` in a new one each time. Changes
+// while it runs make one run after it, however many there were. False if
+// it couldn't start watching at all.
+pub fn watch(
+    script: &Path,
+    fresh: bool,
+    kernel: Option<&str>,
+    existing: Option<&str>,
+    log_file: Option<&Path>,
+) -> bool {
+    let watcher = match ConnectionWatcher::new(script) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            eprintln!("jupyterm: couldn't watch {}: {}", script.display(), err);
+            return false;
+        }
+    };
+    let config = Config::load();
+    let started = match existing {
+        Some(file) => crate::attach_session("watch", file),
+        None => start(kernel, log_file, &config),
+    };
+    let mut session = match started {
+        Some(session) => session,
+        None => return false,
+    };
+    handle_ctrl_c();
+
+    let mut runs = 0;
+    while !STOP.load(Ordering::SeqCst) {
+        if runs > 0 && fresh {
+            crate::close_session(&mut session, false, false);
+            session = match start(kernel, log_file, &config) {
+                Some(session) => session,
+                None => return false,
+            };
+        }
+        runs += 1;
+        run(&mut session.client, script, runs);
+        println!(
+            "{}",
+            format!("watching {}, Ctrl-C to stop", script.display()).dim()
+        );
+
+        // what changed while it ran counts as one change
+        while !watcher.changed() && !STOP.load(Ordering::SeqCst) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        thread::sleep(SETTLE_TIME);
+        watcher.changed();
+    }
+    let keep = !session.owned;
+    crate::close_session(&mut session, keep, false);
+    true
+}
+
+fn start(kernel: Option<&str>, log_file: Option<&Path>, config: &Config) -> Option<KernelSession> {
+    let mut session = crate::launch_session("watch", kernel, false, log_file)?;
+    session.run_startup(config);
+    Some(session)
+}
+
+// One run of the script, between a line saying when it started and one
+// saying how long it took. Its errors are printed like any other output.
+fn run(client: &mut Cutypr, script: &Path, n: u32) {
+    let started = Local::now().format("%H:%M:%S");
+    let heading = format!("── {} {}, run {} ──", started, script.display(), n);
+    println!("{}", heading.bold());
+    let code = match fs::read_to_string(script) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("jupyterm: couldn't read {}: {}", script.display(), err);
+            return;
+        }
+    };
+
+    let start = Instant::now();
+    let options = ExecuteOptions {
+        allow_stdin: false,
+        ..ExecuteOptions::default()
+    };
+    let msg_id = client.execute_with(&code, &options);
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    RUNNING.store(true, Ordering::SeqCst);
+    let mut interrupted = false;
+    let mut status = None;
+    let mut idle = false;
+    while (!idle || status.is_none()) && !STOP.load(Ordering::SeqCst) {
+        if INTERRUPTED.load(Ordering::SeqCst) && !interrupted {
+            interrupted = true;
+            if let Err(err) = client.interrupt_kernel() {
+                eprintln!("jupyterm: couldn't interrupt the kernel: {}", err);
+            }
+        }
+        let sockets = match client.poll_all(10) {
+            Ok(sockets) => sockets,
+            Err(err) => {
+                eprintln!("jupyterm: {}", err);
+                break;
+            }
+        };
+        for socket in sockets {
+            let msg = match socket {
+                ReadySocket::Iopub => match client.get_msg() {
+                    Some(msg) => msg,
+                    None => continue,
+                },
+                ReadySocket::Shell => match client.get_shell_msg() {
+                    Some(msg) => msg,
+                    None => continue,
+                },
+                ReadySocket::Stdin | ReadySocket::Heartbeat => continue,
+            };
+            if msg["parent_header"]["msg_id"] != msg_id.as_str() {
+                continue;
+            }
+            let msg_type = msg["header"]["msg_type"].as_str().unwrap_or_default();
+            match msg_type {
+                "status" => idle = msg["content"]["execution_state"] == "idle",
+                "execute_reply" => {
+                    status = Some(
+                        msg["content"]["status"]
+                            .as_str()
+                            .unwrap_or("ok")
+                            .to_string(),
+                    )
+                }
+                // all of it, there's no :traceback to go back to
+                "error" => {
+                    let traceback = msg["content"]["traceback"].as_array();
+                    for entry in traceback.into_iter().flatten() {
+                        eprintln!("{}", entry.as_str().unwrap_or_default());
+                    }
+                }
+                _ => {
+                    if let Some(text) = capture::text_of(msg_type, &msg["content"]) {
+                        match msg["content"]["name"].as_str() {
+                            Some("stderr") => eprint!("{}", text),
+                            _ => print!("{}", text),
+                        }
+                    }
+                }
+            }
+        }
+    }
+    RUNNING.store(false, Ordering::SeqCst);
+
+    let took = display::format_duration(start.elapsed());
+    let footer = match status.as_deref() {
+        _ if interrupted => format!("interrupted after {}", took),
+        Some("ok") => format!("done in {}", took),
+        Some(_) => format!("failed after {}", took),
+        None => format!("stopped after {}", took),
+    };
+    println!("{}", footer.dim());
+}
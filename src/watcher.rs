@@ -0,0 +1,41 @@
+use crate::error::Error;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+
+// Notices a connection file being written again, the way another client
+// restarting the kernel on new ports leaves it, or a script jupyterm watch
+// runs being saved. Its directory is watched, since files are often
+// replaced rather than written in place.
+#[derive(Debug)]
+pub struct ConnectionWatcher {
+    // events stop when it's dropped
+    _watcher: RecommendedWatcher,
+    changes: Receiver<()>,
+}
+
+impl ConnectionWatcher {
+    pub fn new(path: &Path) -> Result<ConnectionWatcher, Error> {
+        let path = path.canonicalize()?;
+        let dir = path.parent().map_or_else(PathBuf::new, Path::to_path_buf);
+        let (sender, changes) = mpsc::channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                if let Ok(event) = event {
+                    if !event.kind.is_access() && event.paths.contains(&path) {
+                        let _ = sender.send(());
+                    }
+                }
+            })?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        Ok(ConnectionWatcher {
+            _watcher: watcher,
+            changes,
+        })
+    }
+
+    // whether the file changed since the last call
+    pub fn changed(&self) -> bool {
+        self.changes.try_iter().count() > 0
+    }
+}
@@ -0,0 +1,139 @@
+use crate::client::{Cutypr, ExecuteOptions, Session};
+use crate::error::Error;
+use crate::events::OutputEvent;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Map, Value};
+use std::io;
+use std::net::TcpListener;
+use std::thread;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::warn;
+
+// how many frames a cell can have waiting for a slow client
+const EVENTS_CAPACITY: usize = 256;
+
+// Listen for --ws-port clients on every interface, each with its own session
+// on the kernel, for as long as jupyterm runs.
+//
+// A client sends execute_requests as JSON, either whole Jupyter messages or
+// just their content, and gets back every message the kernel sends about
+// one as an OutputEvent frame, the execute_reply too.
+pub fn serve(port: u16, session: Session, ports: Value) -> Result<(), Error> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_io()
+        .build()?;
+
+    thread::spawn(move || {
+        runtime.block_on(async move {
+            let listener = match tokio::net::TcpListener::from_std(listener) {
+                Ok(listener) => listener,
+                Err(err) => return warn!(%err, "couldn't listen for websockets"),
+            };
+            let mut clients = 0;
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        warn!(%err, "couldn't accept a websocket client");
+                        continue;
+                    }
+                };
+                clients += 1;
+                let session = Session {
+                    key: session.key.clone(),
+                    session_id: format!("{}-ws-{}", session.session_id, clients),
+                    signature_scheme: session.signature_scheme,
+                };
+                let ports = ports.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = bridge(stream, session, ports).await {
+                        warn!(%err, "websocket client went away");
+                    }
+                });
+            }
+        })
+    });
+    Ok(())
+}
+
+async fn bridge(stream: TcpStream, session: Session, ports: Value) -> Result<(), Error> {
+    let mut websocket = tokio_tungstenite::accept_async(stream).await?;
+    // the client blocks on the kernel, so it only runs where that's allowed
+    let mut client = tokio::task::spawn_blocking(move || {
+        let mut client = Cutypr::new(session, ports);
+        client.initialize_channels();
+        client
+    })
+    .await
+    .map_err(io::Error::other)?;
+
+    while let Some(message) = websocket.next().await {
+        let text = match message? {
+            Message::Text(text) => text,
+            Message::Close(_) => return Ok(()),
+            _ => continue,
+        };
+        match execute_request(&text) {
+            Ok((code, options)) => {
+                let (events, mut receiver) = mpsc::channel(EVENTS_CAPACITY);
+                let cell = tokio::task::spawn_blocking(move || {
+                    let msg_id = client.execute_with(&code, &options);
+                    // a client that went away still leaves the kernel to finish
+                    let result = client.for_each_message(&msg_id, |msg| {
+                        let event = serde_json::to_string(&OutputEvent::from_msg(msg))?;
+                        let _ = events.blocking_send(event);
+                        Ok(())
+                    });
+                    (client, result)
+                });
+                while let Some(event) = receiver.recv().await {
+                    websocket.send(Message::text(event)).await?;
+                }
+                let (returned, result) = cell.await.map_err(io::Error::other)?;
+                client = returned;
+                result?;
+            }
+            Err(reason) => {
+                let frame = json!({ "error": reason }).to_string();
+                websocket.send(Message::text(frame)).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// The code and options of a frame, or why it isn't an execute_request.
+// There is nobody to answer input() over the bridge, so stdin stays off.
+fn execute_request(text: &str) -> Result<(String, ExecuteOptions), String> {
+    let frame: Value = serde_json::from_str(text).map_err(|err| err.to_string())?;
+    let content = match frame["header"]["msg_type"].as_str() {
+        Some("execute_request") => &frame["content"],
+        Some(other) => return Err(format!("{} isn't supported, only execute_request", other)),
+        None => &frame,
+    };
+    let code = content["code"]
+        .as_str()
+        .ok_or("execute_request without code")?
+        .to_string();
+
+    let defaults = ExecuteOptions::default();
+    let options = ExecuteOptions {
+        silent: content["silent"].as_bool().unwrap_or(defaults.silent),
+        store_history: content["store_history"]
+            .as_bool()
+            .unwrap_or(defaults.store_history),
+        allow_stdin: false,
+        user_expressions: content["user_expressions"]
+            .as_object()
+            .cloned()
+            .unwrap_or_else(Map::new),
+        stop_on_error: content["stop_on_error"]
+            .as_bool()
+            .unwrap_or(defaults.stop_on_error),
+    };
+    Ok((code, options))
+}
@@ -0,0 +1,173 @@
+use crate::graphics::Mark;
+use crate::html;
+use serde_json::Value;
+use std::collections::HashMap;
+
+// the comm target ipywidgets opens its models on
+pub const TARGET: &str = "jupyter.widget";
+// what a display_data showing a widget has instead of a picture of it
+pub const VIEW_MIME: &str = "application/vnd.jupyter.widget-view+json";
+
+// how wide a progress bar is drawn
+const BAR_WIDTH: usize = 20;
+// boxes in boxes deeper than this aren't looked into
+const MAX_DEPTH: usize = 8;
+
+// The ipywidgets models a kernel has open, by comm id, with their state as
+// comm_open and the comm_msg updates since left it. It's enough to show a
+// line like `[widget: FloatProgress ████░░░░ 20%]` for a widget, tqdm's
+// progress bars and HTML widgets kept up to date, but nothing is drawn or
+// ever sent back to the kernel.
+#[derive(Debug, Default)]
+pub struct Widgets {
+    // each one's state, an object
+    models: HashMap<String, Value>,
+    // the widgets on the screen, and the lines they're on
+    shown: Vec<(String, Mark)>,
+}
+
+impl Widgets {
+    // a comm_open's content, left alone when it isn't a widget's
+    pub fn open(&mut self, content: &Value) {
+        if content["target_name"] != TARGET {
+            return;
+        }
+        let state = &content["data"]["state"];
+        if let Some(id) = content["comm_id"].as_str().filter(|_| state.is_object()) {
+            self.models.insert(id.to_string(), state.clone());
+        }
+    }
+
+    // A comm_msg's content, and the id of the model it changed if it was
+    // a widget's update.
+    pub fn update(&mut self, content: &Value) -> Option<String> {
+        let id = content["comm_id"].as_str()?;
+        let data = &content["data"];
+        if data["method"] != "update" {
+            return None;
+        }
+        let model = self.models.get_mut(id)?.as_object_mut()?;
+        for (key, value) in data["state"].as_object()? {
+            model.insert(key.clone(), value.clone());
+        }
+        Some(id.to_string())
+    }
+
+    // a comm_close's content, the widget's lines stay as they last were
+    pub fn close(&mut self, content: &Value) {
+        if let Some(id) = content["comm_id"].as_str() {
+            self.models.remove(id);
+            self.shown.retain(|(shown, _)| shown != id);
+        }
+    }
+
+    // the ipywidgets model `id` is, like "FloatProgressModel", if it's one
+    pub fn model_name(&self, id: &str) -> Option<&str> {
+        self.models.get(id)?["_model_name"].as_str()
+    }
+
+    // `id` was just shown on the line at `mark`
+    pub fn show(&mut self, id: &str, mark: Mark) {
+        self.shown.push((id.to_string(), mark));
+    }
+
+    // for a new output area, where the marks of the last one mean nothing
+    pub fn forget_shown(&mut self) {
+        self.shown.clear();
+    }
+
+    // The widgets on the screen that show `id`, itself or in a box.
+    pub fn shown_with(&self, id: &str) -> Vec<(String, Mark)> {
+        self.shown
+            .iter()
+            .filter(|(shown, _)| self.contains(shown, id, 0))
+            .cloned()
+            .collect()
+    }
+
+    fn contains(&self, outer: &str, id: &str, depth: usize) -> bool {
+        outer == id
+            || depth < MAX_DEPTH
+                && self
+                    .children(outer)
+                    .any(|child| self.contains(child, id, depth + 1))
+    }
+
+    // the models in a box's children, "IPY_MODEL_<id>" in its state
+    fn children<'a>(&'a self, id: &str) -> impl Iterator<Item = &'a str> {
+        self.models
+            .get(id)
+            .and_then(|state| state["children"].as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|child| child.as_str()?.strip_prefix("IPY_MODEL_"))
+    }
+
+    // The line for widget `id`, at most `width` columns. One whose
+    // comm_open was never seen, like one opened before jupyterm attached,
+    // only says it's a widget.
+    pub fn describe(&self, id: &str, width: usize) -> String {
+        let name = match self.models.get(id) {
+            Some(state) => state["_model_name"].as_str().unwrap_or("Widget"),
+            None => return "[widget]".to_string(),
+        };
+        let name = name.strip_suffix("Model").unwrap_or(name);
+        let line = match self.text(id, 0) {
+            text if text.is_empty() => format!("[widget: {}]", name),
+            text => format!("[widget: {} {}]", name, text),
+        };
+        html::truncate(&line, width.max(2))
+    }
+
+    // What a model shows: a box's children side by side, a progress bar,
+    // or the value of an HTML widget, label or anything else with one,
+    // after its description. A bar on its own says how far along it is,
+    // tqdm puts that in an HTML widget next to it.
+    fn text(&self, id: &str, depth: usize) -> String {
+        let state = match self.models.get(id) {
+            Some(state) => state,
+            None => return String::new(),
+        };
+        let model = state["_model_name"].as_str().unwrap_or("");
+        let value = if state["children"].is_array() {
+            if depth >= MAX_DEPTH {
+                return String::new();
+            }
+            let children: Vec<String> = self
+                .children(id)
+                .map(|child| self.text(child, depth + 1))
+                .filter(|text| !text.is_empty())
+                .collect();
+            children.join(" ")
+        } else if model.ends_with("ProgressModel") {
+            let number = |key: &str| state[key].as_f64().unwrap_or(0.0);
+            let (min, max) = (number("min"), number("max"));
+            let done = if max > min {
+                ((number("value") - min) / (max - min)).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let filled = (done * BAR_WIDTH as f64).round() as usize;
+            let bar = "█".repeat(filled) + &"░".repeat(BAR_WIDTH - filled);
+            if depth == 0 {
+                format!("{} {:.0}%", bar, done * 100.0)
+            } else {
+                bar
+            }
+        } else {
+            match &state["value"] {
+                Value::String(value) if model.starts_with("HTML") => html::text_of(value),
+                Value::String(value) => value.clone(),
+                Value::Null => String::new(),
+                value => value.to_string(),
+            }
+        };
+
+        let description = state["description"].as_str().unwrap_or("");
+        match (description.is_empty(), value.is_empty()) {
+            (true, _) => value,
+            (false, true) => description.to_string(),
+            (false, false) => format!("{} {}", description, value),
+        }
+    }
+}
@@ -0,0 +1,82 @@
+# End to end against a real ipykernel, for the client built with
+# `maturin develop`: pytest tests/python
+import threading
+import time
+
+import pytest
+
+_client = pytest.importorskip("jupyterm._client")
+pytest.importorskip("ipykernel")
+from jupyter_client import KernelManager  # noqa: E402
+
+
+@pytest.fixture(scope="module")
+def kernel():
+    manager = KernelManager()
+    manager.start_kernel()
+    yield manager
+    manager.shutdown_kernel(now=True)
+
+
+@pytest.fixture
+def client(kernel):
+    return _client.Client(kernel.connection_file)
+
+
+def test_execute(client):
+    result = client.execute("print('hello ' + 'wörld')\n1 + 1")
+    assert result["status"] == "ok"
+    assert isinstance(result["execution_count"], int)
+    kinds = [output["msg_type"] for output in result["outputs"]]
+    assert kinds == ["stream", "execute_result"]
+    assert result["outputs"][0]["text"] == "hello wörld\n"
+    assert result["outputs"][1]["data"]["text/plain"] == "2"
+
+
+def test_execute_error(client):
+    result = client.execute("1 / 0")
+    assert result["status"] == "error"
+    assert result["outputs"][-1]["msg_type"] == "error"
+    assert result["outputs"][-1]["ename"] == "ZeroDivisionError"
+
+
+def test_execute_iter(client):
+    messages = list(client.execute_iter("import time; time.sleep(0.1); 'done'"))
+    types = [msg["msg_type"] for msg in messages]
+    assert types[0] == "status"
+    assert "execute_input" in types
+    assert "execute_result" in types
+    assert "execute_reply" in types
+    for msg in messages:
+        assert msg["msg_id"] == msg["header"]["msg_id"]
+        assert msg["parent_header"]["msg_type"] == "execute_request"
+
+
+def test_other_threads_run_while_it_waits(client):
+    ticks = []
+    stop = threading.Event()
+
+    def tick():
+        while not stop.is_set():
+            ticks.append(time.monotonic())
+            time.sleep(0.01)
+
+    thread = threading.Thread(target=tick)
+    thread.start()
+    try:
+        client.execute("import time; time.sleep(0.5)")
+    finally:
+        stop.set()
+        thread.join()
+    assert len(ticks) > 10
+
+
+def test_interrupt_from_another_thread(client):
+    timer = threading.Timer(0.5, client.interrupt)
+    timer.start()
+    started = time.monotonic()
+    result = client.execute("import time; time.sleep(30)")
+    timer.join()
+    assert time.monotonic() - started < 10
+    assert result["status"] == "error"
+    assert result["outputs"][-1]["ename"] == "KeyboardInterrupt"